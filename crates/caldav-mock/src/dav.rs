@@ -0,0 +1,724 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{
+        header::{
+            AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH, RETRY_AFTER,
+            WWW_AUTHENTICATE,
+        },
+        HeaderMap, Method, StatusCode, Uri,
+    },
+    response::{IntoResponse, Response},
+    Router,
+};
+use base64::Engine;
+use minidom::Element;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::{
+    store::{matches_time_range, parse_time, Store},
+    Request, Shared,
+};
+
+static DAV: &str = "DAV:";
+static CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+static CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+static APPLE_ICAL: &str = "http://apple.com/ns/ical/";
+
+/// The prefix of the sync tokens handed out, followed by a revision.
+static SYNC_TOKEN: &str = "http://caldav-mock/sync/";
+
+type Prop = (String, String);
+
+type DavResult = Result<Response, Failure>;
+
+/// A request which could not be carried out, answered with a status and an explanation.
+#[derive(Debug)]
+struct Failure {
+    status: StatusCode,
+    body: String,
+}
+
+impl Failure {
+    fn new(status: StatusCode, body: impl Into<String>) -> Failure {
+        Failure {
+            status,
+            body: body.into(),
+        }
+    }
+}
+
+impl From<StatusCode> for Failure {
+    fn from(status: StatusCode) -> Failure {
+        Failure::new(status, "")
+    }
+}
+
+impl IntoResponse for Failure {
+    fn into_response(self) -> Response {
+        let content_type = if self.body.starts_with('<') {
+            "application/xml; charset=utf-8"
+        } else {
+            "text/plain; charset=utf-8"
+        };
+        (self.status, [(CONTENT_TYPE, content_type)], self.body).into_response()
+    }
+}
+
+pub(crate) fn router(shared: Arc<Shared>) -> Router {
+    Router::new().fallback(handle).with_state(shared)
+}
+
+/// What a path refers to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Resource {
+    /// The server root, which only knows who the current user is.
+    Root,
+    /// The current user's principal, which is also their home set unless the quirks separate them.
+    Principal {
+        home: bool,
+    },
+    Home,
+    Calendar(String),
+    Item(String, String),
+}
+
+impl Resource {
+    fn href(&self, shared: &Shared) -> String {
+        match self {
+            Resource::Root => shared.quirks.prefix.clone(),
+            Resource::Principal { .. } => shared.principal_path(),
+            Resource::Home => shared.home_set_path(),
+            Resource::Calendar(path) => path.clone(),
+            Resource::Item(calendar, name) => format!("{calendar}{name}"),
+        }
+    }
+}
+
+/// Collapse repeated slashes, as Radicale does.
+fn normalize(path: &str) -> String {
+    let mut normalized = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c == '/' && normalized.ends_with('/') {
+            continue;
+        }
+        normalized.push(c);
+    }
+    normalized
+}
+
+/// Split a path into its parent collection (with a trailing slash) and last segment.
+fn split_path(path: &str) -> Option<(String, String)> {
+    let trimmed = path.trim_end_matches('/');
+    let (parent, name) = trimmed.rsplit_once('/')?;
+    Some((format!("{parent}/"), name.to_string()))
+}
+
+fn resolve(shared: &Shared, store: &Store, path: &str) -> Option<Resource> {
+    let collection = format!("{}/", path.trim_end_matches('/'));
+    if collection == shared.principal_path() {
+        return Some(Resource::Principal {
+            home: !shared.quirks.separate_home_set,
+        });
+    }
+    if collection == shared.home_set_path() {
+        return Some(Resource::Home);
+    }
+    if store.calendar(&collection).is_some() {
+        return Some(Resource::Calendar(collection));
+    }
+    if let Some((parent, name)) = split_path(path) {
+        if store
+            .calendar(&parent)
+            .is_some_and(|calendar| calendar.items.contains_key(&name))
+        {
+            return Some(Resource::Item(parent, name));
+        }
+    }
+    if collection == "/" || collection == shared.quirks.prefix {
+        return Some(Resource::Root);
+    }
+    None
+}
+
+fn children(shared: &Shared, store: &Store, resource: &Resource) -> Vec<Resource> {
+    match resource {
+        Resource::Principal { home: true } | Resource::Home => {
+            let home = shared.home_set_path();
+            store
+                .calendars
+                .keys()
+                .filter(|path| split_path(path).is_some_and(|(parent, _)| parent == home))
+                .map(|path| Resource::Calendar(path.clone()))
+                .collect()
+        }
+        Resource::Calendar(path) => store
+            .calendar(path)
+            .map(|calendar| {
+                calendar
+                    .items
+                    .keys()
+                    .map(|name| Resource::Item(path.clone(), name.clone()))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Characters which are percent-encoded in hrefs, leaving the separators between segments.
+const PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+fn href(shared: &Shared, path: &str) -> String {
+    let encoded = utf8_percent_encode(path, PATH).to_string();
+    let href = if shared.quirks.absolute_hrefs {
+        format!("{}{encoded}", shared.origin)
+    } else {
+        encoded
+    };
+    format!("<d:href>{}</d:href>", escape(&href))
+}
+
+/// The decoded path of a request, or of an href sent by a client, which may be a full url.
+fn request_path(href: &str) -> String {
+    let path = match href.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => href,
+    };
+    percent_decode_str(&normalize(path))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// The value of a property as XML, or `None` if the resource does not have it.
+fn prop_value(
+    shared: &Shared,
+    store: &Store,
+    resource: &Resource,
+    (ns, name): (&str, &str),
+) -> Option<String> {
+    let calendar = match resource {
+        Resource::Calendar(path) => store.calendar(path),
+        _ => None,
+    };
+    let item = match resource {
+        Resource::Item(path, name) => store.calendar(path).and_then(|c| c.items.get(name)),
+        _ => None,
+    };
+
+    match (ns, name) {
+        (ns, "current-user-principal") if ns == DAV => Some(href(shared, &shared.principal_path())),
+        (ns, "resourcetype") if ns == DAV => Some(match resource {
+            Resource::Root | Resource::Home => "<d:collection/>".to_string(),
+            Resource::Principal { home } => {
+                let collection = if *home { "<d:collection/>" } else { "" };
+                format!("{collection}<d:principal/>")
+            }
+            Resource::Calendar(_) => format!("<d:collection/><calendar xmlns=\"{CALDAV}\"/>"),
+            Resource::Item(..) => String::new(),
+        }),
+        (ns, "displayname") if ns == DAV => {
+            calendar.and_then(|c| c.display_name.as_deref()).map(escape)
+        }
+        (ns, "getetag") if ns == DAV => item.map(|item| escape(&item.etag)),
+        (ns, "getcontenttype") if ns == DAV => {
+            item.map(|_| "text/calendar; charset=utf-8".to_string())
+        }
+        (ns, "sync-token") if ns == DAV => calendar.map(|c| format!("{SYNC_TOKEN}{}", c.revision)),
+        (ns, "calendar-home-set") if ns == CALDAV => match resource {
+            Resource::Principal { .. } => Some(href(shared, &shared.home_set_path())),
+            _ => None,
+        },
+        (ns, "supported-calendar-component-set") if ns == CALDAV => calendar.map(|c| {
+            c.components
+                .iter()
+                .map(|comp| format!("<comp xmlns=\"{CALDAV}\" name=\"{}\"/>", escape(comp)))
+                .collect()
+        }),
+        (ns, "calendar-data") if ns == CALDAV => item.map(|item| escape(&item.data)),
+        (ns, "calendar-timezone") if ns == APPLE_ICAL || ns == CALDAV => calendar
+            .and_then(|c| c.timezone.as_deref())
+            .map(|timezone| {
+                if shared.quirks.timezone_as_vtimezone {
+                    escape(&vtimezone(timezone))
+                } else {
+                    escape(timezone)
+                }
+            }),
+        (ns, "calendar-description") if ns == CALDAV => {
+            calendar.and_then(|c| c.description.as_deref()).map(escape)
+        }
+        (ns, "calendar-color") if ns == APPLE_ICAL => {
+            calendar.and_then(|c| c.color.as_deref()).map(escape)
+        }
+        (ns, "calendar-order") if ns == APPLE_ICAL => {
+            calendar.and_then(|c| c.order.as_deref()).map(escape)
+        }
+        (ns, "getctag") if ns == CALENDARSERVER => calendar.map(|c| c.revision.to_string()),
+        _ => None,
+    }
+}
+
+/// A minimal VCALENDAR holding a VTIMEZONE, as Nextcloud returns for calendar-timezone.
+fn vtimezone(timezone: &str) -> String {
+    format!(
+        "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//caldav-mock//EN\r\n\
+         BEGIN:VTIMEZONE\r\nTZID:{timezone}\r\nBEGIN:STANDARD\r\n\
+         DTSTART:19700101T000000\r\nTZOFFSETFROM:+0000\r\nTZOFFSETTO:+0000\r\n\
+         END:STANDARD\r\nEND:VTIMEZONE\r\nEND:VCALENDAR\r\n"
+    )
+}
+
+/// The TZID of a calendar-timezone value, which is the TZID itself if it isn't a VCALENDAR.
+fn tzid(timezone: &str) -> Option<String> {
+    if !timezone.trim().starts_with("BEGIN:VCALENDAR") {
+        return Some(timezone.trim().to_string());
+    }
+    timezone
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("TZID:"))
+        .map(str::to_string)
+}
+
+fn prop_element((ns, name): (&str, &str), value: &str) -> String {
+    if value.is_empty() {
+        format!("<{name} xmlns=\"{ns}\"/>")
+    } else {
+        format!("<{name} xmlns=\"{ns}\">{value}</{name}>")
+    }
+}
+
+/// A response element for a resource, with found properties in one propstat
+/// and missing ones in another, as real servers do.
+fn prop_response(shared: &Shared, store: &Store, resource: &Resource, props: &[Prop]) -> String {
+    let mut found = String::new();
+    let mut missing = String::new();
+    for (ns, name) in props {
+        let prop = (ns.as_str(), name.as_str());
+        match prop_value(shared, store, resource, prop) {
+            Some(value) => found.push_str(&prop_element(prop, &value)),
+            None => missing.push_str(&prop_element(prop, "")),
+        }
+    }
+
+    let mut propstats = String::new();
+    if !found.is_empty() {
+        propstats.push_str(&format!(
+            "<d:propstat><d:prop>{found}</d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>"
+        ));
+    }
+    if !missing.is_empty() {
+        propstats.push_str(&format!(
+            "<d:propstat><d:prop>{missing}</d:prop><d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>"
+        ));
+    }
+    format!(
+        "<d:response>{}{propstats}</d:response>",
+        href(shared, &resource.href(shared))
+    )
+}
+
+fn status_response(shared: &Shared, path: &str, status: &str) -> String {
+    format!(
+        "<d:response>{}<d:status>HTTP/1.1 {status}</d:status></d:response>",
+        href(shared, path)
+    )
+}
+
+fn multistatus(responses: Vec<String>, extra: &str) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<d:multistatus xmlns:d=\"DAV:\">{}{extra}</d:multistatus>",
+        responses.concat()
+    );
+    (
+        StatusCode::MULTI_STATUS,
+        [(CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+/// The properties asked for in a request body's prop element.
+/// An empty body or allprop asks for a default set.
+fn requested_props(root: Option<&Element>) -> Vec<Prop> {
+    let prop = root.and_then(|root| root.children().find(|c| c.name() == "prop"));
+    match prop {
+        Some(prop) => prop
+            .children()
+            .map(|c| (c.ns(), c.name().to_string()))
+            .collect(),
+        None => [
+            (DAV, "resourcetype"),
+            (DAV, "displayname"),
+            (DAV, "getetag"),
+            (DAV, "getcontenttype"),
+        ]
+        .iter()
+        .map(|(ns, name)| (ns.to_string(), name.to_string()))
+        .collect(),
+    }
+}
+
+fn parse_body(body: &str) -> Result<Option<Element>, Failure> {
+    if body.trim().is_empty() {
+        return Ok(None);
+    }
+    body.trim()
+        .parse()
+        .map(Some)
+        .map_err(|e| Failure::new(StatusCode::BAD_REQUEST, format!("invalid xml: {e}")))
+}
+
+fn authorized(shared: &Shared, headers: &HeaderMap) -> bool {
+    let expected = base64::engine::general_purpose::STANDARD
+        .encode(format!("{}:{}", shared.username, shared.password));
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .is_some_and(|given| given == expected)
+}
+
+async fn handle(
+    State(shared): State<Arc<Shared>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let path = request_path(uri.path());
+    tracing::debug!("mock caldav {} {}", method, path);
+    shared.requests.lock().unwrap().push(Request {
+        method: method.clone(),
+        path: path.clone(),
+        headers: headers.clone(),
+    });
+
+    if let Some(status) = shared.failures.lock().unwrap().pop_front() {
+        return match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                (status, [(RETRY_AFTER, "0")]).into_response()
+            }
+            _ => status.into_response(),
+        };
+    }
+
+    if !authorized(&shared, &headers) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, "Basic realm=\"caldav-mock\"")],
+        )
+            .into_response();
+    }
+
+    let result = match method.as_str() {
+        "OPTIONS" => Ok((
+            StatusCode::OK,
+            [("DAV", "1, 2, 3, calendar-access, extended-mkcol")],
+        )
+            .into_response()),
+        "PROPFIND" => propfind(&shared, &path, &headers, &body),
+        "REPORT" => report(&shared, &path, &body),
+        "GET" => get(&shared, &path),
+        "PUT" => put(&shared, &path, &headers, body),
+        "DELETE" => delete(&shared, &path, &headers),
+        "MKCOL" | "MKCALENDAR" => mkcol(&shared, &path, &body),
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED.into()),
+    };
+    if let Some(status) = shared.lost_responses.lock().unwrap().pop_front() {
+        return (status, [(RETRY_AFTER, "0")]).into_response();
+    }
+    result.unwrap_or_else(IntoResponse::into_response)
+}
+
+fn propfind(shared: &Shared, path: &str, headers: &HeaderMap, body: &str) -> DavResult {
+    let root = parse_body(body)?;
+    let props = requested_props(root.as_ref());
+    let depth = headers
+        .get("Depth")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("0");
+
+    let store = shared.store.lock().unwrap();
+    let resource = resolve(shared, &store, path).ok_or(StatusCode::NOT_FOUND)?;
+    let mut resources = vec![resource.clone()];
+    if depth != "0" {
+        resources.extend(children(shared, &store, &resource));
+    }
+
+    let responses = resources
+        .iter()
+        .map(|resource| prop_response(shared, &store, resource, &props))
+        .collect();
+    Ok(multistatus(responses, ""))
+}
+
+fn report(shared: &Shared, path: &str, body: &str) -> DavResult {
+    let root = parse_body(body)?.ok_or(StatusCode::BAD_REQUEST)?;
+    let props = requested_props(Some(&root));
+    let store = shared.store.lock().unwrap();
+    let resource = resolve(shared, &store, path).ok_or(StatusCode::NOT_FOUND)?;
+
+    match root.name() {
+        "calendar-query" => {
+            let Resource::Calendar(calendar_path) = &resource else {
+                return Err(StatusCode::FORBIDDEN.into());
+            };
+            let (component, range) = query_filter(&root);
+            let range = range.filter(|_| shared.quirks.filter_time_range);
+            let responses = children(shared, &store, &resource)
+                .iter()
+                .filter(|item| match item {
+                    Resource::Item(_, name) => store
+                        .calendar(calendar_path)
+                        .and_then(|c| c.items.get(name))
+                        .is_some_and(|item| matches_time_range(&item.data, &component, range)),
+                    _ => false,
+                })
+                .map(|item| prop_response(shared, &store, item, &props))
+                .collect();
+            Ok(multistatus(responses, ""))
+        }
+        "calendar-multiget" => {
+            let responses = root
+                .children()
+                .filter(|c| c.name() == "href")
+                .map(|c| {
+                    let path = request_path(&c.text());
+                    match resolve(shared, &store, &path) {
+                        Some(item @ Resource::Item(..)) => {
+                            prop_response(shared, &store, &item, &props)
+                        }
+                        _ => status_response(shared, &path, "404 Not Found"),
+                    }
+                })
+                .collect();
+            Ok(multistatus(responses, ""))
+        }
+        "sync-collection" => {
+            let Resource::Calendar(calendar_path) = &resource else {
+                return Err(StatusCode::FORBIDDEN.into());
+            };
+            let calendar = store.calendar(calendar_path).ok_or(StatusCode::NOT_FOUND)?;
+            let token = root
+                .children()
+                .find(|c| c.name() == "sync-token")
+                .map(|c| c.text())
+                .unwrap_or_default();
+            let since = match token.trim() {
+                "" => 0,
+                token => token
+                    .strip_prefix(SYNC_TOKEN)
+                    .and_then(|revision| revision.parse().ok())
+                    .filter(|revision| *revision <= calendar.revision)
+                    .ok_or_else(invalid_sync_token)?,
+            };
+
+            let responses = calendar
+                .changes
+                .iter()
+                .filter(|(_, revision)| **revision > since)
+                .filter_map(|(name, _)| {
+                    if calendar.items.contains_key(name) {
+                        let item = Resource::Item(calendar_path.clone(), name.clone());
+                        Some(prop_response(shared, &store, &item, &props))
+                    } else if since > 0 {
+                        Some(status_response(
+                            shared,
+                            &format!("{calendar_path}{name}"),
+                            "404 Not Found",
+                        ))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            let token = format!(
+                "<d:sync-token>{SYNC_TOKEN}{}</d:sync-token>",
+                calendar.revision
+            );
+            Ok(multistatus(responses, &token))
+        }
+        _ => Err(StatusCode::NOT_IMPLEMENTED.into()),
+    }
+}
+
+fn invalid_sync_token() -> Failure {
+    Failure::new(
+        StatusCode::FORBIDDEN,
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<d:error xmlns:d=\"DAV:\"><d:valid-sync-token/></d:error>",
+    )
+}
+
+type TimeRange = (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>);
+
+/// The component and time range a calendar-query filters on.
+/// Only the usual VCALENDAR > component > time-range shape is understood.
+fn query_filter(root: &Element) -> (String, Option<TimeRange>) {
+    let inner = root
+        .children()
+        .find(|c| c.name() == "filter")
+        .and_then(|filter| filter.children().find(|c| c.name() == "comp-filter"))
+        .and_then(|calendar| calendar.children().find(|c| c.name() == "comp-filter"));
+    let Some(inner) = inner else {
+        return ("VEVENT".to_string(), None);
+    };
+
+    let component = inner.attr("name").unwrap_or("VEVENT").to_string();
+    let range = inner
+        .children()
+        .find(|c| c.name() == "time-range")
+        .map(|range| {
+            let bound = |name, default| {
+                range
+                    .attr(name)
+                    .and_then(parse_time)
+                    .map(|(time, _)| time)
+                    .unwrap_or(default)
+            };
+            (
+                bound("start", chrono::DateTime::<chrono::Utc>::MIN_UTC),
+                bound("end", chrono::DateTime::<chrono::Utc>::MAX_UTC),
+            )
+        });
+    (component, range)
+}
+
+fn get(shared: &Shared, path: &str) -> DavResult {
+    let store = shared.store.lock().unwrap();
+    let Some(Resource::Item(calendar, name)) = resolve(shared, &store, path) else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let item = store
+        .calendar(&calendar)
+        .and_then(|c| c.items.get(&name))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok((
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, "text/calendar; charset=utf-8".to_string()),
+            (ETAG, item.etag.clone()),
+        ],
+        item.data.clone(),
+    )
+        .into_response())
+}
+
+fn put(shared: &Shared, path: &str, headers: &HeaderMap, body: String) -> DavResult {
+    let (calendar_path, name) = split_path(path).ok_or(StatusCode::CONFLICT)?;
+    let mut store = shared.store.lock().unwrap();
+    let calendar = store.calendar(&calendar_path).ok_or(StatusCode::CONFLICT)?;
+    let existing = calendar.items.get(&name).map(|item| item.etag.clone());
+
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let precondition_failed = match (header(IF_NONE_MATCH), header(IF_MATCH)) {
+        (Some("*"), _) => existing.is_some(),
+        (_, Some(etag)) => existing.as_deref() != Some(etag),
+        _ => false,
+    };
+    if precondition_failed {
+        return Err(StatusCode::PRECONDITION_FAILED.into());
+    }
+    if icalendar::parser::read_calendar(&icalendar::parser::unfold(&body)).is_err() {
+        return Err(Failure::new(
+            StatusCode::BAD_REQUEST,
+            "request body is not a valid icalendar object",
+        ));
+    }
+
+    let (etag, replaced) = store
+        .put_item(&calendar_path, &name, body)
+        .ok_or(StatusCode::CONFLICT)?;
+    let status = if replaced {
+        shared.quirks.update_status
+    } else {
+        StatusCode::CREATED
+    };
+    Ok((status, [(ETAG, etag)]).into_response())
+}
+
+fn delete(shared: &Shared, path: &str, headers: &HeaderMap) -> DavResult {
+    let mut store = shared.store.lock().unwrap();
+    match resolve(shared, &store, path) {
+        Some(Resource::Item(calendar, name)) => {
+            let existing = store
+                .calendar(&calendar)
+                .and_then(|c| c.items.get(&name))
+                .map(|item| item.etag.as_str());
+            let if_match = headers.get(IF_MATCH).and_then(|value| value.to_str().ok());
+            if if_match.is_some_and(|etag| existing != Some(etag)) {
+                return Err(StatusCode::PRECONDITION_FAILED.into());
+            }
+            store.delete_item(&calendar, &name);
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        Some(Resource::Calendar(calendar)) => {
+            store.calendars.remove(&calendar);
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        Some(_) => Err(StatusCode::FORBIDDEN.into()),
+        None => Err(StatusCode::NOT_FOUND.into()),
+    }
+}
+
+/// Create a calendar with an extended MKCOL (RFC 5689) or a MKCALENDAR (RFC 4791),
+/// whose bodies only differ in their root element.
+fn mkcol(shared: &Shared, path: &str, body: &str) -> DavResult {
+    let collection = format!("{}/", path.trim_end_matches('/'));
+    let mut store = shared.store.lock().unwrap();
+    if resolve(shared, &store, &collection).is_some() {
+        return Err(StatusCode::METHOD_NOT_ALLOWED.into());
+    }
+    if split_path(&collection).map(|(parent, _)| parent) != Some(shared.home_set_path()) {
+        return Err(StatusCode::CONFLICT.into());
+    }
+
+    let root = parse_body(body)?;
+    let prop = root.as_ref().and_then(|root| {
+        root.children()
+            .find(|c| c.name() == "set")
+            .and_then(|set| set.children().find(|c| c.name() == "prop"))
+    });
+    let text = |name: &str| {
+        prop.and_then(|prop| prop.children().find(|c| c.name() == name))
+            .map(|c| c.text())
+    };
+    let components: Vec<String> = prop
+        .and_then(|prop| {
+            prop.children()
+                .find(|c| c.name() == "supported-calendar-component-set")
+        })
+        .map(|set| {
+            set.children()
+                .filter_map(|comp| comp.attr("name"))
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // the timezone is stored by its TZID, whether it is set by id or with a VTIMEZONE
+    let timezone = text("calendar-timezone-id")
+        .or_else(|| text("calendar-timezone").and_then(|timezone| tzid(&timezone)));
+    store.create_calendar(&collection, text("displayname"), timezone);
+    if let Some(calendar) = store.calendars.get_mut(&collection) {
+        calendar.description = text("calendar-description");
+        calendar.color = text("calendar-color");
+        calendar.order = text("calendar-order");
+        if !components.is_empty() {
+            calendar.components = components;
+        }
+    }
+    Ok(StatusCode::CREATED.into_response())
+}
@@ -0,0 +1,161 @@
+use std::{collections::BTreeMap, path::Path};
+
+use base64::Engine;
+
+/// The credentials recorded fixtures use in place of real ones.
+pub static FIXTURE_USERNAME: &str = "user";
+pub static FIXTURE_PASSWORD: &str = "password";
+
+/// Request headers worth keeping in a fixture. Anything else, including
+/// Authorization and cookies, is dropped when recording.
+static REQUEST_HEADERS: &[&str] = &["content-type", "depth", "if-match", "if-none-match"];
+static RESPONSE_HEADERS: &[&str] = &["content-type", "dav", "etag", "location"];
+/// Request headers which change what a server does, so a replayed request must have
+/// the same ones as the recorded request to be given its response.
+static MATCHED_HEADERS: &[&str] = &["depth", "if-match", "if-none-match"];
+/// Properties of icalendar objects which the client generates, so differ between runs.
+static GENERATED_PROPERTIES: &[&str] = &["UID:", "DTSTAMP:"];
+
+/// A request sent to a server and the response it gave.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Interaction {
+    pub method: String,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub request_headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub request_body: String,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub response_headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub response_body: String,
+}
+
+impl Interaction {
+    /// Keep only the headers that matter for replaying, with lowercase names.
+    pub(crate) fn keep_headers<'a>(
+        headers: impl Iterator<Item = (&'a str, &'a str)>,
+        request: bool,
+    ) -> BTreeMap<String, String> {
+        let allowed = if request {
+            REQUEST_HEADERS
+        } else {
+            RESPONSE_HEADERS
+        };
+        headers
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+            .filter(|(name, _)| allowed.contains(&name.as_str()))
+            .collect()
+    }
+
+    /// Whether a request is the same as the recorded one, ignoring how its body is laid out
+    /// and the parts of it which are generated.
+    pub(crate) fn matches(&self, headers: &BTreeMap<String, String>, body: &str) -> bool {
+        MATCHED_HEADERS
+            .iter()
+            .all(|name| self.request_headers.get(*name) == headers.get(*name))
+            && normalize_body(&self.request_body) == normalize_body(body)
+    }
+
+    /// The collection a request's path is in, used to match items whose names were generated.
+    pub(crate) fn parent(path: &str) -> &str {
+        let trimmed = path.trim_end_matches('/');
+        match trimmed.rfind('/') {
+            Some(index) => &trimmed[..=index],
+            None => "/",
+        }
+    }
+}
+
+/// A request body without indentation, blank lines or generated icalendar properties.
+pub(crate) fn normalize_body(body: &str) -> String {
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .filter(|line| !GENERATED_PROPERTIES.iter().any(|p| line.starts_with(p)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Replace `from` wherever it is a whole segment of a path, e.g. in a request's path
+/// or in the hrefs of a response, leaving it alone where it is part of other text.
+fn replace_segment(text: &str, from: &str, to: &str) -> String {
+    let needle = format!("/{from}");
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find(&needle) {
+        let after = &rest[index + needle.len()..];
+        result.push_str(&rest[..index]);
+        if after.is_empty() || after.starts_with(['/', '<', '"', '?', '#']) {
+            result.push('/');
+            result.push_str(to);
+        } else {
+            result.push_str(&needle);
+        }
+        rest = after;
+    }
+    result.push_str(rest);
+    result
+}
+
+/// A conversation with one CalDAV server, which can be replayed in tests.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Fixture {
+    /// The server and version the fixture came from, e.g. "Radicale 3.1.8"
+    pub server: String,
+    /// How the fixture was made, and anything else worth knowing about it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// The path clients start from, i.e. the path of the url they are configured with
+    pub prefix: String,
+    /// The display name of the calendar the interactions use
+    pub calendar: String,
+    pub interactions: Vec<Interaction>,
+}
+
+impl Fixture {
+    pub fn load(path: &Path) -> std::io::Result<Fixture> {
+        let text = std::fs::read_to_string(path)?;
+        serde_json::from_str(&text).map_err(std::io::Error::other)
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let text = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+        std::fs::write(path, text + "\n")
+    }
+
+    /// Replace the real credentials with the fixture ones.
+    /// Any Authorization header is replaced, and the username wherever it is a segment of
+    /// a path, such as a principal's url, so that the paths stay consistent with each other.
+    /// Other text, e.g. event summaries or email addresses, is left as it was.
+    pub fn scrub(&mut self, username: &str) {
+        let encoded = username.replace('@', "%40");
+        let scrub = |text: &mut String| {
+            if username.is_empty() {
+                return;
+            }
+            for from in [username, encoded.as_str()] {
+                *text = replace_segment(text, from, FIXTURE_USERNAME);
+            }
+        };
+        let authorization = format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD
+                .encode(format!("{FIXTURE_USERNAME}:{FIXTURE_PASSWORD}"))
+        );
+
+        scrub(&mut self.prefix);
+        for interaction in &mut self.interactions {
+            scrub(&mut interaction.path);
+            scrub(&mut interaction.request_body);
+            scrub(&mut interaction.response_body);
+            if let Some(value) = interaction.request_headers.get_mut("authorization") {
+                *value = authorization.clone();
+            }
+            if let Some(location) = interaction.response_headers.get_mut("location") {
+                scrub(location);
+            }
+        }
+    }
+}
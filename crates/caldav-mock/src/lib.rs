@@ -0,0 +1,305 @@
+//! An in-memory CalDAV server for testing clients without a real server.
+//! It implements enough of WebDAV and CalDAV for discovery, queries and
+//! writing items, and can be told to behave like the servers the scheduler is used with.
+//!
+//! Conversations with real servers can also be recorded with a [`Recorder`]
+//! and replayed later with a [`ReplayServer`].
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::http::{HeaderMap, Method, StatusCode};
+
+mod dav;
+pub mod fixture;
+pub mod record;
+pub mod replay;
+mod store;
+
+pub use fixture::{Fixture, Interaction};
+pub use record::Recorder;
+pub use replay::ReplayServer;
+pub use store::Item;
+use store::Store;
+
+/// Differences between CalDAV servers that clients have to cope with.
+#[derive(Clone, Debug)]
+pub struct Quirks {
+    /// The path the server is rooted at, which is where clients are pointed.
+    pub prefix: String,
+    /// Whether calendars live in a different collection from the principal,
+    /// as in Nextcloud, rather than directly under it, as in Radicale.
+    pub separate_home_set: bool,
+    /// The status returned when a PUT replaces an existing item.
+    pub update_status: StatusCode,
+    /// Whether calendar-query time ranges are applied.
+    /// When false every item with a matching component is returned.
+    pub filter_time_range: bool,
+    /// Whether calendar-timezone is returned as a VCALENDAR with a VTIMEZONE,
+    /// rather than as the bare timezone name it was set to.
+    pub timezone_as_vtimezone: bool,
+    /// Whether hrefs are absolute urls, rather than absolute paths.
+    pub absolute_hrefs: bool,
+}
+
+impl Quirks {
+    pub fn radicale() -> Quirks {
+        Quirks {
+            prefix: "/".to_string(),
+            separate_home_set: false,
+            update_status: StatusCode::CREATED,
+            filter_time_range: true,
+            timezone_as_vtimezone: false,
+            absolute_hrefs: false,
+        }
+    }
+
+    pub fn nextcloud() -> Quirks {
+        Quirks {
+            prefix: "/remote.php/dav/".to_string(),
+            separate_home_set: true,
+            update_status: StatusCode::NO_CONTENT,
+            filter_time_range: true,
+            timezone_as_vtimezone: true,
+            absolute_hrefs: false,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::radicale()
+    }
+}
+
+/// A request the server received, for asserting on what a client did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub headers: HeaderMap,
+}
+
+/// The state shared between the server and the handle used by tests.
+#[derive(Debug)]
+pub(crate) struct Shared {
+    pub(crate) quirks: Quirks,
+    /// The scheme and authority the server is reached at, for absolute hrefs.
+    pub(crate) origin: String,
+    pub(crate) username: String,
+    pub(crate) password: String,
+    pub(crate) store: Mutex<Store>,
+    pub(crate) requests: Mutex<Vec<Request>>,
+    /// Statuses to answer the next requests with instead of handling them.
+    pub(crate) failures: Mutex<VecDeque<StatusCode>>,
+    /// Statuses to answer the next requests with after handling them.
+    pub(crate) lost_responses: Mutex<VecDeque<StatusCode>>,
+}
+
+impl Shared {
+    pub(crate) fn principal_path(&self) -> String {
+        if self.quirks.separate_home_set {
+            format!("{}principals/users/{}/", self.quirks.prefix, self.username)
+        } else {
+            format!("{}{}/", self.quirks.prefix, self.username)
+        }
+    }
+
+    pub(crate) fn home_set_path(&self) -> String {
+        if self.quirks.separate_home_set {
+            format!("{}calendars/{}/", self.quirks.prefix, self.username)
+        } else {
+            self.principal_path()
+        }
+    }
+}
+
+/// The configuration for a mock server, which is started with [`MockCaldav::start`].
+#[derive(Clone, Debug)]
+pub struct MockCaldav {
+    quirks: Quirks,
+    username: String,
+    password: String,
+    calendars: Vec<(String, String)>,
+}
+
+impl MockCaldav {
+    pub fn new(quirks: Quirks) -> MockCaldav {
+        MockCaldav {
+            quirks,
+            username: "user".to_string(),
+            password: "password".to_string(),
+            calendars: Vec::new(),
+        }
+    }
+
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.username = username.to_string();
+        self.password = password.to_string();
+        self
+    }
+
+    /// Create a calendar in the home set before the server starts.
+    pub fn with_calendar(mut self, id: &str, display_name: &str) -> Self {
+        self.calendars
+            .push((id.to_string(), display_name.to_string()));
+        self
+    }
+
+    /// Start serving on a random local port.
+    pub async fn start(self) -> std::io::Result<MockServer> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            quirks: self.quirks,
+            origin: format!("http://{addr}"),
+            username: self.username,
+            password: self.password,
+            store: Mutex::new(Store::default()),
+            requests: Mutex::new(Vec::new()),
+            failures: Mutex::new(VecDeque::new()),
+            lost_responses: Mutex::new(VecDeque::new()),
+        });
+        {
+            let home = shared.home_set_path();
+            let mut store = shared.store.lock().unwrap();
+            for (id, display_name) in &self.calendars {
+                store.create_calendar(
+                    &format!("{home}{id}/"),
+                    Some(display_name.clone()),
+                    Some("UTC".to_string()),
+                );
+            }
+        }
+
+        let server = axum::Server::from_tcp(listener)
+            .map_err(std::io::Error::other)?
+            .serve(dav::router(shared.clone()).into_make_service());
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                tracing::error!("mock caldav server failed: {}", e);
+            }
+        });
+
+        Ok(MockServer { addr, shared })
+    }
+}
+
+/// A running mock server. It stops when the runtime it was started on shuts down.
+#[derive(Clone, Debug)]
+pub struct MockServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+impl MockServer {
+    /// The url clients should be configured with.
+    pub fn url(&self) -> String {
+        format!("http://{}{}", self.addr, self.shared.quirks.prefix)
+    }
+
+    pub fn username(&self) -> &str {
+        &self.shared.username
+    }
+
+    pub fn password(&self) -> &str {
+        &self.shared.password
+    }
+
+    /// The path of a calendar in the home set.
+    pub fn calendar_path(&self, id: &str) -> String {
+        format!("{}{}/", self.shared.home_set_path(), id)
+    }
+
+    /// Store an item directly, bypassing HTTP.
+    pub fn put_item(&self, calendar_id: &str, name: &str, data: &str) {
+        let path = self.calendar_path(calendar_id);
+        let mut store = self.shared.store.lock().unwrap();
+        store
+            .put_item(&path, name, data.to_string())
+            .expect("calendar does not exist");
+    }
+
+    /// The items in a calendar, by name.
+    pub fn items(&self, calendar_id: &str) -> Vec<(String, Item)> {
+        let path = self.calendar_path(calendar_id);
+        let store = self.shared.store.lock().unwrap();
+        store
+            .calendar(&path)
+            .map(|calendar| {
+                calendar
+                    .items
+                    .iter()
+                    .map(|(name, item)| (name.clone(), item.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The display names of every calendar in the home set.
+    pub fn calendar_names(&self) -> Vec<String> {
+        let store = self.shared.store.lock().unwrap();
+        store
+            .calendars
+            .values()
+            .filter_map(|calendar| calendar.display_name.clone())
+            .collect()
+    }
+
+    /// The properties of the calendar with the given display name, by their names
+    /// without namespaces, for asserting on how it was created.
+    pub fn calendar_properties(&self, display_name: &str) -> BTreeMap<String, String> {
+        let store = self.shared.store.lock().unwrap();
+        let Some(calendar) = store
+            .calendars
+            .values()
+            .find(|calendar| calendar.display_name.as_deref() == Some(display_name))
+        else {
+            return BTreeMap::new();
+        };
+        [
+            ("displayname", calendar.display_name.clone()),
+            ("calendar-timezone", calendar.timezone.clone()),
+            ("calendar-description", calendar.description.clone()),
+            ("calendar-color", calendar.color.clone()),
+            ("calendar-order", calendar.order.clone()),
+            (
+                "supported-calendar-component-set",
+                Some(calendar.components.join(",")),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value?)))
+        .collect()
+    }
+
+    /// Every request received so far, oldest first.
+    pub fn requests(&self) -> Vec<Request> {
+        self.shared.requests.lock().unwrap().clone()
+    }
+
+    pub fn clear_requests(&self) {
+        self.shared.requests.lock().unwrap().clear();
+    }
+
+    /// Answer the next `count` requests with the given status rather than handling them,
+    /// e.g. to test how a client copes with a server which is briefly unavailable.
+    /// 429 and 503 responses ask for the request to be retried immediately with Retry-After.
+    pub fn fail_next(&self, count: usize, status: StatusCode) {
+        let mut failures = self.shared.failures.lock().unwrap();
+        failures.extend(std::iter::repeat_n(status, count));
+    }
+
+    /// Handle the next `count` requests, but answer them with the given status,
+    /// as when a gateway times out after the server has acted on a request.
+    pub fn lose_next(&self, count: usize, status: StatusCode) {
+        let mut lost = self.shared.lost_responses.lock().unwrap();
+        lost.extend(std::iter::repeat_n(status, count));
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,169 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+
+use crate::fixture::{Fixture, Interaction};
+
+/// Request headers that belong to the connection to the proxy rather than the request.
+static HOP_HEADERS: &[&str] = &["host", "connection", "content-length", "transfer-encoding"];
+
+#[derive(Debug)]
+struct Recording {
+    /// The scheme, host and port of the real server.
+    origin: String,
+    /// The scheme, host and port of the proxy, which replaces the origin in responses.
+    local: Mutex<String>,
+    client: reqwest::Client,
+    interactions: Mutex<Vec<Interaction>>,
+}
+
+/// A proxy which forwards requests to a real CalDAV server and records them,
+/// so that a client's conversation with the server can be saved as a [`Fixture`].
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    addr: SocketAddr,
+    prefix: String,
+    recording: Arc<Recording>,
+}
+
+impl Recorder {
+    /// Start recording requests to the server at the given url.
+    pub async fn start(upstream: &str) -> std::io::Result<Recorder> {
+        let upstream = url::Url::parse(upstream)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let origin = upstream.origin().ascii_serialization();
+        let recording = Arc::new(Recording {
+            origin,
+            local: Mutex::new(String::new()),
+            client: reqwest::Client::new(),
+            interactions: Mutex::new(Vec::new()),
+        });
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        *recording.local.lock().unwrap() = format!("http://{addr}");
+        let router = Router::new()
+            .fallback(forward)
+            .with_state(recording.clone());
+        let server = axum::Server::from_tcp(listener)
+            .map_err(std::io::Error::other)?
+            .serve(router.into_make_service());
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                tracing::error!("recording proxy failed: {}", e);
+            }
+        });
+
+        Ok(Recorder {
+            addr,
+            prefix: upstream.path().to_string(),
+            recording,
+        })
+    }
+
+    /// The url clients should be configured with instead of the real server's.
+    pub fn url(&self) -> String {
+        format!("http://{}{}", self.addr, self.prefix)
+    }
+
+    /// The interactions recorded so far, with the given username scrubbed.
+    /// Passwords are only sent in the Authorization header, which isn't recorded.
+    pub fn fixture(&self, server: &str, calendar: &str, username: &str) -> Fixture {
+        let mut fixture = Fixture {
+            server: server.to_string(),
+            note: None,
+            prefix: self.prefix.clone(),
+            calendar: calendar.to_string(),
+            interactions: self.recording.interactions.lock().unwrap().clone(),
+        };
+        fixture.scrub(username);
+        fixture
+    }
+}
+
+async fn forward(
+    State(recording): State<Arc<Recording>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let path = uri
+        .path_and_query()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| uri.path().to_string());
+
+    let mut req = recording
+        .client
+        .request(method.clone(), format!("{}{}", recording.origin, path));
+    for (name, value) in &headers {
+        if !HOP_HEADERS.contains(&name.as_str()) {
+            req = req.header(name, value);
+        }
+    }
+    let res = match req.body(body.clone()).send().await {
+        Ok(res) => res,
+        Err(e) => return (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    };
+
+    let status = res.status();
+    let response_headers = res.headers().clone();
+    let response_body = match res.text().await {
+        Ok(text) => text,
+        Err(e) => return (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    };
+
+    // absolute urls are made relative in the fixture, and point at the proxy for the client
+    let local = recording.local.lock().unwrap().clone();
+    let header_pairs = |headers: &HeaderMap| -> Vec<(String, String)> {
+        headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect()
+    };
+    let request_pairs = header_pairs(&headers);
+    let response_pairs: Vec<(String, String)> = header_pairs(&response_headers)
+        .into_iter()
+        .map(|(name, value)| (name, value.replace(&recording.origin, "")))
+        .collect();
+    recording.interactions.lock().unwrap().push(Interaction {
+        method: method.to_string(),
+        path: uri.path().to_string(),
+        request_headers: Interaction::keep_headers(
+            request_pairs.iter().map(|(n, v)| (n.as_str(), v.as_str())),
+            true,
+        ),
+        request_body: String::from_utf8_lossy(&body).to_string(),
+        status: status.as_u16(),
+        response_headers: Interaction::keep_headers(
+            response_pairs.iter().map(|(n, v)| (n.as_str(), v.as_str())),
+            false,
+        ),
+        response_body: response_body.replace(&recording.origin, ""),
+    });
+
+    let mut response = (status, response_body.replace(&recording.origin, &local)).into_response();
+    for (name, value) in &response_headers {
+        if HOP_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        let value = value
+            .to_str()
+            .ok()
+            .and_then(|value| HeaderValue::from_str(&value.replace(&recording.origin, &local)).ok())
+            .unwrap_or_else(|| value.clone());
+        if let Ok(name) = HeaderName::from_bytes(name.as_str().as_bytes()) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
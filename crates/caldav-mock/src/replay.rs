@@ -0,0 +1,160 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+
+use crate::fixture::{Fixture, Interaction};
+
+#[derive(Debug)]
+struct Replay {
+    /// The recorded interactions, and whether each has been replayed yet.
+    interactions: Mutex<Vec<(Interaction, bool)>>,
+}
+
+impl Replay {
+    /// Find the earliest unused interaction for a request, which must have the same
+    /// headers and body as the recorded request.
+    /// Item and collection names are often generated by the client, so when no
+    /// interaction has the same path those in the same parent collection are used.
+    fn take(
+        &self,
+        method: &Method,
+        path: &str,
+        headers: &BTreeMap<String, String>,
+        body: &str,
+    ) -> Result<Interaction, String> {
+        let mut interactions = self.interactions.lock().unwrap();
+        let unused_where = |same: &dyn Fn(&Interaction) -> bool| -> Vec<usize> {
+            interactions
+                .iter()
+                .enumerate()
+                .filter(|(_, (i, used))| !used && i.method == method.as_str() && same(i))
+                .map(|(index, _)| index)
+                .collect()
+        };
+        let mut candidates = unused_where(&|i| i.path == path);
+        if candidates.is_empty() {
+            candidates =
+                unused_where(&|i| Interaction::parent(&i.path) == Interaction::parent(path));
+        }
+        let Some(first) = candidates.first() else {
+            return Err(format!("no recorded response for {method} {path}"));
+        };
+        let index = candidates
+            .iter()
+            .copied()
+            .find(|index| interactions[*index].0.matches(headers, body))
+            .ok_or_else(|| {
+                let expected = &interactions[*first].0;
+                format!(
+                    "{method} {path} does not match the recorded request, \
+                     which had headers {:?} and body {:?}",
+                    expected.request_headers, expected.request_body
+                )
+            })?;
+        interactions[index].1 = true;
+        Ok(interactions[index].0.clone())
+    }
+}
+
+/// A server which answers requests with the responses from a [`Fixture`].
+#[derive(Clone, Debug)]
+pub struct ReplayServer {
+    addr: SocketAddr,
+    prefix: String,
+    replay: Arc<Replay>,
+}
+
+impl ReplayServer {
+    pub async fn start(fixture: Fixture) -> std::io::Result<ReplayServer> {
+        let replay = Arc::new(Replay {
+            interactions: Mutex::new(
+                fixture
+                    .interactions
+                    .into_iter()
+                    .map(|interaction| (interaction, false))
+                    .collect(),
+            ),
+        });
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let router = Router::new().fallback(respond).with_state(replay.clone());
+        let server = axum::Server::from_tcp(listener)
+            .map_err(std::io::Error::other)?
+            .serve(router.into_make_service());
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                tracing::error!("replay server failed: {}", e);
+            }
+        });
+
+        Ok(ReplayServer {
+            addr,
+            prefix: fixture.prefix,
+            replay,
+        })
+    }
+
+    /// The url clients should be configured with.
+    pub fn url(&self) -> String {
+        format!("http://{}{}", self.addr, self.prefix)
+    }
+
+    /// The interactions which have not been replayed,
+    /// which means the client did less than it did when the fixture was recorded.
+    pub fn unused(&self) -> Vec<Interaction> {
+        self.replay
+            .interactions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, used)| !used)
+            .map(|(interaction, _)| interaction.clone())
+            .collect()
+    }
+}
+
+async fn respond(
+    State(replay): State<Arc<Replay>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let headers = Interaction::keep_headers(
+        headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?))),
+        true,
+    );
+    let body = String::from_utf8_lossy(&body);
+    let interaction = match replay.take(&method, uri.path(), &headers, &body) {
+        Ok(interaction) => interaction,
+        Err(e) => {
+            tracing::warn!("{}", e);
+            return (StatusCode::NOT_IMPLEMENTED, e).into_response();
+        }
+    };
+
+    let status = StatusCode::from_u16(interaction.status).unwrap_or(StatusCode::OK);
+    let mut response = (status, interaction.response_body).into_response();
+    for (name, value) in &interaction.response_headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
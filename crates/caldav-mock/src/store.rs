@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+
+/// A calendar object resource.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Item {
+    pub etag: String,
+    pub data: String,
+}
+
+/// A calendar collection and its history, for answering sync-collection reports.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Collection {
+    pub(crate) display_name: Option<String>,
+    pub(crate) timezone: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) color: Option<String>,
+    pub(crate) order: Option<String>,
+    pub(crate) components: Vec<String>,
+    pub(crate) items: BTreeMap<String, Item>,
+    /// The revision each item was last changed or removed in.
+    pub(crate) changes: BTreeMap<String, u64>,
+    pub(crate) revision: u64,
+}
+
+/// Everything the server holds, keyed by collection path.
+#[derive(Debug, Default)]
+pub(crate) struct Store {
+    revision: u64,
+    pub(crate) calendars: BTreeMap<String, Collection>,
+}
+
+impl Store {
+    fn next_revision(&mut self) -> u64 {
+        self.revision += 1;
+        self.revision
+    }
+
+    pub(crate) fn calendar(&self, path: &str) -> Option<&Collection> {
+        self.calendars.get(path)
+    }
+
+    pub(crate) fn create_calendar(
+        &mut self,
+        path: &str,
+        display_name: Option<String>,
+        timezone: Option<String>,
+    ) {
+        let revision = self.next_revision();
+        self.calendars.insert(
+            path.to_string(),
+            Collection {
+                display_name,
+                timezone,
+                components: vec!["VEVENT".to_string(), "VTODO".to_string()],
+                revision,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Store an item, returning its new etag and whether it replaced an existing one.
+    /// Returns `None` if the calendar does not exist.
+    pub(crate) fn put_item(
+        &mut self,
+        calendar_path: &str,
+        name: &str,
+        data: String,
+    ) -> Option<(String, bool)> {
+        if !self.calendars.contains_key(calendar_path) {
+            return None;
+        }
+        let revision = self.next_revision();
+        let calendar = self.calendars.get_mut(calendar_path)?;
+        let etag = format!("\"{revision}\"");
+        let replaced = calendar
+            .items
+            .insert(
+                name.to_string(),
+                Item {
+                    etag: etag.clone(),
+                    data,
+                },
+            )
+            .is_some();
+        calendar.changes.insert(name.to_string(), revision);
+        calendar.revision = revision;
+        Some((etag, replaced))
+    }
+
+    /// Remove an item, returning whether it existed.
+    pub(crate) fn delete_item(&mut self, calendar_path: &str, name: &str) -> bool {
+        let revision = self.revision + 1;
+        let Some(calendar) = self.calendars.get_mut(calendar_path) else {
+            return false;
+        };
+        if calendar.items.remove(name).is_none() {
+            return false;
+        }
+        calendar.changes.insert(name.to_string(), revision);
+        calendar.revision = revision;
+        self.revision = revision;
+        true
+    }
+}
+
+/// Whether an icalendar object has a component of the given kind which may overlap a range.
+/// Recurring components always match, and times with a TZID are treated as UTC,
+/// which is close enough for the ranges clients query.
+pub(crate) fn matches_time_range(
+    data: &str,
+    component: &str,
+    range: Option<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>,
+) -> bool {
+    let unfolded = icalendar::parser::unfold(data);
+    let Ok(calendar) = icalendar::parser::read_calendar(&unfolded) else {
+        return false;
+    };
+    calendar
+        .components
+        .iter()
+        .filter(|c| c.name.as_str() == component)
+        .any(|c| {
+            let Some((start, end)) = range else {
+                return true;
+            };
+            let value = |name: &str| {
+                c.properties
+                    .iter()
+                    .find(|p| p.name.as_str() == name)
+                    .map(|p| p.val.as_str().to_string())
+            };
+            if value("RRULE").is_some() {
+                return true;
+            }
+            let Some((item_start, all_day)) = value("DTSTART").as_deref().and_then(parse_time)
+            else {
+                // components without a start, such as a VAVAILABILITY, cover all time
+                return true;
+            };
+            let item_end = match value("DTEND").as_deref().and_then(parse_time) {
+                Some((end, _)) => end,
+                None if all_day => item_start + chrono::Duration::days(1),
+                None => item_start,
+            };
+            item_start < end && start <= item_end
+        })
+}
+
+/// Parse an icalendar DATE or DATE-TIME, returning whether it was a date.
+pub(crate) fn parse_time(value: &str) -> Option<(chrono::DateTime<chrono::Utc>, bool)> {
+    let value = value.trim_end_matches('Z');
+    if let Ok(datetime) = chrono::NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S") {
+        return Some((chrono::DateTime::from_utc(datetime, chrono::Utc), false));
+    }
+    let date = chrono::NaiveDate::parse_from_str(value, "%Y%m%d").ok()?;
+    Some((
+        chrono::DateTime::from_utc(date.and_hms_opt(0, 0, 0)?, chrono::Utc),
+        true,
+    ))
+}
@@ -0,0 +1,115 @@
+use reqwest::{Method, StatusCode};
+
+use crate::{MockCaldav, MockServer, Quirks};
+
+static EVENT: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\n\
+    BEGIN:VEVENT\r\nUID:one\r\nDTSTAMP:20231201T000000Z\r\n\
+    DTSTART:20231219T100000Z\r\nDTEND:20231219T103000Z\r\n\
+    END:VEVENT\r\nEND:VCALENDAR\r\n";
+
+async fn request(
+    server: &MockServer,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: String,
+) -> Result<(StatusCode, String), Box<dyn std::error::Error>> {
+    let url = reqwest::Url::parse(&server.url())?.join(path)?;
+    let mut req = reqwest::Client::new()
+        .request(Method::from_bytes(method.as_bytes())?, url)
+        .basic_auth(server.username(), Some(server.password()))
+        .header("Depth", "1");
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+    let res = req.body(body).send().await?;
+    Ok((res.status(), res.text().await?))
+}
+
+fn sync_body(token: &str) -> String {
+    format!(
+        r#"<d:sync-collection xmlns:d="DAV:">
+          <d:sync-token>{token}</d:sync-token>
+          <d:sync-level>1</d:sync-level>
+          <d:prop><d:getetag/></d:prop>
+        </d:sync-collection>"#
+    )
+}
+
+fn sync_token(body: &str) -> String {
+    let root: minidom::Element = body.parse().unwrap();
+    root.children()
+        .find(|c| c.name() == "sync-token")
+        .unwrap()
+        .text()
+}
+
+#[tokio::test]
+async fn sync_collection_reports_changes() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let calendar = server.calendar_path("booked");
+    server.put_item("booked", "one.ics", EVENT);
+
+    let (status, body) = request(&server, "REPORT", &calendar, &[], sync_body("")).await?;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    assert!(body.contains("one.ics"));
+    let token = sync_token(&body);
+
+    // nothing has changed since the first sync
+    let (_, body) = request(&server, "REPORT", &calendar, &[], sync_body(&token)).await?;
+    assert!(!body.contains("one.ics"));
+    assert_eq!(sync_token(&body), token);
+
+    // deletions are reported as 404 responses
+    let item = format!("{calendar}one.ics");
+    let (status, _) = request(&server, "DELETE", &item, &[], String::new()).await?;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, body) = request(&server, "REPORT", &calendar, &[], sync_body(&token)).await?;
+    assert!(body.contains("one.ics") && body.contains("404 Not Found"));
+
+    let (status, body) = request(&server, "REPORT", &calendar, &[], sync_body("bogus")).await?;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(body.contains("valid-sync-token"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn multiget_and_preconditions() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::nextcloud())
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let calendar = server.calendar_path("booked");
+    server.put_item("booked", "one.ics", EVENT);
+
+    let body = format!(
+        r#"<c:calendar-multiget xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+          <d:prop><d:getetag/><c:calendar-data/></d:prop>
+          <d:href>{calendar}one.ics</d:href>
+          <d:href>{calendar}missing.ics</d:href>
+        </c:calendar-multiget>"#
+    );
+    let (status, body) = request(&server, "REPORT", &calendar, &[], body).await?;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    assert!(body.contains("UID:one"));
+    assert!(body.contains("missing.ics") && body.contains("404 Not Found"));
+
+    // replacing an item checks If-Match and answers with the quirk's status
+    let item = format!("{calendar}one.ics");
+    let stale = [("If-Match", "\"stale\"")];
+    let (status, _) = request(&server, "PUT", &item, &stale, EVENT.to_string()).await?;
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+
+    let etag = server.items("booked")[0].1.etag.clone();
+    let current = [("If-Match", etag.as_str())];
+    let (status, _) = request(&server, "PUT", &item, &current, EVENT.to_string()).await?;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let create_only = [("If-None-Match", "*")];
+    let (status, _) = request(&server, "PUT", &item, &create_only, EVENT.to_string()).await?;
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    Ok(())
+}
@@ -1,12 +1,19 @@
-use anyhow::anyhow;
 use chrono::TimeZone;
+use chrono_tz::Tz;
 use icalendar::Component;
-use rrule::{RRule, Tz, Unvalidated};
+use rrule::{RRule, Unvalidated};
 use serde_with::DurationSeconds;
 use tracing::info;
 
-use crate::caldav::{calendar::Calendar, event::Event};
+use crate::caldav::event::{param_value, Event};
 use crate::error::{CaldavError, CaldavResult};
+use crate::source::CalendarSource;
+
+pub mod vavailability;
+pub mod working_hours;
+
+use vavailability::vavailability_matrix;
+use working_hours::WorkingHours;
 
 #[serde_with::serde_as]
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
@@ -34,16 +41,14 @@ pub struct AvailabilityResponse {
     pub matrix: Vec<bool>,
 }
 
-/// Parse a datetime string from icalendar
-/// This may or may not have the timezone specified.
-/// TODO: support timezones other than UTC?
-fn parse_datetime_from_str(tz: &Tz, datetime_str: &str) -> CaldavResult<chrono::DateTime<Tz>> {
-    if datetime_str.ends_with('Z') {
-        let datetime = tz.datetime_from_str(datetime_str, "%Y%m%dT%H%M%SZ")?;
-        Ok(datetime)
-    } else {
-        let datetime = tz.datetime_from_str(datetime_str, "%Y%m%dT%H%M%S")?;
-        Ok(datetime)
+/// The timezone of a calendar, which its floating times and all-day events are in.
+/// Calendars without one are in UTC.
+fn calendar_timezone(timezone: Option<&str>) -> CaldavResult<Tz> {
+    match timezone {
+        None => Ok(chrono_tz::UTC),
+        Some(tzid) => tzid
+            .parse()
+            .map_err(|_| CaldavError::UnknownTimezone(tzid.to_string())),
     }
 }
 
@@ -116,25 +121,241 @@ pub fn generate_matrix_no_rrule(
     Ok(matrix)
 }
 
+/// Parse a date-time value of one of an event's properties into UTC.
+/// Values with a TZID parameter are in that timezone, and floating ones, including whole dates
+/// such as those of all-day events, in the calendar's timezone.
+fn property_datetime(
+    event: &icalendar::Event,
+    property: &icalendar::Property,
+    value: &str,
+    tz: &Tz,
+) -> CaldavResult<chrono::DateTime<chrono::Utc>> {
+    if let Some(utc) = value.strip_suffix('Z') {
+        let datetime = chrono::NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S")?;
+        return Ok(chrono::Utc.from_utc_datetime(&datetime));
+    }
+    let invalid = |reason: String| CaldavError::InvalidICalendar {
+        href: format!("event {}", event.get_uid().unwrap_or_default()),
+        reason,
+    };
+    let zone = match param_value(property, "TZID") {
+        Some(tzid) => tzid
+            .parse()
+            .map_err(|_| invalid(format!("unknown TZID {tzid}")))?,
+        None => *tz,
+    };
+    // whole dates, as in all-day events, start at midnight
+    let local = match is_date(value) {
+        true => {
+            chrono::NaiveDate::parse_from_str(value, "%Y%m%d")?.and_time(chrono::NaiveTime::MIN)
+        }
+        false => chrono::NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")?,
+    };
+    zone.from_local_datetime(&local)
+        .earliest()
+        .map(|dt| dt.with_timezone(&chrono::Utc))
+        .ok_or_else(|| invalid(format!("{value} does not exist in {}", zone.name())))
+}
+
+/// Whether a date-time value is a whole date, e.g. `20231226`.
+fn is_date(value: &str) -> bool {
+    value.len() == 8
+}
+
+/// The start and end of an event, or of the first occurrence of a recurring one,
+/// from its DTEND or else its DURATION. All-day events last at least the day they start on.
+fn event_bounds(
+    event: &icalendar::Event,
+    tz: &Tz,
+) -> CaldavResult<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)> {
+    let dtstart = event_datetime(event, "DTSTART", tz)?;
+    let dtend = match (
+        event.property_value("DTEND"),
+        event.property_value("DURATION"),
+    ) {
+        (None, Some(duration)) => Some(
+            vavailability::parse_duration(duration)
+                .map(|duration| dtstart + duration)
+                .ok_or_else(|| CaldavError::InvalidICalendar {
+                    href: format!("event {}", event.get_uid().unwrap_or_default()),
+                    reason: format!("invalid DURATION {duration}"),
+                })?,
+        ),
+        (Some(_), _) => Some(event_datetime(event, "DTEND", tz)?),
+        (None, None) => None,
+    };
+    if !is_date(required_property(event, "DTSTART")?) {
+        return dtend
+            .ok_or_else(|| CaldavError::MissingProperty {
+                resource: format!("event {}", event.get_uid().unwrap_or_default()),
+                property: "DTEND".to_string(),
+            })
+            .map(|dtend| (dtstart, dtend));
+    }
+    let day_end = dtstart + chrono::Duration::days(1);
+    Ok((
+        dtstart,
+        dtend.map_or(day_end, |dtend| std::cmp::max(dtend, day_end)),
+    ))
+}
+
+/// The value of a date-time property of an event in UTC, e.g. its DTSTART.
+fn event_datetime(
+    event: &icalendar::Event,
+    name: &str,
+    tz: &Tz,
+) -> CaldavResult<chrono::DateTime<chrono::Utc>> {
+    let value = required_property(event, name)?;
+    property_datetime(event, &event.properties()[name], value, tz)
+}
+
 fn get_rruleset(event: &icalendar::Event, tz: &Tz) -> CaldavResult<Option<rrule::RRuleSet>> {
     let rrule_str = match event.property_value("RRULE") {
         None => return Ok(None),
         Some(rule) => rule,
     };
 
-    let dtstart = {
-        let as_str = event
-            .property_value("DTSTART")
-            .ok_or_else(|| CaldavError::Anyhow(anyhow!("DTSTART not found")))?;
-        let dtstart_local = parse_datetime_from_str(tz, as_str)?;
-        Tz::UTC.from_utc_datetime(&dtstart_local.naive_utc())
+    let dtstart_str = required_property(event, "DTSTART")?;
+    let tzid = match param_value(&event.properties()["DTSTART"], "TZID") {
+        _ if dtstart_str.ends_with('Z') => None,
+        Some(tzid) => Some(tzid),
+        // floating times are in the calendar's timezone
+        None if *tz != chrono_tz::UTC && !is_date(dtstart_str) => Some(tz.name().to_string()),
+        None => None,
+    };
+    let mut rrule = match tzid {
+        // occurrences are stepped in the event's own timezone,
+        // so that they keep their local time across daylight saving changes
+        Some(tzid) => format!("DTSTART;TZID={tzid}:{dtstart_str}\nRRULE:{rrule_str}").parse()?,
+        None => {
+            let dtstart = event_datetime(event, "DTSTART", tz)?;
+            let rrule: RRule<Unvalidated> = rrule_str.parse()?;
+            rrule.build(rrule::Tz::UTC.from_utc_datetime(&dtstart.naive_utc()))?
+        }
     };
 
-    let rrule: RRule<Unvalidated> = rrule_str.parse()?;
-    let rrule = rrule.build(dtstart)?;
+    // occurrences excluded with EXDATE do not cover any slots
+    let exdates = event
+        .properties()
+        .values()
+        .chain(event.multi_properties().iter())
+        .filter(|p| p.key() == "EXDATE")
+        .flat_map(|p| p.value().split(',').map(move |value| (p, value)));
+    for (property, exdate) in exdates {
+        match property_datetime(event, property, exdate, tz) {
+            Ok(exdate) => {
+                rrule = rrule.exdate(rrule::Tz::UTC.from_utc_datetime(&exdate.naive_utc()))
+            }
+            Err(e) => tracing::warn!("ignoring EXDATE {}: {}", exdate, e),
+        }
+    }
     Ok(Some(rrule))
 }
 
+/// How many occurrences of a recurrence set are expanded at a time.
+const OCCURRENCES_PER_PAGE: u16 = 1000;
+
+/// The most occurrences of a recurrence set that are expanded within a range,
+/// which only something recurring every few seconds or minutes over a long range reaches.
+const MAX_OCCURRENCES: usize = 100_000;
+
+/// Every occurrence of a recurrence set from `after` to `before` inclusive, up to
+/// [`MAX_OCCURRENCES`] of them, expanded a page at a time.
+pub(crate) fn expand_rruleset(
+    set: &rrule::RRuleSet,
+    after: chrono::DateTime<chrono::Utc>,
+    before: chrono::DateTime<chrono::Utc>,
+) -> Vec<chrono::DateTime<chrono::Utc>> {
+    let mut occurrences = Vec::new();
+    let mut from = after;
+    loop {
+        let (page, limited) = set
+            .clone()
+            .after(rrule::Tz::UTC.from_utc_datetime(&from.naive_utc()))
+            .before(rrule::Tz::UTC.from_utc_datetime(&before.naive_utc()))
+            .all(OCCURRENCES_PER_PAGE);
+        let full = page.len() == usize::from(OCCURRENCES_PER_PAGE);
+        occurrences.extend(page.iter().map(|dt| dt.with_timezone(&chrono::Utc)));
+
+        if occurrences.len() >= MAX_OCCURRENCES {
+            tracing::warn!(
+                "only the first {} occurrences from {} are used",
+                MAX_OCCURRENCES,
+                after
+            );
+            occurrences.truncate(MAX_OCCURRENCES);
+            return occurrences;
+        }
+        match occurrences.last() {
+            // the next page starts after the last occurrence of this one
+            Some(last) if full => from = *last + chrono::Duration::seconds(1),
+            _ => {
+                if limited {
+                    tracing::warn!("expanding the occurrences from {} was cut short", after);
+                }
+                return occurrences;
+            }
+        }
+    }
+}
+
+/// The start and end of each occurrence of a recurring event which ends after `start`
+/// and begins before `end`.
+fn rrule_occurrences(
+    event: &icalendar::Event,
+    tz: &Tz,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+) -> CaldavResult<Vec<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>> {
+    let (dtstart, dtend) = event_bounds(event, tz)?;
+    let duration = dtend - dtstart;
+
+    let rrule = get_rruleset(event, tz)?.ok_or_else(|| CaldavError::MissingProperty {
+        resource: format!("event {}", event.get_uid().unwrap_or_default()),
+        property: "RRULE".to_string(),
+    })?;
+
+    // occurrences which ended before the range can't overlap it, so they are skipped
+    let after = std::cmp::max(dtstart, start - duration);
+    let detected_events = expand_rruleset(&rrule, after, end);
+    tracing::debug!("detected_events: {:#?}", detected_events);
+
+    Ok(detected_events
+        .into_iter()
+        .map(|start| (start, start + duration))
+        .filter(|(begin, finish)| begin < finish && *begin < end && *finish > start)
+        .collect())
+}
+
+/// The start and end of each occurrence of an event which overlaps the given range,
+/// expanding its RRULE if it has one.
+/// Floating times are in the calendar's `timezone`, and all-day events cover whole days
+/// from midnight in it.
+pub fn event_occurrences(
+    event: &Event,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    timezone: Option<String>,
+) -> CaldavResult<Vec<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>> {
+    let tz = calendar_timezone(timezone.as_deref())?;
+    let event = event.vevent().ok_or_else(|| CaldavError::MissingProperty {
+        resource: "icalendar object".to_string(),
+        property: "VEVENT".to_string(),
+    })?;
+
+    match event.property_value("RRULE") {
+        Some(_) => rrule_occurrences(event, &tz, start, end),
+        None => {
+            let (dtstart, dtend) = event_bounds(event, &tz)?;
+            Ok(if dtstart < end && start < dtend {
+                vec![(dtstart, dtend)]
+            } else {
+                Vec::new()
+            })
+        }
+    }
+}
+
 pub fn get_event_matrix(
     start: chrono::DateTime<chrono::Utc>,
     end: chrono::DateTime<chrono::Utc>,
@@ -152,58 +373,28 @@ pub fn get_event_matrix(
     tracing::debug!("generating matrix for event: {:#?}", event);
     // determine the time of the event compared to the requested time range.
     // First, we need to get the properties from the inner icalendar::Event.
-    let comps = &event.ical.components;
-    // Assume there is only one component.
-    let event_comp = comps
-        .iter()
-        .find(|c| matches!(c, icalendar::CalendarComponent::Event(_)));
-    if event_comp.is_none() {
-        return Err(CaldavError::Anyhow(anyhow!(
-            "no event component found in event"
-        )));
+    let event = event.vevent().ok_or_else(|| CaldavError::MissingProperty {
+        resource: "icalendar object".to_string(),
+        property: "VEVENT".to_string(),
+    })?;
+
+    let tz = calendar_timezone(timezone.as_deref())?;
+
+    // all-day events, such as birthdays, don't take up any particular time
+    if is_date(required_property(event, "DTSTART")?) {
+        tracing::debug!(
+            "ignoring all-day event {}",
+            event.get_uid().unwrap_or_default()
+        );
+        return Ok(vec![false; num_slots as usize]);
     }
-    let event = event_comp.unwrap().as_event().unwrap();
-
-    // Get the start and end times of the event.
-    let dtstart_str = event.property_value("DTSTART").unwrap();
-    let dtend_str = event.property_value("DTEND").unwrap();
-    tracing::debug!("dtstart_str: {:#?}", dtstart_str);
-    tracing::debug!("dtend_str: {:#?}", dtend_str);
-
-    let tz = match timezone {
-        Some(tz) => {
-            let tz_str = tz.as_str();
-            match tz_str {
-                "UTC" => Tz::UTC,
-                _ => unimplemented!(),
-            }
-        }
-        _ => Tz::UTC,
-    };
-
-    // TODO: fix formatting of the date string
-    // It may be necessary to add a trailing Z to the date string
-    let str_has_z = dtstart_str.ends_with('Z');
-    let format = if str_has_z {
-        "%Y%m%dT%H%M%SZ"
-    } else {
-        "%Y%m%dT%H%M%S"
-    };
 
     match event.property_value("RRULE") {
         Some(_) => generate_matrix_rrule(event, &tz, start, end, num_slots, granularity),
         None => {
-            let dtstart_local = tz.datetime_from_str(dtstart_str, format).unwrap();
-            let dtend_local = tz.datetime_from_str(dtend_str, format).unwrap();
-            // Convert the start and end times to UTC.
-            let dtstart = chrono::Utc
-                .from_local_datetime(&dtstart_local.naive_utc())
-                .unwrap();
-            let dtend = chrono::Utc
-                .from_local_datetime(&dtend_local.naive_utc())
-                .unwrap();
-            tracing::debug!("dtstart_local: {:#?}", dtstart_local);
-            tracing::debug!("dtend_local: {:#?}", dtend_local);
+            // Get the start and end times of the event in UTC.
+            let dtstart = event_datetime(event, "DTSTART", &tz)?;
+            let dtend = event_datetime(event, "DTEND", &tz)?;
             tracing::debug!("dtstart: {:#?}", dtstart);
             tracing::debug!("dtend: {:#?}", dtend);
             generate_matrix_no_rrule(start, dtstart, dtend, num_slots, granularity)
@@ -211,82 +402,208 @@ pub fn get_event_matrix(
     }
 }
 
-pub async fn calendar_availability(
-    client: &reqwest::Client,
-    calendar: &Calendar,
+/// The value of a property an event can't be placed without.
+fn required_property<'a>(event: &'a icalendar::Event, property: &str) -> CaldavResult<&'a str> {
+    event
+        .property_value(property)
+        .ok_or_else(|| CaldavError::MissingProperty {
+            resource: format!("event {}", event.get_uid().unwrap_or_default()),
+            property: property.to_string(),
+        })
+}
+
+/// Whether an error is caused by the contents of a single event,
+/// rather than something which affects every event.
+fn is_invalid_event(error: &CaldavError) -> bool {
+    matches!(
+        error,
+        CaldavError::InvalidICalendar { .. }
+            | CaldavError::MissingProperty { .. }
+            | CaldavError::ChronoParse(_)
+            | CaldavError::RRule(_)
+            | CaldavError::UnknownTimezone(_)
+    )
+}
+
+/// Combine the matrices of several events into one,
+/// where a slot is true if any of the events covers it.
+pub fn events_matrix(
     start: chrono::DateTime<chrono::Utc>,
     end: chrono::DateTime<chrono::Utc>,
     granularity: chrono::Duration,
+    events: &[Event],
+    timezone: Option<String>,
 ) -> CaldavResult<Vec<bool>> {
     let num_slots = get_num_slots(start, end, granularity);
-    let all_false = vec![false; num_slots];
 
-    Ok(calendar
-        .get_events(client, start, end)
-        .await?
+    events
         .iter()
-        .map(|event| get_event_matrix(start, end, granularity, event, calendar.timezone.clone()))
-        .fold(all_false, |acc: Vec<bool>, x| {
-            let x = x.unwrap();
-            acc.iter().zip(x.iter()).map(|(a, b)| *a || *b).collect()
-        }))
+        .map(|event| get_event_matrix(start, end, granularity, event, timezone.clone()))
+        .try_fold(vec![false; num_slots], |acc: Vec<bool>, x| {
+            let x = match x {
+                Ok(x) => x,
+                // an event which can't be placed doesn't cover any slots
+                Err(e) if is_invalid_event(&e) => {
+                    tracing::warn!("skipping invalid event: {}", e);
+                    return Ok(acc);
+                }
+                Err(e) => return Err(e),
+            };
+            Ok(acc.iter().zip(x.iter()).map(|(a, b)| *a || *b).collect())
+        })
 }
 
-pub async fn get_availability(
-    client: &reqwest::Client,
-    availability: &Calendar,
-    booked: &Calendar,
+/// Build a matrix where a slot is true if any of the given time ranges covers it.
+/// Ranges are placed in the same way as events without an RRULE.
+pub fn ranges_matrix(
     start: chrono::DateTime<chrono::Utc>,
     end: chrono::DateTime<chrono::Utc>,
     granularity: chrono::Duration,
-) -> CaldavResult<AvailabilityResponse> {
-    let duration = end - start;
-    let num_slots = duration.num_minutes() / granularity.num_minutes();
-
-    // lookup events in the calendar
-    let events = availability.get_events(client, start, end).await?;
-    info!("found {} events", events.len());
-    tracing::debug!("events: {:#?}", events);
+    ranges: &[(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)],
+) -> CaldavResult<Vec<bool>> {
+    let num_slots = get_num_slots(start, end, granularity);
 
-    let all_false = vec![false; num_slots as usize];
-    let matrix = events
+    ranges
         .iter()
-        .map(|event| {
-            get_event_matrix(
+        .filter(|(range_start, range_end)| range_start < range_end && *range_end > start)
+        .map(|(range_start, range_end)| {
+            generate_matrix_no_rrule(
                 start,
-                end,
+                *range_start,
+                *range_end,
+                num_slots as i64,
                 granularity,
-                event,
-                availability.timezone.clone(),
             )
         })
-        .fold(all_false, |acc, x| {
-            let x = x.unwrap();
-            acc.iter().zip(x.iter()).map(|(a, b)| *a || *b).collect()
-        });
+        .try_fold(vec![false; num_slots], |acc: Vec<bool>, x| {
+            let x = x?;
+            Ok(acc.iter().zip(x.iter()).map(|(a, b)| *a || *b).collect())
+        })
+}
+
+pub async fn calendar_availability(
+    calendar: &dyn CalendarSource,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    granularity: chrono::Duration,
+) -> CaldavResult<Vec<bool>> {
+    let events = calendar.events(start, end).await?;
+    events_matrix(start, end, granularity, &events, calendar.timezone())
+}
+
+/// Somewhere to find the times that are open for booking.
+#[derive(Clone, Copy, Debug)]
+pub enum AvailabilitySource<'a> {
+    /// Events in a calendar, typically recurring ones
+    Calendar(&'a dyn CalendarSource),
+    /// VAVAILABILITY components in a calendar
+    VAvailability(&'a dyn CalendarSource),
+    /// A template of working hours from configuration
+    WorkingHours(&'a WorkingHours),
+}
+
+impl AvailabilitySource<'_> {
+    pub async fn matrix(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        granularity: chrono::Duration,
+    ) -> CaldavResult<Vec<bool>> {
+        match self {
+            AvailabilitySource::Calendar(calendar) => {
+                calendar_availability(*calendar, start, end, granularity).await
+            }
+            AvailabilitySource::VAvailability(calendar) => {
+                let components = calendar.availabilities(start, end).await?;
+                vavailability_matrix(start, end, granularity, &components)
+            }
+            AvailabilitySource::WorkingHours(hours) => hours.matrix(start, end, granularity),
+        }
+    }
+}
+
+pub async fn get_availability(
+    availability: &dyn CalendarSource,
+    booked: &dyn CalendarSource,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    granularity: chrono::Duration,
+) -> CaldavResult<AvailabilityResponse> {
+    get_availability_excluding(availability, booked, start, end, granularity, &[]).await
+}
+
+/// Determine availability while ignoring the booked events with the given UIDs.
+/// This is useful when moving an existing booking, since its current time
+/// should not prevent it from being moved to an overlapping one.
+pub async fn get_availability_excluding(
+    availability: &dyn CalendarSource,
+    booked: &dyn CalendarSource,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    granularity: chrono::Duration,
+    excluded: &[&str],
+) -> CaldavResult<AvailabilityResponse> {
+    get_availability_from(
+        &[AvailabilitySource::Calendar(availability)],
+        booked,
+        start,
+        end,
+        granularity,
+        excluded,
+    )
+    .await
+}
+
+/// Determine availability from several sources, where a slot is open if any of
+/// them covers it, while ignoring the booked events with the given UIDs.
+pub async fn get_availability_from(
+    sources: &[AvailabilitySource<'_>],
+    booked: &dyn CalendarSource,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    granularity: chrono::Duration,
+    excluded: &[&str],
+) -> CaldavResult<AvailabilityResponse> {
+    let mut matrix = vec![false; get_num_slots(start, end, granularity)];
+    for source in sources {
+        let open = source.matrix(start, end, granularity).await?;
+        matrix = matrix
+            .iter()
+            .zip(open.iter())
+            .map(|(a, b)| *a || *b)
+            .collect();
+    }
 
     // Now, we need to do the same thing for the booked calendar, but we need to
     // invert the matrix modifications so that the booked times are marked as unavailable.
-    let booked_events = booked.get_events(client, start, end).await?;
+    let booked_events: Vec<Event> = booked
+        .events(start, end)
+        .await?
+        .into_iter()
+        .filter(|event| !event.uid().is_some_and(|uid| excluded.contains(&uid)))
+        .collect();
     info!("found {} booked events", booked_events.len());
     tracing::debug!("booked_events: {:#?}", booked_events);
 
-    let booked_matrix = booked_events
-        .iter()
-        .map(|event| get_event_matrix(start, end, granularity, event, booked.timezone.clone()))
-        .fold(matrix, |acc, x| {
-            let x = x.unwrap();
-            acc.iter().zip(x.iter()).map(|(a, b)| *a && !b).collect()
-        });
+    let booked_matrix = events_matrix(start, end, granularity, &booked_events, booked.timezone())?;
 
     Ok(AvailabilityResponse {
         start,
         end,
         granularity,
-        matrix: booked_matrix,
+        matrix: subtract_matrix(&matrix, &booked_matrix),
     })
 }
 
+/// Mark every slot that is set in `busy` as unavailable.
+pub fn subtract_matrix(available: &[bool], busy: &[bool]) -> Vec<bool> {
+    available
+        .iter()
+        .zip(busy.iter())
+        .map(|(a, b)| *a && !b)
+        .collect()
+}
+
 pub fn generate_matrix_rrule(
     // event containing availability
     event: &icalendar::Event,
@@ -298,42 +615,8 @@ pub fn generate_matrix_rrule(
     num_slots: i64,
     granularity: chrono::Duration,
 ) -> CaldavResult<Vec<bool>> {
-    let dtstart = {
-        let dtstart_str = event.property_value("DTSTART").unwrap();
-        tracing::debug!("dtstart: {:#?}", dtstart_str);
-        let dtstart_local = parse_datetime_from_str(tz, dtstart_str)?;
-        chrono::Utc
-            .from_local_datetime(&dtstart_local.naive_utc())
-            .unwrap()
-    };
-    let dtend = {
-        let dtend_str = event.property_value("DTEND").unwrap();
-        let dtend_local = parse_datetime_from_str(tz, dtend_str)?;
-        chrono::Utc
-            .from_local_datetime(&dtend_local.naive_utc())
-            .unwrap()
-    };
-
-    let tz_start = Tz::UTC.from_utc_datetime(&dtstart.naive_utc());
-
-    // Convert the requested time-range to rrule compatible datetimes
-    let range_tz_end = Tz::UTC.from_utc_datetime(&end.naive_utc());
-
-    let rrule = get_rruleset(event, tz)?.unwrap();
-
-    let (detected_events, _) = rrule.after(tz_start).before(range_tz_end).all(100);
-    tracing::debug!("detected_events: {:#?}", detected_events);
-
-    // for each event, determine the time range it covers.
-    let event_duration = dtend - dtstart;
-    let event_ranges = detected_events
-        .iter()
-        .map(|e| {
-            let start = chrono::Utc.from_local_datetime(&e.naive_utc()).unwrap();
-            let end = start + event_duration;
-            (start, end)
-        })
-        .collect::<Vec<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>>();
+    // for each occurrence, determine the time range it covers.
+    let event_ranges = rrule_occurrences(event, tz, start, end)?;
     tracing::debug!("event_ranges: {:#?}", event_ranges);
 
     // Now that the rrule has been resolved to multiple events, we can treat them
@@ -354,10 +637,10 @@ pub fn generate_matrix_rrule(
                 None
             }
         })
-        .fold(vec![false; num_slots as usize], |acc, x| {
-            let x = x.unwrap();
-            acc.iter().zip(x.iter()).map(|(a, b)| *a || *b).collect()
-        });
+        .try_fold(vec![false; num_slots as usize], |acc, x| {
+            let x = x?;
+            Ok::<_, CaldavError>(acc.iter().zip(x.iter()).map(|(a, b)| *a || *b).collect())
+        })?;
 
     Ok(final_matrix)
 }
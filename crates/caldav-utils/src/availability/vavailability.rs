@@ -0,0 +1,487 @@
+use chrono::TimeZone;
+use icalendar::{CalendarComponent, Component};
+
+use crate::{
+    caldav::event::{param_value, Event},
+    error::{CaldavError, CaldavResult},
+    format::DATETIME,
+};
+
+use super::{expand_rruleset, ranges_matrix, AvailabilityResponse};
+
+/// How the time in a VAVAILABILITY's range is busy when no AVAILABLE covers it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BusyType {
+    Busy,
+    #[default]
+    BusyUnavailable,
+    BusyTentative,
+}
+
+impl BusyType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BusyType::Busy => "BUSY",
+            BusyType::BusyUnavailable => "BUSY-UNAVAILABLE",
+            BusyType::BusyTentative => "BUSY-TENTATIVE",
+        }
+    }
+
+    fn parse(value: &str) -> BusyType {
+        match value {
+            "BUSY" => BusyType::Busy,
+            "BUSY-TENTATIVE" => BusyType::BusyTentative,
+            _ => BusyType::BusyUnavailable,
+        }
+    }
+}
+
+/// An AVAILABLE component, a time which is open within its VAVAILABILITY.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Available {
+    pub uid: Option<String>,
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+    /// The TZID of DTSTART, so that recurrences follow daylight saving changes
+    pub timezone: Option<String>,
+    pub summary: Option<String>,
+    pub rrule: Option<String>,
+    pub rdates: Vec<chrono::DateTime<chrono::Utc>>,
+    /// RDATEs given as a PERIOD, which have their own end rather than the component's duration
+    pub rperiods: Vec<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>,
+    pub exdates: Vec<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Available {
+    fn parse(component: &impl Component, default_tz: chrono_tz::Tz) -> CaldavResult<Available> {
+        let missing = |property: &str| CaldavError::MissingProperty {
+            resource: format!(
+                "AVAILABLE {}",
+                component.property_value("UID").unwrap_or_default()
+            ),
+            property: property.to_string(),
+        };
+        let start = component
+            .properties()
+            .get("DTSTART")
+            .and_then(|p| property_datetime(p, default_tz))
+            .ok_or_else(|| missing("DTSTART"))?;
+        let end = component_end(component, start, default_tz).ok_or_else(|| missing("DTEND"))?;
+
+        Ok(Available {
+            uid: component.property_value("UID").map(str::to_string),
+            start,
+            end,
+            timezone: component
+                .properties()
+                .get("DTSTART")
+                .and_then(|p| param_value(p, "TZID")),
+            summary: component.property_value("SUMMARY").map(str::to_string),
+            rrule: component.property_value("RRULE").map(str::to_string),
+            rdates: property_datetimes(component, "RDATE", default_tz),
+            rperiods: property_periods(component, "RDATE", default_tz),
+            exdates: property_datetimes(component, "EXDATE", default_tz),
+        })
+    }
+
+    /// The start and end of every occurrence which overlaps the given range.
+    pub fn occurrences(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>> {
+        let duration = self.end - self.start;
+
+        let mut starts = match &self.rrule {
+            None => vec![self.start],
+            Some(rrule) => {
+                let dtstart = match &self.timezone {
+                    Some(tzid) => {
+                        let tz: chrono_tz::Tz = tzid
+                            .parse()
+                            .map_err(|_| CaldavError::UnknownTimezone(tzid.clone()))?;
+                        let local = self.start.with_timezone(&tz).naive_local();
+                        format!("DTSTART;TZID={tzid}:{}", local.format("%Y%m%dT%H%M%S"))
+                    }
+                    None => format!("DTSTART:{}", self.start.format(DATETIME)),
+                };
+                let set: rrule::RRuleSet = format!("{dtstart}\nRRULE:{rrule}").parse()?;
+                expand_rruleset(&set, start - duration, end)
+            }
+        };
+        starts.extend(self.rdates.iter().copied());
+
+        Ok(starts
+            .into_iter()
+            .map(|dt| (dt, dt + duration))
+            .chain(self.rperiods.iter().copied())
+            .filter(|(open, _)| !self.exdates.contains(open))
+            .filter(|(open, close)| *open < end && start < *close)
+            .collect())
+    }
+}
+
+/// A VAVAILABILITY component (RFC 7953), describing when its owner can be booked.
+/// Within its range, only the times covered by one of its AVAILABLE components are open.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VAvailability {
+    pub uid: Option<String>,
+    /// The start of the range this component covers, or unbounded if not set
+    pub start: Option<chrono::DateTime<chrono::Utc>>,
+    /// The end of the range this component covers, or unbounded if not set
+    pub end: Option<chrono::DateTime<chrono::Utc>>,
+    /// 1 is the highest priority and 9 the lowest, while 0 is undefined and ranks below 9
+    pub priority: u8,
+    /// How the time not covered by an AVAILABLE is busy. Tentatively busy time doesn't
+    /// close slots which a lower priority component leaves open.
+    pub busy_type: BusyType,
+    pub summary: Option<String>,
+    pub available: Vec<Available>,
+}
+
+impl VAvailability {
+    fn parse(component: &impl Component, default_tz: chrono_tz::Tz) -> CaldavResult<VAvailability> {
+        let start = component
+            .properties()
+            .get("DTSTART")
+            .and_then(|p| property_datetime(p, default_tz));
+        let end = match start {
+            Some(start) => component_end(component, start, default_tz),
+            None => None,
+        };
+
+        Ok(VAvailability {
+            uid: component.property_value("UID").map(str::to_string),
+            start,
+            end,
+            priority: component
+                .property_value("PRIORITY")
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(0),
+            busy_type: component
+                .property_value("BUSYTYPE")
+                .map(BusyType::parse)
+                .unwrap_or_default(),
+            summary: component.property_value("SUMMARY").map(str::to_string),
+            available: component
+                .components()
+                .iter()
+                .filter(|c| c.component_kind() == "AVAILABLE")
+                .map(|c| Available::parse(c, default_tz))
+                .collect::<CaldavResult<_>>()?,
+        })
+    }
+
+    /// Find the VAVAILABILITY components in an icalendar object.
+    /// Dates and floating times are in the given timezone, or UTC if there is none.
+    pub fn from_event(event: &Event, timezone: Option<&str>) -> CaldavResult<Vec<VAvailability>> {
+        let default_tz = match timezone {
+            Some(tzid) => tzid
+                .parse()
+                .map_err(|_| CaldavError::UnknownTimezone(tzid.to_string()))?,
+            None => chrono_tz::UTC,
+        };
+        event
+            .ical
+            .components
+            .iter()
+            .filter_map(|c| match c {
+                CalendarComponent::Other(other) if other.component_kind() == "VAVAILABILITY" => {
+                    Some(VAvailability::parse(other, default_tz))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Describe the open slots of an availability matrix, so that it can be published.
+    pub fn from_matrix(uid: &str, availability: &AvailabilityResponse) -> VAvailability {
+        let slot_start = |i: usize| availability.start + availability.granularity * i as i32;
+
+        let mut available = Vec::new();
+        let mut open_since = None;
+        for (i, open) in availability
+            .matrix
+            .iter()
+            .chain(std::iter::once(&false))
+            .enumerate()
+        {
+            match (open, open_since) {
+                (true, None) => open_since = Some(i),
+                (false, Some(first)) => {
+                    available.push(Available {
+                        uid: Some(format!("{uid}-{first}")),
+                        start: slot_start(first),
+                        end: slot_start(i),
+                        timezone: None,
+                        summary: None,
+                        rrule: None,
+                        rdates: Vec::new(),
+                        rperiods: Vec::new(),
+                        exdates: Vec::new(),
+                    });
+                    open_since = None;
+                }
+                _ => {}
+            }
+        }
+
+        VAvailability {
+            uid: Some(uid.to_string()),
+            start: Some(availability.start),
+            end: Some(availability.end),
+            priority: 0,
+            busy_type: BusyType::BusyUnavailable,
+            summary: None,
+            available,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self.priority {
+            0 => 10,
+            priority => priority,
+        }
+    }
+
+    /// Build an icalendar object containing this component.
+    pub fn to_ical(&self) -> icalendar::Calendar {
+        let dtstamp = chrono::Utc::now().format(DATETIME).to_string();
+
+        let mut vavailability = new_component("VAVAILABILITY");
+        if let CalendarComponent::Other(component) = &mut vavailability {
+            component.add_property("DTSTAMP", &dtstamp);
+            if let Some(uid) = &self.uid {
+                component.add_property("UID", uid);
+            }
+            if let Some(start) = self.start {
+                component.add_property("DTSTART", &start.format(DATETIME).to_string());
+            }
+            if let Some(end) = self.end {
+                component.add_property("DTEND", &end.format(DATETIME).to_string());
+            }
+            if self.priority != 0 {
+                component.add_property("PRIORITY", &self.priority.to_string());
+            }
+            component.add_property("BUSYTYPE", self.busy_type.as_str());
+            if let Some(summary) = &self.summary {
+                component.add_property("SUMMARY", summary);
+            }
+
+            for available in &self.available {
+                let CalendarComponent::Other(mut child) = new_component("AVAILABLE") else {
+                    continue;
+                };
+                child.add_property("DTSTAMP", &dtstamp);
+                if let Some(uid) = &available.uid {
+                    child.add_property("UID", uid);
+                }
+                child.add_property("DTSTART", &available.start.format(DATETIME).to_string());
+                child.add_property("DTEND", &available.end.format(DATETIME).to_string());
+                if let Some(summary) = &available.summary {
+                    child.add_property("SUMMARY", summary);
+                }
+                if let Some(rrule) = &available.rrule {
+                    child.add_property("RRULE", rrule);
+                }
+                for (key, dates) in [("RDATE", &available.rdates), ("EXDATE", &available.exdates)] {
+                    for date in dates {
+                        child.append_multi_property(icalendar::Property::new(
+                            key,
+                            &date.format(DATETIME).to_string(),
+                        ));
+                    }
+                }
+                for (open, close) in &available.rperiods {
+                    child.append_multi_property(
+                        icalendar::Property::new(
+                            "RDATE",
+                            &format!("{}/{}", open.format(DATETIME), close.format(DATETIME)),
+                        )
+                        .add_parameter("VALUE", "PERIOD")
+                        .done(),
+                    );
+                }
+                component.append_component(child);
+            }
+        }
+
+        icalendar::Calendar::new().push(vavailability).done()
+    }
+}
+
+/// Build a matrix where a slot is true if the VAVAILABILITY components make it available.
+/// Where components overlap the one with the highest priority decides, unless it is only
+/// tentatively busy, and slots outside of every component are not available.
+pub fn vavailability_matrix(
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    granularity: chrono::Duration,
+    components: &[VAvailability],
+) -> CaldavResult<Vec<bool>> {
+    let mut ordered: Vec<&VAvailability> = components.iter().collect();
+    // apply the lowest priority first, so that higher priorities overwrite it
+    ordered.sort_by_key(|c| std::cmp::Reverse(c.rank()));
+
+    let mut matrix = vec![false; super::get_num_slots(start, end, granularity)];
+    for component in ordered {
+        let range = (
+            component.start.unwrap_or(start),
+            component.end.unwrap_or(end),
+        );
+        let covered = ranges_matrix(start, end, granularity, &[range])?;
+
+        let mut ranges = Vec::new();
+        for available in &component.available {
+            ranges.extend(available.occurrences(start, end)?);
+        }
+        let open = ranges_matrix(start, end, granularity, &ranges)?;
+
+        let tentative = component.busy_type == BusyType::BusyTentative;
+        for ((slot, covered), open) in matrix.iter_mut().zip(covered).zip(open) {
+            if covered && (open || !tentative) {
+                *slot = open;
+            }
+        }
+    }
+    Ok(matrix)
+}
+
+fn new_component(name: &str) -> CalendarComponent {
+    CalendarComponent::from(icalendar::parser::Component {
+        name: name.into(),
+        properties: Vec::new(),
+        components: Vec::new(),
+    })
+}
+
+/// The end of a component from its DTEND, or its DTSTART and DURATION.
+fn component_end(
+    component: &impl Component,
+    start: chrono::DateTime<chrono::Utc>,
+    default_tz: chrono_tz::Tz,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    match component.properties().get("DTEND") {
+        Some(dtend) => property_datetime(dtend, default_tz),
+        None => component
+            .property_value("DURATION")
+            .and_then(parse_duration)
+            .map(|duration| start + duration),
+    }
+}
+
+/// Parse a DATE or DATE-TIME property value into UTC.
+/// Dates start at midnight, and they and floating times are in the default timezone.
+fn property_datetime(
+    property: &icalendar::Property,
+    default_tz: chrono_tz::Tz,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    parse_datetime(property.value(), &property_tz(property, default_tz)?)
+}
+
+/// The timezone of a property's TZID, or the default if it has none.
+fn property_tz(property: &icalendar::Property, default_tz: chrono_tz::Tz) -> Option<chrono_tz::Tz> {
+    match param_value(property, "TZID") {
+        Some(tzid) => tzid.parse().ok(),
+        None => Some(default_tz),
+    }
+}
+
+fn parse_datetime(value: &str, tz: &chrono_tz::Tz) -> Option<chrono::DateTime<chrono::Utc>> {
+    if let Ok(utc) = chrono::NaiveDateTime::parse_from_str(value, DATETIME) {
+        return Some(chrono::Utc.from_utc_datetime(&utc));
+    }
+
+    let local = match chrono::NaiveDate::parse_from_str(value, "%Y%m%d") {
+        Ok(date) => date.and_hms_opt(0, 0, 0)?,
+        Err(_) => chrono::NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?,
+    };
+    let dt = tz.from_local_datetime(&local).earliest()?;
+    Some(dt.with_timezone(&chrono::Utc))
+}
+
+/// The comma separated values of the properties with the given name, with their timezone.
+fn property_values<'a>(
+    component: &'a impl Component,
+    key: &'a str,
+    default_tz: chrono_tz::Tz,
+) -> impl Iterator<Item = (&'a icalendar::Property, &'a str, chrono_tz::Tz)> {
+    component
+        .properties()
+        .values()
+        .chain(component.multi_properties().iter())
+        .filter(move |p| p.key() == key)
+        .filter_map(move |p| Some((p, property_tz(p, default_tz)?)))
+        .flat_map(|(p, tz)| p.value().split(',').map(move |value| (p, value, tz)))
+}
+
+fn is_period(property: &icalendar::Property) -> bool {
+    param_value(property, "VALUE").as_deref() == Some("PERIOD")
+}
+
+/// Every date in the properties with the given name, other than PERIODs.
+fn property_datetimes(
+    component: &impl Component,
+    key: &str,
+    default_tz: chrono_tz::Tz,
+) -> Vec<chrono::DateTime<chrono::Utc>> {
+    property_values(component, key, default_tz)
+        .filter(|(p, _, _)| !is_period(p))
+        .filter_map(|(_, value, tz)| parse_datetime(value, &tz))
+        .collect()
+}
+
+/// Every PERIOD in the properties with the given name, whose end is either
+/// a DATE-TIME or a duration after its start.
+fn property_periods(
+    component: &impl Component,
+    key: &str,
+    default_tz: chrono_tz::Tz,
+) -> Vec<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)> {
+    property_values(component, key, default_tz)
+        .filter(|(p, _, _)| is_period(p))
+        .filter_map(|(_, value, tz)| {
+            let (start, end) = value.split_once('/')?;
+            let start = parse_datetime(start, &tz)?;
+            let end = match parse_duration(end) {
+                Some(duration) => start + duration,
+                None => parse_datetime(end, &tz)?,
+            };
+            Some((start, end))
+        })
+        .collect()
+}
+
+/// Parse an RFC 5545 duration such as "PT1H30M" or "P1W".
+pub(crate) fn parse_duration(value: &str) -> Option<chrono::Duration> {
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let value = value.strip_prefix('P')?;
+
+    let mut duration = chrono::Duration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in value.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' => in_time = true,
+            _ => {
+                let n: i64 = number.parse().ok()?;
+                number.clear();
+                duration = duration
+                    + match (c, in_time) {
+                        ('W', false) => chrono::Duration::weeks(n),
+                        ('D', false) => chrono::Duration::days(n),
+                        ('H', true) => chrono::Duration::hours(n),
+                        ('M', true) => chrono::Duration::minutes(n),
+                        ('S', true) => chrono::Duration::seconds(n),
+                        _ => return None,
+                    };
+            }
+        }
+    }
+
+    Some(if negative { -duration } else { duration })
+}
@@ -0,0 +1,178 @@
+use chrono::{Datelike, TimeZone};
+
+use crate::error::{CaldavError, CaldavResult};
+
+/// A range of time within a day, in the working hours' timezone.
+/// Times are written as "09:00", or "09:00:30" when seconds are needed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Hours {
+    #[serde(with = "time_of_day")]
+    pub start: chrono::NaiveTime,
+    #[serde(with = "time_of_day")]
+    pub end: chrono::NaiveTime,
+}
+
+mod time_of_day {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &chrono::NaiveTime, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(&time.format("%H:%M:%S"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<chrono::NaiveTime, D::Error> {
+        let value = String::deserialize(d)?;
+        chrono::NaiveTime::parse_from_str(&value, "%H:%M:%S")
+            .or_else(|_| chrono::NaiveTime::parse_from_str(&value, "%H:%M"))
+            .map_err(D::Error::custom)
+    }
+}
+
+impl Hours {
+    /// Remove the given ranges, splitting these hours around them.
+    fn without(self, breaks: &[Hours]) -> Vec<Hours> {
+        breaks.iter().fold(vec![self], |hours, pause| {
+            hours
+                .into_iter()
+                .flat_map(|hours| {
+                    if pause.end <= hours.start || hours.end <= pause.start {
+                        return vec![hours];
+                    }
+                    let before = Hours {
+                        start: hours.start,
+                        end: pause.start,
+                    };
+                    let after = Hours {
+                        start: pause.end,
+                        end: hours.end,
+                    };
+                    [before, after]
+                        .into_iter()
+                        .filter(|hours| hours.start < hours.end)
+                        .collect()
+                })
+                .collect()
+        })
+    }
+}
+
+/// The hours that are open on the given days of every week.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct WeeklyHours {
+    pub days: Vec<chrono::Weekday>,
+    #[serde(flatten)]
+    pub hours: Hours,
+}
+
+/// Replace the weekly hours on a specific date, e.g. a shorter day before a holiday.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct DateOverride {
+    pub date: chrono::NaiveDate,
+    /// The hours that are open on the date, or none to close for the whole day
+    #[serde(default)]
+    pub hours: Vec<Hours>,
+}
+
+/// A template of working hours, which can be used instead of (or as well as)
+/// maintaining recurring events in an availability calendar.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct WorkingHours {
+    /// The name of the timezone the hours are in, e.g. "Europe/Berlin"
+    pub timezone: String,
+    pub weekly: Vec<WeeklyHours>,
+    /// Taken out of the weekly hours every day, e.g. a lunch break
+    #[serde(default)]
+    pub breaks: Vec<Hours>,
+    /// Used as-is instead of the weekly hours, so breaks are not taken out of them
+    #[serde(default)]
+    pub overrides: Vec<DateOverride>,
+}
+
+impl WorkingHours {
+    /// Parse working hours from JSON, checking the timezone exists
+    /// and that every range of hours ends after it starts.
+    pub fn from_json(json: &str) -> CaldavResult<WorkingHours> {
+        let hours: WorkingHours = serde_json::from_str(json)
+            .map_err(|e| CaldavError::Anyhow(anyhow::anyhow!("invalid working hours: {e}")))?;
+        hours.tz()?;
+        let ranges = hours
+            .weekly
+            .iter()
+            .map(|weekly| &weekly.hours)
+            .chain(&hours.breaks)
+            .chain(hours.overrides.iter().flat_map(|o| &o.hours));
+        for range in ranges {
+            if range.start >= range.end {
+                return Err(CaldavError::Anyhow(anyhow::anyhow!(
+                    "invalid working hours: {} does not end after it starts at {}",
+                    range.end,
+                    range.start
+                )));
+            }
+        }
+        Ok(hours)
+    }
+
+    pub fn tz(&self) -> CaldavResult<chrono_tz::Tz> {
+        self.timezone
+            .parse()
+            .map_err(|_| CaldavError::UnknownTimezone(self.timezone.clone()))
+    }
+
+    /// The hours that are open on the given date.
+    pub fn hours_on(&self, date: chrono::NaiveDate) -> Vec<Hours> {
+        if let Some(date_override) = self.overrides.iter().find(|o| o.date == date) {
+            return date_override.hours.clone();
+        }
+
+        self.weekly
+            .iter()
+            .filter(|weekly| weekly.days.contains(&date.weekday()))
+            .flat_map(|weekly| weekly.hours.without(&self.breaks))
+            .collect()
+    }
+
+    /// The open time ranges in UTC which overlap the given range.
+    pub fn ranges(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>> {
+        let tz = self.tz()?;
+        let first = start.with_timezone(&tz).date_naive();
+        let last = end.with_timezone(&tz).date_naive();
+
+        let to_utc = |date: chrono::NaiveDate, time: chrono::NaiveTime| {
+            let local = tz.from_local_datetime(&date.and_time(time));
+            // times skipped by a daylight saving change open at the change instead
+            local
+                .earliest()
+                .or_else(|| {
+                    tz.from_local_datetime(&(date.and_time(time) + chrono::Duration::hours(1)))
+                        .earliest()
+                })
+                .map(|dt| dt.with_timezone(&chrono::Utc))
+        };
+
+        let ranges = first
+            .iter_days()
+            .take_while(|date| *date <= last)
+            .flat_map(|date| {
+                self.hours_on(date).into_iter().filter_map(move |hours| {
+                    Some((to_utc(date, hours.start)?, to_utc(date, hours.end)?))
+                })
+            })
+            .filter(|(open, close)| *open < end && start < *close)
+            .collect();
+        Ok(ranges)
+    }
+
+    /// Build a matrix where a slot is true if the working hours cover it.
+    pub fn matrix(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        granularity: chrono::Duration,
+    ) -> CaldavResult<Vec<bool>> {
+        super::ranges_matrix(start, end, granularity, &self.ranges(start, end)?)
+    }
+}
@@ -1,19 +1,212 @@
-use icalendar::{Component, EventLike};
-use minidom::Element;
 use reqwest::Method;
 use url::Url;
 
+use crate::availability::vavailability::VAvailability;
 use crate::error::{CaldavError, CaldavResult};
 use crate::format;
-use crate::util::find_elements;
+use crate::util::{collection_url, escape_xml, find_element, find_elements, parse_xml};
 
 use super::client::DavClient;
-use super::event::Event;
+use super::event::{Event, NewEvent};
+
+static CHANGE_TOKEN_BODY: &str = r#"
+    <d:propfind xmlns:d="DAV:" xmlns:cs="http://calendarserver.org/ns/">
+      <d:prop>
+        <d:sync-token />
+        <cs:getctag />
+      </d:prop>
+    </d:propfind>
+"#;
+
+/// How a calendar is created on the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CreateMethod {
+    /// MKCALENDAR (RFC 4791), which every CalDAV server supports.
+    #[default]
+    MkCalendar,
+    /// An extended MKCOL (RFC 5689).
+    ExtendedMkcol,
+}
+
+impl CreateMethod {
+    pub fn method(&self) -> CaldavResult<Method> {
+        Ok(match self {
+            CreateMethod::MkCalendar => Method::from_bytes(b"MKCALENDAR")?,
+            CreateMethod::ExtendedMkcol => Method::from_bytes(b"MKCOL")?,
+        })
+    }
+}
+
+/// The details of a calendar that is to be created.
+/// Properties which aren't set are left for the server to decide.
+#[derive(Clone, Debug)]
+pub struct NewCalendar {
+    pub display_name: String,
+    pub description: Option<String>,
+    /// A color such as `#ff0000`, as shown by clients.
+    pub color: Option<String>,
+    pub timezone: Option<String>,
+    /// The kinds of components the calendar holds, e.g. VEVENT or VTODO.
+    pub components: Vec<String>,
+    /// Where clients sort the calendar among the others.
+    pub order: Option<u32>,
+    pub method: CreateMethod,
+}
+
+impl NewCalendar {
+    pub fn new(display_name: &str) -> NewCalendar {
+        NewCalendar {
+            display_name: display_name.to_string(),
+            description: None,
+            color: None,
+            timezone: None,
+            components: Vec::new(),
+            order: None,
+            method: CreateMethod::default(),
+        }
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    pub fn color(mut self, color: &str) -> Self {
+        self.color = Some(color.to_string());
+        self
+    }
+
+    pub fn timezone(mut self, timezone: &str) -> Self {
+        self.timezone = Some(timezone.to_string());
+        self
+    }
+
+    pub fn component(mut self, component: &str) -> Self {
+        self.components.push(component.to_uppercase());
+        self
+    }
+
+    pub fn order(mut self, order: u32) -> Self {
+        self.order = Some(order);
+        self
+    }
+
+    pub fn method(mut self, method: CreateMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// The body of the request creating the calendar, for its method.
+    pub fn to_xml(&self) -> String {
+        let mut props = String::new();
+        if self.method == CreateMethod::ExtendedMkcol {
+            props.push_str("<D:resourcetype><D:collection/><C:calendar/></D:resourcetype>");
+        }
+        props.push_str(&format!(
+            "<D:displayname>{}</D:displayname>",
+            escape_xml(&self.display_name)
+        ));
+        if let Some(description) = &self.description {
+            props.push_str(&format!(
+                "<C:calendar-description>{}</C:calendar-description>",
+                escape_xml(description)
+            ));
+        }
+        if !self.components.is_empty() {
+            let components: String = self
+                .components
+                .iter()
+                .map(|component| format!(r#"<C:comp name="{}"/>"#, escape_xml(component)))
+                .collect();
+            props.push_str(&format!(
+                "<C:supported-calendar-component-set>{components}</C:supported-calendar-component-set>"
+            ));
+        }
+        // calendar-timezone holds a VCALENDAR with the VTIMEZONE, so timezones it can't be
+        // generated for are given by their id as in RFC 7809
+        if let Some(timezone) = self.timezone.as_deref() {
+            match calendar_vtimezone(timezone) {
+                Some(vtimezone) => props.push_str(&format!(
+                    "<C:calendar-timezone>{}</C:calendar-timezone>",
+                    escape_xml(&vtimezone)
+                )),
+                None => props.push_str(&format!(
+                    "<C:calendar-timezone-id>{}</C:calendar-timezone-id>",
+                    escape_xml(timezone)
+                )),
+            }
+        }
+        if let Some(color) = &self.color {
+            props.push_str(&format!(
+                "<ICAL:calendar-color>{}</ICAL:calendar-color>",
+                escape_xml(color)
+            ));
+        }
+        if let Some(order) = self.order {
+            props.push_str(&format!(
+                "<ICAL:calendar-order>{order}</ICAL:calendar-order>"
+            ));
+        }
+
+        let root = match self.method {
+            CreateMethod::MkCalendar => "C:mkcalendar",
+            CreateMethod::ExtendedMkcol => "D:mkcol",
+        };
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<{root} xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav" xmlns:ICAL="http://apple.com/ns/ical/">
+  <D:set>
+    <D:prop>{props}</D:prop>
+  </D:set>
+</{root}>
+"#
+        )
+    }
+}
+
+/// The value of calendar-timezone for a calendar in UTC.
+const UTC_VTIMEZONE: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//caldav-utils//EN\r\n\
+    BEGIN:VTIMEZONE\r\nTZID:UTC\r\nBEGIN:STANDARD\r\nDTSTART:19700101T000000\r\n\
+    TZNAME:UTC\r\nTZOFFSETFROM:+0000\r\nTZOFFSETTO:+0000\r\nEND:STANDARD\r\n\
+    END:VTIMEZONE\r\nEND:VCALENDAR\r\n";
+
+/// The value of calendar-timezone for a calendar in the given timezone,
+/// if it is one that is known.
+fn calendar_vtimezone(timezone: &str) -> Option<String> {
+    if timezone == "UTC" {
+        return Some(UTC_VTIMEZONE.to_string());
+    }
+    let tz: chrono_tz::Tz = timezone.parse().ok()?;
+    let vtimezone = crate::vtimezone::vtimezone(tz, chrono::Utc::now());
+    Some(
+        icalendar::Calendar::new()
+            .push(vtimezone)
+            .done()
+            .to_string(),
+    )
+}
+
+/// The TZID of a calendar-timezone value, which should be a VCALENDAR holding a VTIMEZONE,
+/// though some servers give the TZID alone. `None` if it has no VTIMEZONE.
+pub fn timezone_id(value: &str) -> Option<String> {
+    let value = value.trim();
+    if !value.starts_with("BEGIN:VCALENDAR") {
+        return Some(value.to_string()).filter(|tzid| !tzid.is_empty());
+    }
+    value
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != "BEGIN:VTIMEZONE")
+        .find_map(|line| line.strip_prefix("TZID:"))
+        .map(str::to_string)
+}
 
 #[derive(Clone, Debug)]
 pub struct Calendar {
     client: DavClient,
+    /// The calendar collection's absolute url, which identifies it.
     url: Url,
+    /// The path of the calendar's url.
     pub path: String,
 
     pub display_name: String,
@@ -24,22 +217,75 @@ impl Calendar {
     pub fn new(
         client: DavClient,
         url: Url,
-        path: String,
         display_name: String,
         timezone: Option<String>,
     ) -> Calendar {
+        let url = collection_url(url);
         Calendar {
             client,
+            path: url.path().to_string(),
             url,
-            path,
             display_name,
             timezone,
         }
     }
 
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
     pub async fn get_events(
         &self,
-        client: &reqwest::Client,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<Event>> {
+        self.query("VEVENT", start, end).await
+    }
+
+    /// Fetch a value which changes whenever anything in the calendar does, from its
+    /// sync-token (RFC 6578) or otherwise its CTag. Servers which support neither give `None`.
+    pub async fn get_change_token(&self) -> CaldavResult<Option<String>> {
+        let url = &self.url;
+        let method = Method::from_bytes(b"PROPFIND")?;
+
+        let req = self
+            .client
+            .request(method, url.as_str())
+            .header("Depth", 0)
+            .header("Content-Type", "application/xml")
+            .body(CHANGE_TOKEN_BODY);
+        let res = self.client.send(req).await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(self.not_found());
+        }
+
+        let text = res.text().await?;
+        let root = parse_xml(url.as_str(), &text)?;
+        Ok(["sync-token", "getctag"]
+            .iter()
+            .filter_map(|name| find_element(&root, name.to_string()))
+            .map(|element| element.text())
+            .find(|token| !token.is_empty()))
+    }
+
+    /// Fetch the VAVAILABILITY components which overlap two datetimes.
+    pub async fn get_availabilities(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<VAvailability>> {
+        let objects = self.query("VAVAILABILITY", start, end).await?;
+        let mut availabilities = Vec::new();
+        for object in &objects {
+            availabilities.extend(VAvailability::from_event(object, self.timezone.as_deref())?);
+        }
+        Ok(availabilities)
+    }
+
+    /// Fetch the icalendar objects with a component of the given kind between two datetimes.
+    async fn query(
+        &self,
+        component: &str,
         start: chrono::DateTime<chrono::Utc>,
         end: chrono::DateTime<chrono::Utc>,
     ) -> CaldavResult<Vec<Event>> {
@@ -56,7 +302,7 @@ impl Calendar {
               </d:prop>
               <c:filter>
                 <c:comp-filter name="VCALENDAR">
-                  <c:comp-filter name="VEVENT" >
+                  <c:comp-filter name="{component}" >
                     <c:time-range start="{start_str}" end="{end_str}" />
                   </c:comp-filter>
                 </c:comp-filter>
@@ -65,89 +311,85 @@ impl Calendar {
         "#
         );
 
-        let mut url = self.url.clone();
-        url.set_path(&self.path);
+        let url = &self.url;
         let method = Method::from_bytes(b"REPORT")?;
 
-        tracing::debug!("fetching events from {}", url);
+        tracing::debug!("fetching {} components from {}", component, url);
 
-        let req = client
+        let req = self
+            .client
             .request(method, url.as_str())
             .header("Depth", 1)
             .header("Content-Type", "application/xml")
-            .basic_auth(
-                self.client.credentials.username.clone(),
-                Some(self.client.credentials.password.clone()),
-            )
             .body(body);
 
         tracing::debug!("request: {:?}", req);
 
-        let res = req.send().await?;
+        let res = self.client.send(req).await?;
 
         tracing::debug!("response: {:?}", res);
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(self.not_found());
+        }
 
         let text = res.text().await?;
 
-        let root: Element = text.parse()?;
-        let data = find_elements(&root, "calendar-data".to_string());
-        let events: Vec<_> = data
-            .iter()
-            .map(|d| d.text())
-            .map(|t| {
-                let ical = icalendar::parser::read_calendar(&t).expect("failed to parse ical");
-                Event { ical: ical.into() }
-            })
-            .collect();
+        let root = parse_xml(url.as_str(), &text)?;
+        let mut events = Vec::new();
+        for response in find_elements(&root, "response".to_string()) {
+            let Some(data) = find_element(response, "calendar-data".to_string()) else {
+                continue;
+            };
+            let href = find_element(response, "href".to_string())
+                .map(|href| href.text())
+                .unwrap_or_else(|| url.to_string());
+            // one invalid object shouldn't stop the rest of the calendar from being used
+            match Event::parse_at(&href, &data.text()) {
+                Ok(event) => events.push(event),
+                Err(e) => tracing::warn!("skipping {}", e),
+            }
+        }
 
         Ok(events)
     }
 
-    pub async fn create_event(
-        &self,
-        client: &reqwest::Client,
-        start: chrono::DateTime<chrono::Utc>,
-        end: chrono::DateTime<chrono::Utc>,
-        name: &str,
-        description: &str,
-    ) -> CaldavResult<Event> {
+    pub async fn create_event(&self, details: &NewEvent) -> CaldavResult<Event> {
         let id = ksuid::Ksuid::generate().to_base62();
 
-        let event = icalendar::Event::new()
-            .uid(&id)
-            .summary(name)
-            .starts(start)
-            .ends(end)
-            .description(description)
-            .done();
-
-        let calendar = icalendar::Calendar::new()
-            .timezone("UTC")
-            .push(event)
-            .done();
+        let calendar = details.to_calendar(&id);
 
         tracing::debug!("creating event: {:?}", calendar.to_string());
 
         // Perform an HTTP PUT request to create a new event
-        let mut url = self.url.clone();
-        url.set_path(&format!("{}/{}.ics", self.path, id));
+        let url = self.event_url(&id);
 
         let method = Method::PUT;
 
-        let res = client
+        let req = self
+            .client
             .request(method, url.as_str())
             .header("Content-Type", "text/calendar")
-            .basic_auth(
-                self.client.credentials.username.clone(),
-                Some(self.client.credentials.password.clone()),
-            )
-            .body(calendar.to_string())
-            // .body(fake_event)
-            .send()
-            .await?;
+            // the id is new, so this can only create the event, which makes it safe to retry
+            .header("If-None-Match", "*")
+            .body(calendar.to_string());
+        let res = self.client.send(req).await?;
 
         let res = match res.status() {
             reqwest::StatusCode::CREATED => res,
+            // nothing else can have the new id, so the event exists because an earlier
+            // attempt created it, and its response was lost before the request was retried
+            reqwest::StatusCode::PRECONDITION_FAILED => {
+                let existing = self.get_event(&id).await?;
+                if existing.uid() != Some(id.as_str()) {
+                    return Err(CaldavError::ServerResponse(format!(
+                        "{url} already exists with another event"
+                    )));
+                }
+                res
+            }
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::CONFLICT => {
+                return Err(self.not_found())
+            }
             _ => {
                 let error = res.text().await?;
                 return Err(CaldavError::ServerResponse(error));
@@ -158,4 +400,175 @@ impl Calendar {
 
         Ok(Event { ical: calendar })
     }
+
+    /// The error for when the calendar's collection no longer exists on the server,
+    /// which is reported as a 404, or a 409 when writing to it.
+    fn not_found(&self) -> CaldavError {
+        CaldavError::CalendarNotFound {
+            calendar_name: self.display_name.clone(),
+        }
+    }
+
+    fn event_url(&self, uid: &str) -> Url {
+        let mut url = self.url.clone();
+        // the UID is pushed as a single segment, so anything not allowed in a path is encoded
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().push(&format!("{uid}.ics"));
+        }
+        url
+    }
+
+    /// Store a VAVAILABILITY component under its UID, replacing any previous version.
+    pub async fn put_availability(&self, availability: &VAvailability) -> CaldavResult<()> {
+        let uid = availability
+            .uid
+            .as_deref()
+            .ok_or_else(|| CaldavError::Anyhow(anyhow::anyhow!("availability has no UID")))?;
+        let url = self.event_url(uid);
+
+        let req = self
+            .client
+            .request(Method::PUT, url.as_str())
+            .header("Content-Type", "text/calendar")
+            .body(availability.to_ical().to_string());
+        let res = self.client.send(req).await?;
+
+        match res.status() {
+            reqwest::StatusCode::OK
+            | reqwest::StatusCode::CREATED
+            | reqwest::StatusCode::NO_CONTENT => Ok(()),
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::CONFLICT => Err(self.not_found()),
+            _ => {
+                let error = res.text().await?;
+                Err(CaldavError::ServerResponse(error))
+            }
+        }
+    }
+
+    /// Fetch a single event by its UID.
+    pub async fn get_event(&self, uid: &str) -> CaldavResult<Event> {
+        Ok(self.get_event_with_etag(uid).await?.0)
+    }
+
+    /// Fetch a single event by its UID, along with its ETag if the server gave one,
+    /// so that it can be updated with [`Calendar::update_event_if_match`].
+    pub async fn get_event_with_etag(&self, uid: &str) -> CaldavResult<(Event, Option<String>)> {
+        let url = self.event_url(uid);
+        tracing::debug!("fetching event from {}", url);
+
+        let req = self.client.request(Method::GET, url.as_str());
+        let res = self.client.send(req).await?;
+
+        let res = match res.status() {
+            reqwest::StatusCode::OK => res,
+            reqwest::StatusCode::NOT_FOUND => {
+                return Err(CaldavError::EventNotFound {
+                    uid: uid.to_string(),
+                })
+            }
+            _ => {
+                let error = res.text().await?;
+                return Err(CaldavError::ServerResponse(error));
+            }
+        };
+
+        let etag = res
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(|etag| etag.to_string());
+        let text = res.text().await?;
+        Ok((Event::parse_at(url.as_str(), &text)?, etag))
+    }
+
+    /// Replace an existing event with the given one.
+    /// The event is stored under its UID, so it must have one.
+    pub async fn update_event(&self, event: &Event) -> CaldavResult<()> {
+        self.update_event_if_match(event, None).await
+    }
+
+    /// Replace an existing event, if it still has the given ETag.
+    /// Fails with [`CaldavError::EventChanged`] if it was changed since the ETag was read,
+    /// so that changes made at the same time aren't lost.
+    pub async fn update_event_if_match(
+        &self,
+        event: &Event,
+        etag: Option<&str>,
+    ) -> CaldavResult<()> {
+        let uid = event
+            .uid()
+            .ok_or_else(|| CaldavError::Anyhow(anyhow::anyhow!("event has no UID")))?;
+        let url = self.event_url(uid);
+
+        let mut req = self
+            .client
+            .request(Method::PUT, url.as_str())
+            .header("Content-Type", "text/calendar")
+            .body(event.ical.to_string());
+        if let Some(etag) = etag {
+            req = req.header(reqwest::header::IF_MATCH, etag);
+        }
+        let res = self.client.send(req).await?;
+
+        match res.status() {
+            reqwest::StatusCode::OK
+            | reqwest::StatusCode::CREATED
+            | reqwest::StatusCode::NO_CONTENT => Ok(()),
+            reqwest::StatusCode::NOT_FOUND => Err(CaldavError::EventNotFound {
+                uid: uid.to_string(),
+            }),
+            reqwest::StatusCode::CONFLICT => Err(self.not_found()),
+            reqwest::StatusCode::PRECONDITION_FAILED => Err(CaldavError::EventChanged {
+                uid: uid.to_string(),
+            }),
+            _ => {
+                let error = res.text().await?;
+                Err(CaldavError::ServerResponse(error))
+            }
+        }
+    }
+
+    /// Remove an event by its UID.
+    pub async fn delete_event(&self, uid: &str) -> CaldavResult<()> {
+        self.delete_event_if_match(uid, None).await
+    }
+
+    /// Remove an event by its UID, if it still has the given ETag.
+    pub async fn delete_event_if_match(&self, uid: &str, etag: Option<&str>) -> CaldavResult<()> {
+        let url = self.event_url(uid);
+
+        let mut req = self.client.request(Method::DELETE, url.as_str());
+        if let Some(etag) = etag {
+            req = req.header(reqwest::header::IF_MATCH, etag);
+        }
+        let res = self.client.send(req).await?;
+
+        match res.status() {
+            reqwest::StatusCode::OK | reqwest::StatusCode::NO_CONTENT => Ok(()),
+            reqwest::StatusCode::NOT_FOUND => Err(CaldavError::EventNotFound {
+                uid: uid.to_string(),
+            }),
+            reqwest::StatusCode::PRECONDITION_FAILED => Err(CaldavError::EventChanged {
+                uid: uid.to_string(),
+            }),
+            _ => {
+                let error = res.text().await?;
+                Err(CaldavError::ServerResponse(error))
+            }
+        }
+    }
+
+    /// Find the events between two datetimes which the given email address is attending.
+    pub async fn get_events_by_attendee(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        email: &str,
+    ) -> CaldavResult<Vec<Event>> {
+        let events = self.get_events(start, end).await?;
+        Ok(events
+            .into_iter()
+            .filter(|event| event.has_attendee(email))
+            .collect())
+    }
 }
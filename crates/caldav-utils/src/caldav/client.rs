@@ -1,10 +1,15 @@
-use minidom::Element;
 use reqwest::{header::CONTENT_TYPE, Method, Result};
 use url::Url;
 
-use crate::util::find_element;
+use crate::{
+    error::{CaldavError, CaldavResult},
+    util::{parse_xml, require_element, resolve_href},
+};
 
-use super::principal::Principal;
+use super::{
+    principal::Principal,
+    retry::{self, RetryPolicy},
+};
 
 static DAVCLIENT_BODY: &str = r#"
     <d:propfind xmlns:d="DAV:">
@@ -26,27 +31,151 @@ impl DavCredentials {
     }
 }
 
+/// How long a request may take in total before it is given up on, unless configured.
+const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// How long connecting to the server may take before it is given up on, unless configured.
+const DEFAULT_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How the HTTP client used to talk to a CalDAV server connects to it.
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    timeout: std::time::Duration,
+    connect_timeout: std::time::Duration,
+    root_certificates: Vec<Vec<u8>>,
+    identity: Option<Vec<u8>>,
+    proxy: Option<String>,
+    user_agent: Option<String>,
+    retry: RetryPolicy,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            root_certificates: Vec::new(),
+            identity: None,
+            proxy: None,
+            user_agent: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl ConnectionConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up on requests which take longer than this in total, 30 seconds by default.
+    pub fn with_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Give up on connecting to the server after this long, 10 seconds by default.
+    pub fn with_connect_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Trust a PEM encoded certificate authority as well as the system's,
+    /// e.g. for a server with a self-signed certificate.
+    pub fn with_root_certificate(mut self, pem: Vec<u8>) -> Self {
+        self.root_certificates.push(pem);
+        self
+    }
+
+    /// Present a client certificate, given as a PEM encoded certificate and private key.
+    pub fn with_identity(mut self, pem: Vec<u8>) -> Self {
+        self.identity = Some(pem);
+        self
+    }
+
+    /// Send every request through the given proxy url.
+    pub fn with_proxy(mut self, proxy: String) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn with_user_agent(mut self, user_agent: String) -> Self {
+        self.user_agent = Some(user_agent);
+        self
+    }
+
+    /// How requests which fail in a way that may be temporary are retried.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Build an HTTP client with this configuration.
+    pub fn build(&self) -> CaldavResult<reqwest::Client> {
+        let user_agent = self
+            .user_agent
+            .clone()
+            .unwrap_or_else(|| format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")));
+        let mut builder = reqwest::Client::builder()
+            .user_agent(user_agent)
+            .timeout(self.timeout)
+            .connect_timeout(self.connect_timeout);
+        for pem in &self.root_certificates {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(pem)?);
+        }
+        if let Some(pem) = &self.identity {
+            builder = builder.identity(reqwest::Identity::from_pem(pem)?);
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// A connection to a CalDAV server.
+/// It owns the HTTP client used for every request, so connections are pooled
+/// across everything created from it, and is cheap to clone.
 #[derive(Clone, Debug)]
 pub struct DavClient {
     url: Url,
     pub(super) credentials: DavCredentials,
+    http: reqwest::Client,
+    retry: RetryPolicy,
 }
 
 impl DavClient {
-    pub fn new(url: String, credentials: DavCredentials) -> Self {
-        let url = Url::parse(&url).expect("failed to parse url");
+    pub fn new(url: String, credentials: DavCredentials) -> CaldavResult<Self> {
+        Self::with_config(url, credentials, &ConnectionConfig::default())
+    }
 
-        DavClient { url, credentials }
+    pub fn with_config(
+        url: String,
+        credentials: DavCredentials,
+        config: &ConnectionConfig,
+    ) -> CaldavResult<Self> {
+        let url = Url::parse(&url).map_err(|source| CaldavError::InvalidUrl { url, source })?;
+
+        Ok(DavClient {
+            url,
+            credentials,
+            http: config.build()?,
+            retry: config.retry.clone(),
+        })
+    }
+
+    /// The HTTP client requests are made with.
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
     }
 
     pub fn create_request(
         &self,
-        client: &reqwest::Client,
         method: Method,
         url: &Url,
         depth: u32,
     ) -> Result<reqwest::RequestBuilder> {
-        let req = client
+        let req = self
+            .http
             .request(method, url.as_str())
             .header("Depth", format!("{depth}"))
             .header(CONTENT_TYPE, "application/xml")
@@ -55,27 +184,83 @@ impl DavClient {
         Ok(req)
     }
 
-    pub async fn get_principal(&self, client: &reqwest::Client) -> Result<Principal> {
-        let method = Method::from_bytes(b"PROPFIND").expect("failed to create PROPFIND method");
+    /// A request with the credentials set, without any WebDAV headers.
+    pub(crate) fn request(&self, method: Method, url: &str) -> reqwest::RequestBuilder {
+        self.http
+            .request(method, url)
+            .basic_auth(&self.credentials.username, Some(&self.credentials.password))
+    }
 
-        let res = self
-            .create_request(client, method, &self.url, 0)?
-            .body(DAVCLIENT_BODY)
-            .send()
-            .await?;
+    /// Send a request, retrying it according to the [`RetryPolicy`] if it fails in a way
+    /// that may be temporary. The last response is returned once the attempts run out,
+    /// or when the server asks for the request to be retried later than the policy allows.
+    pub async fn send(&self, req: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let req = req.build()?;
+        let retryable = self.retry.is_retryable(req.method(), req.headers());
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            tracing::debug!("{} {} (attempt {})", req.method(), req.url(), attempts);
+            let attempt = match req.try_clone() {
+                Some(attempt) if retryable && attempts < self.retry.max_attempts => attempt,
+                // bodies which can't be cloned can only be sent once
+                _ => return self.http.execute(req).await,
+            };
 
-        let text = res.text().await?;
+            let delay = match self.http.execute(attempt).await {
+                Ok(res) if retry::is_transient(res.status()) => {
+                    let now = chrono::Utc::now();
+                    let Some(delay) = self.retry.transient_delay(res.headers(), attempts, now)
+                    else {
+                        tracing::warn!(
+                            "{} {} failed with {}, and asked to be retried later than allowed",
+                            req.method(),
+                            req.url(),
+                            res.status()
+                        );
+                        return Ok(res);
+                    };
+                    tracing::warn!(
+                        "{} {} failed with {}, retrying in {:?}",
+                        req.method(),
+                        req.url(),
+                        res.status(),
+                        delay
+                    );
+                    delay
+                }
+                Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => {
+                    let delay = self.retry.jittered_delay(attempts);
+                    tracing::warn!(
+                        "{} {} failed: {}, retrying in {:?}",
+                        req.method(),
+                        req.url(),
+                        e,
+                        delay
+                    );
+                    delay
+                }
+                res => return res,
+            };
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    pub async fn get_principal(&self) -> CaldavResult<Principal> {
+        let method = Method::from_bytes(b"PROPFIND")?;
 
-        let root: Element = text.parse().expect("failed to parse xml");
-        let principal = find_element(&root, "current-user-principal".to_string())
-            .expect("failed to find current-user-principal");
-        let principal_href =
-            find_element(principal, "href".to_string()).expect("failed to find principal's href");
+        let req = self
+            .create_request(method, &self.url, 0)?
+            .body(DAVCLIENT_BODY);
+        let res = self.send(req).await?;
+
+        let text = res.text().await?;
 
-        let href = principal_href.text();
+        let root = parse_xml(self.url.as_str(), &text)?;
+        let principal = require_element(&root, "current-user-principal", self.url.as_str())?;
+        let principal_href = require_element(principal, "href", self.url.as_str())?;
 
-        let mut url = self.url.clone();
-        url.set_path(&href);
+        let url = resolve_href(&self.url, &principal_href.text())?;
 
         Ok(Principal::new(self.clone(), url))
     }
@@ -1,10 +1,71 @@
+use chrono::TimeZone;
+use icalendar::{CalendarDateTime, Component, DatePerhapsTime, EventLike};
+
+use crate::error::{CaldavError, CaldavResult};
 use crate::format;
 
+/// Properties which may appear more than once on a single component.
+/// These are stored as multi-properties so that none of them are lost when parsing.
+static MULTI_PROPERTIES: &[&str] = &[
+    "ATTACH",
+    "ATTENDEE",
+    "CATEGORIES",
+    "COMMENT",
+    "CONTACT",
+    "EXDATE",
+    "RDATE",
+    "RELATED-TO",
+    "REQUEST-STATUS",
+    "RESOURCES",
+];
+
+/// Check that unfolded icalendar text is a VCALENDAR whose components are all closed,
+/// in the order they were opened.
+fn check_nesting(unfolded: &str) -> Result<(), String> {
+    let mut open: Vec<&str> = Vec::new();
+    for line in unfolded
+        .lines()
+        .map(str::trim_end)
+        .filter(|l| !l.is_empty())
+    {
+        if let Some(name) = line.strip_prefix("BEGIN:") {
+            if open.is_empty() && !name.eq_ignore_ascii_case("VCALENDAR") {
+                return Err(format!("expected BEGIN:VCALENDAR, found {line}"));
+            }
+            open.push(name);
+        } else if let Some(name) = line.strip_prefix("END:") {
+            match open.pop() {
+                Some(begun) if begun.eq_ignore_ascii_case(name) => {}
+                Some(begun) => return Err(format!("{begun} is ended by {line}")),
+                None => return Err(format!("{line} without a BEGIN")),
+            }
+        } else if open.is_empty() {
+            return Err(format!("{line} outside of a VCALENDAR"));
+        }
+    }
+    match open.last() {
+        None => Ok(()),
+        Some(name) => Err(format!("truncated icalendar object, {name} is not ended")),
+    }
+}
+
 #[derive(Debug)]
 pub struct Event {
     pub ical: icalendar::Calendar,
 }
 
+// icalendar::Calendar does not implement Clone, but all of its fields do
+impl Clone for Event {
+    fn clone(&self) -> Self {
+        Event {
+            ical: icalendar::Calendar {
+                properties: self.ical.properties.clone(),
+                components: self.ical.components.clone(),
+            },
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Property {
     DateTime(chrono::DateTime<chrono::Utc>),
@@ -16,6 +77,90 @@ impl Event {
         Event { ical }
     }
 
+    /// Parse the text of an icalendar object into an event.
+    /// Unlike converting the output of `icalendar::parser::read_calendar` directly,
+    /// this keeps every occurrence of properties such as ATTENDEE.
+    pub fn parse(text: &str) -> CaldavResult<Event> {
+        Event::parse_at("icalendar object", text)
+    }
+
+    /// Parse the icalendar object found at the given href, which an invalid object is reported with.
+    pub fn parse_at(href: &str, text: &str) -> CaldavResult<Event> {
+        let invalid = |reason: String| CaldavError::InvalidICalendar {
+            href: href.to_string(),
+            reason,
+        };
+        let unfolded = icalendar::parser::unfold(text);
+        // the parser panics on truncated input, so the components must be closed first
+        check_nesting(&unfolded).map_err(invalid)?;
+        let parsed =
+            icalendar::parser::read_calendar(&unfolded).map_err(|e| invalid(e.to_string()))?;
+
+        let mut ical = icalendar::Calendar::empty();
+        for property in parsed.properties {
+            ical.append_property(property);
+        }
+        for component in parsed.components {
+            match component.name.as_str() {
+                "VEVENT" => {
+                    let mut event = icalendar::Event::new();
+                    let names: Vec<String> = component
+                        .properties
+                        .iter()
+                        .map(|p| p.name.as_str().to_string())
+                        .collect();
+                    for property in component.properties {
+                        let name = property.name.as_str();
+                        let repeated = names.iter().filter(|n| *n == name).count() > 1;
+                        if repeated || MULTI_PROPERTIES.contains(&name) {
+                            event.append_multi_property(property);
+                        } else {
+                            event.append_property(property);
+                        }
+                    }
+                    for child in component.components {
+                        event.append_component(child);
+                    }
+                    ical.push(event);
+                }
+                "VAVAILABILITY" => {
+                    ical.push(other_component(component));
+                }
+                _ => {
+                    ical.push(icalendar::CalendarComponent::from(component));
+                }
+            }
+        }
+
+        Ok(Event { ical })
+    }
+
+    /// Split an icalendar object containing several VEVENTs, such as an exported
+    /// calendar, into one event per VEVENT. Other components like VTIMEZONE are kept
+    /// alongside every event.
+    pub fn split(&self) -> Vec<Event> {
+        let (events, others): (Vec<_>, Vec<_>) = self
+            .ical
+            .components
+            .iter()
+            .cloned()
+            .partition(|c| matches!(c, icalendar::CalendarComponent::Event(_)));
+
+        events
+            .into_iter()
+            .map(|event| {
+                let mut components = others.clone();
+                components.push(event);
+                Event {
+                    ical: icalendar::Calendar {
+                        properties: self.ical.properties.clone(),
+                        components,
+                    },
+                }
+            })
+            .collect()
+    }
+
     pub fn add_property(&mut self, key: &str, property: Property) {
         let property = match property {
             Property::DateTime(dt) => {
@@ -27,4 +172,543 @@ impl Event {
 
         self.ical.append_property(property);
     }
+
+    /// The first VEVENT component contained in this event, if any.
+    pub fn vevent(&self) -> Option<&icalendar::Event> {
+        self.ical.components.iter().find_map(|c| c.as_event())
+    }
+
+    pub fn vevent_mut(&mut self) -> Option<&mut icalendar::Event> {
+        self.ical.components.iter_mut().find_map(|c| match c {
+            icalendar::CalendarComponent::Event(event) => Some(event),
+            _ => None,
+        })
+    }
+
+    pub fn uid(&self) -> Option<&str> {
+        self.vevent()?.get_uid()
+    }
+
+    pub fn summary(&self) -> Option<&str> {
+        self.vevent()?.get_summary()
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.vevent()?.get_description()
+    }
+
+    /// The start of the event in UTC.
+    pub fn start(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        to_utc(self.vevent()?.get_start()?)
+    }
+
+    /// Whether the event lasts whole days rather than having start and end times.
+    pub fn is_all_day(&self) -> bool {
+        matches!(
+            self.vevent().and_then(|event| event.get_start()),
+            Some(DatePerhapsTime::Date(_))
+        )
+    }
+
+    /// The end of the event in UTC.
+    pub fn end(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        to_utc(self.vevent()?.get_end()?)
+    }
+
+    pub fn sequence(&self) -> u32 {
+        self.vevent()
+            .and_then(|event| event.get_sequence())
+            .unwrap_or(0)
+    }
+
+    /// Move the event to a new time, incrementing its SEQUENCE so that
+    /// clients treat it as a revision of the existing event.
+    pub fn reschedule(
+        &mut self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) {
+        let sequence = self.sequence() + 1;
+        if let Some(event) = self.vevent_mut() {
+            event
+                .starts(start)
+                .ends(end)
+                .sequence(sequence)
+                .timestamp(chrono::Utc::now());
+        }
+    }
+
+    pub fn status(&self) -> Option<icalendar::EventStatus> {
+        self.vevent()?.get_status()
+    }
+
+    /// Change the status of the event, incrementing its SEQUENCE.
+    pub fn set_status(&mut self, status: icalendar::EventStatus) {
+        let sequence = self.sequence() + 1;
+        if let Some(event) = self.vevent_mut() {
+            event
+                .status(status)
+                .sequence(sequence)
+                .timestamp(chrono::Utc::now());
+        }
+    }
+
+    pub fn organizer(&self) -> Option<Organizer> {
+        let event = self.vevent()?;
+        let property = event.properties().get("ORGANIZER")?;
+        Some(Organizer {
+            email: strip_mailto(property.value()).to_string(),
+            common_name: param_value(property, "CN"),
+        })
+    }
+
+    pub fn attendees(&self) -> Vec<Attendee> {
+        let event = match self.vevent() {
+            Some(event) => event,
+            None => return Vec::new(),
+        };
+
+        event
+            .multi_properties()
+            .iter()
+            .chain(event.properties().values())
+            .filter(|p| p.key() == "ATTENDEE")
+            .map(|p| Attendee {
+                email: strip_mailto(p.value()).to_string(),
+                common_name: param_value(p, "CN"),
+                partstat: param_value(p, "PARTSTAT")
+                    .and_then(|s| PartStat::from_str(&s))
+                    .unwrap_or_default(),
+                rsvp: param_value(p, "RSVP")
+                    .map(|s| s.eq_ignore_ascii_case("TRUE"))
+                    .unwrap_or(false),
+            })
+            .collect()
+    }
+
+    /// Add an attendee to the event.
+    pub fn add_attendee(&mut self, attendee: &Attendee) {
+        if let Some(event) = self.vevent_mut() {
+            event
+                .append_multi_property(attendee.to_property())
+                .timestamp(chrono::Utc::now());
+        }
+    }
+
+    /// Remove an attendee from the event.
+    /// Returns false if they were not attending.
+    pub fn remove_attendee(&mut self, email: &str) -> bool {
+        if !self.has_attendee(email) {
+            return false;
+        }
+        self.retain_properties(|p| {
+            p.key() != "ATTENDEE" || !strip_mailto(p.value()).eq_ignore_ascii_case(email)
+        });
+        if let Some(event) = self.vevent_mut() {
+            event.timestamp(chrono::Utc::now());
+        }
+        true
+    }
+
+    /// Remove the properties of the VEVENT for which the predicate returns false.
+    /// icalendar does not allow removing properties, so the VEVENT is rebuilt without them.
+    pub fn retain_properties(&mut self, f: impl Fn(&icalendar::Property) -> bool) {
+        if let Some(event) = self.vevent_mut() {
+            let mut rebuilt = icalendar::Event::new();
+            for property in event.properties().values().filter(|p| f(p)) {
+                rebuilt.append_property(property.clone());
+            }
+            for property in event.multi_properties().iter().filter(|p| f(p)) {
+                rebuilt.append_multi_property(property.clone());
+            }
+            for component in event.components() {
+                rebuilt.append_component(component.clone());
+            }
+            *event = rebuilt.done();
+        }
+    }
+
+    /// Whether the given email address is one of the event's attendees.
+    pub fn has_attendee(&self, email: &str) -> bool {
+        self.attendees()
+            .iter()
+            .any(|a| a.email.eq_ignore_ascii_case(email))
+    }
+
+    /// All occurrences of the given property on the VEVENT, in the order they were added.
+    pub fn properties_named(&self, key: &str) -> Vec<&icalendar::Property> {
+        match self.vevent() {
+            Some(event) => event
+                .properties()
+                .values()
+                .chain(event.multi_properties().iter())
+                .filter(|p| p.key() == key)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// All values of the given property on the VEVENT, in the order they were added.
+    pub fn property_values(&self, key: &str) -> Vec<String> {
+        self.properties_named(key)
+            .iter()
+            .map(|p| p.value().to_string())
+            .collect()
+    }
+}
+
+fn to_utc(value: DatePerhapsTime) -> Option<chrono::DateTime<chrono::Utc>> {
+    match value {
+        DatePerhapsTime::DateTime(CalendarDateTime::Utc(dt)) => Some(dt),
+        DatePerhapsTime::DateTime(CalendarDateTime::Floating(naive)) => {
+            Some(chrono::Utc.from_utc_datetime(&naive))
+        }
+        DatePerhapsTime::DateTime(CalendarDateTime::WithTimezone { date_time, tzid }) => {
+            let tz: chrono_tz::Tz = tzid.parse().ok()?;
+            let local = tz.from_local_datetime(&date_time).earliest()?;
+            Some(local.with_timezone(&chrono::Utc))
+        }
+        DatePerhapsTime::Date(date) => {
+            Some(chrono::Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?))
+        }
+    }
+}
+
+fn strip_mailto(value: &str) -> &str {
+    value
+        .strip_prefix("mailto:")
+        .or_else(|| value.strip_prefix("MAILTO:"))
+        .unwrap_or(value)
+}
+
+/// Convert a parsed component which icalendar has no type for, keeping repeated properties
+/// in its nested components (e.g. the EXDATEs of an AVAILABLE) as multi-properties.
+fn other_component(component: icalendar::parser::Component) -> icalendar::CalendarComponent {
+    let names: Vec<String> = component
+        .properties
+        .iter()
+        .map(|p| p.name.as_str().to_string())
+        .collect();
+    let (multi, single): (Vec<_>, Vec<_>) = component.properties.into_iter().partition(|p| {
+        let name = p.name.as_str();
+        names.iter().filter(|n| *n == name).count() > 1 || MULTI_PROPERTIES.contains(&name)
+    });
+
+    let mut converted = icalendar::CalendarComponent::from(icalendar::parser::Component {
+        name: component.name,
+        properties: single,
+        components: Vec::new(),
+    });
+    if let icalendar::CalendarComponent::Other(other) = &mut converted {
+        for property in multi {
+            other.append_multi_property(property);
+        }
+        for child in component.components {
+            if let icalendar::CalendarComponent::Other(child) = other_component(child) {
+                other.append_component(child);
+            }
+        }
+    }
+    converted
+}
+
+/// Read a parameter of a property, removing any quoting.
+pub fn param_value(property: &icalendar::Property, key: &str) -> Option<String> {
+    property.get_param_as(key, |v| Some(v.trim_matches('"').to_string()))
+}
+
+/// Make text safe to use as a parameter value.
+/// Double quotes and control characters can't appear in one even when it is quoted,
+/// so they are replaced or dropped, and values containing `:`, `;` or `,` are quoted.
+pub fn quote_param(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| if c == '"' { '\'' } else { c })
+        .filter(|c| !c.is_control())
+        .collect();
+    if value.contains([':', ';', ',']) {
+        format!("\"{value}\"")
+    } else {
+        value
+    }
+}
+
+/// Escape text to be a TEXT property value, as in RFC 5545 section 3.3.11.
+/// icalendar only escapes line breaks itself, which values written with this no longer contain.
+pub fn escape_text(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Read a TEXT property value, undoing [`escape_text`].
+pub fn unescape_text(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Participation status of an attendee, as defined in RFC 5545 section 3.2.12.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PartStat {
+    #[default]
+    NeedsAction,
+    Accepted,
+    Declined,
+    Tentative,
+    Delegated,
+}
+
+impl PartStat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PartStat::NeedsAction => "NEEDS-ACTION",
+            PartStat::Accepted => "ACCEPTED",
+            PartStat::Declined => "DECLINED",
+            PartStat::Tentative => "TENTATIVE",
+            PartStat::Delegated => "DELEGATED",
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(value: &str) -> Option<PartStat> {
+        match value.to_uppercase().as_str() {
+            "NEEDS-ACTION" => Some(PartStat::NeedsAction),
+            "ACCEPTED" => Some(PartStat::Accepted),
+            "DECLINED" => Some(PartStat::Declined),
+            "TENTATIVE" => Some(PartStat::Tentative),
+            "DELEGATED" => Some(PartStat::Delegated),
+            _ => None,
+        }
+    }
+}
+
+/// The user who organizes an event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Organizer {
+    pub email: String,
+    pub common_name: Option<String>,
+}
+
+impl Organizer {
+    pub fn new(email: String, common_name: Option<String>) -> Organizer {
+        Organizer { email, common_name }
+    }
+
+    pub fn to_property(&self) -> icalendar::Property {
+        let mut property = icalendar::Property::new("ORGANIZER", &format!("mailto:{}", self.email));
+        if let Some(cn) = &self.common_name {
+            property.add_parameter("CN", &quote_param(cn));
+        }
+        property
+    }
+}
+
+/// A participant in an event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Attendee {
+    pub email: String,
+    pub common_name: Option<String>,
+    pub partstat: PartStat,
+    pub rsvp: bool,
+}
+
+impl Attendee {
+    pub fn new(email: String, common_name: Option<String>) -> Attendee {
+        Attendee {
+            email,
+            common_name,
+            partstat: PartStat::default(),
+            rsvp: false,
+        }
+    }
+
+    pub fn partstat(mut self, partstat: PartStat) -> Self {
+        self.partstat = partstat;
+        self
+    }
+
+    pub fn rsvp(mut self, rsvp: bool) -> Self {
+        self.rsvp = rsvp;
+        self
+    }
+
+    pub fn to_property(&self) -> icalendar::Property {
+        let mut property = icalendar::Property::new("ATTENDEE", &format!("mailto:{}", self.email));
+        if let Some(cn) = &self.common_name {
+            property.add_parameter("CN", &quote_param(cn));
+        }
+        property
+            .add_parameter("PARTSTAT", self.partstat.as_str())
+            .add_parameter("RSVP", if self.rsvp { "TRUE" } else { "FALSE" });
+        property
+    }
+}
+
+/// The details of an event that is to be created on a calendar.
+#[derive(Clone, Debug)]
+pub struct NewEvent {
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+    pub summary: String,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub url: Option<String>,
+    pub organizer: Option<Organizer>,
+    pub attendees: Vec<Attendee>,
+    pub status: Option<icalendar::EventStatus>,
+    /// The timezone the start and end are written in, instead of UTC.
+    /// This matters for recurring events, whose occurrences keep their local time.
+    pub timezone: Option<chrono_tz::Tz>,
+    /// Additional properties, typically `X-` properties holding application metadata.
+    pub properties: Vec<icalendar::Property>,
+}
+
+impl NewEvent {
+    pub fn new(
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        summary: &str,
+    ) -> NewEvent {
+        NewEvent {
+            start,
+            end,
+            summary: summary.to_string(),
+            description: None,
+            location: None,
+            url: None,
+            organizer: None,
+            attendees: Vec::new(),
+            status: None,
+            timezone: None,
+            properties: Vec::new(),
+        }
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    pub fn location(mut self, location: &str) -> Self {
+        self.location = Some(location.to_string());
+        self
+    }
+
+    pub fn url(mut self, url: &str) -> Self {
+        self.url = Some(url.to_string());
+        self
+    }
+
+    pub fn organizer(mut self, organizer: Organizer) -> Self {
+        self.organizer = Some(organizer);
+        self
+    }
+
+    pub fn attendee(mut self, attendee: Attendee) -> Self {
+        self.attendees.push(attendee);
+        self
+    }
+
+    pub fn status(mut self, status: icalendar::EventStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn timezone(mut self, timezone: chrono_tz::Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    pub fn property(mut self, key: &str, value: &str) -> Self {
+        self.properties.push(icalendar::Property::new(key, value));
+        self
+    }
+
+    /// Add a property which has parameters.
+    pub fn append_property(mut self, property: icalendar::Property) -> Self {
+        self.properties.push(property);
+        self
+    }
+
+    /// Build the VEVENT for this event with the given UID.
+    pub fn to_ical(&self, uid: &str) -> icalendar::Event {
+        let mut event = icalendar::Event::new();
+        event.uid(uid).summary(&self.summary);
+        match self.timezone.filter(|tz| *tz != chrono_tz::UTC) {
+            Some(tz) => {
+                let local = |dt: chrono::DateTime<chrono::Utc>| CalendarDateTime::WithTimezone {
+                    date_time: dt.with_timezone(&tz).naive_local(),
+                    tzid: tz.name().to_string(),
+                };
+                event.starts(local(self.start)).ends(local(self.end));
+            }
+            None => {
+                event.starts(self.start).ends(self.end);
+            }
+        }
+
+        if let Some(description) = &self.description {
+            event.description(description);
+        }
+        if let Some(location) = &self.location {
+            event.location(location);
+        }
+        if let Some(url) = &self.url {
+            event.url(url);
+        }
+        if let Some(organizer) = &self.organizer {
+            event.append_property(organizer.to_property());
+        }
+        for attendee in &self.attendees {
+            event.append_multi_property(attendee.to_property());
+        }
+        if let Some(status) = self.status {
+            event.status(status);
+        }
+        for property in &self.properties {
+            event.append_multi_property(property.clone());
+        }
+
+        event.done()
+    }
+
+    /// The VTIMEZONE for the timezone the event is written in, which isn't needed for UTC.
+    pub fn vtimezone(&self) -> Option<icalendar::CalendarComponent> {
+        self.timezone
+            .filter(|tz| *tz != chrono_tz::UTC)
+            .map(|tz| crate::vtimezone::vtimezone(tz, self.start))
+    }
+
+    /// Build the icalendar object for this event with the given UID,
+    /// including the VTIMEZONE its times refer to.
+    pub fn to_calendar(&self, uid: &str) -> icalendar::Calendar {
+        let mut calendar = icalendar::Calendar::new();
+        calendar.timezone("UTC");
+        if let Some(vtimezone) = self.vtimezone() {
+            calendar.push(vtimezone);
+        }
+        calendar.push(self.to_ical(uid)).done()
+    }
 }
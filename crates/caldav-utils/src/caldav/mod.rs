@@ -2,3 +2,4 @@ pub mod calendar;
 pub mod client;
 pub mod event;
 pub mod principal;
+pub mod retry;
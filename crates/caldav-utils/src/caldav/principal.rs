@@ -1,14 +1,15 @@
-use minidom::Element;
 use reqwest::{
-    header::{CONTENT_LENGTH, CONTENT_TYPE},
-    Client, Method, Result,
+    header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
+    Method,
 };
 use url::Url;
 
 use crate::error::{CaldavError, CaldavResult};
-use crate::util::{find_element, find_elements};
+use crate::util::{
+    collection_url, find_element, find_elements, parse_xml, require_element, resolve_href,
+};
 
-use super::calendar::Calendar;
+use super::calendar::{timezone_id, Calendar, CreateMethod, NewCalendar};
 use super::client::DavClient;
 
 static HOMESET_BODY: &str = r#"
@@ -52,109 +53,102 @@ impl Principal {
         }
     }
 
-    pub async fn get_home_set(&mut self, client: &Client) -> Result<Url> {
-        let method = Method::from_bytes(b"PROPFIND").expect("failed to create PROPFIND method");
+    pub async fn get_home_set(&mut self) -> CaldavResult<Url> {
+        let method = Method::from_bytes(b"PROPFIND")?;
 
-        let res = client
+        let req = self
+            .client
             .request(method, self.url.as_str())
             .header("Depth", "0")
             .header(CONTENT_TYPE, "application/xml")
-            .basic_auth(
-                &self.client.credentials.username,
-                Some(&self.client.credentials.password),
-            )
-            .body(HOMESET_BODY)
-            .send()
-            .await?;
+            .body(HOMESET_BODY);
+        let res = self.client.send(req).await?;
 
         let text = res.text().await?;
 
         tracing::debug!("principal response: {}", text);
 
-        let root: Element = text.parse().expect("failed to parse xml");
-        let homeset =
-            find_element(&root, "response".to_string()).expect("failed to find calendar-home-set");
-        let homeset_href =
-            find_element(homeset, "href".to_string()).expect("failed to find homeset's href");
-        let href = homeset_href.text();
-
-        let mut url = self.url.clone();
-        url.set_path(&href);
+        let root = parse_xml(self.url.as_str(), &text)?;
+        let homeset = require_element(&root, "calendar-home-set", self.url.as_str())?;
+        let homeset_href = require_element(homeset, "href", self.url.as_str())?;
+        let url = collection_url(resolve_href(&self.url, &homeset_href.text())?);
 
         self.homeset_url = Some(url.clone());
         Ok(url)
     }
 
-    pub async fn get_calendars(&mut self, client: &Client) -> Result<Vec<Calendar>> {
+    /// The principal's calendars, which are only fetched the first time.
+    pub async fn get_calendars(&mut self) -> CaldavResult<Vec<Calendar>> {
         // short-circuit if we already have the calendars
         if !self.calendars.is_empty() {
             return Ok(self.calendars.clone());
         }
+        self.refresh_calendars().await
+    }
 
+    /// Fetch the principal's calendars again, e.g. after one was created or deleted elsewhere.
+    pub async fn refresh_calendars(&mut self) -> CaldavResult<Vec<Calendar>> {
         let homeset_url = match &self.homeset_url {
             Some(url) => url.clone(),
-            None => self.get_home_set(client).await?,
+            None => self.get_home_set().await?,
         };
         tracing::debug!("getting calendars from {}", homeset_url);
 
-        let method = Method::from_bytes(b"PROPFIND").expect("failed to create PROPFIND method");
+        let calendars: Vec<_> = self
+            .find_calendars(&homeset_url, 1)
+            .await?
+            .into_iter()
+            .filter(|calendar| *calendar.url() != homeset_url)
+            .collect();
+
+        self.calendars = calendars.clone();
+        Ok(calendars)
+    }
+
+    /// PROPFIND the calendars at a url, with hrefs resolved against it.
+    async fn find_calendars(&self, url: &Url, depth: u32) -> CaldavResult<Vec<Calendar>> {
+        let method = Method::from_bytes(b"PROPFIND")?;
 
-        let res = client
-            .request(method, homeset_url.clone())
-            .header("Depth", "1")
+        let req = self
+            .client
+            .request(method, url.as_str())
+            .header("Depth", depth)
             .header(CONTENT_TYPE, "application/xml")
-            .basic_auth(
-                &self.client.credentials.username,
-                Some(&self.client.credentials.password),
-            )
-            .body(CALENDAR_BODY)
-            .send()
-            .await?;
+            .body(CALENDAR_BODY);
+        let res = self.client.send(req).await?;
 
         let text = res.text().await?;
 
         tracing::debug!("calendar response: {}", text);
 
-        let root: Element = text.parse().expect("failed to parse xml");
-        let responses = find_elements(&root, "response".to_string());
-        let calendars: Vec<_> = responses
-            .iter()
-            .filter_map(|response| {
-                let displayname = find_element(response, "displayname".to_string())
-                    .expect("failed to find displayname")
-                    .text();
-                if displayname.is_empty() {
-                    return None;
-                }
-
-                let timezone = find_element(response, "calendar-timezone".to_string())
-                    .expect("failed to find calendar-timezone")
-                    .text();
-
-                let href = find_element(response, "href".to_string())
-                    .expect("failed to find href")
-                    .text();
-
-                Some(Calendar::new(
-                    self.client.clone(),
-                    self.url.clone(),
-                    href,
-                    displayname,
-                    Some(timezone),
-                ))
-            })
-            .collect();
+        let root = parse_xml(url.as_str(), &text)?;
+        let mut calendars = Vec::new();
+        for response in find_elements(&root, "response".to_string()) {
+            // collections without a name, like the home set itself, aren't calendars
+            let displayname = find_element(response, "displayname".to_string())
+                .map(|element| element.text())
+                .unwrap_or_default();
+            if displayname.is_empty() {
+                continue;
+            }
 
-        self.calendars = calendars.clone();
+            let timezone = find_element(response, "calendar-timezone".to_string())
+                .and_then(|element| timezone_id(&element.text()));
+
+            let href = require_element(response, "href", url.as_str())?.text();
+
+            calendars.push(Calendar::new(
+                self.client.clone(),
+                resolve_href(url, &href)?,
+                displayname,
+                timezone,
+            ));
+        }
         Ok(calendars)
     }
 
-    pub async fn get_calendar(
-        &mut self,
-        client: &reqwest::Client,
-        calendar_name: &str,
-    ) -> CaldavResult<Calendar> {
-        let calendars = self.get_calendars(client).await?;
+    pub async fn get_calendar(&mut self, calendar_name: &str) -> CaldavResult<Calendar> {
+        let calendars = self.get_calendars().await?;
         let calendar = calendars
             .iter()
             .find(|c| c.display_name == calendar_name)
@@ -164,60 +158,37 @@ impl Principal {
         Ok(calendar.clone())
     }
 
-    pub async fn create_calendar_mkcol(
-        &mut self,
-        client: &reqwest::Client,
-        calendar_name: &str,
-    ) -> CaldavResult<Calendar> {
-        let mut url = match &self.homeset_url {
+    /// Create a calendar with an extended MKCOL, in UTC like the calendars the scheduler uses.
+    pub async fn create_calendar_mkcol(&mut self, calendar_name: &str) -> CaldavResult<Calendar> {
+        let details = NewCalendar::new(calendar_name)
+            .timezone("UTC")
+            .method(CreateMethod::ExtendedMkcol);
+        self.create_calendar(&details).await
+    }
+
+    /// Create a calendar in the home set, with the method and properties given.
+    pub async fn create_calendar(&mut self, details: &NewCalendar) -> CaldavResult<Calendar> {
+        let homeset_url = match &self.homeset_url {
             Some(url) => url.clone(),
-            None => self.get_home_set(client).await?,
+            None => self.get_home_set().await?,
         };
         // generate a unique id for the calendar
         let id = ksuid::Ksuid::generate().to_base62();
-        url.set_path(&format!("{}{}/", url.path(), id));
-        let url = format!("{url}");
+        let url = resolve_href(&homeset_url, &format!("{id}/"))?;
         tracing::info!("url: {}", url);
 
-        let method = Method::from_bytes(b"MKCOL").expect("failed to create MKCOL method");
-
-        let body = format!(
-            r#"<?xml version="1.0"?>
-            <mkcol xmlns="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav" xmlns:ICAL="http://apple.com/ns/ical/">
-              <set>
-                <prop>
-                  <resourcetype>
-                    <collection />
-                    <C:calendar />
-                  </resourcetype>
-                  <C:supported-calendar-component-set>
-                    <C:comp name="VEVENT" />
-                    <C:comp name="VJOURNAL" />
-                    <C:comp name="VTODO" />
-                  </C:supported-calendar-component-set>
-                  <displayname>{calendar_name}</displayname>
-                  <ICAL:calendar-timezone>UTC</ICAL:calendar-timezone>
-                  <ICAL:calendar-color>#ff0000</ICAL:calendar-color>
-                </prop>
-              </set>
-            </mkcol>
-            "#
-        );
+        let method = details.method.method()?;
+        let body = details.to_xml();
 
         tracing::debug!("calendar: {}", body);
 
-        // Make sure to set the calendar timezone to UTC
-        let res = client
-            .request(method, url.clone())
+        let req = self
+            .client
+            .request(method, url.as_str())
             .header(CONTENT_TYPE, "application/xml")
             .header(CONTENT_LENGTH, body.len())
-            .basic_auth(
-                &self.client.credentials.username,
-                Some(&self.client.credentials.password),
-            )
-            .body(body)
-            .send()
-            .await?;
+            .body(body);
+        let res = self.client.send(req).await?;
 
         tracing::debug!("response: {:?}", res);
         let res = match res.status() {
@@ -227,17 +198,29 @@ impl Principal {
                 return Err(CaldavError::ServerResponse(error));
             }
         };
+        // a Location header says where the server created the collection, if it differs
+        let url = match res.headers().get(LOCATION).and_then(|l| l.to_str().ok()) {
+            Some(location) => resolve_href(&url, location)?,
+            None => url,
+        };
         let text = res.text().await?;
 
         tracing::debug!("calendar response: {}", text);
 
-        Ok(Calendar::new(
-            self.client.clone(),
-            self.url.clone(),
-            // TODO: this is wrong, need to get the href from the response
-            url,
-            calendar_name.to_string(),
-            Some("UTC".to_string()),
-        ))
+        // read the calendar back, so that it is identified by the href the server gives it
+        let calendar = self
+            .find_calendars(&url, 0)
+            .await?
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| {
+                Calendar::new(
+                    self.client.clone(),
+                    url,
+                    details.display_name.clone(),
+                    details.timezone.clone(),
+                )
+            });
+        Ok(calendar)
     }
 }
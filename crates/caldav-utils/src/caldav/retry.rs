@@ -0,0 +1,141 @@
+use std::{
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use reqwest::{
+    header::{HeaderMap, IF_NONE_MATCH, RETRY_AFTER},
+    Method, StatusCode,
+};
+
+/// How requests to a CalDAV server are retried when they fail in a way that may be temporary,
+/// i.e. a connection error, a timeout, or a 408, 429, 502, 503 or 504 response.
+///
+/// Only requests which can safely be sent twice are retried. By default these are
+/// PROPFIND, REPORT and GET, as well as a PUT with If-None-Match, since it can only
+/// create a resource once.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The most times a request is sent, including the first.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// The methods which are always retried.
+    pub methods: Vec<Method>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+            methods: vec![
+                Method::from_bytes(b"PROPFIND").expect("failed to create PROPFIND method"),
+                Method::from_bytes(b"REPORT").expect("failed to create REPORT method"),
+                Method::GET,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Send every request once.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_base_delay(mut self, delay: Duration) -> Self {
+        self.base_delay = delay;
+        self
+    }
+
+    pub fn with_max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Also retry requests with the given method, which should be idempotent for the server.
+    pub fn with_method(mut self, method: Method) -> Self {
+        self.methods.push(method);
+        self
+    }
+
+    /// Whether a request may be sent again.
+    pub fn is_retryable(&self, method: &Method, headers: &HeaderMap) -> bool {
+        self.methods.contains(method)
+            || (*method == Method::PUT && headers.contains_key(IF_NONE_MATCH))
+    }
+
+    /// The delay after the given number of failed attempts, doubling after each failure.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.base_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+
+    /// How long to wait before sending a request again after a transient response,
+    /// which is as long as its Retry-After header asks for if it has one.
+    /// Servers which ask for a longer wait than `max_delay` aren't retried, so this is `None`.
+    pub fn transient_delay(
+        &self,
+        headers: &HeaderMap,
+        attempts: u32,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Option<Duration> {
+        match retry_after(headers, now) {
+            Some(delay) if delay > self.max_delay => None,
+            Some(delay) => Some(delay),
+            None => Some(self.jittered_delay(attempts)),
+        }
+    }
+
+    /// The delay after the given number of failed attempts, randomly shortened by up to
+    /// half so that clients which failed together don't all retry together.
+    pub fn jittered_delay(&self, attempts: u32) -> Duration {
+        let delay = self.delay(attempts);
+        let random = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish();
+        delay / 2 + delay / 2 * (random % 1000) as u32 / 1000
+    }
+}
+
+/// Whether a response means the request might succeed if it is sent again.
+pub(crate) fn is_transient(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// How long a response asks for the request to be delayed, from its Retry-After header,
+/// which is either a number of seconds or an HTTP date.
+pub(crate) fn retry_after(
+    headers: &HeaderMap,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&chrono::Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
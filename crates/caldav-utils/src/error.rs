@@ -8,16 +8,34 @@ pub enum CaldavError {
     ChronoParse(#[from] chrono::ParseError),
     #[error("Calendar not found: {calendar_name}")]
     CalendarNotFound { calendar_name: String },
+    #[error("Event not found: {uid}")]
+    EventNotFound { uid: String },
+    /// The event was changed by someone else since it was read.
+    #[error("Event changed since it was read: {uid}")]
+    EventChanged { uid: String },
     #[error(transparent)]
-    InvalidMethod(#[from] http::method::InvalidMethod),
+    Io(#[from] std::io::Error),
+    #[error("Invalid iCalendar data at {href}: {reason}")]
+    InvalidICalendar { href: String, reason: String },
     #[error(transparent)]
-    Minidom(#[from] minidom::Error),
+    InvalidMethod(#[from] http::method::InvalidMethod),
+    #[error("Invalid url {url}: {source}")]
+    InvalidUrl {
+        url: String,
+        source: url::ParseError,
+    },
+    #[error("Malformed XML from {url}: {source}")]
+    MalformedXml { url: String, source: minidom::Error },
+    #[error("{resource} has no {property}")]
+    MissingProperty { resource: String, property: String },
     #[error(transparent)]
     Reqwest(#[from] reqwest::Error),
     #[error(transparent)]
     RRule(#[from] rrule::RRuleError),
     #[error("Error calling caldav server: {0}")]
     ServerResponse(String),
+    #[error("Unknown timezone: {0}")]
+    UnknownTimezone(String),
 }
 
 pub type CaldavResult<T> = Result<T, CaldavError>;
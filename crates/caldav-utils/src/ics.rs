@@ -0,0 +1,247 @@
+use std::{
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use icalendar::Component;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use url::Url;
+
+use crate::{
+    availability::{event_occurrences, ranges_matrix},
+    caldav::event::Event,
+    error::{CaldavError, CaldavResult},
+};
+
+/// Where an icalendar feed is read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IcsLocation {
+    Url(Url),
+    File(PathBuf),
+}
+
+/// The last response from a remote feed, used to make conditional requests.
+#[derive(Clone, Debug)]
+struct CachedFeed {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: String,
+}
+
+/// A read-only icalendar feed, such as a webcal subscription or an exported `.ics` file.
+/// Its events are treated as busy time in the same way as the booked calendar's.
+#[derive(Clone, Debug)]
+pub struct IcsSource {
+    location: IcsLocation,
+    cache: Arc<Mutex<Option<CachedFeed>>>,
+    client: reqwest::Client,
+}
+
+impl IcsSource {
+    pub fn new(location: IcsLocation) -> IcsSource {
+        IcsSource {
+            location,
+            cache: Arc::new(Mutex::new(None)),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Fetch remote feeds with the given client, e.g. [`DavClient::http`] to share its
+    /// connections and configuration.
+    ///
+    /// [`DavClient::http`]: crate::caldav::client::DavClient::http
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// A remote feed. `webcal://` urls are fetched over https.
+    pub fn url(url: &str) -> CaldavResult<IcsSource> {
+        let url = match url.strip_prefix("webcal://") {
+            Some(rest) => format!("https://{rest}"),
+            None => url.to_string(),
+        };
+        let url = Url::parse(&url).map_err(|source| CaldavError::InvalidUrl { url, source })?;
+        Ok(IcsSource::new(IcsLocation::Url(url)))
+    }
+
+    pub fn file(path: impl Into<PathBuf>) -> IcsSource {
+        IcsSource::new(IcsLocation::File(path.into()))
+    }
+
+    pub fn location(&self) -> &IcsLocation {
+        &self.location
+    }
+
+    /// Read the feed's icalendar text.
+    /// Remote feeds are only downloaded again if they have changed since the last fetch,
+    /// according to their ETag or Last-Modified headers, and if one can't be downloaded
+    /// the text from the last successful fetch is used.
+    pub async fn fetch(&self) -> CaldavResult<String> {
+        let url = match &self.location {
+            IcsLocation::File(path) => return Ok(tokio::fs::read_to_string(path).await?),
+            IcsLocation::Url(url) => url,
+        };
+
+        let cached = self.cache.lock().unwrap().clone();
+        match self.fetch_url(url, cached.as_ref()).await {
+            Err(e) if cached.is_some() => {
+                tracing::warn!(
+                    "using the cached copy of {}, failed to fetch it: {}",
+                    url,
+                    e
+                );
+                Ok(cached.map(|cached| cached.body).unwrap_or_default())
+            }
+            result => result,
+        }
+    }
+
+    async fn fetch_url(&self, url: &Url, cached: Option<&CachedFeed>) -> CaldavResult<String> {
+        let mut req = self.client.get(url.as_str());
+        if let Some(cached) = cached {
+            if let Some(etag) = &cached.etag {
+                req = req.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                req = req.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        tracing::debug!("fetching feed from {}", url);
+        let res = req.send().await?;
+
+        match res.status() {
+            reqwest::StatusCode::NOT_MODIFIED => match cached {
+                Some(cached) => Ok(cached.body.clone()),
+                None => Err(CaldavError::ServerResponse(format!(
+                    "{url} was not modified, but has not been fetched before"
+                ))),
+            },
+            status if status.is_success() => {
+                let header = |name| {
+                    res.headers()
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string)
+                };
+                let etag = header(ETAG);
+                let last_modified = header(LAST_MODIFIED);
+                let body = res.text().await?;
+
+                *self.cache.lock().unwrap() = Some(CachedFeed {
+                    etag,
+                    last_modified,
+                    body: body.clone(),
+                });
+                Ok(body)
+            }
+            _ => {
+                let error = res.text().await?;
+                Err(CaldavError::ServerResponse(error))
+            }
+        }
+    }
+
+    /// A token which changes whenever the feed does: a file's modification time, or a remote
+    /// feed's ETag or Last-Modified header, falling back to a hash of its text.
+    /// Remote feeds are fetched conditionally, so this is cheap when they haven't changed.
+    pub async fn change_token(&self) -> CaldavResult<Option<String>> {
+        if let IcsLocation::File(path) = &self.location {
+            let modified = tokio::fs::metadata(path).await?.modified()?;
+            return Ok(Some(format!("{modified:?}")));
+        }
+
+        let text = self.fetch().await?;
+        let cached = self.cache.lock().unwrap().clone();
+        Ok(cached
+            .and_then(|cached| cached.etag.or(cached.last_modified))
+            .or_else(|| {
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                std::hash::Hash::hash(&text, &mut hasher);
+                Some(format!("{:x}", std::hash::Hasher::finish(&hasher)))
+            }))
+    }
+
+    /// The events in the feed, each in its own icalendar object.
+    pub async fn events(&self) -> CaldavResult<Vec<Event>> {
+        let text = self.fetch().await?;
+        let href = match &self.location {
+            IcsLocation::Url(url) => url.to_string(),
+            IcsLocation::File(path) => path.to_string_lossy().into_owned(),
+        };
+        Ok(Event::parse_at(&href, &text)?.split())
+    }
+
+    /// Build a matrix where every slot covered by one of the feed's events is true.
+    pub async fn busy_matrix(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        granularity: chrono::Duration,
+    ) -> CaldavResult<Vec<bool>> {
+        let events = self.events().await?;
+        busy_matrix(start, end, granularity, &events)
+    }
+}
+
+impl FromStr for IcsSource {
+    type Err = CaldavError;
+
+    /// Urls with an http, https or webcal scheme are remote feeds, anything else is a file path.
+    fn from_str(s: &str) -> CaldavResult<IcsSource> {
+        if ["http://", "https://", "webcal://"]
+            .iter()
+            .any(|scheme| s.starts_with(scheme))
+        {
+            IcsSource::url(s)
+        } else {
+            Ok(IcsSource::file(s))
+        }
+    }
+}
+
+/// Build a matrix where every slot covered by one of the events is true.
+/// Unlike a CalDAV calendar, a feed is not filtered by the server, so events which are
+/// transparent or cancelled are skipped here, and all-day events cover their whole days.
+pub fn busy_matrix(
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    granularity: chrono::Duration,
+    events: &[Event],
+) -> CaldavResult<Vec<bool>> {
+    let mut ranges = Vec::new();
+    for event in events {
+        let Some(vevent) = event.vevent() else {
+            continue;
+        };
+        if vevent.property_value("TRANSP") == Some("TRANSPARENT")
+            || event.status() == Some(icalendar::EventStatus::Cancelled)
+        {
+            continue;
+        }
+        if vevent.property_value("RRULE").is_some() {
+            match event_occurrences(event, start, end, None) {
+                Ok(occurrences) => ranges.extend(occurrences),
+                Err(e) => tracing::warn!("skipping feed event {:?}: {}", event.uid(), e),
+            }
+            continue;
+        }
+
+        let Some(event_start) = event.start() else {
+            continue;
+        };
+        let event_end = match event.end() {
+            Some(event_end) => event_end,
+            None if event.is_all_day() => event_start + chrono::Duration::days(1),
+            None => {
+                tracing::warn!("skipping feed event {:?} without an end", event.uid());
+                continue;
+            }
+        };
+        ranges.push((event_start, event_end));
+    }
+
+    ranges_matrix(start, end, granularity, &ranges)
+}
@@ -2,7 +2,11 @@ pub mod availability;
 pub mod caldav;
 pub mod error;
 pub mod format;
+pub mod ics;
+pub mod source;
 pub mod util;
+pub mod vdir;
+pub mod vtimezone;
 
 #[cfg(test)]
 mod tests;
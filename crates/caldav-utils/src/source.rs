@@ -0,0 +1,327 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use icalendar::Component;
+
+use crate::{
+    availability::vavailability::VAvailability,
+    caldav::{
+        calendar::Calendar,
+        event::{Event, NewEvent},
+    },
+    error::CaldavResult,
+};
+
+/// Somewhere events can be read from when determining availability.
+#[async_trait::async_trait]
+pub trait CalendarSource: std::fmt::Debug + Send + Sync {
+    /// The events which may occur between two datetimes.
+    /// Recurring events are returned whenever any of their occurrences might.
+    async fn events(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<Event>>;
+
+    /// The timezone that floating times in the events are in.
+    fn timezone(&self) -> Option<String> {
+        None
+    }
+
+    /// The VAVAILABILITY components which overlap two datetimes.
+    async fn availabilities(
+        &self,
+        _start: chrono::DateTime<chrono::Utc>,
+        _end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<VAvailability>> {
+        Ok(Vec::new())
+    }
+
+    /// A value which changes whenever the source's events do, if the source can provide one
+    /// cheaply. When this returns `None` the source has to be read to know if it has changed.
+    async fn change_token(&self) -> CaldavResult<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// A calendar which events can be written to, as well as read from.
+#[async_trait::async_trait]
+pub trait CalendarStore: CalendarSource {
+    /// The name the calendar is shown with.
+    fn display_name(&self) -> &str;
+
+    /// Where the calendar is stored, for logging.
+    fn path(&self) -> &str;
+
+    async fn create_event(&self, details: &NewEvent) -> CaldavResult<Event>;
+
+    async fn get_event(&self, uid: &str) -> CaldavResult<Event>;
+
+    /// Fetch an event along with a tag identifying its version, if the store has them.
+    async fn get_event_with_etag(&self, uid: &str) -> CaldavResult<(Event, Option<String>)> {
+        Ok((self.get_event(uid).await?, None))
+    }
+
+    async fn update_event(&self, event: &Event) -> CaldavResult<()>;
+
+    /// Replace an event only if it is still the version with the given tag, failing with
+    /// [`CaldavError::EventChanged`](crate::error::CaldavError::EventChanged) otherwise.
+    async fn update_event_if_match(&self, event: &Event, etag: Option<&str>) -> CaldavResult<()> {
+        let _ = etag;
+        self.update_event(event).await
+    }
+
+    async fn delete_event(&self, uid: &str) -> CaldavResult<()>;
+
+    /// Remove an event only if it is still the version with the given tag.
+    async fn delete_event_if_match(&self, uid: &str, etag: Option<&str>) -> CaldavResult<()> {
+        let _ = etag;
+        self.delete_event(uid).await
+    }
+
+    async fn put_availability(&self, availability: &VAvailability) -> CaldavResult<()>;
+
+    /// Find the events between two datetimes which the given email address is attending.
+    async fn get_events_by_attendee(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        email: &str,
+    ) -> CaldavResult<Vec<Event>> {
+        let events = self.events(start, end).await?;
+        Ok(events
+            .into_iter()
+            .filter(|event| event.has_attendee(email))
+            .collect())
+    }
+}
+
+#[async_trait::async_trait]
+impl CalendarSource for Calendar {
+    async fn events(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<Event>> {
+        self.get_events(start, end).await
+    }
+
+    fn timezone(&self) -> Option<String> {
+        self.timezone.clone()
+    }
+
+    async fn availabilities(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<VAvailability>> {
+        self.get_availabilities(start, end).await
+    }
+
+    async fn change_token(&self) -> CaldavResult<Option<String>> {
+        self.get_change_token().await
+    }
+}
+
+#[async_trait::async_trait]
+impl CalendarStore for Calendar {
+    fn display_name(&self) -> &str {
+        &self.display_name
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    async fn create_event(&self, details: &NewEvent) -> CaldavResult<Event> {
+        Calendar::create_event(self, details).await
+    }
+
+    async fn get_event(&self, uid: &str) -> CaldavResult<Event> {
+        Calendar::get_event(self, uid).await
+    }
+
+    async fn get_event_with_etag(&self, uid: &str) -> CaldavResult<(Event, Option<String>)> {
+        Calendar::get_event_with_etag(self, uid).await
+    }
+
+    async fn update_event(&self, event: &Event) -> CaldavResult<()> {
+        Calendar::update_event(self, event).await
+    }
+
+    async fn update_event_if_match(&self, event: &Event, etag: Option<&str>) -> CaldavResult<()> {
+        Calendar::update_event_if_match(self, event, etag).await
+    }
+
+    async fn delete_event(&self, uid: &str) -> CaldavResult<()> {
+        Calendar::delete_event(self, uid).await
+    }
+
+    async fn delete_event_if_match(&self, uid: &str, etag: Option<&str>) -> CaldavResult<()> {
+        Calendar::delete_event_if_match(self, uid, etag).await
+    }
+
+    async fn put_availability(&self, availability: &VAvailability) -> CaldavResult<()> {
+        Calendar::put_availability(self, availability).await
+    }
+}
+
+/// Whether an event may occur between two datetimes.
+pub(crate) fn may_overlap(
+    event: &Event,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+) -> bool {
+    if event
+        .vevent()
+        .is_some_and(|vevent| vevent.property_value("RRULE").is_some())
+    {
+        return true;
+    }
+    let Some(event_start) = event.start() else {
+        return false;
+    };
+    let event_end = event.end().unwrap_or(if event.is_all_day() {
+        event_start + chrono::Duration::days(1)
+    } else {
+        event_start
+    });
+    event_start < end && start <= event_end
+}
+
+/// Events kept in memory, e.g. for tests or events imported from elsewhere.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryCalendar {
+    events: Arc<RwLock<Vec<Event>>>,
+    availabilities: Arc<Vec<VAvailability>>,
+    timezone: Option<String>,
+}
+
+impl MemoryCalendar {
+    pub fn new(events: Vec<Event>) -> MemoryCalendar {
+        MemoryCalendar {
+            events: Arc::new(RwLock::new(events)),
+            availabilities: Arc::new(Vec::new()),
+            timezone: None,
+        }
+    }
+
+    pub fn with_timezone(mut self, timezone: String) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    pub fn with_availabilities(mut self, availabilities: Vec<VAvailability>) -> Self {
+        self.availabilities = Arc::new(availabilities);
+        self
+    }
+
+    /// Copy the events and VAVAILABILITY components of another source between two datetimes,
+    /// so that availability within them can be found many times without reading it again.
+    pub async fn snapshot(
+        source: &dyn CalendarSource,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<MemoryCalendar> {
+        let calendar = MemoryCalendar::new(source.events(start, end).await?)
+            .with_availabilities(source.availabilities(start, end).await?);
+        Ok(match source.timezone() {
+            Some(timezone) => calendar.with_timezone(timezone),
+            None => calendar,
+        })
+    }
+
+    /// Add an event, replacing any existing event with the same UID.
+    pub fn insert(&self, event: Event) {
+        let mut events = self.events.write().unwrap();
+        if let Some(uid) = event.uid() {
+            events.retain(|existing| existing.uid() != Some(uid));
+        }
+        events.push(event);
+    }
+
+    /// Remove the event with the given UID, returning whether it existed.
+    pub fn remove(&self, uid: &str) -> bool {
+        let mut events = self.events.write().unwrap();
+        let before = events.len();
+        events.retain(|event| event.uid() != Some(uid));
+        events.len() != before
+    }
+}
+
+#[async_trait::async_trait]
+impl CalendarSource for MemoryCalendar {
+    async fn events(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<Event>> {
+        Ok(self
+            .events
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|event| may_overlap(event, start, end))
+            .cloned()
+            .collect())
+    }
+
+    fn timezone(&self) -> Option<String> {
+        self.timezone.clone()
+    }
+
+    async fn availabilities(
+        &self,
+        _start: chrono::DateTime<chrono::Utc>,
+        _end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<VAvailability>> {
+        Ok(self.availabilities.to_vec())
+    }
+}
+
+/// A local directory of `.ics` files, which are read every time events are requested.
+#[derive(Clone, Debug)]
+pub struct IcsDirectory {
+    path: PathBuf,
+}
+
+impl IcsDirectory {
+    pub fn new(path: PathBuf) -> IcsDirectory {
+        IcsDirectory { path }
+    }
+}
+
+#[async_trait::async_trait]
+impl CalendarSource for IcsDirectory {
+    async fn events(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<Event>> {
+        let mut events = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("ics") {
+                continue;
+            }
+            let text = tokio::fs::read_to_string(&path).await?;
+            let event = match Event::parse_at(&path.to_string_lossy(), &text) {
+                Ok(event) => event,
+                Err(e) => {
+                    tracing::warn!("skipping {}", e);
+                    continue;
+                }
+            };
+            events.extend(
+                event
+                    .split()
+                    .into_iter()
+                    .filter(|event| may_overlap(event, start, end)),
+            );
+        }
+        Ok(events)
+    }
+}
@@ -1,11 +1,29 @@
+use caldav_mock::{
+    fixture::{FIXTURE_PASSWORD, FIXTURE_USERNAME},
+    Fixture, MockCaldav, MockServer, Quirks, Recorder, ReplayServer,
+};
 use icalendar::{Component, EventLike, Property};
 
 use crate::{
     availability::{
-        generate_matrix_no_rrule, generate_matrix_rrule, get_event_matrix, get_num_slots,
+        event_occurrences, events_matrix, generate_matrix_no_rrule, generate_matrix_rrule,
+        get_availability_excluding, get_event_matrix, get_num_slots, ranges_matrix,
+        vavailability::{vavailability_matrix, BusyType, VAvailability},
+        working_hours::WorkingHours,
+        AvailabilityResponse,
+    },
+    caldav::{
+        calendar::{timezone_id, CreateMethod, NewCalendar},
+        client::{ConnectionConfig, DavClient, DavCredentials},
+        event::{Attendee, Event, NewEvent, Organizer, PartStat},
+        retry::{self, RetryPolicy},
     },
-    caldav::event::Event,
+    error::{CaldavError, CaldavResult},
     format::DATETIME,
+    ics::IcsSource,
+    source::{CalendarSource, IcsDirectory, MemoryCalendar},
+    util,
+    vdir::Vdir,
 };
 
 fn build_event(
@@ -56,7 +74,7 @@ fn build_matrix_test(
     matrix.expect("Failed to build matrix")
 }
 
-fn print_matrix_diff(left: &Vec<bool>, right: &Vec<bool>) {
+fn print_matrix_diff(left: &[bool], right: &[bool]) {
     let mut left_iter = left.iter();
     let mut right_iter = right.iter();
     let mut index = 0;
@@ -100,8 +118,6 @@ async fn availability_30_min() {
     println!("expected: {:?}", expected);
     println!("res: {:?}", res);
     assert_eq!(res, expected);
-
-    assert!(true);
 }
 
 #[tokio::test]
@@ -131,8 +147,127 @@ async fn availability_30_min_rrule() {
     );
     print_matrix_diff(&res, &expected);
     assert_eq!(res, expected);
+}
+
+#[tokio::test]
+async fn availability_rrule_exdate() {
+    let range_start = chrono::DateTime::parse_from_rfc3339("2023-01-09T00:00:00Z")
+        .unwrap()
+        .with_timezone(&chrono::Utc);
+    let range_end = range_start + chrono::Duration::days(5);
+    let granularity = chrono::Duration::minutes(30);
+
+    let event_start = range_start + chrono::Duration::hours(9);
+    let event_end = event_start + chrono::Duration::hours(1);
+    let rrule = Some("FREQ=DAILY;COUNT=3".to_string());
+    let skipped = event_start + chrono::Duration::days(1);
+
+    let mut event = build_event(event_start, event_end, rrule.clone());
+    event.append_multi_property(Property::new(
+        "EXDATE",
+        &format!("{}", skipped.format(DATETIME)),
+    ));
+    let event = Event::new(build_calendar(vec![event]));
+    let res = get_event_matrix(range_start, range_end, granularity, &event, None).unwrap();
+
+    let all = build_matrix_test(
+        range_start,
+        range_end,
+        event_start,
+        event_end,
+        rrule,
+        granularity,
+    );
+    // only the skipped day differs from the series without an EXDATE
+    let day = 48;
+    assert!(all[day..day * 2].iter().any(|x| *x));
+    assert!(res[day..day * 2].iter().all(|x| !*x));
+    assert_eq!(res[..day], all[..day]);
+    assert_eq!(res[day * 2..], all[day * 2..]);
+}
+
+#[test]
+fn every_occurrence_in_range_is_expanded() -> Result<(), Box<dyn std::error::Error>> {
+    let range_start =
+        chrono::DateTime::parse_from_rfc3339("2023-01-09T00:00:00Z")?.with_timezone(&chrono::Utc);
+    let range_end = range_start + chrono::Duration::days(60);
+    let granularity = chrono::Duration::minutes(30);
+
+    // hourly for 60 days is 1440 occurrences, more than are expanded at a time
+    let event_start = range_start;
+    let event_end = event_start + chrono::Duration::minutes(30);
+    let event = build_event(event_start, event_end, Some("FREQ=HOURLY".to_string()));
+    let event = Event::new(build_calendar(vec![event]));
+
+    let occurrences = event_occurrences(&event, range_start, range_end, None)?;
+    assert_eq!(occurrences.len(), 24 * 60);
+    assert_eq!(
+        occurrences.last(),
+        Some(&(
+            range_end - chrono::Duration::hours(1),
+            range_end - chrono::Duration::minutes(30)
+        ))
+    );
+    // each occurrence is only expanded once
+    assert!(occurrences.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    let matrix = get_event_matrix(range_start, range_end, granularity, &event, None)?;
+    // including the last day, long after the first hundred occurrences
+    let last_day = &matrix[matrix.len() - 48..];
+    assert_eq!(last_day.iter().filter(|busy| **busy).count(), 24);
+    Ok(())
+}
+
+#[test]
+fn floating_times_are_in_the_calendar_timezone() -> Result<(), Box<dyn std::error::Error>> {
+    // 9:00 to 10:00 in Berlin, every day over the change to summer time on the 26th of March
+    let mut event = icalendar::Event::new();
+    event.append_property(Property::new("DTSTART", "20230324T090000"));
+    event.append_property(Property::new("DTEND", "20230324T100000"));
+    event.append_property(Property::new("RRULE", "FREQ=DAILY;COUNT=4"));
+    let event = Event::new(build_calendar(vec![event]));
+
+    let range_start =
+        chrono::DateTime::parse_from_rfc3339("2023-03-24T00:00:00Z")?.with_timezone(&chrono::Utc);
+    let range_end = range_start + chrono::Duration::days(4);
+    let timezone = Some("Europe/Berlin".to_string());
+    let occurrences = event_occurrences(&event, range_start, range_end, timezone.clone())?;
+    let utc = |hour: i64, day: i64| {
+        range_start + chrono::Duration::days(day) + chrono::Duration::hours(hour)
+    };
+    // the occurrences keep their local time, so they are an hour earlier in UTC after the change
+    assert_eq!(
+        occurrences,
+        vec![
+            (utc(8, 0), utc(9, 0)),
+            (utc(8, 1), utc(9, 1)),
+            (utc(7, 2), utc(8, 2)),
+            (utc(7, 3), utc(8, 3)),
+        ]
+    );
+    let granularity = chrono::Duration::hours(1);
+    let matrix = get_event_matrix(range_start, range_end, granularity, &event, timezone)?;
+    assert_eq!(
+        matrix,
+        ranges_matrix(range_start, range_end, granularity, &occurrences)?
+    );
+
+    // all-day events cover the whole day in the calendar's timezone
+    let mut event = icalendar::Event::new();
+    event.append_property(Property::new("DTSTART", "20230324"));
+    let event = Event::new(build_calendar(vec![event]));
+    let occurrences = event_occurrences(&event, range_start, range_end, Some("Asia/Tokyo".into()))?;
+    assert_eq!(occurrences, vec![(utc(-9, 0), utc(15, 0))]);
 
-    assert!(true);
+    // events can't be placed in a timezone which isn't known, and are skipped
+    let unknown = Some("Mars/Olympus".to_string());
+    assert!(matches!(
+        event_occurrences(&event, range_start, range_end, unknown.clone()),
+        Err(CaldavError::UnknownTimezone(_))
+    ));
+    let matrix = events_matrix(range_start, range_end, granularity, &[event], unknown)?;
+    assert!(matrix.iter().all(|busy| !busy));
+    Ok(())
 }
 
 #[tokio::test]
@@ -179,7 +314,7 @@ async fn tests_no_rrule() -> Result<(), Box<dyn std::error::Error>> {
         expected: Vec<bool>,
     }
 
-    fn print_test_details(test_case: &TestCase, matrix: &Vec<bool>, num_slots: usize) {
+    fn print_test_details(test_case: &TestCase, matrix: &[bool], num_slots: usize) {
         println!(
             r#"
             Test case:
@@ -193,7 +328,7 @@ async fn tests_no_rrule() -> Result<(), Box<dyn std::error::Error>> {
         );
     }
 
-    let availability_ranges = vec![
+    let availability_ranges = [
         AvailabilityRange {
             start: chrono::DateTime::parse_from_rfc3339("2023-01-12T14:00:00.000000000Z")
                 .unwrap()
@@ -220,7 +355,7 @@ async fn tests_no_rrule() -> Result<(), Box<dyn std::error::Error>> {
         },
     ];
 
-    let test_cases = vec![
+    let test_cases = [
         TestCase {
             start: chrono::DateTime::parse_from_rfc3339("2023-01-12T14:00:00.000000000Z")
                 .unwrap()
@@ -316,7 +451,7 @@ async fn tests_rrule() -> Result<(), Box<dyn std::error::Error>> {
         expected: Vec<bool>,
     }
 
-    fn print_test_details(test_case: &TestCase, matrix: &Vec<bool>, num_slots: usize) {
+    fn print_test_details(test_case: &TestCase, matrix: &[bool], num_slots: usize) {
         println!(
             r#"
             Test case:
@@ -330,7 +465,7 @@ async fn tests_rrule() -> Result<(), Box<dyn std::error::Error>> {
         );
     }
 
-    let availability_ranges = vec![AvailabilityRange {
+    let availability_ranges = [AvailabilityRange {
         start: chrono::DateTime::parse_from_rfc3339("2023-01-12T14:00:00.000000000Z")
             .unwrap()
             .into(),
@@ -340,7 +475,7 @@ async fn tests_rrule() -> Result<(), Box<dyn std::error::Error>> {
         rrule: "FREQ=DAILY;COUNT=3".to_string(),
     }];
 
-    let test_cases = vec![
+    let test_cases = [
         TestCase {
             start: chrono::DateTime::parse_from_rfc3339("2023-01-12T14:00:00.000000000Z")
                 .unwrap()
@@ -429,7 +564,7 @@ async fn tests_rrule() -> Result<(), Box<dyn std::error::Error>> {
 
                 generate_matrix_rrule(
                     &event,
-                    &rrule::Tz::UTC,
+                    &chrono_tz::UTC,
                     test_case.start,
                     test_case.end,
                     num_slots as i64,
@@ -444,9 +579,1362 @@ async fn tests_rrule() -> Result<(), Box<dyn std::error::Error>> {
                     .collect()
             });
 
-        print_test_details(&test_case, &matrix, num_slots);
+        print_test_details(test_case, &matrix, num_slots);
         assert_eq!(matrix, test_case.expected);
     }
 
     Ok(())
 }
+
+#[test]
+fn event_attendees_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+    let start = chrono::DateTime::parse_from_rfc3339("2023-01-12T14:00:00Z")?.into();
+    let end = chrono::DateTime::parse_from_rfc3339("2023-01-12T14:30:00Z")?.into();
+
+    let details = NewEvent::new(start, end, "meeting")
+        .description("talk about things")
+        .location("room 1")
+        .url("https://example.com/call")
+        .organizer(Organizer::new(
+            "host@example.com".to_string(),
+            Some("Host".to_string()),
+        ))
+        .attendee(
+            Attendee::new(
+                "first@example.com".to_string(),
+                Some("Doe, Jane".to_string()),
+            )
+            .partstat(PartStat::Accepted),
+        )
+        .attendee(Attendee::new("second@example.com".to_string(), None).rsvp(true))
+        .attendee(Attendee::new(
+            "third@example.com".to_string(),
+            Some("Jim \"JJ\" Jones, Jr".to_string()),
+        ))
+        .property("X-BOOKING-EMAIL", "first@example.com");
+
+    let calendar = build_calendar(vec![details.to_ical("abc")]);
+    let event = Event::parse(&calendar.to_string())?;
+
+    assert_eq!(event.uid(), Some("abc"));
+    assert_eq!(
+        event.organizer(),
+        Some(Organizer::new(
+            "host@example.com".to_string(),
+            Some("Host".to_string())
+        ))
+    );
+
+    let attendees = event.attendees();
+    assert_eq!(attendees.len(), 3);
+    assert!(attendees.contains(
+        &Attendee::new(
+            "first@example.com".to_string(),
+            Some("Doe, Jane".to_string())
+        )
+        .partstat(PartStat::Accepted)
+    ));
+    assert!(attendees.contains(&Attendee::new("second@example.com".to_string(), None).rsvp(true)));
+    // double quotes can't appear in a parameter value
+    assert!(attendees.contains(&Attendee::new(
+        "third@example.com".to_string(),
+        Some("Jim 'JJ' Jones, Jr".to_string())
+    )));
+    assert!(event.has_attendee("SECOND@example.com"));
+    assert!(!event.has_attendee("fourth@example.com"));
+
+    let vevent = event.vevent().unwrap();
+    assert_eq!(vevent.get_location(), Some("room 1"));
+    assert_eq!(vevent.get_url(), Some("https://example.com/call"));
+    assert_eq!(
+        event.property_values("X-BOOKING-EMAIL"),
+        vec!["first@example.com"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn event_add_and_remove_attendees() -> Result<(), Box<dyn std::error::Error>> {
+    let start = chrono::DateTime::parse_from_rfc3339("2023-01-12T14:00:00Z")?.into();
+    let end = chrono::DateTime::parse_from_rfc3339("2023-01-12T15:00:00Z")?.into();
+
+    let details = NewEvent::new(start, end, "workshop")
+        .attendee(Attendee::new("first@example.com".to_string(), None))
+        .property("X-BOOKING-CAPACITY", "10");
+    let calendar = build_calendar(vec![details.to_ical("abc")]);
+    let mut event = Event::parse(&calendar.to_string())?;
+
+    event.add_attendee(&Attendee::new(
+        "second@example.com".to_string(),
+        Some("Second".to_string()),
+    ));
+    let mut event = Event::parse(&event.ical.to_string())?;
+    assert_eq!(event.attendees().len(), 2);
+
+    assert!(event.remove_attendee("FIRST@example.com"));
+    assert!(!event.remove_attendee("third@example.com"));
+
+    // the rest of the event is untouched
+    let event = Event::parse(&event.ical.to_string())?;
+    assert_eq!(
+        event.attendees(),
+        vec![Attendee::new(
+            "second@example.com".to_string(),
+            Some("Second".to_string())
+        )]
+    );
+    assert_eq!(event.uid(), Some("abc"));
+    assert_eq!(event.start(), Some(start));
+    assert_eq!(event.property_values("X-BOOKING-CAPACITY"), vec!["10"]);
+
+    Ok(())
+}
+
+#[test]
+fn event_split_all_day() -> Result<(), Box<dyn std::error::Error>> {
+    let calendar = Event::parse(
+        "BEGIN:VCALENDAR\r\n\
+         VERSION:2.0\r\n\
+         PRODID:test\r\n\
+         BEGIN:VEVENT\r\n\
+         UID:holiday\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART;VALUE=DATE:20231225\r\n\
+         END:VEVENT\r\n\
+         BEGIN:VEVENT\r\n\
+         UID:meeting\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART:20231227T100000Z\r\n\
+         DTEND:20231227T110000Z\r\n\
+         END:VEVENT\r\n\
+         END:VCALENDAR\r\n",
+    )?;
+
+    let events = calendar.split();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].uid(), Some("holiday"));
+    assert!(events[0].is_all_day());
+    assert_eq!(
+        events[0].start(),
+        Some(chrono::DateTime::parse_from_rfc3339("2023-12-25T00:00:00Z")?.into())
+    );
+    assert_eq!(events[1].uid(), Some("meeting"));
+    assert!(!events[1].is_all_day());
+    Ok(())
+}
+
+#[test]
+fn working_hours_ranges() -> Result<(), Box<dyn std::error::Error>> {
+    let hours = WorkingHours::from_json(
+        r#"{
+            "timezone": "Europe/Berlin",
+            "weekly": [{ "days": ["mon", "tue", "wed", "thu", "fri"], "start": "09:00", "end": "17:00" }],
+            "breaks": [{ "start": "12:00", "end": "13:00" }],
+            "overrides": [
+                { "date": "2023-12-21" },
+                { "date": "2023-12-22", "hours": [{ "start": "10:00", "end": "14:00" }] }
+            ]
+        }"#,
+    )?;
+
+    let utc = |s: &str| -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    };
+    // Wednesday to Sunday, where Berlin is an hour ahead of UTC
+    let ranges = hours.ranges(utc("2023-12-20T00:00:00Z"), utc("2023-12-25T00:00:00Z"))?;
+    assert_eq!(
+        ranges,
+        vec![
+            (utc("2023-12-20T08:00:00Z"), utc("2023-12-20T11:00:00Z")),
+            (utc("2023-12-20T12:00:00Z"), utc("2023-12-20T16:00:00Z")),
+            (utc("2023-12-22T09:00:00Z"), utc("2023-12-22T13:00:00Z")),
+        ]
+    );
+
+    assert!(WorkingHours::from_json(r#"{ "timezone": "Mars/Olympus", "weekly": [] }"#).is_err());
+    // hours which end before they start would never be open
+    assert!(WorkingHours::from_json(
+        r#"{ "timezone": "UTC", "weekly": [{ "days": ["Mon"], "start": "17:00", "end": "09:00" }] }"#
+    )
+    .is_err());
+    assert!(WorkingHours::from_json(
+        r#"{ "timezone": "UTC", "weekly": [], "breaks": [{ "start": "12:00", "end": "12:00" }] }"#
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn vavailability_priorities() -> Result<(), Box<dyn std::error::Error>> {
+    // weekday mornings in Berlin, except the 20th,
+    // overridden by a higher priority component closing the 21st
+    let object = Event::parse(
+        "BEGIN:VCALENDAR\r\n\
+         VERSION:2.0\r\n\
+         PRODID:test\r\n\
+         BEGIN:VAVAILABILITY\r\n\
+         UID:mornings\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         BEGIN:AVAILABLE\r\n\
+         UID:mornings-1\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART;TZID=Europe/Berlin:20231218T090000\r\n\
+         DURATION:PT3H\r\n\
+         RRULE:FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR\r\n\
+         EXDATE;TZID=Europe/Berlin:20231220T090000\r\n\
+         END:AVAILABLE\r\n\
+         END:VAVAILABILITY\r\n\
+         BEGIN:VAVAILABILITY\r\n\
+         UID:away\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         PRIORITY:1\r\n\
+         BUSYTYPE:BUSY\r\n\
+         DTSTART:20231221T000000Z\r\n\
+         DTEND:20231222T000000Z\r\n\
+         END:VAVAILABILITY\r\n\
+         END:VCALENDAR\r\n",
+    )?;
+    let components = VAvailability::from_event(&object, None)?;
+    assert_eq!(components.len(), 2);
+    assert_eq!(components[1].priority, 1);
+    assert_eq!(components[1].busy_type, BusyType::Busy);
+
+    let utc = |s: &str| -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    };
+    let start = utc("2023-12-19T00:00:00Z");
+    let end = utc("2023-12-23T00:00:00Z");
+    let granularity = chrono::Duration::minutes(30);
+
+    let matrix = vavailability_matrix(start, end, granularity, &components)?;
+    let expected = ranges_matrix(
+        start,
+        end,
+        granularity,
+        &[
+            (utc("2023-12-19T08:00:00Z"), utc("2023-12-19T11:00:00Z")),
+            (utc("2023-12-22T08:00:00Z"), utc("2023-12-22T11:00:00Z")),
+        ],
+    )?;
+    assert_eq!(matrix, expected);
+
+    // publishing the matrix describes the same open slots
+    let response = AvailabilityResponse {
+        start,
+        end,
+        granularity,
+        matrix: matrix.clone(),
+    };
+    let published = VAvailability::from_matrix("published", &response);
+    let reparsed =
+        VAvailability::from_event(&Event::parse(&published.to_ical().to_string())?, None)?;
+    assert_eq!(reparsed.len(), 1);
+    assert_eq!(reparsed[0].available.len(), 2);
+    let mut open = vec![false; matrix.len()];
+    for available in &reparsed[0].available {
+        let first = ((available.start - start).num_minutes() / 30) as usize;
+        let last = ((available.end - start).num_minutes() / 30) as usize;
+        open[first..last].iter_mut().for_each(|slot| *slot = true);
+    }
+    assert_eq!(open, matrix);
+    Ok(())
+}
+
+#[test]
+fn vavailability_dates_periods_and_tentative() -> Result<(), Box<dyn std::error::Error>> {
+    // a day in Berlin with a floating hour and a period, overlapped by a
+    // higher priority component which is only tentatively busy
+    let object = Event::parse(
+        "BEGIN:VCALENDAR\r\n\
+         VERSION:2.0\r\n\
+         PRODID:test\r\n\
+         BEGIN:VAVAILABILITY\r\n\
+         UID:day\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         PRIORITY:2\r\n\
+         DTSTART;VALUE=DATE:20231219\r\n\
+         DTEND;VALUE=DATE:20231220\r\n\
+         BEGIN:AVAILABLE\r\n\
+         UID:day-1\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART:20231219T100000\r\n\
+         DURATION:PT1H\r\n\
+         RDATE;VALUE=PERIOD:20231219T140000Z/PT2H\r\n\
+         END:AVAILABLE\r\n\
+         END:VAVAILABILITY\r\n\
+         BEGIN:VAVAILABILITY\r\n\
+         UID:maybe\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         PRIORITY:1\r\n\
+         BUSYTYPE:BUSY-TENTATIVE\r\n\
+         DTSTART:20231219T000000Z\r\n\
+         DTEND:20231220T000000Z\r\n\
+         BEGIN:AVAILABLE\r\n\
+         UID:maybe-1\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART:20231219T170000Z\r\n\
+         DURATION:PT1H\r\n\
+         END:AVAILABLE\r\n\
+         END:VAVAILABILITY\r\n\
+         END:VCALENDAR\r\n",
+    )?;
+    let components = VAvailability::from_event(&object, Some("Europe/Berlin"))?;
+    assert_eq!(components[1].busy_type, BusyType::BusyTentative);
+
+    let utc = |s: &str| -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    };
+    // the date is a day in Berlin rather than in UTC
+    assert_eq!(components[0].start, Some(utc("2023-12-18T23:00:00Z")));
+    assert_eq!(components[0].end, Some(utc("2023-12-19T23:00:00Z")));
+    assert_eq!(
+        components[0].available[0].rperiods,
+        vec![(utc("2023-12-19T14:00:00Z"), utc("2023-12-19T16:00:00Z"))]
+    );
+
+    let start = utc("2023-12-19T00:00:00Z");
+    let end = utc("2023-12-20T00:00:00Z");
+    let granularity = chrono::Duration::minutes(30);
+    let matrix = vavailability_matrix(start, end, granularity, &components)?;
+    let expected = ranges_matrix(
+        start,
+        end,
+        granularity,
+        &[
+            (utc("2023-12-19T09:00:00Z"), utc("2023-12-19T10:00:00Z")),
+            (utc("2023-12-19T14:00:00Z"), utc("2023-12-19T16:00:00Z")),
+            (utc("2023-12-19T17:00:00Z"), utc("2023-12-19T18:00:00Z")),
+        ],
+    )?;
+    assert_eq!(matrix, expected);
+
+    // periods survive being written out
+    let reparsed =
+        VAvailability::from_event(&Event::parse(&components[0].to_ical().to_string())?, None)?;
+    assert_eq!(
+        reparsed[0].available[0].rperiods,
+        components[0].available[0].rperiods
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn ics_feed_is_cached() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{
+        extract::State,
+        http::{header, HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
+    use std::sync::{Arc, Mutex};
+
+    const FEED: &str = "BEGIN:VCALENDAR\r\n\
+         VERSION:2.0\r\n\
+         PRODID:test\r\n\
+         BEGIN:VEVENT\r\n\
+         UID:dentist\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART;TZID=Europe/Berlin:20231219T100000\r\n\
+         DTEND;TZID=Europe/Berlin:20231219T110000\r\n\
+         END:VEVENT\r\n\
+         BEGIN:VEVENT\r\n\
+         UID:reminder\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART:20231219T140000Z\r\n\
+         DTEND:20231219T150000Z\r\n\
+         TRANSP:TRANSPARENT\r\n\
+         END:VEVENT\r\n\
+         BEGIN:VEVENT\r\n\
+         UID:holiday\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART;VALUE=DATE:20231220\r\n\
+         END:VEVENT\r\n\
+         BEGIN:VEVENT\r\n\
+         UID:standup\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART:20231218T080000Z\r\n\
+         DURATION:PT30M\r\n\
+         RRULE:FREQ=DAILY;COUNT=3\r\n\
+         END:VEVENT\r\n\
+         BEGIN:VEVENT\r\n\
+         UID:anniversary\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART;VALUE=DATE:20201221\r\n\
+         RRULE:FREQ=YEARLY\r\n\
+         END:VEVENT\r\n\
+         END:VCALENDAR\r\n";
+
+    // a feed server which only sends the feed when the client's copy is out of date,
+    // unless it has been told to fail
+    #[derive(Clone, Default)]
+    struct Requests(Arc<Mutex<Vec<StatusCode>>>, Arc<Mutex<bool>>);
+    async fn feed(State(requests): State<Requests>, headers: HeaderMap) -> impl IntoResponse {
+        let status = match headers.get(header::IF_NONE_MATCH) {
+            _ if *requests.1.lock().unwrap() => StatusCode::INTERNAL_SERVER_ERROR,
+            Some(etag) if etag == "\"v1\"" => StatusCode::NOT_MODIFIED,
+            _ => StatusCode::OK,
+        };
+        requests.0.lock().unwrap().push(status);
+        let body = if status == StatusCode::OK { FEED } else { "" };
+        (status, [(header::ETAG, "\"v1\"")], body)
+    }
+
+    let requests = Requests::default();
+    let app = Router::new()
+        .route("/feed.ics", get(feed))
+        .with_state(requests.clone());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::Server::from_tcp(listener)?.serve(app.into_make_service()));
+
+    let source: IcsSource = format!("http://{addr}/feed.ics").parse()?;
+    let utc = |s: &str| -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    };
+    let start = utc("2023-12-19T00:00:00Z");
+    let end = utc("2023-12-22T00:00:00Z");
+    let granularity = chrono::Duration::minutes(30);
+
+    // the second fetch reuses the cached feed
+    let first = source.busy_matrix(start, end, granularity).await?;
+    let second = source.busy_matrix(start, end, granularity).await?;
+    assert_eq!(first, second);
+    assert_eq!(
+        *requests.0.lock().unwrap(),
+        vec![StatusCode::OK, StatusCode::NOT_MODIFIED]
+    );
+
+    // transparent events are not busy, while all-day ones cover the whole day,
+    // and recurring events are expanded whether they have a DURATION or are all-day
+    let expected = ranges_matrix(
+        start,
+        end,
+        granularity,
+        &[
+            (utc("2023-12-19T08:00:00Z"), utc("2023-12-19T08:30:00Z")),
+            (utc("2023-12-19T09:00:00Z"), utc("2023-12-19T10:00:00Z")),
+            (utc("2023-12-20T00:00:00Z"), utc("2023-12-22T00:00:00Z")),
+        ],
+    )?;
+    assert_eq!(first, expected);
+
+    // when the server fails, the last copy of the feed is used
+    *requests.1.lock().unwrap() = true;
+    assert_eq!(source.busy_matrix(start, end, granularity).await?, expected);
+    assert_eq!(
+        requests.0.lock().unwrap().last(),
+        Some(&StatusCode::INTERNAL_SERVER_ERROR)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn availability_from_local_sources() -> Result<(), Box<dyn std::error::Error>> {
+    let utc = |s: &str| -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    };
+    let event = |uid: &str, start: &str, end: &str| -> Result<Event, Box<dyn std::error::Error>> {
+        let details = NewEvent::new(utc(start), utc(end), uid);
+        Ok(Event::parse(
+            &build_calendar(vec![details.to_ical(uid)]).to_string(),
+        )?)
+    };
+
+    // open 09:00 to 12:00, with bookings from 10:00 and 11:00
+    let available = MemoryCalendar::new(vec![event(
+        "open",
+        "2023-12-19T09:00:00Z",
+        "2023-12-19T12:00:00Z",
+    )?]);
+    let dir = std::env::temp_dir().join(ksuid::Ksuid::generate().to_base62());
+    std::fs::create_dir_all(&dir)?;
+    for (uid, start, end) in [
+        ("first", "2023-12-19T10:00:00Z", "2023-12-19T10:30:00Z"),
+        ("second", "2023-12-19T11:00:00Z", "2023-12-19T11:30:00Z"),
+        ("next-week", "2023-12-26T10:00:00Z", "2023-12-26T11:00:00Z"),
+    ] {
+        std::fs::write(
+            dir.join(format!("{uid}.ics")),
+            event(uid, start, end)?.ical.to_string(),
+        )?;
+    }
+    let booked = IcsDirectory::new(dir.clone());
+
+    let start = utc("2023-12-19T00:00:00Z");
+    let end = utc("2023-12-20T00:00:00Z");
+    let granularity = chrono::Duration::minutes(30);
+    assert_eq!(booked.events(start, end).await?.len(), 2);
+
+    let avail =
+        get_availability_excluding(&available, &booked, start, end, granularity, &[]).await?;
+    let open = |ranges: &[(&str, &str)]| {
+        let ranges: Vec<_> = ranges.iter().map(|(a, b)| (utc(a), utc(b))).collect();
+        ranges_matrix(start, end, granularity, &ranges).unwrap()
+    };
+    let busy = open(&[
+        ("2023-12-19T10:00:00Z", "2023-12-19T10:30:00Z"),
+        ("2023-12-19T11:00:00Z", "2023-12-19T11:30:00Z"),
+    ]);
+    let expected: Vec<bool> = open(&[("2023-12-19T09:00:00Z", "2023-12-19T12:00:00Z")])
+        .iter()
+        .zip(busy)
+        .map(|(open, busy)| *open && !busy)
+        .collect();
+    assert_eq!(avail.matrix, expected);
+
+    // excluding a booking frees its slot
+    let avail = get_availability_excluding(
+        &available,
+        &booked,
+        start,
+        end,
+        granularity,
+        &["first", "second"],
+    )
+    .await?;
+    assert_eq!(
+        avail.matrix,
+        open(&[("2023-12-19T09:00:00Z", "2023-12-19T12:00:00Z")])
+    );
+
+    // events in memory can be replaced and removed
+    available.insert(event(
+        "open",
+        "2023-12-19T13:00:00Z",
+        "2023-12-19T14:00:00Z",
+    )?);
+    assert_eq!(available.events(start, end).await?.len(), 1);
+    assert!(available.remove("open"));
+    assert!(available.events(start, end).await?.is_empty());
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn vdir_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+    let utc = |s: &str| -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    };
+    let root = std::env::temp_dir().join(ksuid::Ksuid::generate().to_base62());
+    std::fs::create_dir_all(&root)?;
+    let vdir = Vdir::new(root.clone());
+
+    vdir.create_calendar(&NewCalendar::new("booked").timezone("UTC"))
+        .await?;
+    vdir.create_calendar(&NewCalendar::new("available")).await?;
+    let names: Vec<String> = vdir
+        .get_calendars()
+        .await?
+        .into_iter()
+        .map(|calendar| calendar.display_name)
+        .collect();
+    assert_eq!(names, ["available", "booked"]);
+
+    // the timezone is kept alongside the display name
+    let calendar = vdir.get_calendar("booked").await?;
+    assert_eq!(calendar.timezone.as_deref(), Some("UTC"));
+    assert_eq!(vdir.get_calendar("available").await?.timezone, None);
+    let details = NewEvent::new(
+        utc("2023-12-19T10:00:00Z"),
+        utc("2023-12-19T10:30:00Z"),
+        "Meeting",
+    )
+    .attendee(Attendee::new("guest@example.com".to_string(), None));
+    let created = calendar.create_event(&details).await?;
+    let uid = created.uid().unwrap().to_string();
+
+    let start = utc("2023-12-19T00:00:00Z");
+    let end = utc("2023-12-20T00:00:00Z");
+    assert_eq!(calendar.get_events(start, end).await?.len(), 1);
+    assert!(calendar
+        .get_events(utc("2023-12-20T00:00:00Z"), utc("2023-12-21T00:00:00Z"))
+        .await?
+        .is_empty());
+    assert_eq!(
+        calendar
+            .get_events_by_attendee(start, end, "guest@example.com")
+            .await?
+            .len(),
+        1
+    );
+
+    let mut event = calendar.get_event(&uid).await?;
+    event.vevent_mut().unwrap().summary("Moved");
+    calendar.update_event(&event).await?;
+    let updated = calendar.get_event(&uid).await?;
+    assert_eq!(updated.vevent().unwrap().get_summary(), Some("Moved"));
+    assert_eq!(calendar.get_events(start, end).await?.len(), 1);
+
+    calendar.delete_event(&uid).await?;
+    assert!(matches!(
+        calendar.get_event(&uid).await,
+        Err(CaldavError::EventNotFound { .. })
+    ));
+
+    vdir.delete_calendar("available").await?;
+    assert!(matches!(
+        vdir.get_calendar("available").await,
+        Err(CaldavError::CalendarNotFound { .. })
+    ));
+
+    std::fs::remove_dir_all(root)?;
+    Ok(())
+}
+
+fn mock_client(server: &MockServer) -> DavClient {
+    DavClient::new(
+        server.url(),
+        DavCredentials::new(server.username().to_string(), server.password().to_string()),
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn client_discovers_calendars() -> Result<(), Box<dyn std::error::Error>> {
+    for quirks in [Quirks::radicale(), Quirks::nextcloud()] {
+        let server = MockCaldav::new(quirks)
+            .with_calendar("booked", "Booked")
+            .with_calendar("available", "Available")
+            .start()
+            .await?;
+
+        let mut principal = mock_client(&server).get_principal().await?;
+        let mut names: Vec<String> = principal
+            .get_calendars()
+            .await?
+            .into_iter()
+            .map(|calendar| calendar.display_name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["Available", "Booked"]);
+
+        let calendar = principal.get_calendar("Booked").await?;
+        assert_eq!(calendar.path, server.calendar_path("booked"));
+        assert!(matches!(
+            principal.get_calendar("Missing").await,
+            Err(CaldavError::CalendarNotFound { .. })
+        ));
+
+        // calendars created elsewhere are only seen once the calendars are refreshed
+        mock_client(&server)
+            .get_principal()
+            .await?
+            .create_calendar_mkcol("Missing")
+            .await?;
+        assert_eq!(principal.get_calendars().await?.len(), 2);
+        assert_eq!(principal.refresh_calendars().await?.len(), 3);
+        principal.get_calendar("Missing").await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_uses_connection_config() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let config = ConnectionConfig::new()
+        .with_timeout(std::time::Duration::from_secs(5))
+        .with_user_agent("scheduler-test".to_string());
+    let dav = DavClient::with_config(
+        server.url(),
+        DavCredentials::new(server.username().to_string(), server.password().to_string()),
+        &config,
+    )?;
+
+    // the calendar shares the client's connection, and so its configuration
+    let calendar = dav.get_principal().await?.get_calendar("Booked").await?;
+    let now = chrono::Utc::now();
+    calendar
+        .get_events(now, now + chrono::Duration::days(1))
+        .await?;
+    let requests = server.requests();
+    assert_eq!(requests.len(), 4);
+    assert!(requests
+        .iter()
+        .all(|request| request.headers.get("user-agent").unwrap() == "scheduler-test"));
+
+    let invalid = ConnectionConfig::new().with_identity(b"not a certificate".to_vec());
+    assert!(matches!(
+        DavClient::with_config(
+            server.url(),
+            DavCredentials::new(String::new(), String::new()),
+            &invalid
+        ),
+        Err(CaldavError::Reqwest(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn retry_policy_delays() {
+    let policy = RetryPolicy::default()
+        .with_base_delay(std::time::Duration::from_secs(1))
+        .with_max_delay(std::time::Duration::from_secs(5));
+    assert_eq!(policy.delay(1), std::time::Duration::from_secs(1));
+    assert_eq!(policy.delay(3), std::time::Duration::from_secs(4));
+    assert_eq!(policy.delay(40), std::time::Duration::from_secs(5));
+    let jittered = policy.jittered_delay(3);
+    assert!(jittered >= std::time::Duration::from_secs(2));
+    assert!(jittered <= std::time::Duration::from_secs(4));
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    assert!(policy.is_retryable(&reqwest::Method::GET, &headers));
+    assert!(!policy.is_retryable(&reqwest::Method::PUT, &headers));
+    assert!(!policy.is_retryable(&reqwest::Method::DELETE, &headers));
+    headers.insert("If-None-Match", "*".parse().unwrap());
+    assert!(policy.is_retryable(&reqwest::Method::PUT, &headers));
+
+    let now = chrono::DateTime::parse_from_rfc3339("2023-12-19T10:00:00Z")
+        .unwrap()
+        .into();
+    let mut headers = reqwest::header::HeaderMap::new();
+    assert_eq!(retry::retry_after(&headers, now), None);
+    headers.insert("Retry-After", "120".parse().unwrap());
+    assert_eq!(
+        retry::retry_after(&headers, now),
+        Some(std::time::Duration::from_secs(120))
+    );
+    headers.insert(
+        "Retry-After",
+        "Tue, 19 Dec 2023 10:00:30 GMT".parse().unwrap(),
+    );
+    assert_eq!(
+        retry::retry_after(&headers, now),
+        Some(std::time::Duration::from_secs(30))
+    );
+
+    // the server's wait is kept in full, unless it is longer than the policy allows
+    assert_eq!(policy.transient_delay(&headers, 1, now), None);
+    headers.insert("Retry-After", "4".parse().unwrap());
+    assert_eq!(
+        policy.transient_delay(&headers, 1, now),
+        Some(std::time::Duration::from_secs(4))
+    );
+}
+
+#[tokio::test]
+async fn client_retries_transient_failures() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let retry = RetryPolicy::default().with_base_delay(std::time::Duration::from_millis(1));
+    let dav = DavClient::with_config(
+        server.url(),
+        DavCredentials::new(server.username().to_string(), server.password().to_string()),
+        &ConnectionConfig::new().with_retry(retry),
+    )?;
+    let calendar = dav.get_principal().await?.get_calendar("Booked").await?;
+    let start = chrono::Utc::now();
+    let end = start + chrono::Duration::days(1);
+    let puts = |server: &MockServer| {
+        server
+            .requests()
+            .iter()
+            .filter(|request| request.method == reqwest::Method::PUT)
+            .count()
+    };
+
+    // queries are retried, with or without a Retry-After header
+    server.fail_next(1, reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    server.fail_next(1, reqwest::StatusCode::BAD_GATEWAY);
+    assert!(calendar.get_events(start, end).await?.is_empty());
+
+    // creating an event can't replace anything, so it is retried
+    server.fail_next(1, reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    let details = NewEvent::new(start, start + chrono::Duration::hours(1), "Retried");
+    let created = calendar.create_event(&details).await?;
+    assert_eq!(puts(&server), 2);
+
+    // even when the first attempt created it before its response was lost
+    server.lose_next(1, reqwest::StatusCode::GATEWAY_TIMEOUT);
+    let details = NewEvent::new(start, start + chrono::Duration::hours(1), "Lost");
+    let lost = calendar.create_event(&details).await?;
+    assert_eq!(puts(&server), 4);
+    assert_eq!(
+        calendar.get_event(lost.uid().unwrap()).await?.uid(),
+        lost.uid()
+    );
+    calendar.delete_event(lost.uid().unwrap()).await?;
+
+    // but replacing one is only sent once
+    server.fail_next(1, reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    assert!(matches!(
+        calendar.update_event(&created).await,
+        Err(CaldavError::ServerResponse(_))
+    ));
+    assert_eq!(puts(&server), 5);
+
+    // and the last response is returned once the attempts run out
+    server.fail_next(3, reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    let uid = created.uid().unwrap();
+    assert!(matches!(
+        calendar.get_event(uid).await,
+        Err(CaldavError::ServerResponse(_))
+    ));
+    assert_eq!(calendar.get_event(uid).await?.uid(), Some(uid));
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_reports_invalid_responses() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let credentials =
+        DavCredentials::new(server.username().to_string(), server.password().to_string());
+    assert!(matches!(
+        DavClient::new("not a url".to_string(), credentials.clone()),
+        Err(CaldavError::InvalidUrl { .. })
+    ));
+
+    let dav = mock_client(&server);
+    server.fail_next(1, reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(matches!(
+        dav.get_principal().await,
+        Err(CaldavError::MalformedXml { .. })
+    ));
+
+    let calendar = dav.get_principal().await?.get_calendar("Booked").await?;
+    let broken = [
+        "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nEND:VCALENDAR\r\n",
+        // truncated
+        "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\n",
+        "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:broken\r\nEND:VTODO\r\nEND:VCALENDAR\r\n",
+        "UID:broken\r\n",
+    ];
+    for (i, data) in broken.iter().enumerate() {
+        server.put_item("booked", &format!("broken-{i}.ics"), data);
+        match calendar.get_event(&format!("broken-{i}")).await {
+            Err(CaldavError::InvalidICalendar { href, .. }) => {
+                assert!(href.ends_with(&format!("broken-{i}.ics")))
+            }
+            other => panic!("expected an invalid icalendar error, got {other:?}"),
+        }
+    }
+    assert!(matches!(
+        Event::parse(broken[1]),
+        Err(CaldavError::InvalidICalendar { .. })
+    ));
+
+    // events which can't be placed are skipped rather than failing the whole matrix
+    let start = chrono::DateTime::parse_from_rfc3339("2023-01-01T00:00:00Z")?.into();
+    let end = start + chrono::Duration::hours(2);
+    let placed = Event::new(build_calendar(vec![build_event(
+        start,
+        start + chrono::Duration::hours(1),
+        None,
+    )]));
+    let unplaced = Event::parse(
+        "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:unplaced\r\nDTSTART:20230101T010000Z\r\n\
+         END:VEVENT\r\nEND:VCALENDAR\r\n",
+    )?;
+    assert!(matches!(
+        get_event_matrix(start, end, chrono::Duration::minutes(30), &unplaced, None),
+        Err(CaldavError::MissingProperty { property, .. }) if property == "DTEND"
+    ));
+    let granularity = chrono::Duration::minutes(30);
+    let matrix = events_matrix(start, end, granularity, &[placed.clone(), unplaced], None)?;
+    assert_eq!(
+        matrix,
+        get_event_matrix(start, end, granularity, &placed, None)?
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_event_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+    let utc = |s: &str| -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    };
+    for quirks in [Quirks::radicale(), Quirks::nextcloud()] {
+        let server = MockCaldav::new(quirks)
+            .with_calendar("booked", "Booked")
+            .start()
+            .await?;
+        let mut principal = mock_client(&server).get_principal().await?;
+        let calendar = principal.get_calendar("Booked").await?;
+
+        let details = NewEvent::new(
+            utc("2023-12-19T10:00:00Z"),
+            utc("2023-12-19T10:30:00Z"),
+            "Meeting",
+        )
+        .attendee(Attendee::new("guest@example.com".to_string(), None));
+        let created = calendar.create_event(&details).await?;
+        let uid = created.uid().unwrap().to_string();
+        assert_eq!(server.items("booked").len(), 1);
+
+        // the server applies the query's time range
+        let start = utc("2023-12-19T00:00:00Z");
+        let end = utc("2023-12-20T00:00:00Z");
+        assert_eq!(calendar.get_events(start, end).await?.len(), 1);
+        assert!(calendar
+            .get_events(end, utc("2023-12-21T00:00:00Z"))
+            .await?
+            .is_empty());
+        assert_eq!(
+            calendar
+                .get_events_by_attendee(start, end, "guest@example.com")
+                .await?
+                .len(),
+            1
+        );
+
+        let mut event = calendar.get_event(&uid).await?;
+        event.vevent_mut().unwrap().summary("Moved");
+        calendar.update_event(&event).await?;
+        let updated = calendar.get_event(&uid).await?;
+        assert_eq!(updated.vevent().unwrap().get_summary(), Some("Moved"));
+        assert_eq!(server.items("booked").len(), 1);
+
+        calendar.delete_event(&uid).await?;
+        assert!(server.items("booked").is_empty());
+        assert!(matches!(
+            calendar.get_event(&uid).await,
+            Err(CaldavError::EventNotFound { .. })
+        ));
+        assert!(matches!(
+            calendar.delete_event(&uid).await,
+            Err(CaldavError::EventNotFound { .. })
+        ));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_creates_calendar() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::radicale()).start().await?;
+    let dav = mock_client(&server);
+
+    let created = dav
+        .get_principal()
+        .await?
+        .create_calendar_mkcol("Bookings")
+        .await?;
+    assert_eq!(server.calendar_names(), ["Bookings"]);
+
+    // the created calendar is identified by the href the server reports for it
+    let calendar = dav.get_principal().await?.get_calendar("Bookings").await?;
+    assert_eq!(calendar.timezone.as_deref(), Some("UTC"));
+    assert_eq!(created.url(), calendar.url());
+    assert_eq!(created.display_name, "Bookings");
+    Ok(())
+}
+
+#[test]
+fn new_calendar_escapes_xml() -> Result<(), Box<dyn std::error::Error>> {
+    let details = NewCalendar::new("Tom & Jerry's <cal>")
+        .description("\"quoted\" </D:prop>")
+        .component("vevent");
+    let xml = details.to_xml();
+    assert!(xml.contains("<D:displayname>Tom &amp; Jerry&apos;s &lt;cal&gt;</D:displayname>"));
+    assert!(xml.contains("&quot;quoted&quot; &lt;/D:prop&gt;"));
+
+    // the values survive being parsed back
+    let root = util::parse_xml("new calendar", &xml)?;
+    assert_eq!(root.name(), "mkcalendar");
+    let name = util::find_element(&root, "displayname".to_string()).unwrap();
+    assert_eq!(name.text(), "Tom & Jerry's <cal>");
+    let description = util::find_element(&root, "calendar-description".to_string()).unwrap();
+    assert_eq!(description.text(), "\"quoted\" </D:prop>");
+    let comp = util::find_element(&root, "comp".to_string()).unwrap();
+    assert_eq!(comp.attr("name"), Some("VEVENT"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_creates_calendar_with_properties() -> Result<(), Box<dyn std::error::Error>> {
+    for method in [CreateMethod::MkCalendar, CreateMethod::ExtendedMkcol] {
+        let server = MockCaldav::new(Quirks::nextcloud()).start().await?;
+        let dav = mock_client(&server);
+
+        let details = NewCalendar::new("Tasks & Events")
+            .description("Work <things>")
+            .color("#00ff00")
+            .timezone("Europe/London")
+            .component("VEVENT")
+            .component("vtodo")
+            .order(3)
+            .method(method);
+        let created = dav.get_principal().await?.create_calendar(&details).await?;
+        assert_eq!(created.display_name, "Tasks & Events");
+        // the VTIMEZONE the server returns is read as its TZID
+        assert_eq!(created.timezone.as_deref(), Some("Europe/London"));
+
+        let expected = match method {
+            CreateMethod::MkCalendar => "MKCALENDAR",
+            CreateMethod::ExtendedMkcol => "MKCOL",
+        };
+        assert!(server
+            .requests()
+            .iter()
+            .any(|request| request.method.as_str() == expected));
+
+        let properties = server.calendar_properties("Tasks & Events");
+        assert_eq!(properties["calendar-description"], "Work <things>");
+        assert_eq!(properties["calendar-color"], "#00ff00");
+        assert_eq!(properties["calendar-order"], "3");
+        assert_eq!(
+            properties["supported-calendar-component-set"],
+            "VEVENT,VTODO"
+        );
+        assert_eq!(properties["calendar-timezone"], "Europe/London");
+    }
+    Ok(())
+}
+
+#[test]
+fn calendar_timezone_holds_a_vtimezone() -> Result<(), Box<dyn std::error::Error>> {
+    let xml = NewCalendar::new("Bookings").timezone("UTC").to_xml();
+    let root = util::parse_xml("new calendar", &xml)?;
+    let timezone = util::find_element(&root, "calendar-timezone".to_string()).unwrap();
+    assert!(timezone.text().starts_with("BEGIN:VCALENDAR"));
+    assert_eq!(timezone_id(&timezone.text()).as_deref(), Some("UTC"));
+
+    // as do other known timezones, with their daylight saving rules
+    let xml = NewCalendar::new("Bookings")
+        .timezone("Europe/London")
+        .to_xml();
+    let root = util::parse_xml("new calendar", &xml)?;
+    let timezone = util::find_element(&root, "calendar-timezone".to_string()).unwrap();
+    assert_eq!(
+        timezone_id(&timezone.text()).as_deref(),
+        Some("Europe/London")
+    );
+    assert!(timezone.text().contains("BEGIN:DAYLIGHT"));
+
+    // and unknown ones are only given by their id
+    let xml = NewCalendar::new("Bookings")
+        .timezone("Mars/Olympus")
+        .to_xml();
+    assert!(xml.contains("<C:calendar-timezone-id>Mars/Olympus</C:calendar-timezone-id>"));
+    assert!(!xml.contains("<C:calendar-timezone>"));
+
+    assert_eq!(
+        timezone_id("Europe/Berlin").as_deref(),
+        Some("Europe/Berlin")
+    );
+    assert_eq!(timezone_id(" "), None);
+    assert_eq!(
+        timezone_id("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nEND:VCALENDAR\r\n"),
+        None
+    );
+    Ok(())
+}
+
+#[test]
+fn events_in_a_timezone_include_its_vtimezone() -> Result<(), Box<dyn std::error::Error>> {
+    let start =
+        chrono::DateTime::parse_from_rfc3339("2023-12-18T08:00:00Z")?.with_timezone(&chrono::Utc);
+    let end = start + chrono::Duration::hours(1);
+
+    let text = NewEvent::new(start, end, "Weekly")
+        .timezone(chrono_tz::Europe::Berlin)
+        .property("RRULE", "FREQ=WEEKLY")
+        .to_calendar("weekly")
+        .to_string();
+    assert!(text.contains("DTSTART;TZID=Europe/Berlin:20231218T090000\r\n"));
+    assert!(text.contains("BEGIN:VTIMEZONE\r\n"));
+    assert!(text.contains("TZID:Europe/Berlin\r\n"));
+    // daylight saving is described by yearly rules
+    for observance in [
+        "BEGIN:DAYLIGHT",
+        "DTSTART:20230326T020000",
+        "RRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU",
+        "TZNAME:CEST",
+        "TZOFFSETFROM:+0100",
+        "TZOFFSETTO:+0200",
+        "BEGIN:STANDARD",
+        "DTSTART:20231029T030000",
+        "RRULE:FREQ=YEARLY;BYMONTH=10;BYDAY=-1SU",
+        "TZNAME:CET",
+    ] {
+        assert!(text.contains(&format!("{observance}\r\n")), "{observance}");
+    }
+    let event = Event::parse(&text)?;
+    assert_eq!(event.start(), Some(start));
+
+    // a timezone without daylight saving has a single observance
+    let text = NewEvent::new(start, end, "Weekly")
+        .timezone(chrono_tz::Asia::Tokyo)
+        .to_calendar("weekly")
+        .to_string();
+    assert!(text.contains("TZID:Asia/Tokyo\r\n"));
+    assert!(text.contains("TZOFFSETTO:+0900\r\n"));
+    assert_eq!(text.matches("BEGIN:STANDARD").count(), 1);
+    assert!(!text.contains("BEGIN:DAYLIGHT"));
+
+    // and UTC needs none
+    let text = NewEvent::new(start, end, "Weekly")
+        .timezone(chrono_tz::UTC)
+        .to_calendar("weekly")
+        .to_string();
+    assert!(!text.contains("BEGIN:VTIMEZONE"));
+    Ok(())
+}
+
+#[test]
+fn hrefs_resolve_against_request_url() -> Result<(), Box<dyn std::error::Error>> {
+    let base = url::Url::parse("https://dav.example.com/remote.php/dav/calendars/user/")?;
+    let cases = [
+        (
+            "/remote.php/dav/calendars/user/work/",
+            "https://dav.example.com/remote.php/dav/calendars/user/work/",
+        ),
+        (
+            "work/",
+            "https://dav.example.com/remote.php/dav/calendars/user/work/",
+        ),
+        (
+            "https://other.example.com/cal/",
+            "https://other.example.com/cal/",
+        ),
+        (
+            " /remote.php/dav/calendars/user/my%20calendar/\n",
+            "https://dav.example.com/remote.php/dav/calendars/user/my%20calendar/",
+        ),
+    ];
+    for (href, expected) in cases {
+        assert_eq!(util::resolve_href(&base, href)?.as_str(), expected);
+    }
+    assert_eq!(
+        util::collection_url(base.join("/remote.php/dav/calendars/user/work")?).as_str(),
+        "https://dav.example.com/remote.php/dav/calendars/user/work/"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_resolves_absolute_hrefs() -> Result<(), Box<dyn std::error::Error>> {
+    let quirks = Quirks {
+        absolute_hrefs: true,
+        ..Quirks::nextcloud()
+    };
+    let server = MockCaldav::new(quirks)
+        .with_calendar("my calendar", "Booked")
+        .start()
+        .await?;
+    let dav = mock_client(&server);
+    let calendar = dav.get_principal().await?.get_calendar("Booked").await?;
+    assert_eq!(
+        calendar.url().as_str(),
+        format!("{}calendars/user/my%20calendar/", server.url())
+    );
+
+    // uids are encoded into a single path segment of the calendar's url
+    let uid = "team meeting@example.com";
+    let ical = format!(
+        "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\nBEGIN:VEVENT\r\nUID:{uid}\r\n\
+         DTSTAMP:20231201T000000Z\r\nDTSTART:20231219T100000Z\r\nDTEND:20231219T103000Z\r\n\
+         END:VEVENT\r\nEND:VCALENDAR\r\n"
+    );
+    server.put_item("my calendar", &format!("{uid}.ics"), &ical);
+    assert_eq!(calendar.get_event(uid).await?.uid(), Some(uid));
+
+    let start = chrono::DateTime::parse_from_rfc3339("2023-12-19T00:00:00Z")?.into();
+    let details = NewEvent::new(start, start + chrono::Duration::hours(1), "Resolved");
+    let created = calendar.create_event(&details).await?;
+    calendar.delete_event(created.uid().unwrap()).await?;
+    assert!(server
+        .requests()
+        .iter()
+        .all(|request| !request.path.contains("//")));
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_reads_availabilities() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("available", "Available")
+        .start()
+        .await?;
+    server.put_item(
+        "available",
+        "weekdays.ics",
+        "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\n\
+         BEGIN:VAVAILABILITY\r\nUID:weekdays\r\nDTSTAMP:20231201T000000Z\r\n\
+         BEGIN:AVAILABLE\r\nUID:weekdays-1\r\nDTSTAMP:20231201T000000Z\r\n\
+         DTSTART:20231218T090000Z\r\nDTEND:20231218T170000Z\r\n\
+         RRULE:FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR\r\nEND:AVAILABLE\r\n\
+         END:VAVAILABILITY\r\nEND:VCALENDAR\r\n",
+    );
+    server.put_item(
+        "available",
+        "event.ics",
+        &build_calendar(vec![build_event(
+            chrono::DateTime::parse_from_rfc3339("2023-12-19T09:00:00Z")?.into(),
+            chrono::DateTime::parse_from_rfc3339("2023-12-19T10:00:00Z")?.into(),
+            None,
+        )])
+        .to_string(),
+    );
+    let calendar = mock_client(&server)
+        .get_principal()
+        .await?
+        .get_calendar("Available")
+        .await?;
+
+    let start = chrono::DateTime::parse_from_rfc3339("2023-12-18T00:00:00Z")?.into();
+    let end = chrono::DateTime::parse_from_rfc3339("2023-12-25T00:00:00Z")?.into();
+    let availabilities = calendar.get_availabilities(start, end).await?;
+    assert_eq!(availabilities.len(), 1);
+    assert_eq!(availabilities[0].uid.as_deref(), Some("weekdays"));
+    assert_eq!(availabilities[0].available.len(), 1);
+
+    calendar.put_availability(&availabilities[0]).await?;
+    assert!(server
+        .items("available")
+        .iter()
+        .any(|(name, _)| name == "weekdays.ics"));
+    Ok(())
+}
+
+/// The conversation recorded in each server's fixture.
+/// Item names and UIDs are generated, so they are not compared with the recording.
+async fn fixture_scenario(dav: &DavClient, calendar_name: &str) -> CaldavResult<()> {
+    let utc = |s: &str| -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    };
+    let mut principal = dav.get_principal().await?;
+    let calendar = principal.get_calendar(calendar_name).await?;
+
+    let details = NewEvent::new(
+        utc("2023-12-19T10:00:00Z"),
+        utc("2023-12-19T10:30:00Z"),
+        "Fixture meeting",
+    );
+    let created = calendar.create_event(&details).await?;
+    let uid = created.uid().unwrap().to_string();
+
+    let summary = |event: &Event| {
+        event
+            .vevent()
+            .and_then(|vevent| vevent.get_summary())
+            .map(str::to_string)
+    };
+    let events = calendar
+        .get_events(utc("2023-12-19T00:00:00Z"), utc("2023-12-20T00:00:00Z"))
+        .await?;
+    assert!(events
+        .iter()
+        .any(|event| summary(event).as_deref() == Some("Fixture meeting")));
+
+    let mut event = calendar.get_event(&uid).await?;
+    assert_eq!(summary(&event).as_deref(), Some("Fixture meeting"));
+    event
+        .vevent_mut()
+        .unwrap()
+        .summary("Fixture meeting (moved)");
+    calendar.update_event(&event).await?;
+    let event = calendar.get_event(&uid).await?;
+    assert_eq!(summary(&event).as_deref(), Some("Fixture meeting (moved)"));
+
+    calendar.delete_event(&uid).await?;
+    Ok(())
+}
+
+/// Replay every fixture recorded with [`record_fixture`].
+#[tokio::test]
+async fn replay_server_fixtures() -> Result<(), Box<dyn std::error::Error>> {
+    let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+    let Ok(entries) = std::fs::read_dir(&fixtures) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let server = path.display();
+        let fixture = Fixture::load(&path)?;
+        let calendar = fixture.calendar.clone();
+        let replay = ReplayServer::start(fixture).await?;
+        let dav = DavClient::new(
+            replay.url(),
+            DavCredentials::new(FIXTURE_USERNAME.to_string(), FIXTURE_PASSWORD.to_string()),
+        )?;
+
+        fixture_scenario(&dav, &calendar).await?;
+        assert!(
+            replay.unused().is_empty(),
+            "{server}: not every recorded request was made: {:?}",
+            replay.unused()
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn record_and_replay() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::nextcloud())
+        .with_credentials("alice", "hunter2")
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let recorder = Recorder::start(&server.url()).await?;
+    let dav = DavClient::new(
+        recorder.url(),
+        DavCredentials::new("alice".to_string(), "hunter2".to_string()),
+    )?;
+    fixture_scenario(&dav, "Booked").await?;
+
+    let fixture = recorder.fixture("caldav-mock", "Booked", "alice");
+    assert_eq!(fixture.interactions.len(), 9);
+    let text = serde_json::to_string(&fixture)?;
+    assert!(!text.contains("alice") && !text.contains("hunter2"));
+
+    let replay = ReplayServer::start(fixture.clone()).await?;
+    let dav = DavClient::new(
+        replay.url(),
+        DavCredentials::new(FIXTURE_USERNAME.to_string(), FIXTURE_PASSWORD.to_string()),
+    )?;
+    fixture_scenario(&dav, "Booked").await?;
+    assert!(replay.unused().is_empty());
+
+    // a request which differs from the recorded one isn't given its response
+    let replay = ReplayServer::start(fixture).await?;
+    let dav = DavClient::new(
+        replay.url(),
+        DavCredentials::new(FIXTURE_USERNAME.to_string(), FIXTURE_PASSWORD.to_string()),
+    )?;
+    let calendar = dav.get_principal().await?.get_calendar("Booked").await?;
+    let utc = |s: &str| -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    };
+    assert!(calendar
+        .get_events(utc("2024-01-01T00:00:00Z"), utc("2024-01-02T00:00:00Z"))
+        .await
+        .is_err());
+    Ok(())
+}
+
+/// Record a fixture from a real server, e.g.
+/// `RECORD_URL=https://example.com/dav/ RECORD_USERNAME=me RECORD_PASSWORD=secret
+/// RECORD_CALENDAR=Test RECORD_SERVER="Radicale 3.1.8" RECORD_FIXTURE=fixtures/radicale.json
+/// cargo test -p caldav-utils record_fixture -- --ignored`
+/// The calendar should be one that is safe to create and delete events in.
+#[tokio::test]
+#[ignore]
+async fn record_fixture() -> Result<(), Box<dyn std::error::Error>> {
+    let var = |name: &str| std::env::var(name).map_err(|_| format!("{name} not set"));
+    let (username, password) = (var("RECORD_USERNAME")?, var("RECORD_PASSWORD")?);
+    let calendar = var("RECORD_CALENDAR")?;
+
+    let recorder = Recorder::start(&var("RECORD_URL")?).await?;
+    let dav = DavClient::new(
+        recorder.url(),
+        DavCredentials::new(username.clone(), password.clone()),
+    )?;
+    fixture_scenario(&dav, &calendar).await?;
+
+    let fixture = recorder.fixture(&var("RECORD_SERVER")?, &calendar, &username);
+    fixture.save(std::path::Path::new(&var("RECORD_FIXTURE")?))?;
+    Ok(())
+}
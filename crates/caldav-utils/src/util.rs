@@ -1,4 +1,7 @@
 use minidom::Element;
+use url::Url;
+
+use crate::error::{CaldavError, CaldavResult};
 
 /// Recursively find elements in the root that match the given tag name
 pub fn find_elements(root: &Element, tag: String) -> Vec<&Element> {
@@ -35,3 +38,47 @@ pub fn find_element(root: &Element, tag: String) -> Option<&Element> {
 
     None
 }
+
+/// Parse the XML body of a response from the given url.
+pub fn parse_xml(url: &str, text: &str) -> CaldavResult<Element> {
+    text.parse().map_err(|source| CaldavError::MalformedXml {
+        url: url.to_string(),
+        source,
+    })
+}
+
+/// Like `find_element`, but an element missing from the response from the given url is an error.
+pub fn require_element<'a>(root: &'a Element, tag: &str, url: &str) -> CaldavResult<&'a Element> {
+    find_element(root, tag.to_string()).ok_or_else(|| CaldavError::MissingProperty {
+        resource: url.to_string(),
+        property: tag.to_string(),
+    })
+}
+
+/// Resolve an href from a response against the url the request was sent to (RFC 3986),
+/// so that absolute urls, absolute paths and relative references are all followed.
+pub fn resolve_href(base: &Url, href: &str) -> CaldavResult<Url> {
+    base.join(href.trim())
+        .map_err(|source| CaldavError::InvalidUrl {
+            url: href.to_string(),
+            source,
+        })
+}
+
+/// The canonical url of a collection, which ends in a slash so that members can be joined onto it.
+pub fn collection_url(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+/// Escape text to be placed in XML element content or attribute values.
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
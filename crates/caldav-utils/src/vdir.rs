@@ -0,0 +1,392 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    availability::vavailability::VAvailability,
+    caldav::{
+        calendar::NewCalendar,
+        event::{Event, NewEvent},
+    },
+    error::{CaldavError, CaldavResult},
+    source::{may_overlap, CalendarSource, CalendarStore},
+};
+
+/// The file in a collection holding the name it is shown with.
+static DISPLAYNAME: &str = "displayname";
+/// The file in a collection holding the timezone its floating times are in.
+/// vdirsyncer doesn't sync one, so collections without it are in UTC.
+static TIMEZONE: &str = "timezone";
+
+/// A vdir storage directory, as used by vdirsyncer and khal.
+/// Each calendar is a subdirectory holding one `.ics` file per item,
+/// which makes it a local stand-in for a CalDAV principal.
+#[derive(Clone, Debug)]
+pub struct Vdir {
+    root: PathBuf,
+}
+
+impl Vdir {
+    pub fn new(root: PathBuf) -> Vdir {
+        Vdir { root }
+    }
+
+    pub async fn get_calendars(&self) -> CaldavResult<Vec<VdirCalendar>> {
+        let mut calendars = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if !entry.file_type().await?.is_dir() || is_hidden(&path) {
+                continue;
+            }
+            calendars.push(VdirCalendar::open(path).await?);
+        }
+        calendars.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        Ok(calendars)
+    }
+
+    pub async fn get_calendar(&self, calendar_name: &str) -> CaldavResult<VdirCalendar> {
+        self.get_calendars()
+            .await?
+            .into_iter()
+            .find(|c| c.display_name == calendar_name)
+            .ok_or_else(|| CaldavError::CalendarNotFound {
+                calendar_name: calendar_name.to_string(),
+            })
+    }
+
+    /// Create a calendar in a new collection directory.
+    /// Only its display name and timezone are kept.
+    pub async fn create_calendar(&self, details: &NewCalendar) -> CaldavResult<VdirCalendar> {
+        let id = ksuid::Ksuid::generate().to_base62();
+        let path = self.root.join(id);
+        tokio::fs::create_dir_all(&path).await?;
+        write_atomic(&path, DISPLAYNAME, &details.display_name).await?;
+        if let Some(timezone) = &details.timezone {
+            write_atomic(&path, TIMEZONE, timezone).await?;
+        }
+        VdirCalendar::open(path).await
+    }
+
+    /// Remove a calendar and every item in it.
+    pub async fn delete_calendar(&self, calendar_name: &str) -> CaldavResult<()> {
+        let calendar = self.get_calendar(calendar_name).await?;
+        tokio::fs::remove_dir_all(&calendar.dir).await?;
+        Ok(())
+    }
+}
+
+/// A calendar collection within a vdir.
+#[derive(Clone, Debug)]
+pub struct VdirCalendar {
+    dir: PathBuf,
+    pub path: String,
+
+    pub display_name: String,
+    pub timezone: Option<String>,
+}
+
+impl VdirCalendar {
+    /// Open a collection directory.
+    /// Collections without a displayname file are named after their directory.
+    pub async fn open(dir: PathBuf) -> CaldavResult<VdirCalendar> {
+        let display_name = match tokio::fs::read_to_string(dir.join(DISPLAYNAME)).await {
+            Ok(name) => name.trim().to_string(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => dir
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            Err(e) => return Err(e.into()),
+        };
+        let timezone = match tokio::fs::read_to_string(dir.join(TIMEZONE)).await {
+            Ok(timezone) => Some(timezone.trim().to_string()).filter(|tz| !tz.is_empty()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(VdirCalendar {
+            path: dir.display().to_string(),
+            dir,
+            display_name,
+            timezone,
+        })
+    }
+
+    /// Every item in the collection, with the file it was read from.
+    async fn items(&self) -> CaldavResult<Vec<(PathBuf, Event)>> {
+        let mut items = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("ics") || is_hidden(&path) {
+                continue;
+            }
+            let text = tokio::fs::read_to_string(&path).await?;
+            match Event::parse_at(&path.to_string_lossy(), &text) {
+                Ok(event) => items.push((path, event)),
+                Err(e) => tracing::warn!("skipping {}", e),
+            }
+        }
+        Ok(items)
+    }
+
+    /// The file an item with the given UID is stored in.
+    /// Items written by other tools may not be named after their UID, so those are searched for.
+    async fn item_path(&self, uid: &str) -> CaldavResult<Option<PathBuf>> {
+        let path = self.dir.join(item_name(uid));
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(Some(path));
+        }
+        Ok(self
+            .items()
+            .await?
+            .into_iter()
+            .find(|(_, event)| event.uid() == Some(uid))
+            .map(|(path, _)| path))
+    }
+
+    pub async fn get_events(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<Event>> {
+        Ok(self
+            .items()
+            .await?
+            .into_iter()
+            .map(|(_, event)| event)
+            .filter(|event| event.vevent().is_some() && may_overlap(event, start, end))
+            .collect())
+    }
+
+    pub async fn create_event(&self, details: &NewEvent) -> CaldavResult<Event> {
+        let id = ksuid::Ksuid::generate().to_base62();
+        let calendar = details.to_calendar(&id);
+
+        write_atomic(&self.dir, &item_name(&id), &calendar.to_string()).await?;
+        Ok(Event { ical: calendar })
+    }
+
+    /// Fetch a single event by its UID.
+    pub async fn get_event(&self, uid: &str) -> CaldavResult<Event> {
+        Ok(self.get_event_with_etag(uid).await?.0)
+    }
+
+    /// Fetch an event along with a tag of its file's contents,
+    /// so that it can be updated with [`VdirCalendar::update_event_if_match`].
+    pub async fn get_event_with_etag(&self, uid: &str) -> CaldavResult<(Event, Option<String>)> {
+        let path = self
+            .item_path(uid)
+            .await?
+            .ok_or_else(|| CaldavError::EventNotFound {
+                uid: uid.to_string(),
+            })?;
+        let text = tokio::fs::read_to_string(&path).await?;
+        let event = Event::parse_at(&path.to_string_lossy(), &text)?;
+        Ok((event, Some(content_tag(&text))))
+    }
+
+    /// Replace an existing event with the given one.
+    /// The event is stored under its UID, so it must have one.
+    pub async fn update_event(&self, event: &Event) -> CaldavResult<()> {
+        self.update_event_if_match(event, None).await
+    }
+
+    /// Replace an existing event, if its file still has the given tag.
+    /// The check and the write aren't atomic, so this only guards against changes
+    /// made between reading and writing the event, not at the same moment.
+    pub async fn update_event_if_match(
+        &self,
+        event: &Event,
+        etag: Option<&str>,
+    ) -> CaldavResult<()> {
+        let uid = event
+            .uid()
+            .ok_or_else(|| CaldavError::Anyhow(anyhow::anyhow!("event has no UID")))?;
+        if let Some(etag) = etag {
+            self.check_tag(uid, etag).await?;
+        }
+        self.write_item(uid, &event.ical.to_string()).await
+    }
+
+    /// Remove an event by its UID.
+    pub async fn delete_event(&self, uid: &str) -> CaldavResult<()> {
+        self.delete_event_if_match(uid, None).await
+    }
+
+    /// Remove an event by its UID, if its file still has the given tag.
+    pub async fn delete_event_if_match(&self, uid: &str, etag: Option<&str>) -> CaldavResult<()> {
+        let path = self
+            .item_path(uid)
+            .await?
+            .ok_or_else(|| CaldavError::EventNotFound {
+                uid: uid.to_string(),
+            })?;
+        if let Some(etag) = etag {
+            self.check_tag(uid, etag).await?;
+        }
+        tokio::fs::remove_file(path).await?;
+        Ok(())
+    }
+
+    /// Fail with [`CaldavError::EventChanged`] unless an item's file has the given tag.
+    async fn check_tag(&self, uid: &str, etag: &str) -> CaldavResult<()> {
+        let current = match self.item_path(uid).await? {
+            Some(path) => Some(content_tag(&tokio::fs::read_to_string(path).await?)),
+            None => None,
+        };
+        if current.as_deref() != Some(etag) {
+            return Err(CaldavError::EventChanged {
+                uid: uid.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Find the events between two datetimes which the given email address is attending.
+    pub async fn get_events_by_attendee(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        email: &str,
+    ) -> CaldavResult<Vec<Event>> {
+        let events = self.get_events(start, end).await?;
+        Ok(events
+            .into_iter()
+            .filter(|event| event.has_attendee(email))
+            .collect())
+    }
+
+    /// Fetch the VAVAILABILITY components in the collection.
+    pub async fn get_availabilities(&self) -> CaldavResult<Vec<VAvailability>> {
+        let mut availabilities = Vec::new();
+        for (_, object) in self.items().await? {
+            availabilities.extend(VAvailability::from_event(
+                &object,
+                self.timezone.as_deref(),
+            )?);
+        }
+        Ok(availabilities)
+    }
+
+    /// Store a VAVAILABILITY component under its UID, replacing any previous version.
+    pub async fn put_availability(&self, availability: &VAvailability) -> CaldavResult<()> {
+        let uid = availability
+            .uid
+            .as_deref()
+            .ok_or_else(|| CaldavError::Anyhow(anyhow::anyhow!("availability has no UID")))?;
+        self.write_item(uid, &availability.to_ical().to_string())
+            .await
+    }
+
+    /// Write an item, replacing the file it is already stored in if there is one.
+    async fn write_item(&self, uid: &str, text: &str) -> CaldavResult<()> {
+        let name = match self.item_path(uid).await? {
+            Some(path) => path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| item_name(uid)),
+            None => item_name(uid),
+        };
+        write_atomic(&self.dir, &name, text).await
+    }
+}
+
+#[async_trait::async_trait]
+impl CalendarSource for VdirCalendar {
+    async fn events(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<Event>> {
+        self.get_events(start, end).await
+    }
+
+    fn timezone(&self) -> Option<String> {
+        self.timezone.clone()
+    }
+
+    async fn availabilities(
+        &self,
+        _start: chrono::DateTime<chrono::Utc>,
+        _end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<VAvailability>> {
+        self.get_availabilities().await
+    }
+}
+
+#[async_trait::async_trait]
+impl CalendarStore for VdirCalendar {
+    fn display_name(&self) -> &str {
+        &self.display_name
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    async fn create_event(&self, details: &NewEvent) -> CaldavResult<Event> {
+        VdirCalendar::create_event(self, details).await
+    }
+
+    async fn get_event(&self, uid: &str) -> CaldavResult<Event> {
+        VdirCalendar::get_event(self, uid).await
+    }
+
+    async fn get_event_with_etag(&self, uid: &str) -> CaldavResult<(Event, Option<String>)> {
+        VdirCalendar::get_event_with_etag(self, uid).await
+    }
+
+    async fn update_event(&self, event: &Event) -> CaldavResult<()> {
+        VdirCalendar::update_event(self, event).await
+    }
+
+    async fn update_event_if_match(&self, event: &Event, etag: Option<&str>) -> CaldavResult<()> {
+        VdirCalendar::update_event_if_match(self, event, etag).await
+    }
+
+    async fn delete_event(&self, uid: &str) -> CaldavResult<()> {
+        VdirCalendar::delete_event(self, uid).await
+    }
+
+    async fn delete_event_if_match(&self, uid: &str, etag: Option<&str>) -> CaldavResult<()> {
+        VdirCalendar::delete_event_if_match(self, uid, etag).await
+    }
+
+    async fn put_availability(&self, availability: &VAvailability) -> CaldavResult<()> {
+        VdirCalendar::put_availability(self, availability).await
+    }
+}
+
+/// A tag for the contents of an item's file, which changes when the file is written.
+fn content_tag(text: &str) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    text.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// The file name for an item, with any characters that cannot appear in one replaced.
+fn item_name(uid: &str) -> String {
+    let name: String = uid
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c => c,
+        })
+        .collect();
+    format!("{name}.ics")
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'))
+}
+
+/// Write a file by renaming a temporary one into place,
+/// so that other programs reading the vdir never see a partial item.
+async fn write_atomic(dir: &Path, name: &str, text: &str) -> CaldavResult<()> {
+    let tmp = dir.join(format!(".{name}.tmp"));
+    tokio::fs::write(&tmp, text).await?;
+    tokio::fs::rename(&tmp, dir.join(name)).await?;
+    Ok(())
+}
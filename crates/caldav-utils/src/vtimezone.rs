@@ -0,0 +1,190 @@
+use chrono::{Datelike, Offset, TimeZone};
+use chrono_tz::{OffsetComponents, OffsetName, Tz};
+use icalendar::parser::{Component, Property};
+
+/// How far ahead a timezone whose changes don't follow a yearly rule is described,
+/// with each change given as its own observance.
+const EXPLICIT_YEARS: i64 = 5;
+
+/// The offset in effect at an instant, as an observance describes it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Observed {
+    /// Seconds ahead of UTC
+    offset: i32,
+    daylight: bool,
+    name: String,
+}
+
+impl Observed {
+    fn at(tz: Tz, at: chrono::DateTime<chrono::Utc>) -> Observed {
+        let offset = tz.offset_from_utc_datetime(&at.naive_utc());
+        Observed {
+            offset: offset.fix().local_minus_utc(),
+            daylight: offset.dst_offset() != chrono::Duration::zero(),
+            name: offset.abbreviation().to_string(),
+        }
+    }
+}
+
+/// A change from one offset to another.
+#[derive(Clone, Debug)]
+struct Transition {
+    at: chrono::DateTime<chrono::Utc>,
+    from: Observed,
+    to: Observed,
+}
+
+impl Transition {
+    /// The wall clock time the change happens at, before it happens, which is its DTSTART.
+    fn local(&self) -> chrono::NaiveDateTime {
+        self.at.naive_utc() + chrono::Duration::seconds(self.from.offset.into())
+    }
+
+    /// The yearly rule of a change happening on the same weekday of the month every year,
+    /// e.g. the last Sunday of March.
+    fn yearly_rule(&self) -> String {
+        let date = self.local().date();
+        let weekday = ["MO", "TU", "WE", "TH", "FR", "SA", "SU"]
+            [date.weekday().num_days_from_monday() as usize];
+        let last = (date + chrono::Duration::days(7)).month() != date.month();
+        let which = match last {
+            true => "-1".to_string(),
+            false => ((date.day() - 1) / 7 + 1).to_string(),
+        };
+        format!(
+            "FREQ=YEARLY;BYMONTH={};BYDAY={which}{weekday}",
+            date.month()
+        )
+    }
+
+    /// Whether a later change repeats this one under the same yearly rule.
+    fn repeated_by(&self, later: &Transition) -> bool {
+        later.from == self.from
+            && later.to == self.to
+            && later.local().time() == self.local().time()
+            && later.yearly_rule() == self.yearly_rule()
+    }
+}
+
+/// Every change of offset between two instants, found to the second.
+/// chrono-tz doesn't expose its transitions, so each day is compared with the one before.
+fn transitions(
+    tz: Tz,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+) -> Vec<Transition> {
+    let mut found = Vec::new();
+    let mut before = start;
+    let mut observed = Observed::at(tz, start);
+    while before < end {
+        let after = (before + chrono::Duration::days(1)).min(end);
+        let next = Observed::at(tz, after);
+        if next != observed {
+            let (mut low, mut high) = (before, after);
+            while high - low > chrono::Duration::seconds(1) {
+                let middle = low + (high - low) / 2;
+                if Observed::at(tz, middle) == observed {
+                    low = middle;
+                } else {
+                    high = middle;
+                }
+            }
+            found.push(Transition {
+                at: high,
+                from: observed,
+                to: next.clone(),
+            });
+            observed = next;
+        }
+        before = after;
+    }
+    found
+}
+
+fn format_offset(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.abs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    match seconds {
+        0 => format!("{sign}{hours:02}{minutes:02}"),
+        _ => format!("{sign}{hours:02}{minutes:02}{seconds:02}"),
+    }
+}
+
+fn component(
+    name: &str,
+    properties: Vec<(&str, String)>,
+    components: Vec<Component<'static>>,
+) -> Component<'static> {
+    Component {
+        name: name.to_string().into(),
+        properties: properties
+            .into_iter()
+            .map(|(name, value)| Property {
+                name: name.to_string().into(),
+                val: value.into(),
+                params: Vec::new(),
+            })
+            .collect(),
+        components,
+    }
+}
+
+fn observance(
+    start: chrono::NaiveDateTime,
+    (from, to): (&Observed, &Observed),
+    rule: Option<String>,
+) -> Component<'static> {
+    let mut properties = vec![("DTSTART", start.format("%Y%m%dT%H%M%S").to_string())];
+    if let Some(rule) = rule {
+        properties.push(("RRULE", rule));
+    }
+    properties.extend([
+        ("TZOFFSETFROM", format_offset(from.offset)),
+        ("TZOFFSETTO", format_offset(to.offset)),
+        ("TZNAME", to.name.clone()),
+    ]);
+    let kind = if to.daylight { "DAYLIGHT" } else { "STANDARD" };
+    component(kind, properties, Vec::new())
+}
+
+/// The VTIMEZONE describing a timezone from a year before the given time onwards,
+/// which an icalendar object must include for every TZID it uses (RFC 5545 §3.2.19).
+///
+/// Changes which recur on the same weekday of the same month every year, as daylight saving
+/// does in most places, are given as yearly rules. Otherwise each change in the next few years
+/// is given on its own.
+pub fn vtimezone(tz: Tz, from: chrono::DateTime<chrono::Utc>) -> icalendar::CalendarComponent {
+    let year = chrono::Duration::days(366);
+    let past = transitions(tz, from - year, from);
+    let next = transitions(tz, from, from + year);
+    let yearly = !past.is_empty()
+        && past
+            .iter()
+            .all(|change| next.iter().any(|later| change.repeated_by(later)));
+
+    let observances = if yearly {
+        past.iter()
+            .map(|change| {
+                let rule = Some(change.yearly_rule());
+                observance(change.local(), (&change.from, &change.to), rule)
+            })
+            .collect()
+    } else {
+        // the offset before the first change, then every change
+        let observed = Observed::at(tz, from - year);
+        let start = (from - year).naive_utc() + chrono::Duration::seconds(observed.offset.into());
+        let mut observances = vec![observance(start, (&observed, &observed), None)];
+        for change in transitions(tz, from - year, from + year * EXPLICIT_YEARS as i32) {
+            observances.push(observance(change.local(), (&change.from, &change.to), None));
+        }
+        observances
+    };
+
+    let vtimezone = component(
+        "VTIMEZONE",
+        vec![("TZID", tz.name().to_string())],
+        observances,
+    );
+    icalendar::CalendarComponent::from(vtimezone)
+}
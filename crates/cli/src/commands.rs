@@ -11,6 +11,8 @@ pub(crate) enum Commands {
     Server(Server),
     /// commands for interacting with a calendar on a caldav server
     Calendar(Calendar),
+    /// commands for inspecting and replaying webhook deliveries
+    Webhook(Webhook),
 }
 
 #[derive(clap::Args, Debug)]
@@ -30,11 +32,32 @@ pub(crate) enum CalendarCommands {
     Event(Event),
     /// get the availability of a calendar between two datetimes
     Availability(AvailabilityCommand),
+    /// publish the scheduler's availability between two datetimes as a VAVAILABILITY
+    PublishAvailability(PublishAvailabilityCommand),
 }
 
 #[derive(clap::Args, Debug)]
 pub(crate) struct CreateCalendarCommand {
+    /// the display name of the calendar
     pub name: String,
+    /// a description of the calendar
+    #[clap(long)]
+    pub description: Option<String>,
+    /// the color clients show the calendar in, e.g. #ff0000
+    #[clap(long)]
+    pub color: Option<String>,
+    /// the calendar's timezone, e.g. Europe/London
+    #[clap(long, default_value = "UTC")]
+    pub timezone: String,
+    /// a kind of component the calendar holds, e.g. VEVENT. May be given more than once
+    #[clap(long = "component")]
+    pub components: Vec<String>,
+    /// where clients sort the calendar among the others
+    #[clap(long)]
+    pub order: Option<u32>,
+    /// create the calendar with an extended MKCOL instead of MKCALENDAR
+    #[clap(long)]
+    pub mkcol: bool,
 }
 
 #[derive(clap::Args, Debug)]
@@ -60,6 +83,9 @@ pub(crate) struct ListEventsCommand {
     pub start: chrono::DateTime<chrono::Utc>,
     /// the end of the time range
     pub end: chrono::DateTime<chrono::Utc>,
+    /// only list events that this email address is attending
+    #[clap(long)]
+    pub attendee: Option<String>,
 }
 
 #[derive(clap::Args, Debug)]
@@ -75,6 +101,18 @@ pub(crate) struct CreateEventCommand {
     pub start: chrono::DateTime<chrono::Utc>,
     /// the end of the time range
     pub end: chrono::DateTime<chrono::Utc>,
+    /// the location of the event
+    #[clap(long)]
+    pub location: Option<String>,
+    /// a url associated with the event
+    #[clap(long)]
+    pub url: Option<String>,
+    /// email address of the organizer
+    #[clap(long)]
+    pub organizer: Option<String>,
+    /// email addresses of attendees
+    #[clap(long)]
+    pub attendee: Vec<String>,
 }
 
 #[derive(clap::Args, Debug)]
@@ -104,3 +142,45 @@ pub(crate) struct AvailabilityCommand {
     #[clap(long, default_value = "30")]
     pub granularity: i64,
 }
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct PublishAvailabilityCommand {
+    /// the name of the calendar to publish to
+    pub name: String,
+    /// the start of the time range
+    pub start: chrono::DateTime<chrono::Utc>,
+    /// the end of the time range
+    pub end: chrono::DateTime<chrono::Utc>,
+    /// the UID of the VAVAILABILITY, which replaces any previously published with it
+    #[clap(long, default_value = "scheduler-availability")]
+    pub uid: String,
+}
+
+#[derive(clap::Args, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+pub(crate) struct Webhook {
+    #[clap(subcommand)]
+    pub command: WebhookCommands,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub(crate) enum WebhookCommands {
+    /// list webhook deliveries
+    List(ListDeliveriesCommand),
+    /// queue a delivery to be sent again by the server, regardless of whether it previously
+    /// succeeded or failed
+    Replay(ReplayDeliveryCommand),
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ListDeliveriesCommand {
+    /// only list deliveries with this status: pending, delivered or failed
+    #[clap(long, default_value = "failed")]
+    pub status: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ReplayDeliveryCommand {
+    /// the id of the delivery
+    pub id: String,
+}
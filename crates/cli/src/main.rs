@@ -3,43 +3,181 @@ use axum::{
     Router,
 };
 use caldav_utils::{
-    availability::{calendar_availability, get_availability},
-    caldav::client::{DavClient, DavCredentials},
+    availability::{
+        calendar_availability, vavailability::VAvailability, working_hours::WorkingHours,
+    },
+    caldav::{
+        calendar::{CreateMethod, NewCalendar},
+        client::{ConnectionConfig, DavClient, DavCredentials},
+        event::{Attendee, NewEvent, Organizer},
+        retry::RetryPolicy,
+    },
+    ics::IcsSource,
+    vdir::Vdir,
 };
 use clap::Parser;
 use commands::ServerCommands;
-use reqwest::Client;
 use scheduling_api::{
-    get_calendars, get_now, request_availability, request_booking, state::CaldavAvailability,
+    approval::HoldExpiry,
+    available_slots,
+    blackout::load_blackouts,
+    get_calendars, get_meeting_types, get_now,
+    hold::Holds,
+    live::{stream_availability, ChangeWatcher},
+    meeting_type::load_meeting_types,
+    notify::{
+        mailer::{FileMailer, Mailer, SmtpMailer},
+        template::Templates,
+        Notifier,
+    },
+    precompute::AvailabilityCache,
+    reminder::{ReminderStore, Reminders},
+    request_approve, request_availability, request_booking, request_cancel, request_decline,
+    request_hold, request_reschedule,
+    state::CaldavAvailability,
+    webhook::{
+        load_subscriptions,
+        queue::{DeliveryQueue, DeliveryStatus},
+        Webhooks,
+    },
 };
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 use tracing::info;
 
 mod commands;
-use crate::commands::{CalendarCommands, Commands, EventCommands};
+use crate::commands::{CalendarCommands, Commands, EventCommands, WebhookCommands};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
 
     // read configuration
-    let username = std::env::var("CALDAV_USERNAME").expect("CALDAV_USERNAME not set");
-    let password = std::env::var("CALDAV_PASSWORD").expect("CALDAV_PASSWORD not set");
-    let credentials = DavCredentials::new(username.to_string(), password.to_string());
-
-    let url = std::env::var("CALDAV_URL").expect("CALDAV_URL not set");
-
-    let dav_client = DavClient::new(url.to_string(), credentials);
+    // calendars are kept in a local vdir when VDIR is set, so no caldav server is needed
+    let vdir = std::env::var("VDIR")
+        .ok()
+        .map(|path| Vdir::new(path.into()));
+    // the caldav server is only connected to when CALDAV_URL is set
+    let dav_client = match std::env::var("CALDAV_URL") {
+        Ok(url) => {
+            let username =
+                std::env::var("CALDAV_USERNAME").map_err(|_| "CALDAV_USERNAME not set")?;
+            let password =
+                std::env::var("CALDAV_PASSWORD").map_err(|_| "CALDAV_PASSWORD not set")?;
+            let credentials = DavCredentials::new(username, password);
+            Some(DavClient::with_config(
+                url,
+                credentials,
+                &connection_from_env()?,
+            )?)
+        }
+        Err(_) => None,
+    };
 
-    let availability_calendar =
-        std::env::var("AVAILABLE_CALENDAR").expect("AVAILABLE_CALENDAR not set");
+    // open times come from AVAILABLE_CALENDAR, WORKING_HOURS_CONFIG, or both
+    let availability_calendar = std::env::var("AVAILABLE_CALENDAR").ok();
+    let working_hours = match std::env::var("WORKING_HOURS_CONFIG") {
+        Ok(config) => Some(WorkingHours::from_json(&std::fs::read_to_string(config)?)?),
+        Err(_) => None,
+    };
+    if availability_calendar.is_none() && working_hours.is_none() {
+        return Err("AVAILABLE_CALENDAR or WORKING_HOURS_CONFIG not set".into());
+    }
     let booked_calendar = std::env::var("BOOKED_CALENDAR").expect("BOOKED_CALENDAR not set");
 
-    let caldav_state = CaldavAvailability::new(
-        availability_calendar.to_string(),
-        booked_calendar.to_string(),
-        dav_client,
-    );
+    let mut caldav_state = match (dav_client, vdir) {
+        (Some(dav_client), None) => {
+            CaldavAvailability::new(availability_calendar, booked_calendar, dav_client)
+        }
+        (Some(dav_client), Some(vdir)) => {
+            CaldavAvailability::new(availability_calendar, booked_calendar, dav_client)
+                .with_vdir(vdir)
+        }
+        (None, Some(vdir)) => {
+            CaldavAvailability::local(availability_calendar, booked_calendar, vdir)
+        }
+        (None, None) => return Err("CALDAV_URL or VDIR not set".into()),
+    };
+    // calendars on the caldav server are discovered again after DISCOVERY_TTL minutes
+    if let Ok(minutes) = std::env::var("DISCOVERY_TTL") {
+        caldav_state = caldav_state.with_discovery_ttl(chrono::Duration::minutes(minutes.parse()?));
+    }
+    if let Some(working_hours) = working_hours {
+        caldav_state = caldav_state.with_working_hours(working_hours);
+    }
+    // recurring bookings keep their local time in HOST_TIMEZONE, e.g. "Europe/Berlin",
+    // which defaults to the working hours' timezone
+    if let Ok(timezone) = std::env::var("HOST_TIMEZONE") {
+        caldav_state = caldav_state.with_timezone(timezone.parse::<chrono_tz::Tz>()?);
+    }
+    // read VAVAILABILITY components from AVAILABLE_CALENDAR rather than events
+    if std::env::var("AVAILABILITY_COMPONENTS").is_ok_and(|v| v == "true") {
+        caldav_state = caldav_state.with_availability_components();
+    }
+    if let Ok(email) = std::env::var("ORGANIZER_EMAIL") {
+        let name = std::env::var("ORGANIZER_NAME").ok();
+        caldav_state = caldav_state.with_organizer(Organizer::new(email, name));
+    }
+    if let Ok(location) = std::env::var("BOOKING_LOCATION") {
+        caldav_state = caldav_state.with_location(location);
+    }
+    if let Ok(url) = std::env::var("BOOKING_URL") {
+        caldav_state = caldav_state.with_url(url);
+    }
+    if let Ok(config) = std::env::var("MEETING_TYPES_CONFIG") {
+        caldav_state = caldav_state.with_meeting_types(load_meeting_types(config.as_ref())?);
+    }
+    if let Ok(config) = std::env::var("BLACKOUTS_CONFIG") {
+        caldav_state = caldav_state.with_blackouts(load_blackouts(config.as_ref())?);
+    }
+    // a comma separated list of .ics urls or files whose events are busy time
+    // they are fetched with the same connection settings as the caldav server
+    if let Ok(feeds) = std::env::var("BUSY_FEEDS") {
+        let http = caldav_state.davclient().map(|client| client.http().clone());
+        let feeds = feeds
+            .split(',')
+            .map(|feed| feed.trim().parse::<IcsSource>())
+            .map(|feed| {
+                feed.map(|feed| match &http {
+                    Some(http) => feed.with_client(http.clone()),
+                    None => feed,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        caldav_state = caldav_state.with_busy_feeds(feeds);
+    }
+    if let Ok(token) = std::env::var("HOST_TOKEN") {
+        caldav_state = caldav_state.with_host_token(token);
+    }
+    if let Ok(minutes) = std::env::var("APPROVAL_EXPIRY") {
+        caldav_state =
+            caldav_state.with_approval_expiry(chrono::Duration::minutes(minutes.parse()?));
+    }
+    // bookings must reserve their slot with a hold first, which lasts for HOLD_TTL minutes
+    let hold_ttl = match std::env::var("HOLD_TTL") {
+        Ok(minutes) => chrono::Duration::minutes(minutes.parse()?),
+        Err(_) => chrono::Duration::minutes(10),
+    };
+    // each client and email may hold HOLDS_PER_CLIENT slots at once
+    let mut holds = Holds::new(hold_ttl);
+    if let Ok(max) = std::env::var("HOLDS_PER_CLIENT") {
+        holds = holds.with_max_per_owner(max.parse()?);
+    }
+    caldav_state = caldav_state.with_holds(holds);
+    if let Some(notifier) = notifier_from_env()? {
+        caldav_state = caldav_state.with_notifier(notifier);
+    }
+    if let Some(webhooks) = webhooks_from_env()? {
+        caldav_state = caldav_state.with_webhooks(webhooks);
+    }
+    // availability for the next AVAILABILITY_CACHE_DAYS is computed in the background,
+    // and requests are answered from it if it was checked within AVAILABILITY_CACHE_FRESHNESS seconds
+    if let Ok(days) = std::env::var("AVAILABILITY_CACHE_DAYS") {
+        let mut cache = AvailabilityCache::new(chrono::Duration::days(days.parse()?));
+        if let Ok(seconds) = std::env::var("AVAILABILITY_CACHE_FRESHNESS") {
+            cache = cache.with_freshness(chrono::Duration::seconds(seconds.parse()?));
+        }
+        caldav_state = caldav_state.with_availability_cache(cache);
+    }
 
     // process commands
     let args = commands::Args::parse();
@@ -54,64 +192,86 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let cmd = calendar.command;
             match cmd {
                 CalendarCommands::Create(create) => {
-                    let client = Client::new();
-                    let mut principal = caldav_state.davclient().get_principal(&client).await?;
-                    let calendar = principal
-                        .create_calendar_mkcol(&client, &create.name)
-                        .await?;
-                    println!("Created calendar: {}", calendar.path);
+                    // floating times are read in the calendar's timezone, so it must be known
+                    if create.timezone.parse::<chrono_tz::Tz>().is_err() {
+                        return Err(format!("unknown calendar timezone {}", create.timezone).into());
+                    }
+                    let mut details = NewCalendar::new(&create.name).timezone(&create.timezone);
+                    if let Some(description) = &create.description {
+                        details = details.description(description);
+                    }
+                    if let Some(color) = &create.color {
+                        details = details.color(color);
+                    }
+                    for component in &create.components {
+                        details = details.component(component);
+                    }
+                    if let Some(order) = create.order {
+                        details = details.order(order);
+                    }
+                    if create.mkcol {
+                        details = details.method(CreateMethod::ExtendedMkcol);
+                    }
+                    let calendar = caldav_state.create_calendar(&details).await?;
+                    println!("Created calendar: {}", calendar.path());
                 }
                 CalendarCommands::List => {
-                    let client = Client::new();
-                    let mut principal = caldav_state.davclient().get_principal(&client).await?;
-                    let calendars = principal.get_calendars(&client).await?;
+                    let calendars = caldav_state.calendars().await?;
                     for calendar in calendars {
-                        println!("calendar {} at {}", calendar.display_name, calendar.path);
+                        println!(
+                            "calendar {} at {}",
+                            calendar.display_name(),
+                            calendar.path()
+                        );
                     }
                 }
                 CalendarCommands::Event(event) => {
                     let cmd = event.command;
                     match cmd {
                         EventCommands::List(list) => {
-                            let client = Client::new();
-                            let mut principal =
-                                caldav_state.davclient().get_principal(&client).await?;
-                            let calendar = principal.get_calendar(&client, &list.name).await?;
-                            let events = calendar.get_events(&client, list.start, list.end).await?;
+                            let calendar = caldav_state.calendar(&list.name).await?;
+                            let events = match &list.attendee {
+                                Some(email) => {
+                                    calendar
+                                        .get_events_by_attendee(list.start, list.end, email)
+                                        .await?
+                                }
+                                None => calendar.events(list.start, list.end).await?,
+                            };
                             tracing::info!("Found {} events", events.len());
                             for event in events {
                                 tracing::info!("event: {:?}", event);
                             }
                         }
                         EventCommands::Create(create) => {
-                            let client = Client::new();
-                            let mut principal =
-                                caldav_state.davclient().get_principal(&client).await?;
-                            let calendar =
-                                principal.get_calendar(&client, &create.calendar).await?;
-                            let event = calendar
-                                .create_event(
-                                    &client,
-                                    create.start,
-                                    create.end,
-                                    &create.name,
-                                    &create.description,
-                                )
-                                .await?;
+                            let calendar = caldav_state.calendar(&create.calendar).await?;
+                            let mut details = NewEvent::new(create.start, create.end, &create.name)
+                                .description(&create.description);
+                            if let Some(location) = &create.location {
+                                details = details.location(location);
+                            }
+                            if let Some(url) = &create.url {
+                                details = details.url(url);
+                            }
+                            if let Some(organizer) = create.organizer {
+                                details = details.organizer(Organizer::new(organizer, None));
+                            }
+                            for attendee in create.attendee {
+                                details =
+                                    details.attendee(Attendee::new(attendee, None).rsvp(true));
+                            }
+                            let event = calendar.create_event(&details).await?;
                             tracing::info!("Created event: {:?}", event);
                         }
                     }
                 }
                 CalendarCommands::Availability(avail) => {
-                    let client = Client::new();
-                    let mut principal = caldav_state.davclient().get_principal(&client).await?;
-                    let calendar = principal.get_calendar(&client, &avail.name).await?;
-                    let events = calendar.get_events(&client, avail.start, avail.end).await?;
+                    let calendar = caldav_state.calendar(&avail.name).await?;
+                    let events = calendar.events(avail.start, avail.end).await?;
                     let granularity = chrono::Duration::minutes(avail.granularity);
                     tracing::info!("Found {} events", events.len());
                     let availability = calendar_availability(
-                        &client,
-                        &calendar,
+                        calendar.as_ref(),
                         avail.start,
                         avail.end,
                         granularity,
@@ -119,6 +279,61 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .await?;
                     tracing::info!("availability: {:?}", availability);
                 }
+                CalendarCommands::PublishAvailability(publish) => {
+                    let (availability_calendar, booked_calendar) =
+                        get_calendars(caldav_state.clone()).await?;
+                    let availability = available_slots(
+                        &caldav_state,
+                        (availability_calendar.as_deref(), booked_calendar.as_ref()),
+                        (publish.start, publish.end),
+                        &[],
+                    )
+                    .await?;
+
+                    let calendar = caldav_state.calendar(&publish.name).await?;
+                    let vavailability = VAvailability::from_matrix(&publish.uid, &availability);
+                    calendar.put_availability(&vavailability).await?;
+                    println!(
+                        "Published {} available periods to {}",
+                        vavailability.available.len(),
+                        calendar.path()
+                    );
+                }
+            }
+        }
+        Commands::Webhook(webhook) => {
+            let webhooks = caldav_state
+                .webhooks()
+                .ok_or("WEBHOOKS_CONFIG not set")?
+                .clone();
+            let cmd = webhook.command;
+            match cmd {
+                WebhookCommands::List(list) => {
+                    let status = match list.status.as_str() {
+                        "pending" => DeliveryStatus::Pending,
+                        "delivered" => DeliveryStatus::Delivered,
+                        "failed" => DeliveryStatus::Failed,
+                        other => return Err(format!("unknown delivery status: {other}").into()),
+                    };
+                    for delivery in webhooks.queue().list(status)? {
+                        println!(
+                            "{} {:?} to {} after {} attempts: {}",
+                            delivery.id,
+                            delivery.event,
+                            delivery.url,
+                            delivery.attempts,
+                            delivery.last_error.unwrap_or_default()
+                        );
+                    }
+                }
+                WebhookCommands::Replay(replay) => {
+                    // the server's worker sends it, so it isn't sent twice while one is running
+                    let delivery = webhooks.queue().replay(&replay.id)?;
+                    println!(
+                        "Queued delivery {} to {} to be sent again",
+                        delivery.id, delivery.url
+                    );
+                }
             }
         }
     }
@@ -132,23 +347,146 @@ async fn scheduler_api(caldav_state: CaldavAvailability) -> Result<(), Box<dyn s
         .and_then(|it| it.parse().ok())
         .unwrap_or(8000);
 
+    if let Some(notifier) = caldav_state.notifier() {
+        let store_path =
+            std::env::var("REMINDER_STORE").unwrap_or_else(|_| "reminders.json".to_string());
+        let offsets = std::env::var("REMINDER_OFFSETS").unwrap_or_else(|_| "1440,60".to_string());
+        let offsets = offsets
+            .split(',')
+            .map(|minutes| minutes.trim().parse().map(chrono::Duration::minutes))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let reminders = Reminders::new(
+            caldav_state.clone(),
+            notifier.clone(),
+            ReminderStore::load(store_path.into())?,
+            offsets,
+        );
+        tokio::spawn(reminders.run(std::time::Duration::from_secs(60)));
+    }
+
+    if let Some(webhooks) = caldav_state.webhooks() {
+        tokio::spawn(webhooks.clone().run());
+    }
+
+    if let Some(holds) = caldav_state.holds() {
+        tokio::spawn(holds.clone().run(std::time::Duration::from_secs(30)));
+    }
+
+    if let Some(cache) = caldav_state.availability_cache() {
+        tokio::spawn(cache.clone().run(caldav_state.clone()));
+    }
+
+    // clients streaming availability are told about changes made outside the server too
+    let watcher = ChangeWatcher::new(caldav_state.clone());
+    tokio::spawn(watcher.run(std::time::Duration::from_secs(30)));
+
+    if caldav_state
+        .meeting_types()
+        .iter()
+        .any(|meeting_type| meeting_type.requires_approval)
+    {
+        let expiry = HoldExpiry::new(caldav_state.clone());
+        tokio::spawn(expiry.run(std::time::Duration::from_secs(60)));
+    }
+
     let app = Router::new()
         .route("/now", get(get_now))
         // POST since JS doesn't support body in GET
         .route("/availability", post(request_availability))
+        // server-sent events with the availability of ?start=&end=, whenever it changes
+        .route("/availability/stream", get(stream_availability))
+        .route("/meeting-types", get(get_meeting_types))
+        .route("/hold", post(request_hold))
         .route("/book", post(request_booking))
+        // require the cancel token returned when booking
+        .route("/cancel", post(request_cancel))
+        .route("/reschedule", post(request_reschedule))
+        // host only, requires the HOST_TOKEN as a bearer token
+        .route("/approve", post(request_approve))
+        .route("/decline", post(request_decline))
         .with_state(caldav_state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     info!("Listening on {addr}");
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 
     Ok(())
 }
 
+/// Configure how the caldav server is connected to from the environment.
+/// CALDAV_TIMEOUT and CALDAV_CONNECT_TIMEOUT are in seconds, 30 and 10 unless set, CALDAV_CA_CERT is a PEM file
+/// of a certificate authority to trust and CALDAV_CLIENT_CERT is a PEM file containing a
+/// client certificate and its private key. Requests which fail in a way that may be temporary
+/// are sent up to CALDAV_RETRIES times, and may be delayed by up to CALDAV_RETRY_MAX_DELAY seconds.
+fn connection_from_env() -> Result<ConnectionConfig, Box<dyn std::error::Error>> {
+    let mut config = ConnectionConfig::new();
+    if let Ok(seconds) = std::env::var("CALDAV_TIMEOUT") {
+        config = config.with_timeout(std::time::Duration::from_secs(seconds.parse()?));
+    }
+    if let Ok(seconds) = std::env::var("CALDAV_CONNECT_TIMEOUT") {
+        config = config.with_connect_timeout(std::time::Duration::from_secs(seconds.parse()?));
+    }
+    if let Ok(path) = std::env::var("CALDAV_CA_CERT") {
+        config = config.with_root_certificate(std::fs::read(path)?);
+    }
+    if let Ok(path) = std::env::var("CALDAV_CLIENT_CERT") {
+        config = config.with_identity(std::fs::read(path)?);
+    }
+    if let Ok(proxy) = std::env::var("CALDAV_PROXY") {
+        config = config.with_proxy(proxy);
+    }
+    if let Ok(user_agent) = std::env::var("CALDAV_USER_AGENT") {
+        config = config.with_user_agent(user_agent);
+    }
+    let mut retry = RetryPolicy::default();
+    if let Ok(attempts) = std::env::var("CALDAV_RETRIES") {
+        retry = retry.with_max_attempts(attempts.parse()?);
+    }
+    if let Ok(seconds) = std::env::var("CALDAV_RETRY_MAX_DELAY") {
+        retry = retry.with_max_delay(std::time::Duration::from_secs(seconds.parse()?));
+    }
+    Ok(config.with_retry(retry))
+}
+
+/// Configure email notifications from the environment.
+/// SMTP_URL sends mail through an SMTP server, while MAIL_DIR writes it to a local maildir.
+fn notifier_from_env() -> Result<Option<Notifier>, Box<dyn std::error::Error>> {
+    let mailer: Arc<dyn Mailer> = if let Ok(url) = std::env::var("SMTP_URL") {
+        Arc::new(SmtpMailer::new(&url)?)
+    } else if let Ok(dir) = std::env::var("MAIL_DIR") {
+        Arc::new(FileMailer::new(dir.into()))
+    } else {
+        return Ok(None);
+    };
+
+    let from = std::env::var("MAIL_FROM").map_err(|_| "MAIL_FROM not set")?;
+    let mut notifier = Notifier::new(mailer, from);
+    if let Ok(dir) = std::env::var("MAIL_TEMPLATE_DIR") {
+        notifier = notifier.with_templates(Templates::load(dir.as_ref())?);
+    }
+
+    Ok(Some(notifier))
+}
+
+/// Configure webhook subscriptions from the environment.
+/// WEBHOOKS_CONFIG is a JSON file listing the subscriptions, and deliveries are
+/// queued in WEBHOOK_QUEUE_DIR. Subscriptions sharing a url need an `id` each.
+fn webhooks_from_env() -> Result<Option<Webhooks>, Box<dyn std::error::Error>> {
+    let config = match std::env::var("WEBHOOKS_CONFIG") {
+        Ok(config) => config,
+        Err(_) => return Ok(None),
+    };
+    let subscriptions = load_subscriptions(config.as_ref())?;
+    let queue_dir = std::env::var("WEBHOOK_QUEUE_DIR").unwrap_or_else(|_| "webhooks".to_string());
+    let queue = DeliveryQueue::new(queue_dir.into())?;
+
+    Ok(Some(Webhooks::new(subscriptions, queue)))
+}
+
 #[allow(dead_code)]
 async fn availability_experiment(
     caldav_state: CaldavAvailability,
@@ -157,18 +495,13 @@ async fn availability_experiment(
     let end = start + chrono::Duration::days(7);
     info!("getting availability from {} to {}", start, end);
 
-    let client = reqwest::Client::new();
-    let (availability_calendar, booked_calendar) = get_calendars(&client, caldav_state).await?;
-
-    let granularity = chrono::Duration::minutes(30);
+    let (availability_calendar, booked_calendar) = get_calendars(caldav_state.clone()).await?;
 
-    let availability = get_availability(
-        &client,
-        &availability_calendar,
-        &booked_calendar,
-        start,
-        end,
-        granularity,
+    let availability = available_slots(
+        &caldav_state,
+        (availability_calendar.as_deref(), booked_calendar.as_ref()),
+        (start, end),
+        &[],
     )
     .await?;
 
@@ -0,0 +1,75 @@
+use crate::{
+    booking::Booking, error::SchedulerResult, get_calendars, invalidate_availability, notify,
+    notify::NotificationKind, publish, state::CaldavAvailability, webhook::LifecycleEvent,
+};
+
+/// How far ahead to look for tentative bookings that may have expired.
+const HOLD_HORIZON_DAYS: i64 = 366;
+
+/// Determine which bookings are tentative holds that were not approved in time.
+pub fn expired_holds(
+    bookings: &[Booking],
+    expiry: chrono::Duration,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<Booking> {
+    bookings
+        .iter()
+        .filter(|booking| booking.hold_expired(expiry, now))
+        .cloned()
+        .collect()
+}
+
+/// Periodically removes tentative bookings that the host has not approved in time,
+/// releasing their slots for others.
+#[derive(Debug)]
+pub struct HoldExpiry {
+    caldav_state: CaldavAvailability,
+}
+
+impl HoldExpiry {
+    pub fn new(caldav_state: CaldavAvailability) -> HoldExpiry {
+        HoldExpiry { caldav_state }
+    }
+
+    /// Check the booked calendar once and remove any expired holds.
+    /// Returns the number of holds removed.
+    pub async fn run_once(&self) -> SchedulerResult<usize> {
+        let now = chrono::Utc::now();
+        let horizon = now + chrono::Duration::days(HOLD_HORIZON_DAYS);
+        let (_, booked_calendar) = get_calendars(self.caldav_state.clone()).await?;
+        // recurring bookings whose occurrences have all passed are left alone
+        let timezone = booked_calendar.timezone();
+        let bookings: Vec<Booking> = booked_calendar
+            .events(now, horizon)
+            .await?
+            .iter()
+            .filter(|event| !Booking::occurrences(event, now, horizon, timezone.clone()).is_empty())
+            .filter_map(Booking::from_event)
+            .collect();
+
+        let expired = expired_holds(&bookings, self.caldav_state.approval_expiry(), now);
+        for mut booking in expired.iter().cloned() {
+            booked_calendar.delete_event(&booking.id).await?;
+            invalidate_availability(&self.caldav_state);
+            booking.sequence += 1;
+
+            notify(&self.caldav_state, NotificationKind::Declined, &booking).await;
+            publish(&self.caldav_state, LifecycleEvent::Expired, &booking);
+        }
+
+        Ok(expired.len())
+    }
+
+    /// Remove expired holds forever, checking the calendar at the given interval.
+    pub async fn run(self, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match self.run_once().await {
+                Ok(0) => {}
+                Ok(expired) => tracing::info!("expired {} tentative bookings", expired),
+                Err(e) => tracing::warn!("failed to expire tentative bookings: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use caldav_utils::{
+    availability::{event_occurrences, get_num_slots, ranges_matrix},
+    caldav::event::Event,
+};
+
+use crate::{error::SchedulerResult, state::CaldavAvailability};
+
+/// Somewhere to read blackout dates from.
+/// Slots covered by a blackout are never available, whatever the other calendars say.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BlackoutSource {
+    /// A calendar on the CalDAV server, e.g. a public holiday calendar
+    Calendar { name: String },
+    /// A local icalendar file
+    File { path: PathBuf },
+    /// Whole days from `start` to `end` inclusive, in UTC
+    Dates {
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+/// Load blackout sources from a JSON file containing a list of them.
+pub fn load_blackouts(path: &Path) -> SchedulerResult<Vec<BlackoutSource>> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&text)?)
+}
+
+/// The events read from a file, and when the file was last modified.
+type FileEvents = (SystemTime, Arc<Vec<Event>>);
+
+/// The events of blackout files, kept until the files are modified
+/// so that they aren't read and parsed for every availability request.
+#[derive(Clone, Debug, Default)]
+pub struct BlackoutFiles {
+    files: Arc<Mutex<HashMap<PathBuf, FileEvents>>>,
+}
+
+impl BlackoutFiles {
+    /// The events in a file, read again only if it was modified since it was last read.
+    async fn events(&self, path: &Path) -> SchedulerResult<Arc<Vec<Event>>> {
+        let modified = tokio::fs::metadata(path).await?.modified()?;
+        if let Some((read_at, events)) = self.files.lock().unwrap().get(path) {
+            if *read_at == modified {
+                return Ok(events.clone());
+            }
+        }
+
+        let text = tokio::fs::read_to_string(path).await?;
+        let events = Arc::new(Event::parse_at(&path.to_string_lossy(), &text)?.split());
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (modified, events.clone()));
+        Ok(events)
+    }
+}
+
+/// The slots covered by each occurrence of an event.
+/// All-day events cover every slot from the start of their first day to the end of their last,
+/// and recurring ones, such as yearly holidays, do so on every day they recur on.
+fn event_matrix(
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    granularity: chrono::Duration,
+    event: &Event,
+    timezone: Option<String>,
+) -> SchedulerResult<Vec<bool>> {
+    let occurrences = event_occurrences(event, start, end, timezone)?;
+    Ok(ranges_matrix(start, end, granularity, &occurrences)?)
+}
+
+impl BlackoutSource {
+    /// The events making up this source's blackouts.
+    async fn events(
+        &self,
+        caldav_state: &CaldavAvailability,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> SchedulerResult<(Arc<Vec<Event>>, Option<String>)> {
+        match self {
+            BlackoutSource::Calendar { name } => {
+                let calendar = caldav_state.calendar(name).await?;
+                let events = calendar.events(start, end).await?;
+                Ok((Arc::new(events), calendar.timezone()))
+            }
+            BlackoutSource::File { path } => {
+                Ok((caldav_state.blackout_files.events(path).await?, None))
+            }
+            BlackoutSource::Dates { .. } => Ok((Arc::new(Vec::new()), None)),
+        }
+    }
+
+    /// A token which changes whenever this source's blackouts do, if it can tell:
+    /// a calendar's change token or a file's modification time.
+    pub async fn change_token(
+        &self,
+        caldav_state: &CaldavAvailability,
+    ) -> SchedulerResult<Option<String>> {
+        match self {
+            BlackoutSource::Calendar { name } => {
+                Ok(caldav_state.calendar(name).await?.change_token().await?)
+            }
+            BlackoutSource::File { path } => {
+                let modified = tokio::fs::metadata(path).await?.modified()?;
+                Ok(Some(format!("{modified:?}")))
+            }
+            // fixed dates never change
+            BlackoutSource::Dates { .. } => Ok(Some(String::new())),
+        }
+    }
+
+    /// Build a matrix where every slot covered by a blackout from this source is true.
+    pub async fn matrix(
+        &self,
+        caldav_state: &CaldavAvailability,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        granularity: chrono::Duration,
+    ) -> SchedulerResult<Vec<bool>> {
+        if let BlackoutSource::Dates {
+            start: first,
+            end: last,
+            ..
+        } = self
+        {
+            let from = first.and_hms_opt(0, 0, 0).unwrap_or_default();
+            let until = last.and_hms_opt(0, 0, 0).unwrap_or_default() + chrono::Duration::days(1);
+            return Ok(ranges_matrix(
+                start,
+                end,
+                granularity,
+                &[(
+                    chrono::DateTime::from_utc(from, chrono::Utc),
+                    chrono::DateTime::from_utc(until, chrono::Utc),
+                )],
+            )?);
+        }
+
+        let (events, timezone) = self.events(caldav_state, start, end).await?;
+        let mut matrix = vec![false; get_num_slots(start, end, granularity)];
+        for event in events.iter() {
+            let covered = event_matrix(start, end, granularity, event, timezone.clone())?;
+            matrix = matrix
+                .iter()
+                .zip(covered.iter())
+                .map(|(a, b)| *a || *b)
+                .collect();
+        }
+        Ok(matrix)
+    }
+}
+
+/// Build a matrix where every slot covered by any blackout is true.
+pub async fn blackout_matrix(
+    caldav_state: &CaldavAvailability,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    granularity: chrono::Duration,
+) -> SchedulerResult<Vec<bool>> {
+    let mut matrix = vec![false; get_num_slots(start, end, granularity)];
+    for source in caldav_state.blackouts() {
+        let covered = source.matrix(caldav_state, start, end, granularity).await?;
+        matrix = matrix
+            .iter()
+            .zip(covered.iter())
+            .map(|(a, b)| *a || *b)
+            .collect();
+    }
+    Ok(matrix)
+}
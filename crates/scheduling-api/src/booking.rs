@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+
+use caldav_utils::{
+    availability::event_occurrences,
+    caldav::event::{escape_text, param_value, quote_param, unescape_text, Event, Organizer},
+    format::DATETIME,
+};
+use icalendar::{Component, EventLike};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::{meeting_type::Answer, recurrence::parse_exdates};
+
+/// The property holding each answer to a meeting type's questions.
+pub static ANSWER_PROPERTY: &str = "X-BOOKING-ANSWER";
+/// The parameter of an answer property holding the id of the question.
+pub static QUESTION_PARAMETER: &str = "X-QUESTION";
+/// The parameter of an answer property holding the attendee who gave it,
+/// only set on group events since they have several attendees.
+pub static ATTENDEE_PARAMETER: &str = "X-ATTENDEE";
+/// The property holding the number of seats on a group event.
+pub static CAPACITY_PROPERTY: &str = "X-BOOKING-CAPACITY";
+/// The property holding a hash of the token an attendee must give to cancel their booking
+/// or seat, or to reschedule it, with the attendee in [`ATTENDEE_PARAMETER`].
+pub static SEAT_TOKEN_PROPERTY: &str = "X-BOOKING-SEAT-TOKEN";
+
+/// Whether a booking has been confirmed or is waiting for the host to approve it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BookingStatus {
+    #[default]
+    Confirmed,
+    /// Holds the slot until the host approves or declines it
+    Tentative,
+}
+
+impl BookingStatus {
+    pub fn to_ical(self) -> icalendar::EventStatus {
+        match self {
+            BookingStatus::Confirmed => icalendar::EventStatus::Confirmed,
+            BookingStatus::Tentative => icalendar::EventStatus::Tentative,
+        }
+    }
+}
+
+/// A meeting that was booked through the scheduler.
+/// This is a view over the event stored in the booked calendar.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Booking {
+    /// The UID of the event in the booked calendar
+    pub id: String,
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+    /// The name of the person who made the booking
+    pub name: String,
+    /// The email address of the person who made the booking
+    pub email: String,
+    pub description: String,
+    pub host_email: Option<String>,
+    pub host_name: Option<String>,
+    pub location: Option<String>,
+    pub url: Option<String>,
+    /// The revision of the event, incremented each time it is changed
+    pub sequence: u32,
+    #[serde(default)]
+    pub status: BookingStatus,
+    /// When the booking was made
+    pub created: Option<chrono::DateTime<chrono::Utc>>,
+    pub meeting_type: Option<String>,
+    /// The number of seats, if this is a group event
+    pub capacity: Option<u32>,
+    /// The RRULE of a recurring booking
+    pub recurrence: Option<String>,
+    /// The timezone a recurring booking keeps its local time in, if not UTC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// Occurrences of a recurring booking that were skipped
+    #[serde(default)]
+    pub skipped: Vec<chrono::DateTime<chrono::Utc>>,
+    /// Answers to the meeting type's questions, keyed by question id
+    #[serde(default)]
+    pub answers: BTreeMap<String, Answer>,
+    /// The token needed to cancel the booking or seat, or to reschedule it.
+    /// Only given to the attendee in the response to their booking, since only a hash is stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancel_token: Option<String>,
+}
+
+impl Booking {
+    /// Read the booking details from an event.
+    /// Returns None if the event is missing the details required for a booking.
+    pub fn from_event(event: &Event) -> Option<Booking> {
+        let attendee = event.attendees().into_iter().next();
+        let email = event
+            .property_values("X-BOOKING-EMAIL")
+            .into_iter()
+            .next()
+            .or_else(|| attendee.as_ref().map(|a| a.email.clone()))?;
+        let name = event
+            .property_values("X-BOOKING-NAME")
+            .into_iter()
+            .next()
+            .or_else(|| attendee.and_then(|a| a.common_name))
+            .or_else(|| event.summary().map(|s| s.to_string()))
+            .unwrap_or_default();
+        let organizer = event.organizer();
+        let vevent = event.vevent()?;
+
+        Some(Booking {
+            id: event.uid()?.to_string(),
+            start: event.start()?,
+            end: event.end()?,
+            name,
+            email,
+            description: event.description().unwrap_or_default().to_string(),
+            host_email: organizer.as_ref().map(|o| o.email.clone()),
+            host_name: organizer.and_then(|o| o.common_name),
+            location: vevent.get_location().map(|s| s.to_string()),
+            url: vevent.get_url().map(|s| s.to_string()),
+            sequence: event.sequence(),
+            status: match event.status() {
+                Some(icalendar::EventStatus::Tentative) => BookingStatus::Tentative,
+                _ => BookingStatus::Confirmed,
+            },
+            created: event
+                .property_values("X-BOOKING-CREATED")
+                .first()
+                .and_then(|s| chrono::NaiveDateTime::parse_from_str(s, DATETIME).ok())
+                .map(|dt| chrono::DateTime::from_utc(dt, chrono::Utc)),
+            meeting_type: event
+                .property_values("X-BOOKING-MEETING-TYPE")
+                .into_iter()
+                .next(),
+            capacity: capacity(event),
+            recurrence: event.property_values("RRULE").into_iter().next(),
+            timezone: vevent
+                .properties()
+                .get("DTSTART")
+                .and_then(|dtstart| param_value(dtstart, "TZID")),
+            skipped: event
+                .property_values("EXDATE")
+                .iter()
+                .flat_map(|value| parse_exdates(value))
+                .collect(),
+            answers: read_answers(event, None),
+            cancel_token: None,
+        })
+    }
+
+    /// Read the booking of each occurrence of an event between two datetimes.
+    /// Only recurring bookings have more than one, and each has the time of its occurrence.
+    pub fn occurrences(
+        event: &Event,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        timezone: Option<String>,
+    ) -> Vec<Booking> {
+        let bookings = Booking::attendees_of(event);
+        let Some(booking) = bookings.first() else {
+            return Vec::new();
+        };
+        if booking.recurrence.is_none() {
+            return bookings;
+        }
+        match event_occurrences(event, start, end, timezone) {
+            Ok(occurrences) => occurrences
+                .into_iter()
+                .flat_map(|(start, end)| {
+                    bookings.iter().map(move |booking| Booking {
+                        start,
+                        end,
+                        ..booking.clone()
+                    })
+                })
+                .collect(),
+            Err(e) => {
+                tracing::warn!("skipping occurrences of booking {}: {}", booking.id, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// The booking of each attendee of an event: one for each seat of a group event,
+    /// or the event's own booking otherwise.
+    pub fn attendees_of(event: &Event) -> Vec<Booking> {
+        let Some(booking) = Booking::from_event(event) else {
+            return Vec::new();
+        };
+        if booking.capacity.is_none() {
+            return vec![booking];
+        }
+        event
+            .attendees()
+            .iter()
+            .filter_map(|attendee| Booking::for_attendee(event, &attendee.email))
+            .collect()
+    }
+
+    /// Read the booking of one attendee of a group event.
+    /// Returns None if they are not attending.
+    pub fn for_attendee(event: &Event, email: &str) -> Option<Booking> {
+        let attendee = event
+            .attendees()
+            .into_iter()
+            .find(|a| a.email.eq_ignore_ascii_case(email))?;
+        let booking = Booking::from_event(event)?;
+        Some(Booking {
+            name: attendee.common_name.unwrap_or_default(),
+            email: attendee.email,
+            answers: read_answers(event, Some(email)),
+            ..booking
+        })
+    }
+
+    /// Whether this booking is a tentative hold that was not approved in time.
+    pub fn hold_expired(
+        &self,
+        expiry: chrono::Duration,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> bool {
+        self.status == BookingStatus::Tentative
+            && self.created.is_some_and(|created| created + expiry <= now)
+    }
+
+    pub fn organizer(&self) -> Option<Organizer> {
+        self.host_email
+            .as_ref()
+            .map(|email| Organizer::new(email.clone(), self.host_name.clone()))
+    }
+}
+
+/// The number of seats on a group event, or None for events with a single attendee.
+pub fn capacity(event: &Event) -> Option<u32> {
+    event
+        .property_values(CAPACITY_PROPERTY)
+        .first()
+        .and_then(|s| s.parse().ok())
+}
+
+/// Build the properties which store answers on an event.
+/// Answers with several values are stored as one property per value.
+/// On group events, the attendee who gave the answers is recorded alongside them.
+pub fn answer_properties(
+    answers: &BTreeMap<String, Answer>,
+    attendee: Option<&str>,
+) -> Vec<icalendar::Property> {
+    answers
+        .iter()
+        .flat_map(|(question, answer)| {
+            answer.values().into_iter().map(move |value| {
+                let mut property = icalendar::Property::new(ANSWER_PROPERTY, &escape_text(value));
+                property.add_parameter(QUESTION_PARAMETER, &quote_param(question));
+                if let Some(attendee) = attendee {
+                    property.add_parameter(ATTENDEE_PARAMETER, &quote_param(attendee));
+                }
+                property.done()
+            })
+        })
+        .collect()
+}
+
+/// Build the property which stores the hash of an attendee's cancel token on a booking.
+pub fn seat_token_property(attendee: &str, token: &str) -> icalendar::Property {
+    let mut property = icalendar::Property::new(SEAT_TOKEN_PROPERTY, &hash_token(token));
+    property.add_parameter(ATTENDEE_PARAMETER, &quote_param(attendee));
+    property.done()
+}
+
+/// Whether the token is the one given to the attendee when they took their seat.
+pub fn seat_token_matches(event: &Event, attendee: &str, token: &str) -> bool {
+    let hash = hash_token(token);
+    event
+        .properties_named(SEAT_TOKEN_PROPERTY)
+        .into_iter()
+        .filter(|property| is_seat_token_of(property, attendee))
+        .any(|property| bool::from(property.value().as_bytes().ct_eq(hash.as_bytes())))
+}
+
+/// Whether a property is the seat token of the attendee.
+pub fn is_seat_token_of(property: &icalendar::Property, attendee: &str) -> bool {
+    property.key() == SEAT_TOKEN_PROPERTY
+        && param_value(property, ATTENDEE_PARAMETER)
+            .is_some_and(|given_to| given_to.eq_ignore_ascii_case(attendee))
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Whether an answer property was given by the attendee, or by nobody in particular if None.
+pub fn is_answer_from(property: &icalendar::Property, attendee: Option<&str>) -> bool {
+    property.key() == ANSWER_PROPERTY
+        && match (param_value(property, ATTENDEE_PARAMETER), attendee) {
+            (Some(given_by), Some(attendee)) => given_by.eq_ignore_ascii_case(attendee),
+            (None, None) => true,
+            _ => false,
+        }
+}
+
+fn read_answers(event: &Event, attendee: Option<&str>) -> BTreeMap<String, Answer> {
+    let mut values: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for property in event.properties_named(ANSWER_PROPERTY) {
+        if !is_answer_from(property, attendee) {
+            continue;
+        }
+        if let Some(question) = param_value(property, QUESTION_PARAMETER) {
+            values
+                .entry(question)
+                .or_default()
+                .push(unescape_text(property.value()));
+        }
+    }
+
+    values
+        .into_iter()
+        .map(|(question, mut values)| {
+            let answer = if values.len() == 1 {
+                Answer::One(values.remove(0))
+            } else {
+                Answer::Many(values)
+            };
+            (question, answer)
+        })
+        .collect()
+}
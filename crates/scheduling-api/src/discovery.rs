@@ -0,0 +1,201 @@
+use std::sync::Arc;
+
+use caldav_utils::{
+    availability::vavailability::VAvailability,
+    caldav::{
+        calendar::Calendar,
+        client::DavClient,
+        event::{Event, NewEvent},
+    },
+    error::{CaldavError, CaldavResult},
+    source::{CalendarSource, CalendarStore},
+};
+
+#[derive(Debug)]
+struct Discovered {
+    at: chrono::DateTime<chrono::Utc>,
+    calendars: Vec<Calendar>,
+}
+
+/// The calendars found on the caldav server, kept so that every request doesn't have to
+/// find the principal, its home set and its calendars again.
+/// They are discovered again once the ttl has passed, or once one of them is found to
+/// no longer exist on the server.
+#[derive(Clone, Debug)]
+pub struct DiscoveryCache {
+    ttl: chrono::Duration,
+    // held while discovering, so concurrent requests wait for one discovery
+    discovered: Arc<tokio::sync::Mutex<Option<Discovered>>>,
+}
+
+impl DiscoveryCache {
+    pub fn new(ttl: chrono::Duration) -> DiscoveryCache {
+        DiscoveryCache {
+            ttl,
+            discovered: Arc::new(tokio::sync::Mutex::new(None)),
+        }
+    }
+
+    pub fn ttl(&self) -> chrono::Duration {
+        self.ttl
+    }
+
+    /// The calendars on the server, discovering them if they aren't cached or are out of date.
+    pub async fn calendars(&self, davclient: &DavClient) -> CaldavResult<Vec<CachedCalendar>> {
+        self.calendars_at(davclient, chrono::Utc::now(), false)
+            .await
+    }
+
+    /// Find a calendar by name. If it isn't in the cache, the calendars are discovered
+    /// again in case it was created since they were last discovered.
+    pub async fn calendar(
+        &self,
+        davclient: &DavClient,
+        name: &str,
+    ) -> CaldavResult<CachedCalendar> {
+        let now = chrono::Utc::now();
+        for refresh in [false, true] {
+            let calendars = self.calendars_at(davclient, now, refresh).await?;
+            if let Some(calendar) = calendars.into_iter().find(|c| c.display_name() == name) {
+                return Ok(calendar);
+            }
+        }
+        Err(CaldavError::CalendarNotFound {
+            calendar_name: name.to_string(),
+        })
+    }
+
+    /// Forget the discovered calendars, so they are discovered again when next needed.
+    pub async fn invalidate(&self) {
+        *self.discovered.lock().await = None;
+    }
+
+    async fn calendars_at(
+        &self,
+        davclient: &DavClient,
+        now: chrono::DateTime<chrono::Utc>,
+        refresh: bool,
+    ) -> CaldavResult<Vec<CachedCalendar>> {
+        let mut discovered = self.discovered.lock().await;
+        let fresh = discovered
+            .as_ref()
+            .is_some_and(|discovered| now - discovered.at < self.ttl);
+        if refresh || !fresh {
+            tracing::debug!("discovering calendars");
+            let mut principal = davclient.get_principal().await?;
+            let calendars = principal.refresh_calendars().await?;
+            *discovered = Some(Discovered { at: now, calendars });
+        }
+
+        Ok(discovered
+            .as_ref()
+            .map(|discovered| discovered.calendars.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|calendar| CachedCalendar {
+                calendar,
+                cache: self.clone(),
+            })
+            .collect())
+    }
+}
+
+/// A calendar from a [`DiscoveryCache`], which invalidates the cache
+/// if the calendar turns out to no longer exist on the server.
+#[derive(Clone, Debug)]
+pub struct CachedCalendar {
+    calendar: Calendar,
+    cache: DiscoveryCache,
+}
+
+impl CachedCalendar {
+    pub fn calendar(&self) -> &Calendar {
+        &self.calendar
+    }
+
+    async fn check<T>(&self, result: CaldavResult<T>) -> CaldavResult<T> {
+        if let Err(CaldavError::CalendarNotFound { calendar_name }) = &result {
+            tracing::info!(
+                "calendar {} is gone, discovering calendars again",
+                calendar_name
+            );
+            self.cache.invalidate().await;
+        }
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl CalendarSource for CachedCalendar {
+    async fn events(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<Event>> {
+        self.check(self.calendar.events(start, end).await).await
+    }
+
+    fn timezone(&self) -> Option<String> {
+        self.calendar.timezone.clone()
+    }
+
+    async fn availabilities(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> CaldavResult<Vec<VAvailability>> {
+        self.check(self.calendar.availabilities(start, end).await)
+            .await
+    }
+
+    async fn change_token(&self) -> CaldavResult<Option<String>> {
+        self.check(self.calendar.change_token().await).await
+    }
+}
+
+#[async_trait::async_trait]
+impl CalendarStore for CachedCalendar {
+    fn display_name(&self) -> &str {
+        &self.calendar.display_name
+    }
+
+    fn path(&self) -> &str {
+        &self.calendar.path
+    }
+
+    async fn create_event(&self, details: &NewEvent) -> CaldavResult<Event> {
+        self.check(self.calendar.create_event(details).await).await
+    }
+
+    async fn get_event(&self, uid: &str) -> CaldavResult<Event> {
+        self.check(self.calendar.get_event(uid).await).await
+    }
+
+    async fn get_event_with_etag(&self, uid: &str) -> CaldavResult<(Event, Option<String>)> {
+        self.check(self.calendar.get_event_with_etag(uid).await)
+            .await
+    }
+
+    async fn update_event(&self, event: &Event) -> CaldavResult<()> {
+        self.check(self.calendar.update_event(event).await).await
+    }
+
+    async fn update_event_if_match(&self, event: &Event, etag: Option<&str>) -> CaldavResult<()> {
+        self.check(self.calendar.update_event_if_match(event, etag).await)
+            .await
+    }
+
+    async fn delete_event(&self, uid: &str) -> CaldavResult<()> {
+        self.check(self.calendar.delete_event(uid).await).await
+    }
+
+    async fn delete_event_if_match(&self, uid: &str, etag: Option<&str>) -> CaldavResult<()> {
+        self.check(self.calendar.delete_event_if_match(uid, etag).await)
+            .await
+    }
+
+    async fn put_availability(&self, availability: &VAvailability) -> CaldavResult<()> {
+        self.check(self.calendar.put_availability(availability).await)
+            .await
+    }
+}
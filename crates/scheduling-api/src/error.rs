@@ -1,17 +1,74 @@
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
 use thiserror::Error;
 
+use crate::meeting_type::FieldError;
+
 #[derive(Error, Debug)]
 pub enum SchedulerError {
+    #[error("Already attending: {0}")]
+    AlreadyAttending(String),
+    #[error("An attendee email is required for group bookings")]
+    AttendeeRequired,
+    #[error("Booking not found: {0}")]
+    BookingNotFound(String),
     #[error(transparent)]
     Caldav(#[from] caldav_utils::error::CaldavError),
+    #[error("No caldav server is configured")]
+    CaldavNotConfigured,
+    #[error("Webhook delivery not found: {0}")]
+    DeliveryNotFound(String),
+    #[error("Webhook subscriptions must have unique ids: {0}")]
+    DuplicateSubscription(String),
+    #[error(transparent)]
+    Email(#[from] lettre::error::Error),
+    #[error(transparent)]
+    EmailAddress(#[from] lettre::address::AddressError),
+    #[error(transparent)]
+    EmailContentType(#[from] lettre::message::header::ContentTypeErr),
+    #[error("Invalid fields: {0:?}")]
+    InvalidFields(Vec<FieldError>),
+    #[error("Invalid meeting type {0}: {1}")]
+    InvalidMeetingType(String, String),
+    #[error("Invalid question {0}: {1}")]
+    InvalidQuestion(String, String),
+    #[error("Invalid time range: {0} to {1}")]
+    InvalidTimeRange(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+    #[error("Group bookings cannot be rescheduled: {0}")]
+    GroupBooking(String),
+    #[error("Booking does not match hold: {0}")]
+    HoldMismatch(String),
+    #[error("Hold not found or expired: {0}")]
+    HoldNotFound(String),
+    #[error("A hold is required to book a slot")]
+    HoldRequired,
+    #[error("Holds are not enabled")]
+    HoldsDisabled,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Meeting type not found: {0}")]
+    MeetingTypeNotFound(String),
+    #[error("Booking is not awaiting approval: {0}")]
+    NotTentative(String),
+    #[error("Recurring bookings cannot be rescheduled: {0}")]
+    RecurringBooking(String),
     #[error(transparent)]
     Reqwest(#[from] reqwest::Error),
+    #[error("Occurrences not available: {0:?}")]
+    SeriesConflicts(Vec<chrono::DateTime<chrono::Utc>>),
+    #[error(transparent)]
+    Smtp(#[from] lettre::transport::smtp::Error),
     #[error("Requested time not available: {0}")]
     TimeNotAvailable(chrono::DateTime<chrono::Utc>),
+    #[error("Too many holds placed, let some expire first")]
+    TooManyHolds,
+    #[error("Unauthorized")]
+    Unauthorized,
 }
 
 pub type SchedulerResult<T> = Result<T, SchedulerError>;
@@ -24,6 +81,52 @@ impl IntoResponse for SchedulerError {
                 format!("Requested time not available: {msg}"),
             )
                 .into_response(),
+            SchedulerError::BookingNotFound(id) => {
+                (StatusCode::NOT_FOUND, format!("Booking not found: {id}")).into_response()
+            }
+            SchedulerError::MeetingTypeNotFound(id) => (
+                StatusCode::NOT_FOUND,
+                format!("Meeting type not found: {id}"),
+            )
+                .into_response(),
+            SchedulerError::HoldNotFound(id) => (
+                StatusCode::NOT_FOUND,
+                format!("Hold not found or expired: {id}"),
+            )
+                .into_response(),
+            SchedulerError::HoldsDisabled => {
+                (StatusCode::NOT_FOUND, "Holds are not enabled").into_response()
+            }
+            SchedulerError::AlreadyAttending(_) => {
+                (StatusCode::CONFLICT, self.to_string()).into_response()
+            }
+            SchedulerError::SeriesConflicts(conflicts) => (
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({ "conflicts": conflicts })),
+            )
+                .into_response(),
+            SchedulerError::TooManyHolds => {
+                (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response()
+            }
+            SchedulerError::AttendeeRequired
+            | SchedulerError::InvalidTimeRange(..)
+            | SchedulerError::GroupBooking(_)
+            | SchedulerError::RecurringBooking(_)
+            | SchedulerError::HoldMismatch(_)
+            | SchedulerError::HoldRequired => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
+            SchedulerError::NotTentative(id) => (
+                StatusCode::CONFLICT,
+                format!("Booking is not awaiting approval: {id}"),
+            )
+                .into_response(),
+            SchedulerError::Unauthorized => StatusCode::UNAUTHORIZED.into_response(),
+            SchedulerError::InvalidFields(errors) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(serde_json::json!({ "errors": errors })),
+            )
+                .into_response(),
             msg => (StatusCode::INTERNAL_SERVER_ERROR, msg.to_string()).into_response(),
         }
     }
@@ -0,0 +1,145 @@
+use caldav_utils::{
+    availability::{
+        get_event_matrix, get_num_slots, ranges_matrix, subtract_matrix, AvailabilityResponse,
+    },
+    caldav::event::Event,
+    error::CaldavResult,
+    source::CalendarStore,
+};
+
+use crate::{
+    available_slots, booking, error::SchedulerResult, meeting_type::MeetingType,
+    state::CaldavAvailability,
+};
+
+/// The availability of a group meeting type's slots.
+#[derive(Clone, Debug)]
+pub struct GroupSlots {
+    /// A slot is available if at least one seat is left in it
+    pub availability: AvailabilityResponse,
+    /// The seats left in each slot
+    pub seats: Vec<u32>,
+    /// The seats left in each slot, ignoring any holds
+    pub booked_seats: Vec<u32>,
+    /// The meeting type's events within the range
+    pub events: Vec<Event>,
+}
+
+/// Whether the event is a group event of the given meeting type.
+pub fn is_group_event_of(event: &Event, meeting_type: &str) -> bool {
+    booking::capacity(event).is_some()
+        && event
+            .property_values("X-BOOKING-MEETING-TYPE")
+            .first()
+            .is_some_and(|id| id == meeting_type)
+}
+
+/// Count the seats left in each slot.
+/// Every attendee of a group event covering a slot takes a seat, as does every held range.
+pub fn seats_matrix(
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    granularity: chrono::Duration,
+    capacity: u32,
+    events: &[Event],
+    held: &[(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)],
+    timezone: Option<String>,
+) -> CaldavResult<Vec<u32>> {
+    let mut seats = vec![capacity; get_num_slots(start, end, granularity)];
+
+    let mut take = |matrix: Vec<bool>, taken: u32| {
+        for (seats, covered) in seats.iter_mut().zip(matrix) {
+            if covered {
+                *seats = seats.saturating_sub(taken);
+            }
+        }
+    };
+    for event in events {
+        let matrix = get_event_matrix(start, end, granularity, event, timezone.clone())?;
+        take(matrix, event.attendees().len() as u32);
+    }
+    for range in held {
+        take(ranges_matrix(start, end, granularity, &[*range])?, 1);
+    }
+
+    Ok(seats)
+}
+
+/// Determine the seats left in a group meeting type's slots.
+/// The meeting type's own events and seat holds only take seats, while everything
+/// else in the booked calendar makes the slot unavailable as usual.
+pub async fn group_slots(
+    caldav_state: &CaldavAvailability,
+    (availability_calendar, booked_calendar): (Option<&dyn CalendarStore>, &dyn CalendarStore),
+    meeting_type: &MeetingType,
+    (start, end): (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+    except_hold: Option<&str>,
+) -> SchedulerResult<GroupSlots> {
+    let granularity = chrono::Duration::minutes(crate::SLOT_MINUTES);
+    let capacity = meeting_type.capacity.unwrap_or(1);
+
+    let events: Vec<Event> = booked_calendar
+        .events(start, end)
+        .await?
+        .into_iter()
+        .filter(|event| is_group_event_of(event, &meeting_type.id))
+        .collect();
+    let excluded: Vec<&str> = events.iter().filter_map(|event| event.uid()).collect();
+
+    let mut availability = available_slots(
+        caldav_state,
+        (availability_calendar, booked_calendar),
+        (start, end),
+        &excluded,
+    )
+    .await?;
+
+    let (held, busy): (Vec<_>, Vec<_>) = caldav_state
+        .holds()
+        .map(|holds| holds.active(chrono::Utc::now()))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|hold| except_hold != Some(hold.id.as_str()))
+        .partition(|hold| hold.meeting_type.as_deref() == Some(meeting_type.id.as_str()));
+    let busy: Vec<_> = busy.iter().map(|hold| (hold.start, hold.end)).collect();
+    let held: Vec<_> = held.iter().map(|hold| (hold.start, hold.end)).collect();
+    availability.matrix = subtract_matrix(
+        &availability.matrix,
+        &ranges_matrix(start, end, granularity, &busy)?,
+    );
+
+    let timezone = booked_calendar.timezone();
+    let only_open = |seats: Vec<u32>| -> Vec<u32> {
+        seats
+            .into_iter()
+            .zip(availability.matrix.iter())
+            .map(|(seats, open)| if *open { seats } else { 0 })
+            .collect()
+    };
+    let booked_seats = only_open(seats_matrix(
+        start,
+        end,
+        granularity,
+        capacity,
+        &events,
+        &[],
+        timezone.clone(),
+    )?);
+    let seats = only_open(seats_matrix(
+        start,
+        end,
+        granularity,
+        capacity,
+        &events,
+        &held,
+        timezone,
+    )?);
+    availability.matrix = seats.iter().map(|seats| *seats > 0).collect();
+
+    Ok(GroupSlots {
+        availability,
+        seats,
+        booked_seats,
+        events,
+    })
+}
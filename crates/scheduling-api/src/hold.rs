@@ -0,0 +1,251 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    error::{SchedulerError, SchedulerResult},
+    live::AvailabilityChanges,
+};
+
+/// A slot reserved for a short time while the attendee fills out the booking form.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Hold {
+    pub id: String,
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+    /// After this time the slot is released for others
+    pub expires: chrono::DateTime<chrono::Utc>,
+    /// The group meeting type this hold takes a seat in.
+    /// Holds without one reserve the whole slot.
+    pub meeting_type: Option<String>,
+    /// Who placed the hold, e.g. their address and email, which are limited in how many
+    /// holds they may have at once. These aren't shared with the client.
+    #[serde(skip)]
+    pub owners: Vec<String>,
+}
+
+impl Hold {
+    pub fn is_expired(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.expires <= now
+    }
+
+    pub fn overlaps(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> bool {
+        self.start < end && start < self.end
+    }
+
+    pub fn covers(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> bool {
+        self.start <= start && end <= self.end
+    }
+}
+
+/// The holds currently placed on slots.
+/// Holds are only kept in memory, so they are lost if the server restarts.
+#[derive(Clone, Debug)]
+pub struct Holds {
+    ttl: chrono::Duration,
+    /// How many holds which have not expired each owner may have.
+    max_per_owner: usize,
+    holds: Arc<Mutex<BTreeMap<String, Hold>>>,
+    /// Told whenever a hold is placed or released, since holds make slots unavailable.
+    changes: Option<AvailabilityChanges>,
+}
+
+impl Holds {
+    pub fn new(ttl: chrono::Duration) -> Holds {
+        Holds {
+            ttl,
+            max_per_owner: 3,
+            holds: Arc::new(Mutex::new(BTreeMap::new())),
+            changes: None,
+        }
+    }
+
+    /// Tell clients streaming availability whenever a hold is placed or released.
+    /// [`CaldavAvailability::with_holds`] does this with its own changes.
+    ///
+    /// [`CaldavAvailability::with_holds`]: crate::state::CaldavAvailability::with_holds
+    pub fn with_changes(mut self, changes: AvailabilityChanges) -> Self {
+        self.changes = Some(changes);
+        self
+    }
+
+    fn notify(&self) {
+        if let Some(changes) = &self.changes {
+            changes.notify();
+        }
+    }
+
+    pub fn with_max_per_owner(mut self, max_per_owner: usize) -> Self {
+        self.max_per_owner = max_per_owner;
+        self
+    }
+
+    pub fn ttl(&self) -> chrono::Duration {
+        self.ttl
+    }
+
+    /// Reserve a slot, failing if it overlaps a hold that has not expired,
+    /// or if any of its owners already have as many holds as they may.
+    /// The caller is responsible for checking the slot is otherwise available.
+    pub fn place(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        now: chrono::DateTime<chrono::Utc>,
+        owners: &[String],
+    ) -> SchedulerResult<Hold> {
+        self.insert(start, end, now, None, owners)
+    }
+
+    /// Reserve a seat in a group meeting type's slot.
+    /// Fails if the slot is held for anything else, or if the other holds for the
+    /// meeting type already take all of the given remaining seats.
+    pub fn place_seat(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        now: chrono::DateTime<chrono::Utc>,
+        meeting_type: &str,
+        seats: u32,
+        owners: &[String],
+    ) -> SchedulerResult<Hold> {
+        self.insert(start, end, now, Some((meeting_type, seats)), owners)
+    }
+
+    fn insert(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        now: chrono::DateTime<chrono::Utc>,
+        seat: Option<(&str, u32)>,
+        owners: &[String],
+    ) -> SchedulerResult<Hold> {
+        if end <= start || end <= now {
+            return Err(SchedulerError::InvalidTimeRange(start, end));
+        }
+
+        let mut holds = self.holds.lock().unwrap();
+        holds.retain(|_, hold| !hold.is_expired(now));
+
+        for owner in owners {
+            let held = holds
+                .values()
+                .filter(|hold| hold.owners.contains(owner))
+                .count();
+            if held >= self.max_per_owner {
+                return Err(SchedulerError::TooManyHolds);
+            }
+        }
+
+        let overlapping: Vec<&Hold> = holds
+            .values()
+            .filter(|hold| hold.overlaps(start, end))
+            .collect();
+        let available = match seat {
+            None => overlapping.is_empty(),
+            Some((meeting_type, seats)) => {
+                overlapping
+                    .iter()
+                    .all(|hold| hold.meeting_type.as_deref() == Some(meeting_type))
+                    && (overlapping.len() as u32) < seats
+            }
+        };
+        if !available {
+            return Err(SchedulerError::TimeNotAvailable(start));
+        }
+
+        let hold = Hold {
+            id: ksuid::Ksuid::generate().to_base62(),
+            start,
+            end,
+            expires: now + self.ttl,
+            meeting_type: seat.map(|(meeting_type, _)| meeting_type.to_string()),
+            owners: owners.to_vec(),
+        };
+        holds.insert(hold.id.clone(), hold.clone());
+        drop(holds);
+        self.notify();
+        Ok(hold)
+    }
+
+    /// Find a hold which has not expired.
+    pub fn get(&self, id: &str, now: chrono::DateTime<chrono::Utc>) -> SchedulerResult<Hold> {
+        self.holds
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|hold| !hold.is_expired(now))
+            .cloned()
+            .ok_or_else(|| SchedulerError::HoldNotFound(id.to_string()))
+    }
+
+    /// The holds which have not expired.
+    pub fn active(&self, now: chrono::DateTime<chrono::Utc>) -> Vec<Hold> {
+        self.holds
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|hold| !hold.is_expired(now))
+            .cloned()
+            .collect()
+    }
+
+    pub fn release(&self, id: &str) {
+        let released = self.holds.lock().unwrap().remove(id);
+        if released.is_some() {
+            self.notify();
+        }
+    }
+
+    /// Release every hold that has expired.
+    /// Returns the number of holds released.
+    pub fn release_expired(&self, now: chrono::DateTime<chrono::Utc>) -> usize {
+        let released = {
+            let mut holds = self.holds.lock().unwrap();
+            let before = holds.len();
+            holds.retain(|_, hold| !hold.is_expired(now));
+            before - holds.len()
+        };
+        if released > 0 {
+            self.notify();
+        }
+        released
+    }
+
+    /// The time ranges of the holds which have not expired, other than the given one.
+    pub fn busy(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        except: Option<&str>,
+    ) -> Vec<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)> {
+        self.holds
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|hold| !hold.is_expired(now))
+            .filter(|hold| except != Some(hold.id.as_str()))
+            .map(|hold| (hold.start, hold.end))
+            .collect()
+    }
+
+    /// Release expired holds forever, checking at the given interval.
+    pub async fn run(self, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let released = self.release_expired(chrono::Utc::now());
+            if released > 0 {
+                tracing::debug!("released {} expired holds", released);
+            }
+        }
+    }
+}
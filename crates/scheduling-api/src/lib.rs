@@ -1,63 +1,248 @@
-use axum::{extract::State, http::StatusCode, Json};
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    Json,
+};
 use caldav_utils::{
-    availability::{get_availability, AvailabilityRequest, AvailabilityResponse},
-    caldav::calendar::Calendar,
+    availability::{
+        get_availability_from, ranges_matrix, subtract_matrix, AvailabilityResponse,
+        AvailabilitySource,
+    },
+    caldav::event::{Attendee, Event, NewEvent, PartStat},
+    error::CaldavError,
+    format::DATETIME,
+    source::{CalendarSource, CalendarStore, MemoryCalendar},
 };
+use icalendar::Component;
 use tracing::info;
 
+use crate::{blackout::blackout_matrix, group::group_slots};
+
+pub mod approval;
+pub mod blackout;
+pub mod booking;
+pub mod discovery;
 pub mod error;
+pub mod group;
+pub mod hold;
+pub mod live;
+pub mod meeting_type;
+pub mod notify;
+pub mod precompute;
+pub mod recurrence;
+pub mod reminder;
 pub mod state;
+pub mod webhook;
+
+#[cfg(test)]
+mod tests;
 
 pub use crate::{
+    booking::{Booking, BookingStatus},
     error::{SchedulerError, SchedulerResult},
+    hold::Hold,
+    meeting_type::{Answer, FieldError, MeetingType},
+    notify::NotificationKind,
+    recurrence::Recurrence,
     state::CaldavAvailability,
+    webhook::LifecycleEvent,
 };
 
+/// The length of the slots availability is divided into.
+pub(crate) const SLOT_MINUTES: i64 = 30;
+
 pub async fn get_calendars(
-    client: &reqwest::Client,
     caldav_state: CaldavAvailability,
-) -> SchedulerResult<(Calendar, Calendar)> {
-    let mut principal = caldav_state.davclient.get_principal(client).await?;
-
+) -> SchedulerResult<(Option<Box<dyn CalendarStore>>, Box<dyn CalendarStore>)> {
+    let availability_calendar = match &caldav_state.availability_calendar {
+        Some(name) => Some(caldav_state.calendar(name).await?),
+        None => None,
+    };
     Ok((
-        principal
-            .get_calendar(client, &caldav_state.availability_calendar)
-            .await?,
-        principal
-            .get_calendar(client, &caldav_state.booked_calendar)
-            .await?,
+        availability_calendar,
+        caldav_state.calendar(&caldav_state.booked_calendar).await?,
     ))
 }
 
+/// Determine availability from the availability calendar and working hours,
+/// ignoring the booked events with the given UIDs.
+/// Events in the busy feeds make slots unavailable in the same way as booked events.
+/// Blackouts take priority over everything else, so slots they cover are never available.
+pub async fn available_slots(
+    caldav_state: &CaldavAvailability,
+    (availability_calendar, booked_calendar): (Option<&dyn CalendarStore>, &dyn CalendarStore),
+    range: (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+    excluded: &[&str],
+) -> SchedulerResult<AvailabilityResponse> {
+    let availability_calendar = availability_calendar.map(|c| c as &dyn CalendarSource);
+    available_from(
+        caldav_state,
+        (availability_calendar, booked_calendar),
+        range,
+        excluded,
+    )
+    .await
+}
+
+/// Determine availability in the same way as [`available_slots`], from calendars
+/// which may only be read, such as a snapshot of them.
+async fn available_from(
+    caldav_state: &CaldavAvailability,
+    (availability_calendar, booked_calendar): (Option<&dyn CalendarSource>, &dyn CalendarSource),
+    (start, end): (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+    excluded: &[&str],
+) -> SchedulerResult<AvailabilityResponse> {
+    let granularity = chrono::Duration::minutes(SLOT_MINUTES);
+
+    let sources: Vec<AvailabilitySource> = availability_calendar
+        .map(|calendar| {
+            if caldav_state.availability_components {
+                AvailabilitySource::VAvailability(calendar)
+            } else {
+                AvailabilitySource::Calendar(calendar)
+            }
+        })
+        .into_iter()
+        .chain(
+            caldav_state
+                .working_hours()
+                .map(AvailabilitySource::WorkingHours),
+        )
+        .collect();
+    let mut avail =
+        get_availability_from(&sources, booked_calendar, start, end, granularity, excluded).await?;
+    for feed in caldav_state.busy_feeds() {
+        // a feed which has never been read can't mark anything as busy,
+        // but shouldn't stop the other calendars from being used
+        match feed.busy_matrix(start, end, granularity).await {
+            Ok(busy) => avail.matrix = subtract_matrix(&avail.matrix, &busy),
+            Err(e) => tracing::warn!("skipping busy feed {:?}: {}", feed.location(), e),
+        }
+    }
+    let blackouts = blackout_matrix(caldav_state, start, end, granularity).await?;
+    avail.matrix = subtract_matrix(&avail.matrix, &blackouts);
+    Ok(avail)
+}
+
+/// Mark the slots reserved by holds as unavailable, other than the given hold.
+fn subtract_holds(
+    caldav_state: &CaldavAvailability,
+    avail: &mut AvailabilityResponse,
+    except: Option<&str>,
+) -> SchedulerResult<()> {
+    if let Some(holds) = &caldav_state.holds {
+        let busy = ranges_matrix(
+            avail.start,
+            avail.end,
+            avail.granularity,
+            &holds.busy(chrono::Utc::now(), except),
+        )?;
+        avail.matrix = subtract_matrix(&avail.matrix, &busy);
+    }
+    Ok(())
+}
+
+/// A request for the availability of a time range.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct SlotRequest {
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+    /// The meeting type to check availability for.
+    /// Group meeting types also report the seats left in each slot.
+    #[serde(default)]
+    pub meeting_type: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct SlotResponse {
+    #[serde(flatten)]
+    pub availability: AvailabilityResponse,
+    /// The seats left in each slot, for group meeting types
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seats: Option<Vec<u32>>,
+}
+
 #[axum::debug_handler]
 pub async fn request_availability(
     State(caldav_state): State<CaldavAvailability>,
-    body: Json<AvailabilityRequest>,
-) -> SchedulerResult<Json<AvailabilityResponse>> {
+    body: Json<SlotRequest>,
+) -> SchedulerResult<Json<SlotResponse>> {
+    Ok(Json(availability_for(&caldav_state, &body).await?))
+}
+
+/// Find the availability for a request, less any holds.
+pub(crate) async fn availability_for(
+    caldav_state: &CaldavAvailability,
+    body: &SlotRequest,
+) -> SchedulerResult<SlotResponse> {
     // TODO: validate the request. e.g. start < end, max range, etc.
 
+    // Availability which has been computed in the background is used when it covers the request
+    let group = group_meeting_type(caldav_state, body.meeting_type.as_deref())?;
+    let cached = caldav_state
+        .availability_cache()
+        .filter(|_| group.is_none())
+        .and_then(|cache| cache.get(body.start, body.end, chrono::Utc::now()));
+    if let Some(mut avail) = cached {
+        subtract_holds(caldav_state, &mut avail, None)?;
+        return Ok(SlotResponse {
+            availability: avail,
+            seats: None,
+        });
+    }
+
     // First, lookup events in the availability calendar
-    let client = reqwest::Client::new();
-    let (availability_calendar, booked_calendar) = get_calendars(&client, caldav_state).await?;
+    let (availability_calendar, booked_calendar) = get_calendars(caldav_state.clone()).await?;
     info!(
-        "Found calendars: {}, {}",
-        availability_calendar.path, booked_calendar.path
+        "Found calendars: {:?}, {}",
+        availability_calendar
+            .as_ref()
+            .map(|calendar| calendar.path()),
+        booked_calendar.path()
     );
 
-    let granularity = chrono::Duration::minutes(30);
+    if let Some(group) = group {
+        let slots = group_slots(
+            caldav_state,
+            (availability_calendar.as_deref(), booked_calendar.as_ref()),
+            group,
+            (body.start, body.end),
+            None,
+        )
+        .await?;
+        return Ok(SlotResponse {
+            availability: slots.availability,
+            seats: Some(slots.seats),
+        });
+    }
 
-    let avail = get_availability(
-        &client,
-        &availability_calendar,
-        &booked_calendar,
-        body.start,
-        body.end,
-        granularity,
+    let mut avail = available_slots(
+        caldav_state,
+        (availability_calendar.as_deref(), booked_calendar.as_ref()),
+        (body.start, body.end),
+        &[],
     )
     .await?;
+    subtract_holds(caldav_state, &mut avail, None)?;
 
     // Err(StatusCode::NOT_IMPLEMENTED)
-    Ok(Json(avail))
+    Ok(SlotResponse {
+        availability: avail,
+        seats: None,
+    })
+}
+
+/// Look up a meeting type, returning it only if it is a group meeting type.
+fn group_meeting_type<'a>(
+    caldav_state: &'a CaldavAvailability,
+    id: Option<&str>,
+) -> SchedulerResult<Option<&'a MeetingType>> {
+    Ok(match id {
+        Some(id) => Some(caldav_state.meeting_type(id)?).filter(|m| m.capacity.is_some()),
+        None => None,
+    })
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -67,45 +252,746 @@ pub struct BookingRequest {
     pub name: String,
     pub email: String,
     pub description: String,
+    /// The id of the meeting type being booked
+    #[serde(default)]
+    pub meeting_type: Option<String>,
+    /// Answers to the meeting type's questions, keyed by question id
+    #[serde(default)]
+    pub answers: BTreeMap<String, Answer>,
+    /// The id of the hold reserving the slot, required when holds are enabled
+    #[serde(default)]
+    pub hold: Option<String>,
+    /// Book a series of meetings starting with this one
+    #[serde(default)]
+    pub recurrence: Option<Recurrence>,
+}
+
+/// Check the recurrence of a booking request.
+/// Group meeting types share events between attendees, so they cannot be booked as a series.
+fn validate_recurrence(body: &BookingRequest, group: Option<&MeetingType>) -> SchedulerResult<()> {
+    let recurrence = match &body.recurrence {
+        Some(recurrence) => recurrence,
+        None => return Ok(()),
+    };
+    recurrence
+        .validate()
+        .map_err(SchedulerError::InvalidFields)?;
+    if group.is_some() {
+        return Err(SchedulerError::InvalidFields(vec![FieldError {
+            field: "recurrence".to_string(),
+            message: "group meeting types cannot be booked as a series".to_string(),
+        }]));
+    }
+    Ok(())
+}
+
+/// Check the attendee's email and the answers in a booking request against its meeting
+/// type's questions, so that a booking is never created with details that can't be used.
+/// Answers cannot be given without a meeting type since there are no questions to answer.
+fn validate_fields(
+    caldav_state: &CaldavAvailability,
+    body: &BookingRequest,
+) -> SchedulerResult<Option<MeetingType>> {
+    let mut errors = Vec::new();
+    if body.email.parse::<lettre::Address>().is_err() {
+        errors.push(FieldError {
+            field: "email".to_string(),
+            message: "must be a valid email address".to_string(),
+        });
+    }
+    let meeting_type = match &body.meeting_type {
+        Some(id) => Some(caldav_state.meeting_type(id)?.clone()),
+        None if body.answers.is_empty() => None,
+        None => Some(MeetingType::default()),
+    };
+    if let Some(meeting_type) = &meeting_type {
+        if let Err(invalid) = meeting_type.validate(&body.answers) {
+            errors.extend(invalid);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(SchedulerError::InvalidFields(errors));
+    }
+    Ok(meeting_type)
+}
+
+/// Check the hold presented with a booking request, if holds are enabled.
+/// Group meeting types must be booked with a seat hold for the same meeting type.
+fn check_hold(
+    caldav_state: &CaldavAvailability,
+    body: &BookingRequest,
+    group: Option<&MeetingType>,
+) -> SchedulerResult<Option<Hold>> {
+    let holds = match &caldav_state.holds {
+        Some(holds) => holds,
+        None => return Ok(None),
+    };
+
+    let id = body.hold.as_deref().ok_or(SchedulerError::HoldRequired)?;
+    let hold = holds.get(id, chrono::Utc::now())?;
+    if !hold.covers(body.start, body.end)
+        || hold.meeting_type.as_deref() != group.map(|m| m.id.as_str())
+    {
+        return Err(SchedulerError::HoldMismatch(hold.id));
+    }
+    Ok(Some(hold))
 }
+
+/// The booking's description followed by each question and its answer,
+/// so that the answers are visible in calendar clients.
+fn describe_answers(
+    description: &str,
+    meeting_type: &MeetingType,
+    answers: &BTreeMap<String, Answer>,
+) -> String {
+    let mut lines = vec![description.to_string()];
+    for question in &meeting_type.questions {
+        if let Some(answer) = answers.get(&question.id) {
+            lines.push(format!(
+                "{}: {}",
+                question.label,
+                answer.values().join(", ")
+            ));
+        }
+    }
+    lines.join("\n")
+}
+
+/// The details shared by every event created for a booking.
+fn booking_event(
+    caldav_state: &CaldavAvailability,
+    body: &BookingRequest,
+    summary: &str,
+) -> NewEvent {
+    let mut event = NewEvent::new(body.start, body.end, summary).property(
+        "X-BOOKING-CREATED",
+        &format!("{}", chrono::Utc::now().format(DATETIME)),
+    );
+    if let Some(organizer) = &caldav_state.organizer {
+        event = event.organizer(organizer.clone());
+    }
+    if let Some(location) = &caldav_state.location {
+        event = event.location(location);
+    }
+    if let Some(url) = &caldav_state.url {
+        event = event.url(url);
+    }
+    event
+}
+
+fn booking_attendee(body: &BookingRequest) -> Attendee {
+    Attendee::new(body.email.clone(), Some(body.name.clone()))
+        .partstat(PartStat::Accepted)
+        .rsvp(false)
+}
+
 /// Attempt to reserve a time slot in the booked calendar
 /// This will fail if the slot is not available
 pub async fn request_booking(
     State(caldav_state): State<CaldavAvailability>,
     body: Json<BookingRequest>,
-) -> SchedulerResult<StatusCode> {
-    let client = reqwest::Client::new();
-    let (availability_calendar, booked_calendar) = get_calendars(&client, caldav_state).await?;
-
-    let granularity = chrono::Duration::minutes(30);
-
-    // First, retrieve the availability and check if the slot is available
-    let avail = get_availability(
-        &client,
-        &availability_calendar,
-        &booked_calendar,
-        body.start,
-        body.end,
-        granularity,
+) -> SchedulerResult<Json<Booking>> {
+    // reject invalid fields before touching the calendar
+    let meeting_type = validate_fields(&caldav_state, &body)?;
+    let group = meeting_type.as_ref().filter(|m| m.capacity.is_some());
+    validate_recurrence(&body, group)?;
+    let hold = check_hold(&caldav_state, &body, group)?;
+    let hold_id = hold.as_ref().map(|h| h.id.as_str());
+
+    let (availability_calendar, booked_calendar) = get_calendars(caldav_state.clone()).await?;
+
+    // the attendee needs this token to cancel or reschedule their booking
+    let token = ksuid::Ksuid::generate().to_base62();
+    let booking = match group {
+        Some(group) => {
+            let event = book_seat(
+                &caldav_state,
+                (availability_calendar.as_deref(), booked_calendar.as_ref()),
+                group,
+                &body,
+                (hold_id, &token),
+            )
+            .await?;
+            Booking::for_attendee(&event, &body.email)
+        }
+        None => {
+            let event = book_slot(
+                &caldav_state,
+                (availability_calendar.as_deref(), booked_calendar.as_ref()),
+                meeting_type.as_ref(),
+                &body,
+                (hold_id, &token),
+            )
+            .await?;
+            Booking::from_event(&event)
+        }
+    }
+    .ok_or_else(|| SchedulerError::BookingNotFound(body.email.clone()))?;
+    invalidate_availability(&caldav_state);
+
+    if let (Some(holds), Some(hold)) = (&caldav_state.holds, hold_id) {
+        holds.release(hold);
+    }
+
+    let kind = match booking.status {
+        BookingStatus::Tentative => NotificationKind::Requested,
+        BookingStatus::Confirmed => NotificationKind::Confirmation,
+    };
+    notify(&caldav_state, kind, &booking).await;
+    publish(&caldav_state, LifecycleEvent::Booked, &booking);
+
+    // the token is only given to the attendee, never to notifications or webhooks
+    Ok(Json(Booking {
+        cancel_token: Some(token),
+        ..booking
+    }))
+}
+
+/// Create an event for a booking with a single attendee.
+/// A hash of the token is stored with the booking, so the attendee can cancel or move it later.
+async fn book_slot(
+    caldav_state: &CaldavAvailability,
+    (availability_calendar, booked_calendar): (Option<&dyn CalendarStore>, &dyn CalendarStore),
+    meeting_type: Option<&MeetingType>,
+    body: &BookingRequest,
+    (hold, token): (Option<&str>, &str),
+) -> SchedulerResult<Event> {
+    let timezone = caldav_state.timezone();
+    let occurrences = match &body.recurrence {
+        Some(recurrence) => recurrence.occurrences(body.start, body.end, timezone),
+        None => vec![(body.start, body.end)],
+    };
+
+    // The calendars are read once for the whole series,
+    // rather than for every occurrence
+    let (first, last) = match (occurrences.first(), occurrences.last()) {
+        (Some((first, _)), Some((_, last))) => (*first, *last),
+        _ => return Err(SchedulerError::TimeNotAvailable(body.start)),
+    };
+    let available_snapshot = match availability_calendar {
+        Some(calendar) => Some(MemoryCalendar::snapshot(calendar, first, last).await?),
+        None => None,
+    };
+    let booked_snapshot = MemoryCalendar::snapshot(booked_calendar, first, last).await?;
+
+    // First, retrieve the availability and check if each occurrence is available
+    let mut conflicts = Vec::new();
+    for (start, end) in &occurrences {
+        let mut avail = available_from(
+            caldav_state,
+            (
+                available_snapshot
+                    .as_ref()
+                    .map(|c| c as &dyn CalendarSource),
+                &booked_snapshot,
+            ),
+            (*start, *end),
+            &[],
+        )
+        .await?;
+        // the attendee's own hold should not prevent them from booking
+        subtract_holds(caldav_state, &mut avail, hold)?;
+
+        /*
+         * Check if the requested time is available
+         */
+        let is_available = avail.matrix.iter().all(|it| *it);
+        if !is_available {
+            conflicts.push(*start);
+        }
+    }
+    match &body.recurrence {
+        _ if conflicts.len() == occurrences.len() => {
+            return Err(SchedulerError::TimeNotAvailable(body.start))
+        }
+        Some(recurrence) if !conflicts.is_empty() && !recurrence.skip_conflicts => {
+            return Err(SchedulerError::SeriesConflicts(conflicts))
+        }
+        _ => {}
+    }
+
+    let status = match meeting_type {
+        Some(meeting_type) if meeting_type.requires_approval => BookingStatus::Tentative,
+        _ => BookingStatus::Confirmed,
+    };
+    let description = match meeting_type {
+        Some(meeting_type) => describe_answers(&body.description, meeting_type, &body.answers),
+        None => body.description.clone(),
+    };
+
+    let mut event = booking_event(caldav_state, body, &body.name)
+        .description(&description)
+        .attendee(booking_attendee(body))
+        .status(status.to_ical())
+        .property("X-BOOKING-NAME", &body.name)
+        .property("X-BOOKING-EMAIL", &body.email)
+        .append_property(booking::seat_token_property(&body.email, token));
+    if let Some(meeting_type) = meeting_type {
+        event = event.property("X-BOOKING-MEETING-TYPE", &meeting_type.id);
+    }
+    for property in booking::answer_properties(&body.answers, None) {
+        event = event.append_property(property);
+    }
+    if let Some(recurrence) = &body.recurrence {
+        // the series keeps its local time in the host's timezone
+        event = event
+            .timezone(timezone)
+            .property("RRULE", &recurrence.to_rrule());
+        for start in conflicts {
+            event = event.append_property(recurrence::exdate_property(start));
+        }
+    }
+
+    // Create an event in the booking calendar
+    Ok(booked_calendar.create_event(&event).await?)
+}
+
+/// Take a seat in a group meeting type's slot, adding the attendee to the event
+/// for the slot or creating it if they are the first.
+/// A hash of the token is stored with the seat, so the attendee can cancel it later.
+async fn book_seat(
+    caldav_state: &CaldavAvailability,
+    calendars: (Option<&dyn CalendarStore>, &dyn CalendarStore),
+    meeting_type: &MeetingType,
+    body: &BookingRequest,
+    (hold, token): (Option<&str>, &str),
+) -> SchedulerResult<Event> {
+    let slots = group_slots(
+        caldav_state,
+        calendars,
+        meeting_type,
+        (body.start, body.end),
+        hold,
     )
     .await?;
+    if !slots.availability.matrix.iter().all(|it| *it) {
+        return Err(SchedulerError::TimeNotAvailable(body.start));
+    }
 
-    /*
-     * Check if the requested time is available
-     */
-    let is_available = avail.matrix.iter().all(|it| *it);
-    if !is_available {
+    let (_, booked_calendar) = calendars;
+    let (same_slot, overlapping): (Vec<Event>, Vec<Event>) = slots
+        .events
+        .into_iter()
+        .partition(|event| event.start() == Some(body.start) && event.end() == Some(body.end));
+    // seats are per slot, so a booking cannot span several group events
+    if !overlapping.is_empty() {
         return Err(SchedulerError::TimeNotAvailable(body.start));
     }
 
-    let description = format!("email: {}\n{}", body.email, body.description);
+    match same_slot.into_iter().next() {
+        Some(event) => {
+            let uid = event
+                .uid()
+                .ok_or_else(|| SchedulerError::TimeNotAvailable(body.start))?;
+            // other attendees may be taking seats at the same time,
+            // so the seats are counted again on the latest version of the event
+            let update = update_seats(booked_calendar, uid, |event| {
+                if event.has_attendee(&body.email) {
+                    return Err(SchedulerError::AlreadyAttending(body.email.clone()));
+                }
+                let seats = booking::capacity(event).or(meeting_type.capacity);
+                if seats.is_some_and(|seats| event.attendees().len() >= seats as usize) {
+                    return Err(SchedulerError::TimeNotAvailable(body.start));
+                }
+                event.add_attendee(&booking_attendee(body));
+                if let Some(vevent) = event.vevent_mut() {
+                    for property in booking::answer_properties(&body.answers, Some(&body.email)) {
+                        vevent.append_multi_property(property);
+                    }
+                    vevent.append_multi_property(booking::seat_token_property(&body.email, token));
+                }
+                Ok(())
+            });
+            update.await
+        }
+        None => {
+            let mut event = booking_event(caldav_state, body, &meeting_type.name)
+                .attendee(booking_attendee(body))
+                .status(BookingStatus::Confirmed.to_ical())
+                .property("X-BOOKING-MEETING-TYPE", &meeting_type.id)
+                .property(
+                    booking::CAPACITY_PROPERTY,
+                    &meeting_type.capacity.unwrap_or(1).to_string(),
+                );
+            for property in booking::answer_properties(&body.answers, Some(&body.email)) {
+                event = event.append_property(property);
+            }
+            event = event.append_property(booking::seat_token_property(&body.email, token));
+            Ok(booked_calendar.create_event(&event).await?)
+        }
+    }
+}
 
-    // Create an event in the booking calendar
-    booked_calendar
-        .create_event(&client, body.start, body.end, &body.name, &description)
+/// How many times to retry changing the seats of a group event
+/// when others change them at the same time.
+const SEAT_UPDATE_ATTEMPTS: usize = 5;
+
+/// Change the seats of a group event, reading it again and retrying
+/// if it was changed by someone else in the meantime.
+/// The event is deleted instead of updated if nobody is left attending.
+async fn update_seats(
+    booked_calendar: &dyn CalendarStore,
+    uid: &str,
+    mut change: impl FnMut(&mut Event) -> SchedulerResult<()>,
+) -> SchedulerResult<Event> {
+    for _ in 0..SEAT_UPDATE_ATTEMPTS {
+        let (mut event, etag) = match booked_calendar.get_event_with_etag(uid).await {
+            Ok(found) => found,
+            Err(CaldavError::EventNotFound { .. }) => {
+                return Err(SchedulerError::BookingNotFound(uid.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        change(&mut event)?;
+        let result = if event.attendees().is_empty() {
+            booked_calendar
+                .delete_event_if_match(uid, etag.as_deref())
+                .await
+        } else {
+            booked_calendar
+                .update_event_if_match(&event, etag.as_deref())
+                .await
+        };
+        match result {
+            Ok(()) => return Ok(event),
+            Err(CaldavError::EventChanged { .. }) => {
+                tracing::debug!("group event {} changed while updating seats, retrying", uid);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(CaldavError::EventChanged {
+        uid: uid.to_string(),
+    }
+    .into())
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct HoldRequest {
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+    /// The group meeting type to hold a seat in
+    #[serde(default)]
+    pub meeting_type: Option<String>,
+    /// The email of the attendee, which limits how many holds they can place
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+/// Reserve a time slot for a short time so that it cannot be taken
+/// while the attendee fills out the booking form
+/// This will fail if the slot is not available, or if the client or email
+/// already holds as many slots as they may.
+/// The client's address is only known when the server is served with connect info.
+pub async fn request_hold(
+    State(caldav_state): State<CaldavAvailability>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    body: Json<HoldRequest>,
+) -> SchedulerResult<Json<Hold>> {
+    let holds = caldav_state
+        .holds
+        .as_ref()
+        .ok_or(SchedulerError::HoldsDisabled)?;
+    if body.end <= body.start {
+        return Err(SchedulerError::InvalidTimeRange(body.start, body.end));
+    }
+    let owners: Vec<String> = connect_info
+        .map(|ConnectInfo(addr)| format!("ip:{}", addr.ip()))
+        .into_iter()
+        .chain(
+            body.email
+                .as_ref()
+                .map(|email| format!("email:{}", email.to_lowercase())),
+        )
+        .collect();
+
+    let (availability_calendar, booked_calendar) = get_calendars(caldav_state.clone()).await?;
+
+    // other holds are checked while placing so that concurrent requests cannot both succeed
+    if let Some(group) = group_meeting_type(&caldav_state, body.meeting_type.as_deref())? {
+        let slots = group_slots(
+            &caldav_state,
+            (availability_calendar.as_deref(), booked_calendar.as_ref()),
+            group,
+            (body.start, body.end),
+            None,
+        )
         .await?;
+        let seats = slots.booked_seats.iter().copied().min().unwrap_or(0);
+        let hold = holds.place_seat(
+            body.start,
+            body.end,
+            chrono::Utc::now(),
+            &group.id,
+            seats,
+            &owners,
+        )?;
+        return Ok(Json(hold));
+    }
+
+    let avail = available_slots(
+        &caldav_state,
+        (availability_calendar.as_deref(), booked_calendar.as_ref()),
+        (body.start, body.end),
+        &[],
+    )
+    .await?;
+    if !avail.matrix.iter().all(|it| *it) {
+        return Err(SchedulerError::TimeNotAvailable(body.start));
+    }
+
+    let hold = holds.place(body.start, body.end, chrono::Utc::now(), &owners)?;
+    Ok(Json(hold))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ApprovalRequest {
+    pub id: String,
+}
+
+/// Look up a booking which is awaiting approval, checking that the request came from the host.
+async fn find_tentative_booking(
+    caldav_state: &CaldavAvailability,
+    headers: &HeaderMap,
+    booked_calendar: &dyn CalendarStore,
+    id: &str,
+) -> SchedulerResult<(Event, Booking)> {
+    caldav_state.authorize_host(
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok()),
+    )?;
+
+    let (event, booking) = find_booking(booked_calendar, id).await?;
+    if booking.status != BookingStatus::Tentative {
+        return Err(SchedulerError::NotTentative(booking.id));
+    }
+    Ok((event, booking))
+}
+
+/// Approve a tentative booking, confirming it
+/// Only the host may approve bookings
+pub async fn request_approve(
+    State(caldav_state): State<CaldavAvailability>,
+    headers: HeaderMap,
+    body: Json<ApprovalRequest>,
+) -> SchedulerResult<Json<Booking>> {
+    let (_, booked_calendar) = get_calendars(caldav_state.clone()).await?;
+
+    let (mut event, booking) =
+        find_tentative_booking(&caldav_state, &headers, booked_calendar.as_ref(), &body.id).await?;
+    event.set_status(icalendar::EventStatus::Confirmed);
+    booked_calendar.update_event(&event).await?;
+    invalidate_availability(&caldav_state);
+    let booking =
+        Booking::from_event(&event).ok_or_else(|| SchedulerError::BookingNotFound(booking.id))?;
+
+    notify(&caldav_state, NotificationKind::Confirmation, &booking).await;
+    publish(&caldav_state, LifecycleEvent::Approved, &booking);
+
+    Ok(Json(booking))
+}
+
+/// Decline a tentative booking, removing it from the booked calendar
+/// Only the host may decline bookings
+pub async fn request_decline(
+    State(caldav_state): State<CaldavAvailability>,
+    headers: HeaderMap,
+    body: Json<ApprovalRequest>,
+) -> SchedulerResult<Json<Booking>> {
+    let (_, booked_calendar) = get_calendars(caldav_state.clone()).await?;
+
+    let (_, mut booking) =
+        find_tentative_booking(&caldav_state, &headers, booked_calendar.as_ref(), &body.id).await?;
+    booked_calendar.delete_event(&booking.id).await?;
+    invalidate_availability(&caldav_state);
+    booking.sequence += 1;
+
+    notify(&caldav_state, NotificationKind::Declined, &booking).await;
+    publish(&caldav_state, LifecycleEvent::Declined, &booking);
+
+    Ok(Json(booking))
+}
+
+/// Send a notification for a booking, if a notifier is configured.
+/// Failing to notify does not fail the request since the booking has already been changed.
+pub(crate) async fn notify(
+    caldav_state: &CaldavAvailability,
+    kind: NotificationKind,
+    booking: &Booking,
+) {
+    if let Some(notifier) = &caldav_state.notifier {
+        if let Err(e) = notifier.notify(kind, booking).await {
+            tracing::warn!("failed to send {} for {}: {}", kind.as_str(), booking.id, e);
+        }
+    }
+}
+
+/// Stop answering requests from precomputed availability, which no longer reflects the
+/// booked calendar. Called straight after writing to it, before anyone is notified.
+pub(crate) fn invalidate_availability(caldav_state: &CaldavAvailability) {
+    if let Some(cache) = &caldav_state.availability_cache {
+        cache.invalidate();
+    }
+}
+
+/// Queue webhook deliveries for a booking, if webhooks are configured.
+/// Deliveries are sent in the background so that slow subscribers do not delay the request.
+/// Clients streaming availability are told it has changed, by the availability cache once it
+/// has been computed again (or failed to be) if there is one, so that they don't all read the
+/// calendars first.
+pub(crate) fn publish(caldav_state: &CaldavAvailability, event: LifecycleEvent, booking: &Booking) {
+    if caldav_state.availability_cache.is_none() {
+        caldav_state.changes.notify();
+    }
+    if let Some(webhooks) = &caldav_state.webhooks {
+        if let Err(e) = webhooks.enqueue(event, booking) {
+            tracing::warn!("failed to queue webhook for {}: {}", booking.id, e);
+        }
+    }
+}
+
+/// Look up a booking by its id.
+async fn find_booking(
+    booked_calendar: &dyn CalendarStore,
+    id: &str,
+) -> SchedulerResult<(Event, Booking)> {
+    let event = match booked_calendar.get_event(id).await {
+        Ok(event) => event,
+        Err(CaldavError::EventNotFound { .. }) => {
+            return Err(SchedulerError::BookingNotFound(id.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let booking = Booking::from_event(&event)
+        .ok_or_else(|| SchedulerError::BookingNotFound(id.to_string()))?;
+    Ok((event, booking))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CancelRequest {
+    pub id: String,
+    /// The attendee giving up their seat, required for group bookings
+    #[serde(default)]
+    pub email: Option<String>,
+    /// The cancel token the attendee was given when booking
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Check that the token is the one given to the attendee when they booked.
+fn authorize_attendee(event: &Event, attendee: &str, token: Option<&str>) -> SchedulerResult<()> {
+    match token {
+        Some(token) if booking::seat_token_matches(event, attendee, token) => Ok(()),
+        _ => Err(SchedulerError::Unauthorized),
+    }
+}
+
+/// Cancel a booking, removing it from the booked calendar
+pub async fn request_cancel(
+    State(caldav_state): State<CaldavAvailability>,
+    body: Json<CancelRequest>,
+) -> SchedulerResult<Json<Booking>> {
+    let (_, booked_calendar) = get_calendars(caldav_state.clone()).await?;
+
+    let (event, booking) = find_booking(booked_calendar.as_ref(), &body.id).await?;
+    let mut booking = match booking.capacity {
+        // other attendees keep their seats, so only this attendee is removed
+        Some(_) => {
+            let email = body
+                .email
+                .as_deref()
+                .ok_or(SchedulerError::AttendeeRequired)?;
+            let token = body.token.as_deref().ok_or(SchedulerError::Unauthorized)?;
+            let mut cancelled = None;
+            update_seats(booked_calendar.as_ref(), &booking.id, |event| {
+                if !booking::seat_token_matches(event, email, token) {
+                    return Err(SchedulerError::Unauthorized);
+                }
+                let booking = Booking::for_attendee(event, email)
+                    .ok_or_else(|| SchedulerError::BookingNotFound(body.id.clone()))?;
+                event.remove_attendee(email);
+                event.retain_properties(|p| {
+                    !booking::is_answer_from(p, Some(email)) && !booking::is_seat_token_of(p, email)
+                });
+                cancelled = Some(booking);
+                Ok(())
+            })
+            .await?;
+            cancelled.ok_or_else(|| SchedulerError::BookingNotFound(body.id.clone()))?
+        }
+        None => {
+            authorize_attendee(&event, &booking.email, body.token.as_deref())?;
+            booked_calendar.delete_event(&booking.id).await?;
+            booking
+        }
+    };
+    invalidate_availability(&caldav_state);
+    booking.sequence += 1;
+
+    notify(&caldav_state, NotificationKind::Cancellation, &booking).await;
+    publish(&caldav_state, LifecycleEvent::Cancelled, &booking);
+
+    Ok(Json(booking))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RescheduleRequest {
+    pub id: String,
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+    /// The cancel token the attendee was given when booking
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Move a booking to a different time slot
+/// This will fail if the new slot is not available
+pub async fn request_reschedule(
+    State(caldav_state): State<CaldavAvailability>,
+    body: Json<RescheduleRequest>,
+) -> SchedulerResult<Json<Booking>> {
+    let (availability_calendar, booked_calendar) = get_calendars(caldav_state.clone()).await?;
+
+    let (mut event, booking) = find_booking(booked_calendar.as_ref(), &body.id).await?;
+    authorize_attendee(&event, &booking.email, body.token.as_deref())?;
+    if booking.capacity.is_some() {
+        return Err(SchedulerError::GroupBooking(booking.id));
+    }
+    if booking.recurrence.is_some() {
+        return Err(SchedulerError::RecurringBooking(booking.id));
+    }
 
-    Ok(StatusCode::OK)
+    // the booking's current slot should not prevent moving it to an overlapping one
+    let mut avail = available_slots(
+        &caldav_state,
+        (availability_calendar.as_deref(), booked_calendar.as_ref()),
+        (body.start, body.end),
+        &[booking.id.as_str()],
+    )
+    .await?;
+    subtract_holds(&caldav_state, &mut avail, None)?;
+    if !avail.matrix.iter().all(|it| *it) {
+        return Err(SchedulerError::TimeNotAvailable(body.start));
+    }
+
+    event.reschedule(body.start, body.end);
+    booked_calendar.update_event(&event).await?;
+    invalidate_availability(&caldav_state);
+    let booking =
+        Booking::from_event(&event).ok_or_else(|| SchedulerError::BookingNotFound(booking.id))?;
+
+    notify(&caldav_state, NotificationKind::Reschedule, &booking).await;
+    publish(&caldav_state, LifecycleEvent::Rescheduled, &booking);
+
+    Ok(Json(booking))
+}
+
+/// Lists the meeting types that can be booked along with their questions
+pub async fn get_meeting_types(
+    State(caldav_state): State<CaldavAvailability>,
+) -> Json<Vec<MeetingType>> {
+    Json(caldav_state.meeting_types().to_vec())
 }
 
 /// gets the current time
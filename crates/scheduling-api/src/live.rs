@@ -0,0 +1,155 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{stream, Stream, StreamExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    availability_for, error::SchedulerResult, get_calendars, precompute::change_tokens,
+    state::CaldavAvailability, SlotRequest, SlotResponse,
+};
+
+/// Tells subscribers when availability may have changed, i.e. when a booking or hold is made
+/// or changed through the server, or the calendars, busy feeds or blackouts change.
+#[derive(Clone, Debug)]
+pub struct AvailabilityChanges {
+    sender: broadcast::Sender<()>,
+}
+
+impl Default for AvailabilityChanges {
+    fn default() -> Self {
+        // subscribers only need to know that something changed, so a lagging one loses nothing
+        let (sender, _) = broadcast::channel(16);
+        AvailabilityChanges { sender }
+    }
+}
+
+impl AvailabilityChanges {
+    pub fn notify(&self) {
+        // there may be no subscribers
+        let _ = self.sender.send(());
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<()> {
+        self.sender.subscribe()
+    }
+}
+
+/// Watches the change tokens of the calendars, busy feeds and blackouts, so that clients
+/// streaming availability are told about changes made outside the server, such as the host
+/// adding an event in their calendar app.
+#[derive(Clone, Debug)]
+pub struct ChangeWatcher {
+    caldav_state: CaldavAvailability,
+    tokens: Option<Vec<Option<String>>>,
+}
+
+impl ChangeWatcher {
+    pub fn new(caldav_state: CaldavAvailability) -> ChangeWatcher {
+        ChangeWatcher {
+            caldav_state,
+            tokens: None,
+        }
+    }
+
+    /// Check the change tokens once, returning whether they changed since the last check.
+    /// On a change the availability cache is invalidated, and tells clients once it has been
+    /// computed again. Without a cache, clients are told straight away.
+    pub async fn check(&mut self) -> SchedulerResult<bool> {
+        let (availability_calendar, booked_calendar) =
+            get_calendars(self.caldav_state.clone()).await?;
+        let tokens = change_tokens(
+            &self.caldav_state,
+            (availability_calendar.as_deref(), booked_calendar.as_ref()),
+        )
+        .await?;
+
+        let changed = self.tokens.as_ref().is_some_and(|last| *last != tokens);
+        self.tokens = Some(tokens);
+        if changed {
+            match self.caldav_state.availability_cache() {
+                Some(cache) => cache.invalidate(),
+                None => self.caldav_state.changes().notify(),
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Watch for changes forever, checking at the given interval.
+    pub async fn run(mut self, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match self.check().await {
+                Ok(true) => tracing::debug!("calendars changed, availability may have too"),
+                Ok(false) => {}
+                Err(e) => tracing::warn!("failed to check calendars for changes: {}", e),
+            }
+        }
+    }
+}
+
+/// The availability for a request, followed by the availability again each time it changes.
+/// The stream ends when the server shuts down.
+pub fn availability_updates(
+    caldav_state: CaldavAvailability,
+    request: SlotRequest,
+) -> impl Stream<Item = SchedulerResult<SlotResponse>> {
+    // subscribe before the first availability is found, so that no change is missed
+    let changes = caldav_state.changes().subscribe();
+    stream::unfold(
+        (changes, None::<String>, true),
+        move |(mut changes, mut last, mut first)| {
+            let caldav_state = caldav_state.clone();
+            let request = request.clone();
+            async move {
+                loop {
+                    if !first {
+                        match changes.recv().await {
+                            Ok(()) | Err(RecvError::Lagged(_)) => {}
+                            Err(RecvError::Closed) => return None,
+                        }
+                    }
+                    first = false;
+
+                    let response = match availability_for(&caldav_state, &request).await {
+                        Ok(response) => response,
+                        Err(e) => return Some((Err(e), (changes, last, first))),
+                    };
+                    // changes elsewhere in the calendars don't always affect the requested range
+                    let serialized = serde_json::to_string(&response).ok();
+                    if serialized != last {
+                        last = serialized;
+                        return Some((Ok(response), (changes, last, first)));
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Stream the availability of a time range as server-sent events.
+/// An `availability` event is sent straight away, and again whenever the availability changes.
+/// Failures to find the availability are sent as `error` events, and the stream carries on.
+pub async fn stream_availability(
+    State(caldav_state): State<CaldavAvailability>,
+    Query(request): Query<SlotRequest>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = availability_updates(caldav_state, request).map(|update| {
+        let event = match update {
+            Ok(response) => Event::default()
+                .event("availability")
+                .json_data(response)
+                .unwrap_or_else(|e| Event::default().event("error").data(e.to_string())),
+            Err(e) => {
+                tracing::warn!("failed to find availability to stream: {}", e);
+                Event::default().event("error").data(e.to_string())
+            }
+        };
+        Ok(event)
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
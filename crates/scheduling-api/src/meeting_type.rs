@@ -0,0 +1,231 @@
+use std::{collections::BTreeMap, path::Path, sync::OnceLock};
+
+use crate::error::{SchedulerError, SchedulerResult};
+
+/// A kind of meeting that can be booked, e.g. "30 minute intro call".
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct MeetingType {
+    pub id: String,
+    pub name: String,
+    /// Bookings are held tentatively until the host approves them.
+    /// Group meeting types cannot require approval, since attendees share one event.
+    #[serde(default)]
+    pub requires_approval: bool,
+    /// The number of attendees who may book each slot.
+    /// When set, attendees are added to a shared event for the slot instead of each
+    /// booking their own.
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    /// Additional questions the person booking must answer
+    #[serde(default)]
+    pub questions: Vec<Question>,
+}
+
+impl MeetingType {
+    /// Check the answers given for this meeting type's questions.
+    /// Returns an error for every question whose answer is missing or invalid,
+    /// and for every answer to a question that does not exist.
+    pub fn validate(&self, answers: &BTreeMap<String, Answer>) -> Result<(), Vec<FieldError>> {
+        let mut errors: Vec<FieldError> = self
+            .questions
+            .iter()
+            .filter_map(|question| {
+                question
+                    .validate(answers.get(&question.id))
+                    .err()
+                    .map(|message| FieldError {
+                        field: question.id.clone(),
+                        message,
+                    })
+            })
+            .collect();
+
+        errors.extend(
+            answers
+                .keys()
+                .filter(|id| !self.questions.iter().any(|q| &&q.id == id))
+                .map(|id| FieldError {
+                    field: id.clone(),
+                    message: "unknown question".to_string(),
+                }),
+        );
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn question(&self, id: &str) -> Option<&Question> {
+        self.questions.iter().find(|q| q.id == id)
+    }
+}
+
+/// Load meeting types from a JSON file containing a list of them.
+/// Their questions are checked, and their patterns compiled, as they are loaded.
+pub fn load_meeting_types(path: &Path) -> SchedulerResult<Vec<MeetingType>> {
+    let text = std::fs::read_to_string(path)?;
+    let meeting_types: Vec<MeetingType> = serde_json::from_str(&text)?;
+    if let Some(meeting_type) = meeting_types
+        .iter()
+        .find(|m| m.requires_approval && m.capacity.is_some())
+    {
+        return Err(SchedulerError::InvalidMeetingType(
+            meeting_type.id.clone(),
+            "group meeting types cannot require approval".to_string(),
+        ));
+    }
+    for question in meeting_types.iter().flat_map(|m| &m.questions) {
+        question
+            .check()
+            .map_err(|e| SchedulerError::InvalidQuestion(question.id.clone(), e))?;
+    }
+    Ok(meeting_types)
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum QuestionKind {
+    Text {
+        #[serde(default)]
+        max_length: Option<usize>,
+    },
+    Choice {
+        options: Vec<String>,
+        /// whether more than one option may be chosen
+        #[serde(default)]
+        multiple: bool,
+    },
+    Phone,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Question {
+    pub id: String,
+    pub label: String,
+    #[serde(flatten)]
+    pub kind: QuestionKind,
+    #[serde(default)]
+    pub required: bool,
+    /// A regular expression which text and phone answers must match
+    #[serde(default)]
+    pub pattern: Option<String>,
+    #[serde(skip)]
+    compiled: OnceLock<Result<regex::Regex, regex::Error>>,
+}
+
+impl Question {
+    /// Check that the question can be used, i.e. that its id can be stored on events
+    /// and its pattern is valid.
+    pub fn check(&self) -> Result<(), String> {
+        // icalendar can't read these back from a parameter, even quoted
+        if self.id.contains(['"', ':', ';']) || self.id.contains(char::is_control) {
+            return Err(
+                "ids may not contain quotes, colons, semicolons or control characters".to_string(),
+            );
+        }
+        match self.regex() {
+            Some(Err(e)) => Err(format!("the pattern is invalid: {e}")),
+            _ => Ok(()),
+        }
+    }
+
+    /// The compiled pattern, which is only compiled once.
+    fn regex(&self) -> Option<&Result<regex::Regex, regex::Error>> {
+        let pattern = self.pattern.as_ref()?;
+        Some(self.compiled.get_or_init(|| regex::Regex::new(pattern)))
+    }
+
+    pub fn validate(&self, answer: Option<&Answer>) -> Result<(), String> {
+        let values = match answer {
+            None => Vec::new(),
+            Some(answer) => answer.values(),
+        };
+        let values: Vec<&str> = values
+            .iter()
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if values.is_empty() {
+            return if self.required {
+                Err("an answer is required".to_string())
+            } else {
+                Ok(())
+            };
+        }
+
+        match &self.kind {
+            QuestionKind::Choice { options, multiple } => {
+                if !multiple && values.len() > 1 {
+                    return Err("only one option may be chosen".to_string());
+                }
+                if let Some(value) = values.iter().find(|v| !options.iter().any(|o| o == *v)) {
+                    return Err(format!("{value} is not one of the options"));
+                }
+            }
+            QuestionKind::Text { max_length } => {
+                let value = single(&values)?;
+                if let Some(max_length) = max_length {
+                    if value.chars().count() > *max_length {
+                        return Err(format!("must be at most {max_length} characters"));
+                    }
+                }
+                self.check_pattern(value)?;
+            }
+            QuestionKind::Phone => {
+                let value = single(&values)?;
+                let valid_chars = value
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || " +-().".contains(c));
+                let digits = value.chars().filter(|c| c.is_ascii_digit()).count();
+                if !valid_chars || !(7..=15).contains(&digits) {
+                    return Err("must be a valid phone number".to_string());
+                }
+                self.check_pattern(value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_pattern(&self, value: &str) -> Result<(), String> {
+        match self.regex() {
+            None => Ok(()),
+            Some(Err(e)) => Err(format!("the question's pattern is invalid: {e}")),
+            Some(Ok(regex)) if regex.is_match(value) => Ok(()),
+            Some(Ok(_)) => Err("does not match the required format".to_string()),
+        }
+    }
+}
+
+fn single<'a>(values: &[&'a str]) -> Result<&'a str, String> {
+    match values {
+        [value] => Ok(value),
+        _ => Err("only one answer may be given".to_string()),
+    }
+}
+
+/// An answer to a question, either a single value or several chosen options.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum Answer {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Answer {
+    pub fn values(&self) -> Vec<&str> {
+        match self {
+            Answer::One(value) => vec![value.as_str()],
+            Answer::Many(values) => values.iter().map(|v| v.as_str()).collect(),
+        }
+    }
+}
+
+/// A problem with a single field of a request.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use lettre::message::Mailbox;
+
+use crate::{booking::Booking, error::SchedulerResult};
+
+pub mod mailer;
+pub mod template;
+
+use mailer::{Email, Invite, Mailer};
+use template::Templates;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotificationKind {
+    Confirmation,
+    /// A booking is waiting for the host's approval
+    Requested,
+    /// The host declined a booking, or it was not approved in time
+    Declined,
+    Cancellation,
+    Reschedule,
+    Reminder,
+}
+
+impl NotificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::Confirmation => "confirmation",
+            NotificationKind::Requested => "requested",
+            NotificationKind::Declined => "declined",
+            NotificationKind::Cancellation => "cancellation",
+            NotificationKind::Reschedule => "reschedule",
+            NotificationKind::Reminder => "reminder",
+        }
+    }
+
+    /// The iTIP method used for the invite attached to this kind of notification.
+    /// Reminders do not change the meeting, so they have no invite.
+    pub fn method(&self) -> Option<&'static str> {
+        match self {
+            NotificationKind::Cancellation | NotificationKind::Declined => Some("CANCEL"),
+            NotificationKind::Reminder => None,
+            _ => Some("REQUEST"),
+        }
+    }
+}
+
+/// Sends emails to the host and attendee of a booking when it changes.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    mailer: Arc<dyn Mailer>,
+    from: String,
+    templates: Templates,
+}
+
+impl Notifier {
+    pub fn new(mailer: Arc<dyn Mailer>, from: String) -> Notifier {
+        Notifier {
+            mailer,
+            from,
+            templates: Templates::default(),
+        }
+    }
+
+    pub fn with_templates(mut self, templates: Templates) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    /// Build the emails for a notification, one for each recipient.
+    pub fn emails(&self, kind: NotificationKind, booking: &Booking) -> Vec<Email> {
+        let (subject, body) = self.templates.get(kind).render(booking);
+        let invite = kind.method().map(|method| Invite {
+            method: method.to_string(),
+            ics: template::invite(kind, booking),
+        });
+
+        // names may contain commas or quotes, so they are quoted by lettre rather than formatted in,
+        // and an attendee whose address can't be used doesn't stop the host being told
+        let mut recipients = Vec::new();
+        match booking.email.parse() {
+            Ok(email) => recipients.push(Mailbox::new(Some(booking.name.clone()), email)),
+            Err(e) => tracing::warn!("not emailing attendee {}: {}", booking.email, e),
+        }
+        if let Some(host) = &booking.host_email {
+            match host.parse() {
+                Ok(email) => recipients.push(Mailbox::new(booking.host_name.clone(), email)),
+                Err(e) => tracing::warn!("not emailing host {}: {}", host, e),
+            }
+        }
+
+        recipients
+            .into_iter()
+            .map(|to| Email {
+                from: self.from.clone(),
+                to,
+                subject: subject.clone(),
+                body: body.clone(),
+                invite: invite.clone(),
+            })
+            .collect()
+    }
+
+    /// Send the emails for a notification.
+    /// Every recipient is tried even if sending to one fails, and the first failure is returned.
+    pub async fn notify(&self, kind: NotificationKind, booking: &Booking) -> SchedulerResult<()> {
+        let mut result = Ok(());
+        for email in self.emails(kind, booking) {
+            if let Err(e) = self.mailer.send(&email).await {
+                tracing::warn!(
+                    "failed to send {} email to {}: {}",
+                    kind.as_str(),
+                    email.to,
+                    e
+                );
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
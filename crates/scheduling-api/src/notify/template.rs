@@ -0,0 +1,198 @@
+use std::path::Path;
+
+use caldav_utils::caldav::event::{Attendee, NewEvent, PartStat};
+use icalendar::Component;
+
+use super::NotificationKind;
+use crate::{booking::Booking, error::SchedulerResult, recurrence::exdate_property};
+
+static TIME_FORMAT: &str = "%Y-%m-%d %H:%M UTC";
+
+/// The subject and body of an email.
+/// Placeholders such as `{name}` and `{start}` are replaced with the booking's details.
+#[derive(Clone, Debug)]
+pub struct Template {
+    pub subject: String,
+    pub body: String,
+}
+
+impl Template {
+    pub fn new(subject: &str, body: &str) -> Template {
+        Template {
+            subject: subject.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    /// Parse a template from text, where the first line is `Subject: ...`
+    /// and everything after it is the body.
+    pub fn parse(text: &str) -> Option<Template> {
+        let (first, body) = text.split_once('\n')?;
+        let subject = first.strip_prefix("Subject:")?.trim();
+        Some(Template::new(subject, body.trim_start_matches('\n')))
+    }
+
+    pub fn render(&self, booking: &Booking) -> (String, String) {
+        (
+            render_str(&self.subject, booking),
+            render_str(&self.body, booking),
+        )
+    }
+}
+
+fn render_str(template: &str, booking: &Booking) -> String {
+    template
+        .replace("{id}", &booking.id)
+        .replace("{name}", &booking.name)
+        .replace("{email}", &booking.email)
+        .replace("{description}", &booking.description)
+        .replace("{start}", &booking.start.format(TIME_FORMAT).to_string())
+        .replace("{end}", &booking.end.format(TIME_FORMAT).to_string())
+        .replace("{location}", booking.location.as_deref().unwrap_or(""))
+        .replace("{url}", booking.url.as_deref().unwrap_or(""))
+        .replace(
+            "{host}",
+            booking
+                .host_name
+                .as_deref()
+                .or(booking.host_email.as_deref())
+                .unwrap_or(""),
+        )
+}
+
+/// The templates used for each kind of notification.
+#[derive(Clone, Debug)]
+pub struct Templates {
+    pub confirmation: Template,
+    pub requested: Template,
+    pub declined: Template,
+    pub cancellation: Template,
+    pub reschedule: Template,
+    pub reminder: Template,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            confirmation: Template::new(
+                "Confirmed: meeting with {name} at {start}",
+                "A meeting has been booked.\n\nWho: {name} <{email}>\nWhen: {start} - {end}\nWhere: {location}\n{url}\n\n{description}\n",
+            ),
+            requested: Template::new(
+                "Requested: meeting with {name} at {start}",
+                "A meeting has been requested and is awaiting approval.\n\nWho: {name} <{email}>\nWhen: {start} - {end}\nWhere: {location}\n{url}\n\n{description}\n",
+            ),
+            declined: Template::new(
+                "Declined: meeting with {name} at {start}",
+                "The meeting with {name} <{email}> at {start} was not approved.\n",
+            ),
+            cancellation: Template::new(
+                "Cancelled: meeting with {name} at {start}",
+                "The meeting with {name} <{email}> at {start} has been cancelled.\n",
+            ),
+            reschedule: Template::new(
+                "Rescheduled: meeting with {name} at {start}",
+                "The meeting with {name} <{email}> has been moved.\n\nWhen: {start} - {end}\nWhere: {location}\n{url}\n",
+            ),
+            reminder: Template::new(
+                "Reminder: meeting with {name} at {start}",
+                "This is a reminder of an upcoming meeting.\n\nWho: {name} <{email}>\nWhen: {start} - {end}\nWhere: {location}\n{url}\n",
+            ),
+        }
+    }
+}
+
+impl Templates {
+    /// Load templates from a directory containing files named after each kind of
+    /// notification, e.g. `confirmation.txt`. Missing files use the default template.
+    pub fn load(dir: &Path) -> SchedulerResult<Templates> {
+        let mut templates = Templates::default();
+        for kind in [
+            NotificationKind::Confirmation,
+            NotificationKind::Requested,
+            NotificationKind::Declined,
+            NotificationKind::Cancellation,
+            NotificationKind::Reschedule,
+            NotificationKind::Reminder,
+        ] {
+            let path = dir.join(format!("{}.txt", kind.as_str()));
+            if !path.exists() {
+                continue;
+            }
+            let text = std::fs::read_to_string(&path)?;
+            match Template::parse(&text) {
+                Some(template) => *templates.get_mut(kind) = template,
+                None => tracing::warn!("ignoring template without a subject line: {:?}", path),
+            }
+        }
+        Ok(templates)
+    }
+
+    pub fn get(&self, kind: NotificationKind) -> &Template {
+        match kind {
+            NotificationKind::Confirmation => &self.confirmation,
+            NotificationKind::Requested => &self.requested,
+            NotificationKind::Declined => &self.declined,
+            NotificationKind::Cancellation => &self.cancellation,
+            NotificationKind::Reschedule => &self.reschedule,
+            NotificationKind::Reminder => &self.reminder,
+        }
+    }
+
+    fn get_mut(&mut self, kind: NotificationKind) -> &mut Template {
+        match kind {
+            NotificationKind::Confirmation => &mut self.confirmation,
+            NotificationKind::Requested => &mut self.requested,
+            NotificationKind::Declined => &mut self.declined,
+            NotificationKind::Cancellation => &mut self.cancellation,
+            NotificationKind::Reschedule => &mut self.reschedule,
+            NotificationKind::Reminder => &mut self.reminder,
+        }
+    }
+}
+
+/// Build the iCalendar invite attached to a notification.
+/// Cancellations use METHOD:CANCEL, everything else is sent as a METHOD:REQUEST.
+pub fn invite(kind: NotificationKind, booking: &Booking) -> String {
+    let method = kind.method().unwrap_or("REQUEST");
+
+    let attendee = Attendee::new(booking.email.clone(), Some(booking.name.clone()))
+        .partstat(PartStat::Accepted);
+    let mut details = NewEvent::new(booking.start, booking.end, &booking.name)
+        .description(&booking.description)
+        .attendee(attendee);
+    if let Some(organizer) = booking.organizer() {
+        details = details.organizer(organizer);
+    }
+    if let Some(location) = &booking.location {
+        details = details.location(location);
+    }
+    if let Some(url) = &booking.url {
+        details = details.url(url);
+    }
+    if let Some(recurrence) = &booking.recurrence {
+        details = details.property("RRULE", recurrence);
+        if let Some(timezone) = booking.timezone.as_deref().and_then(|tz| tz.parse().ok()) {
+            details = details.timezone(timezone);
+        }
+    }
+    for skipped in &booking.skipped {
+        details = details.append_property(exdate_property(*skipped));
+    }
+
+    let mut event = details.to_ical(&booking.id);
+    event.sequence(booking.sequence);
+    event.status(match kind {
+        NotificationKind::Cancellation | NotificationKind::Declined => {
+            icalendar::EventStatus::Cancelled
+        }
+        _ => booking.status.to_ical(),
+    });
+
+    let mut calendar = icalendar::Calendar::new();
+    calendar.append_property(("METHOD", method));
+    if let Some(vtimezone) = details.vtimezone() {
+        calendar.push(vtimezone);
+    }
+    calendar.push(event.done()).done().to_string()
+}
@@ -0,0 +1,220 @@
+use std::sync::{Arc, Mutex};
+
+use caldav_utils::{
+    availability::{get_num_slots, AvailabilityResponse},
+    source::CalendarStore,
+};
+use chrono::DurationRound;
+
+use crate::{available_slots, error::SchedulerResult, get_calendars, state::CaldavAvailability};
+
+/// The change tokens of everything availability is computed from: the calendars,
+/// busy feeds and blackouts. A `None` means that source can't tell whether it has changed.
+pub(crate) async fn change_tokens(
+    caldav_state: &CaldavAvailability,
+    (availability_calendar, booked_calendar): (Option<&dyn CalendarStore>, &dyn CalendarStore),
+) -> SchedulerResult<Vec<Option<String>>> {
+    let mut tokens = vec![booked_calendar.change_token().await?];
+    if let Some(calendar) = availability_calendar {
+        tokens.push(calendar.change_token().await?);
+    }
+    for feed in caldav_state.busy_feeds() {
+        // a failing feed is skipped when computing availability too
+        tokens.push(feed.change_token().await.unwrap_or_else(|e| {
+            tracing::warn!("failed to check {:?} for changes: {}", feed.location(), e);
+            None
+        }));
+    }
+    for blackout in caldav_state.blackouts() {
+        tokens.push(blackout.change_token(caldav_state).await?);
+    }
+    Ok(tokens)
+}
+
+/// Availability computed for the whole window, and what it was computed from.
+#[derive(Clone, Debug)]
+struct Snapshot {
+    availability: AvailabilityResponse,
+    /// The change tokens of everything the availability was computed from.
+    tokens: Vec<Option<String>>,
+    computed: chrono::DateTime<chrono::Utc>,
+    /// When the calendars were last found to be unchanged since the availability was computed.
+    checked: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    snapshot: Option<Snapshot>,
+    /// Incremented whenever the snapshot is invalidated, so that availability which was
+    /// being computed at the time isn't stored.
+    generation: u64,
+    /// Whether a booking changed since clients streaming availability were last told,
+    /// which they must be even if the matrix is the same, since it doesn't show the seats
+    /// left in group events.
+    unannounced: bool,
+}
+
+/// Availability for a rolling window of the coming days, computed in the background so
+/// that requests don't have to read the calendars every time.
+///
+/// The worker started with [`AvailabilityCache::run`] checks the change tokens of the
+/// calendars (their CTag or sync-token), busy feeds and blackouts, and only computes the
+/// availability again when they change, or once it is `max_age` old.
+/// Requests are only answered from the cache if it was found to be current within the
+/// last `freshness`, and never after a booking changes until it has been computed again.
+#[derive(Clone, Debug)]
+pub struct AvailabilityCache {
+    window: chrono::Duration,
+    freshness: chrono::Duration,
+    max_age: chrono::Duration,
+    inner: Arc<Mutex<Inner>>,
+    wake: Arc<tokio::sync::Notify>,
+}
+
+impl AvailabilityCache {
+    pub fn new(window: chrono::Duration) -> AvailabilityCache {
+        AvailabilityCache {
+            window,
+            freshness: chrono::Duration::seconds(30),
+            max_age: chrono::Duration::minutes(10),
+            inner: Arc::new(Mutex::new(Inner::default())),
+            wake: Arc::new(tokio::sync::Notify::new()),
+        }
+    }
+
+    /// How recently the cache must have been found to be current for requests to use it.
+    /// The calendars are checked for changes twice as often.
+    pub fn with_freshness(mut self, freshness: chrono::Duration) -> Self {
+        self.freshness = freshness;
+        self
+    }
+
+    /// How often the availability is computed again even if the calendars haven't changed.
+    pub fn with_max_age(mut self, max_age: chrono::Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// The cached availability between two datetimes, if it is fresh and covers them.
+    pub fn get(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Option<AvailabilityResponse> {
+        let inner = self.inner.lock().unwrap();
+        let snapshot = inner.snapshot.as_ref()?;
+        let cached = &snapshot.availability;
+        if now - snapshot.checked > self.freshness || start < cached.start || end > cached.end {
+            return None;
+        }
+        // the requested slots must line up with the cached ones
+        let offset = start - cached.start;
+        if offset.num_seconds() % cached.granularity.num_seconds() != 0 {
+            return None;
+        }
+
+        let first = get_num_slots(cached.start, start, cached.granularity);
+        let count = get_num_slots(start, end, cached.granularity);
+        Some(AvailabilityResponse {
+            start,
+            end,
+            granularity: cached.granularity,
+            matrix: cached.matrix.get(first..first + count)?.to_vec(),
+        })
+    }
+
+    /// Stop answering requests from the cache until the availability has been computed
+    /// again, which the worker is woken to do straight away. Called whenever a booking changes.
+    pub fn invalidate(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.snapshot = None;
+        inner.generation += 1;
+        inner.unannounced = true;
+        self.wake.notify_one();
+    }
+
+    /// Check the calendars for changes, and compute the availability again if they have
+    /// changed or it is out of date. Returns whether it was computed.
+    /// Clients streaming availability are told when the computed availability differs, or
+    /// when a booking changed since they were last told, even if it can't be computed.
+    pub async fn refresh(&self, caldav_state: &CaldavAvailability) -> SchedulerResult<bool> {
+        let result = self.compute(caldav_state).await;
+        if result.is_err() && std::mem::take(&mut self.inner.lock().unwrap().unannounced) {
+            // requests read the calendars themselves until it is computed again
+            caldav_state.changes().notify();
+        }
+        result
+    }
+
+    async fn compute(&self, caldav_state: &CaldavAvailability) -> SchedulerResult<bool> {
+        let now = chrono::Utc::now();
+        let generation = self.inner.lock().unwrap().generation;
+        let (availability_calendar, booked_calendar) = get_calendars(caldav_state.clone()).await?;
+        let tokens = change_tokens(
+            caldav_state,
+            (availability_calendar.as_deref(), booked_calendar.as_ref()),
+        )
+        .await?;
+
+        if let Some(snapshot) = &mut self.inner.lock().unwrap().snapshot {
+            if tokens.iter().all(Option::is_some)
+                && snapshot.tokens == tokens
+                && now - snapshot.computed < self.max_age
+            {
+                snapshot.checked = now;
+                return Ok(false);
+            }
+        }
+
+        let granularity = chrono::Duration::minutes(crate::SLOT_MINUTES);
+        let start = now.duration_trunc(granularity).unwrap_or(now);
+        let availability = available_slots(
+            caldav_state,
+            (availability_calendar.as_deref(), booked_calendar.as_ref()),
+            (start, start + self.window),
+            &[],
+        )
+        .await?;
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation != generation {
+            // a booking changed while computing, and the worker has been woken to compute again
+            return Ok(false);
+        }
+        let changed = std::mem::take(&mut inner.unannounced)
+            || inner
+                .snapshot
+                .as_ref()
+                .is_none_or(|snapshot| snapshot.availability.matrix != availability.matrix);
+        inner.snapshot = Some(Snapshot {
+            availability,
+            tokens,
+            computed: now,
+            checked: now,
+        });
+        if changed {
+            caldav_state.changes().notify();
+        }
+        Ok(true)
+    }
+
+    /// Keep the cache up to date forever.
+    pub async fn run(self, caldav_state: CaldavAvailability) {
+        let interval = (self.freshness / 2)
+            .to_std()
+            .unwrap_or_default()
+            .max(std::time::Duration::from_secs(1));
+        loop {
+            match self.refresh(&caldav_state).await {
+                Ok(true) => tracing::debug!("computed availability for the next {}", self.window),
+                Ok(false) => {}
+                Err(e) => tracing::warn!("failed to refresh availability cache: {}", e),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {},
+                _ = self.wake.notified() => {},
+            }
+        }
+    }
+}
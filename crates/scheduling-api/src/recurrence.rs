@@ -0,0 +1,128 @@
+use caldav_utils::format::DATETIME;
+use chrono::TimeZone;
+
+use crate::meeting_type::FieldError;
+
+/// The most occurrences a series may have.
+pub const MAX_OCCURRENCES: u32 = 52;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Frequency {
+    Daily,
+    Weekly,
+}
+
+impl Frequency {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+        }
+    }
+
+    /// The number of days in one period.
+    fn days(&self) -> u64 {
+        match self {
+            Frequency::Daily => 1,
+            Frequency::Weekly => 7,
+        }
+    }
+}
+
+/// How a booking repeats, e.g. every Tuesday for 8 weeks.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Recurrence {
+    pub frequency: Frequency,
+    /// The number of periods between occurrences, e.g. 2 for every other week
+    #[serde(default = "default_interval")]
+    pub interval: u32,
+    /// The number of occurrences, including the first
+    pub count: u32,
+    /// Book the occurrences that are available and skip the rest,
+    /// instead of rejecting the series if any occurrence conflicts
+    #[serde(default)]
+    pub skip_conflicts: bool,
+}
+
+fn default_interval() -> u32 {
+    1
+}
+
+impl Recurrence {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        if self.interval == 0 {
+            errors.push(FieldError {
+                field: "recurrence.interval".to_string(),
+                message: "must be at least 1".to_string(),
+            });
+        }
+        if !(1..=MAX_OCCURRENCES).contains(&self.count) {
+            errors.push(FieldError {
+                field: "recurrence.count".to_string(),
+                message: format!("must be between 1 and {MAX_OCCURRENCES}"),
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The start and end of every occurrence of the series.
+    /// Occurrences are stepped in the given timezone, so that they keep their local time
+    /// across daylight saving changes, as an RRULE with a DTSTART in that timezone does.
+    pub fn occurrences(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        timezone: chrono_tz::Tz,
+    ) -> Vec<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)> {
+        let local = start.with_timezone(&timezone).naive_local();
+        let duration = end - start;
+        (0..self.count as u64)
+            .filter_map(|i| {
+                let days = self.frequency.days() * self.interval as u64 * i;
+                let local = local.checked_add_days(chrono::Days::new(days))?;
+                // a time skipped by a daylight saving change is moved on by the change,
+                // and a time which happens twice is the first of them
+                let start = timezone
+                    .from_local_datetime(&local)
+                    .earliest()
+                    .or_else(|| {
+                        let later = local + chrono::Duration::hours(1);
+                        timezone.from_local_datetime(&later).earliest()
+                    })?
+                    .with_timezone(&chrono::Utc);
+                Some((start, start + duration))
+            })
+            .collect()
+    }
+
+    /// The value of the RRULE property describing the series.
+    pub fn to_rrule(&self) -> String {
+        format!(
+            "FREQ={};INTERVAL={};COUNT={}",
+            self.frequency.as_str(),
+            self.interval,
+            self.count
+        )
+    }
+}
+
+/// Build an EXDATE property skipping the occurrence starting at the given time.
+pub fn exdate_property(start: chrono::DateTime<chrono::Utc>) -> icalendar::Property {
+    icalendar::Property::new("EXDATE", &format!("{}", start.format(DATETIME)))
+}
+
+/// Parse the occurrences skipped by an EXDATE property value.
+pub fn parse_exdates(value: &str) -> Vec<chrono::DateTime<chrono::Utc>> {
+    value
+        .split(',')
+        .filter_map(|s| chrono::NaiveDateTime::parse_from_str(s, DATETIME).ok())
+        .map(|dt| chrono::DateTime::from_utc(dt, chrono::Utc))
+        .collect()
+}
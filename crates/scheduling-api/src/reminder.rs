@@ -0,0 +1,232 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::{
+    booking::{Booking, BookingStatus},
+    error::SchedulerResult,
+    get_calendars,
+    notify::{NotificationKind, Notifier},
+    state::CaldavAvailability,
+};
+
+/// Records which reminders have been sent so that they are not sent again,
+/// even if the server is restarted.
+#[derive(Debug)]
+pub struct ReminderStore {
+    path: PathBuf,
+    /// maps the key of each sent reminder to the start of the meeting it was for
+    sent: BTreeMap<String, chrono::DateTime<chrono::Utc>>,
+}
+
+impl ReminderStore {
+    /// Load the store from a file, starting empty if the file does not exist.
+    pub fn load(path: PathBuf) -> SchedulerResult<ReminderStore> {
+        let sent = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(ReminderStore { path, sent })
+    }
+
+    pub fn save(&self) -> SchedulerResult<()> {
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&self.sent)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// The key includes the start time, so moving a booking schedules new reminders.
+    fn key(booking: &Booking, offset: chrono::Duration) -> String {
+        format!("{}/{}", failure_key(booking), offset.num_minutes())
+    }
+
+    pub fn is_sent(&self, booking: &Booking, offset: chrono::Duration) -> bool {
+        self.sent.contains_key(&Self::key(booking, offset))
+    }
+
+    pub fn mark_sent(&mut self, booking: &Booking, offset: chrono::Duration) {
+        self.sent.insert(Self::key(booking, offset), booking.start);
+    }
+
+    /// Forget reminders for meetings which have already started.
+    pub fn prune(&mut self, now: chrono::DateTime<chrono::Utc>) {
+        self.sent.retain(|_, start| *start > now);
+    }
+}
+
+/// Determine which bookings are due a reminder.
+/// Returns each booking along with the reminder offsets that are now due for it.
+/// A reminder is due once the time until the meeting is less than its offset.
+/// Bookings still awaiting approval are not reminded of.
+pub fn due_reminders(
+    bookings: &[Booking],
+    offsets: &[chrono::Duration],
+    store: &ReminderStore,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<(Booking, Vec<chrono::Duration>)> {
+    bookings
+        .iter()
+        .filter(|booking| booking.start > now)
+        .filter(|booking| booking.status == BookingStatus::Confirmed)
+        .filter_map(|booking| {
+            let due: Vec<_> = offsets
+                .iter()
+                .filter(|offset| booking.start - **offset <= now)
+                .filter(|offset| !store.is_sent(booking, **offset))
+                .cloned()
+                .collect();
+            if due.is_empty() {
+                None
+            } else {
+                Some((booking.clone(), due))
+            }
+        })
+        .collect()
+}
+
+/// How many times a reminder is tried before it is given up on.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// A reminder which could not be sent, and when it is next tried.
+#[derive(Clone, Copy, Debug)]
+struct Failure {
+    attempts: u32,
+    retry_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Identifies a meeting and who is reminded of it.
+/// Each attendee of a group event has their own booking, so they are told apart by email.
+fn failure_key(booking: &Booking) -> String {
+    match booking.capacity {
+        Some(_) => format!(
+            "{}/{}/{}",
+            booking.id,
+            booking.email.to_lowercase(),
+            booking.start.timestamp()
+        ),
+        None => format!("{}/{}", booking.id, booking.start.timestamp()),
+    }
+}
+
+/// Periodically sends reminders for upcoming meetings in the booked calendar.
+#[derive(Debug)]
+pub struct Reminders {
+    caldav_state: CaldavAvailability,
+    notifier: Notifier,
+    store: ReminderStore,
+    offsets: Vec<chrono::Duration>,
+    /// Reminders which failed to send, by booking id, attendee and start, so that they are
+    /// backed off.
+    failures: BTreeMap<String, Failure>,
+}
+
+impl Reminders {
+    pub fn new(
+        caldav_state: CaldavAvailability,
+        notifier: Notifier,
+        store: ReminderStore,
+        offsets: Vec<chrono::Duration>,
+    ) -> Reminders {
+        Reminders {
+            caldav_state,
+            notifier,
+            store,
+            offsets,
+            failures: BTreeMap::new(),
+        }
+    }
+
+    /// Check the booked calendar once and send any reminders that are due.
+    /// Returns the number of reminders sent.
+    pub async fn run_once(&mut self) -> SchedulerResult<usize> {
+        let now = chrono::Utc::now();
+        let horizon = match self.offsets.iter().max() {
+            Some(offset) => now + *offset,
+            None => return Ok(0),
+        };
+
+        let (_, booked_calendar) = get_calendars(self.caldav_state.clone()).await?;
+        // each occurrence of a recurring booking, and each attendee of a group one, is reminded of
+        let timezone = booked_calendar.timezone();
+        let bookings: Vec<Booking> = booked_calendar
+            .events(now, horizon)
+            .await?
+            .iter()
+            .flat_map(|event| Booking::occurrences(event, now, horizon, timezone.clone()))
+            .collect();
+
+        let mut sent = 0;
+        for (booking, offsets) in due_reminders(&bookings, &self.offsets, &self.store, now) {
+            let key = failure_key(&booking);
+            let failure = self.failures.get(&key).copied();
+            if failure.is_some_and(|failure| failure.retry_at > now) {
+                continue;
+            }
+
+            // when several reminders are due at once, e.g. after downtime, only send one
+            match self
+                .notifier
+                .notify(NotificationKind::Reminder, &booking)
+                .await
+            {
+                Ok(()) => {
+                    self.failures.remove(&key);
+                    sent += 1;
+                }
+                Err(e) => {
+                    let attempts = failure.map_or(0, |failure| failure.attempts) + 1;
+                    if attempts < MAX_ATTEMPTS {
+                        // back off, so that a failing reminder doesn't re-email
+                        // the recipients it did reach on every tick
+                        let delay = chrono::Duration::minutes(5) * 2i32.pow(attempts - 1);
+                        tracing::warn!(
+                            "failed to send reminder for {}, retrying in {} minutes: {}",
+                            booking.id,
+                            delay.num_minutes(),
+                            e
+                        );
+                        self.failures.insert(
+                            key,
+                            Failure {
+                                attempts,
+                                retry_at: now + delay,
+                            },
+                        );
+                        continue;
+                    }
+                    tracing::warn!(
+                        "giving up on reminder for {} after {} attempts: {}",
+                        booking.id,
+                        attempts,
+                        e
+                    );
+                    self.failures.remove(&key);
+                }
+            }
+            for offset in offsets {
+                self.store.mark_sent(&booking, offset);
+            }
+            self.store.save()?;
+        }
+
+        self.store.prune(now);
+        // forget failures for meetings which were cancelled or have started
+        let keys: Vec<_> = bookings.iter().map(failure_key).collect();
+        self.failures.retain(|key, _| keys.contains(key));
+        self.store.save()?;
+        Ok(sent)
+    }
+
+    /// Send reminders forever, checking the calendar at the given interval.
+    pub async fn run(mut self, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match self.run_once().await {
+                Ok(0) => {}
+                Ok(sent) => tracing::info!("sent {} reminders", sent),
+                Err(e) => tracing::warn!("failed to send reminders: {}", e),
+            }
+        }
+    }
+}
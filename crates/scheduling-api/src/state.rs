@@ -1,28 +1,342 @@
-use caldav_utils::caldav::client::DavClient;
+use std::sync::Arc;
+
+use subtle::ConstantTimeEq;
+
+use caldav_utils::{
+    availability::working_hours::WorkingHours,
+    caldav::{calendar::NewCalendar, client::DavClient, event::Organizer},
+    ics::IcsSource,
+    source::CalendarStore,
+    vdir::Vdir,
+};
+
+use crate::{
+    blackout::{BlackoutFiles, BlackoutSource},
+    discovery::DiscoveryCache,
+    error::{SchedulerError, SchedulerResult},
+    hold::Holds,
+    live::AvailabilityChanges,
+    meeting_type::MeetingType,
+    notify::Notifier,
+    precompute::AvailabilityCache,
+    webhook::Webhooks,
+};
 
 /// The details needed to connect to a caldav server
 /// and find relevant calendars to determine availability.
 #[derive(Clone, Debug)]
 pub struct CaldavAvailability {
-    pub(crate) availability_calendar: String,
+    /// The calendar whose events are the times open for booking.
+    pub(crate) availability_calendar: Option<String>,
+    /// Read VAVAILABILITY components from the availability calendar instead of events.
+    pub(crate) availability_components: bool,
+    /// Working hours open for booking, as well as any availability calendar.
+    pub(crate) working_hours: Option<Arc<WorkingHours>>,
+    /// The host's timezone, which recurring bookings keep their local time in.
+    pub(crate) timezone: Option<chrono_tz::Tz>,
     pub(crate) booked_calendar: String,
-    pub(crate) davclient: DavClient,
+    /// The caldav server's client, which isn't needed when calendars are kept in a vdir.
+    pub(crate) davclient: Option<DavClient>,
+    /// The calendars discovered on the caldav server.
+    pub(crate) discovery: DiscoveryCache,
+    /// A local vdir to read and write calendars in, instead of the caldav server.
+    pub(crate) vdir: Option<Vdir>,
+    /// The host who is recorded as the organizer of booked events.
+    pub(crate) organizer: Option<Organizer>,
+    /// The location set on booked events.
+    pub(crate) location: Option<String>,
+    /// The url set on booked events, e.g. a link to a video call.
+    pub(crate) url: Option<String>,
+    /// Sends emails when bookings are made or changed.
+    pub(crate) notifier: Option<Notifier>,
+    /// Sends webhooks to subscribers when bookings are made or changed.
+    pub(crate) webhooks: Option<Webhooks>,
+    /// The kinds of meetings that can be booked.
+    pub(crate) meeting_types: Arc<Vec<MeetingType>>,
+    /// The bearer token the host uses to approve or decline bookings.
+    pub(crate) host_token: Option<String>,
+    /// How long a tentative booking holds its slot before it expires.
+    pub(crate) approval_expiry: chrono::Duration,
+    /// Slots reserved while attendees fill out the booking form.
+    /// When set, bookings must present a hold.
+    pub(crate) holds: Option<Holds>,
+    /// Days which are never available, such as public holidays.
+    pub(crate) blackouts: Arc<Vec<BlackoutSource>>,
+    /// The events read from blackout files.
+    pub(crate) blackout_files: BlackoutFiles,
+    /// Read-only feeds of the host's other commitments, which are busy like booked events.
+    pub(crate) busy_feeds: Arc<Vec<IcsSource>>,
+    /// Availability computed in the background, which requests are answered from when possible.
+    pub(crate) availability_cache: Option<AvailabilityCache>,
+    /// Tells clients streaming availability when it may have changed.
+    pub(crate) changes: AvailabilityChanges,
 }
 
 impl CaldavAvailability {
     pub fn new(
-        availability_calendar: String,
+        availability_calendar: Option<String>,
         booked_calendar: String,
         davclient: DavClient,
+    ) -> Self {
+        Self::build(availability_calendar, booked_calendar, Some(davclient))
+    }
+
+    /// Keep calendars in a local vdir, without connecting to a caldav server.
+    pub fn local(
+        availability_calendar: Option<String>,
+        booked_calendar: String,
+        vdir: Vdir,
+    ) -> Self {
+        Self::build(availability_calendar, booked_calendar, None).with_vdir(vdir)
+    }
+
+    fn build(
+        availability_calendar: Option<String>,
+        booked_calendar: String,
+        davclient: Option<DavClient>,
     ) -> Self {
         Self {
             availability_calendar,
+            availability_components: false,
+            working_hours: None,
+            timezone: None,
             booked_calendar,
             davclient,
+            discovery: DiscoveryCache::new(chrono::Duration::minutes(5)),
+            vdir: None,
+            organizer: None,
+            location: None,
+            url: None,
+            notifier: None,
+            webhooks: None,
+            meeting_types: Arc::new(Vec::new()),
+            host_token: None,
+            approval_expiry: chrono::Duration::hours(24),
+            holds: None,
+            blackouts: Arc::new(Vec::new()),
+            blackout_files: BlackoutFiles::default(),
+            busy_feeds: Arc::new(Vec::new()),
+            availability_cache: None,
+            changes: AvailabilityChanges::default(),
         }
     }
 
-    pub fn davclient(&self) -> &DavClient {
-        &self.davclient
+    pub fn with_organizer(mut self, organizer: Organizer) -> Self {
+        self.organizer = Some(organizer);
+        self
+    }
+
+    pub fn with_location(mut self, location: String) -> Self {
+        self.location = Some(location);
+        self
+    }
+
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = Some(url);
+        self
+    }
+
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    pub fn with_webhooks(mut self, webhooks: Webhooks) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    pub fn with_meeting_types(mut self, meeting_types: Vec<MeetingType>) -> Self {
+        self.meeting_types = Arc::new(meeting_types);
+        self
+    }
+
+    pub fn with_host_token(mut self, host_token: String) -> Self {
+        self.host_token = Some(host_token);
+        self
+    }
+
+    pub fn with_approval_expiry(mut self, approval_expiry: chrono::Duration) -> Self {
+        self.approval_expiry = approval_expiry;
+        self
+    }
+
+    /// Clients streaming availability are told whenever a hold is placed or released.
+    pub fn with_holds(mut self, holds: Holds) -> Self {
+        self.holds = Some(holds.with_changes(self.changes.clone()));
+        self
+    }
+
+    pub fn holds(&self) -> Option<&Holds> {
+        self.holds.as_ref()
+    }
+
+    pub fn with_blackouts(mut self, blackouts: Vec<BlackoutSource>) -> Self {
+        self.blackouts = Arc::new(blackouts);
+        self
+    }
+
+    pub fn blackouts(&self) -> &[BlackoutSource] {
+        &self.blackouts
+    }
+
+    pub fn with_busy_feeds(mut self, busy_feeds: Vec<IcsSource>) -> Self {
+        self.busy_feeds = Arc::new(busy_feeds);
+        self
+    }
+
+    pub fn busy_feeds(&self) -> &[IcsSource] {
+        &self.busy_feeds
+    }
+
+    pub fn with_availability_cache(mut self, cache: AvailabilityCache) -> Self {
+        self.availability_cache = Some(cache);
+        self
+    }
+
+    pub fn availability_cache(&self) -> Option<&AvailabilityCache> {
+        self.availability_cache.as_ref()
+    }
+
+    pub fn changes(&self) -> &AvailabilityChanges {
+        &self.changes
+    }
+
+    pub fn with_availability_components(mut self) -> Self {
+        self.availability_components = true;
+        self
+    }
+
+    pub fn with_working_hours(mut self, working_hours: WorkingHours) -> Self {
+        self.working_hours = Some(Arc::new(working_hours));
+        self
+    }
+
+    pub fn working_hours(&self) -> Option<&WorkingHours> {
+        self.working_hours.as_deref()
+    }
+
+    pub fn with_timezone(mut self, timezone: chrono_tz::Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    /// The host's timezone, which is the working hours' timezone unless one is set,
+    /// or UTC if there are neither.
+    pub fn timezone(&self) -> chrono_tz::Tz {
+        self.timezone
+            .or_else(|| self.working_hours().and_then(|hours| hours.tz().ok()))
+            .unwrap_or(chrono_tz::UTC)
+    }
+
+    pub fn with_vdir(mut self, vdir: Vdir) -> Self {
+        self.vdir = Some(vdir);
+        self
+    }
+
+    pub fn vdir(&self) -> Option<&Vdir> {
+        self.vdir.as_ref()
+    }
+
+    /// How long calendars discovered on the caldav server are used before they are discovered again.
+    pub fn with_discovery_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.discovery = DiscoveryCache::new(ttl);
+        self
+    }
+
+    pub fn discovery(&self) -> &DiscoveryCache {
+        &self.discovery
+    }
+
+    /// Find a calendar by name, in the vdir if one is configured or on the caldav server otherwise.
+    pub async fn calendar(&self, name: &str) -> SchedulerResult<Box<dyn CalendarStore>> {
+        if let Some(vdir) = &self.vdir {
+            return Ok(Box::new(vdir.get_calendar(name).await?));
+        }
+        Ok(Box::new(
+            self.discovery.calendar(self.caldav()?, name).await?,
+        ))
+    }
+
+    /// All of the calendars, in the vdir if one is configured or on the caldav server otherwise.
+    pub async fn calendars(&self) -> SchedulerResult<Vec<Box<dyn CalendarStore>>> {
+        if let Some(vdir) = &self.vdir {
+            return Ok(vdir
+                .get_calendars()
+                .await?
+                .into_iter()
+                .map(|calendar| Box::new(calendar) as Box<dyn CalendarStore>)
+                .collect());
+        }
+        Ok(self
+            .discovery
+            .calendars(self.caldav()?)
+            .await?
+            .into_iter()
+            .map(|calendar| Box::new(calendar) as Box<dyn CalendarStore>)
+            .collect())
+    }
+
+    /// Create a calendar, in the vdir if one is configured or on the caldav server otherwise.
+    /// Only the display name and timezone are kept in a vdir.
+    pub async fn create_calendar(
+        &self,
+        details: &NewCalendar,
+    ) -> SchedulerResult<Box<dyn CalendarStore>> {
+        if let Some(vdir) = &self.vdir {
+            return Ok(Box::new(vdir.create_calendar(details).await?));
+        }
+        let mut principal = self.caldav()?.get_principal().await?;
+        let calendar = principal.create_calendar(details).await?;
+        self.discovery.invalidate().await;
+        Ok(Box::new(calendar))
+    }
+
+    pub fn approval_expiry(&self) -> chrono::Duration {
+        self.approval_expiry
+    }
+
+    /// Check that a request was made by the host.
+    /// Host endpoints are disabled unless a host token is configured.
+    pub fn authorize_host(&self, authorization: Option<&str>) -> SchedulerResult<()> {
+        let token = self
+            .host_token
+            .as_ref()
+            .ok_or(SchedulerError::Unauthorized)?;
+        match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+            // compared in constant time, so the token can't be guessed from response times
+            Some(given) if bool::from(given.as_bytes().ct_eq(token.as_bytes())) => Ok(()),
+            _ => Err(SchedulerError::Unauthorized),
+        }
+    }
+
+    pub fn meeting_types(&self) -> &[MeetingType] {
+        &self.meeting_types
+    }
+
+    pub fn meeting_type(&self, id: &str) -> SchedulerResult<&MeetingType> {
+        self.meeting_types
+            .iter()
+            .find(|m| m.id == id)
+            .ok_or_else(|| SchedulerError::MeetingTypeNotFound(id.to_string()))
+    }
+
+    pub fn davclient(&self) -> Option<&DavClient> {
+        self.davclient.as_ref()
+    }
+
+    /// The caldav server's client, for when calendars aren't kept in a vdir.
+    fn caldav(&self) -> SchedulerResult<&DavClient> {
+        self.davclient
+            .as_ref()
+            .ok_or(SchedulerError::CaldavNotConfigured)
+    }
+
+    pub fn notifier(&self) -> Option<&Notifier> {
+        self.notifier.as_ref()
+    }
+
+    pub fn webhooks(&self) -> Option<&Webhooks> {
+        self.webhooks.as_ref()
     }
 }
@@ -0,0 +1,1620 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use caldav_mock::{MockCaldav, Quirks};
+use caldav_utils::{
+    availability::{get_event_matrix, ranges_matrix},
+    caldav::{
+        client::{DavClient, DavCredentials},
+        event::{Event, NewEvent},
+    },
+    error::CaldavError,
+    ics::IcsSource,
+};
+
+use crate::{
+    approval::expired_holds,
+    available_slots,
+    blackout::{blackout_matrix, BlackoutSource},
+    booking::{
+        self, answer_properties, is_answer_from, seat_token_property, Booking, BookingStatus,
+    },
+    get_calendars,
+    group::seats_matrix,
+    hold::Holds,
+    live::{availability_updates, AvailabilityChanges, ChangeWatcher},
+    meeting_type::{Answer, MeetingType},
+    notify::{
+        mailer::{Email, FileMailer, Mailer},
+        NotificationKind, Notifier,
+    },
+    precompute::AvailabilityCache,
+    recurrence::{exdate_property, Frequency, Recurrence},
+    reminder::{due_reminders, ReminderStore, Reminders},
+    state::CaldavAvailability,
+    webhook::{
+        queue::{DeliveryQueue, DeliveryStatus},
+        sign, LifecycleEvent, RetryPolicy, Subscription, Webhooks,
+    },
+    SlotRequest,
+};
+
+fn build_booking() -> Booking {
+    Booking {
+        id: "booking-id".to_string(),
+        start: chrono::DateTime::parse_from_rfc3339("2023-01-12T14:00:00Z")
+            .unwrap()
+            .into(),
+        end: chrono::DateTime::parse_from_rfc3339("2023-01-12T14:30:00Z")
+            .unwrap()
+            .into(),
+        name: "Jane Doe".to_string(),
+        email: "jane@example.com".to_string(),
+        description: "talk about things".to_string(),
+        host_email: Some("host@example.com".to_string()),
+        host_name: Some("Host".to_string()),
+        location: Some("room 1".to_string()),
+        url: None,
+        sequence: 0,
+        status: BookingStatus::Confirmed,
+        created: None,
+        meeting_type: None,
+        capacity: None,
+        recurrence: None,
+        timezone: None,
+        skipped: Vec::new(),
+        answers: Default::default(),
+        cancel_token: None,
+    }
+}
+
+fn temp_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!(
+        "scheduling-api-{}",
+        ksuid::Ksuid::generate().to_base62()
+    ))
+}
+
+#[tokio::test]
+async fn notifier_writes_invites_to_maildir() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = temp_dir();
+    let notifier = Notifier::new(
+        Arc::new(FileMailer::new(dir.clone())),
+        "scheduler@example.com".to_string(),
+    );
+
+    let booking = build_booking();
+    notifier
+        .notify(NotificationKind::Confirmation, &booking)
+        .await?;
+
+    // one email for the attendee and one for the host
+    let messages: Vec<_> = std::fs::read_dir(dir.join("new"))?
+        .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+        .collect();
+    assert_eq!(messages.len(), 2);
+    for message in &messages {
+        assert!(message.contains("Subject: Confirmed: meeting with Jane Doe"));
+        assert!(message.contains("method=REQUEST"));
+    }
+    assert!(messages
+        .iter()
+        .any(|m| m.contains("To: Host <host@example.com>")));
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn notifier_quotes_attendee_names() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = temp_dir();
+    let notifier = Notifier::new(
+        Arc::new(FileMailer::new(dir.clone())),
+        "scheduler@example.com".to_string(),
+    );
+
+    let mut booking = build_booking();
+    booking.name = "Doe, Jane \"JD\" <Jr>".to_string();
+    let emails = notifier.emails(NotificationKind::Confirmation, &booking);
+    assert_eq!(emails.len(), 2);
+    assert_eq!(emails[0].to.email.to_string(), "jane@example.com");
+    assert_eq!(emails[0].to.name.as_deref(), Some("Doe, Jane \"JD\" <Jr>"));
+    notifier
+        .notify(NotificationKind::Confirmation, &booking)
+        .await?;
+    assert_eq!(std::fs::read_dir(dir.join("new"))?.count(), 2);
+
+    // the host is still told about a booking whose attendee address can't be used
+    booking.email = "not an address".to_string();
+    let emails = notifier.emails(NotificationKind::Confirmation, &booking);
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].to.email.to_string(), "host@example.com");
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn cancellation_invite() {
+    let mut booking = build_booking();
+    booking.sequence = 1;
+
+    let ics = crate::notify::template::invite(NotificationKind::Cancellation, &booking);
+    assert!(ics.contains("METHOD:CANCEL"));
+    assert!(ics.contains("STATUS:CANCELLED"));
+    assert!(ics.contains("SEQUENCE:1"));
+    assert!(ics.contains("UID:booking-id"));
+}
+
+#[test]
+fn reminders_are_sent_once() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = temp_dir();
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("reminders.json");
+
+    let booking = build_booking();
+    let bookings = [booking.clone()];
+    let day = chrono::Duration::hours(24);
+    let hour = chrono::Duration::hours(1);
+    let offsets = [day, hour];
+
+    // two days before, nothing is due
+    let mut store = ReminderStore::load(path.clone())?;
+    let now = booking.start - chrono::Duration::hours(48);
+    assert!(due_reminders(&bookings, &offsets, &store, now).is_empty());
+
+    // within a day, only the 24h reminder is due
+    let now = booking.start - chrono::Duration::hours(5);
+    let due = due_reminders(&bookings, &offsets, &store, now);
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].1, vec![day]);
+    store.mark_sent(&booking, day);
+    store.save()?;
+
+    // the store survives a restart
+    let store = ReminderStore::load(path.clone())?;
+    assert!(due_reminders(&bookings, &offsets, &store, now).is_empty());
+
+    let now = booking.start - chrono::Duration::minutes(30);
+    let due = due_reminders(&bookings, &offsets, &store, now);
+    assert_eq!(due[0].1, vec![hour]);
+
+    // moving the booking schedules new reminders
+    let mut moved = booking.clone();
+    moved.start += chrono::Duration::hours(2);
+    moved.end += chrono::Duration::hours(2);
+    let due = due_reminders(&[moved], &offsets, &store, now);
+    assert_eq!(due[0].1, vec![day]);
+
+    // meetings that have started are not reminded
+    let now = booking.start + chrono::Duration::minutes(1);
+    assert!(due_reminders(&[booking], &offsets, &store, now).is_empty());
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+/// Fails to send to one address, and counts every email it is asked to send.
+#[derive(Debug, Default)]
+struct FailingMailer {
+    sent: std::sync::Mutex<Vec<String>>,
+}
+
+#[async_trait::async_trait]
+impl Mailer for FailingMailer {
+    async fn send(&self, email: &Email) -> crate::error::SchedulerResult<()> {
+        let to = email.to.email.to_string();
+        self.sent.lock().unwrap().push(to.clone());
+        if to == "fail@example.com" {
+            return Err(std::io::Error::other("mailbox unavailable").into());
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn failing_reminders_are_backed_off() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let start = chrono::Utc::now() + chrono::Duration::minutes(30);
+    for (uid, email) in [
+        ("failing", "fail@example.com"),
+        ("working", "ok@example.com"),
+    ] {
+        let details = NewEvent::new(start, start + chrono::Duration::minutes(30), "meeting")
+            .property("X-BOOKING-EMAIL", email);
+        let ical = icalendar::Calendar::new()
+            .push(details.to_ical(uid))
+            .done()
+            .to_string();
+        server.put_item("booked", &format!("{uid}.ics"), &ical);
+    }
+
+    let dir = temp_dir();
+    std::fs::create_dir_all(&dir)?;
+    let mailer = Arc::new(FailingMailer::default());
+    let state = CaldavAvailability::new(
+        None,
+        "Booked".to_string(),
+        DavClient::new(
+            server.url(),
+            DavCredentials::new(server.username().to_string(), server.password().to_string()),
+        )?,
+    );
+    let mut reminders = Reminders::new(
+        state,
+        Notifier::new(mailer.clone(), "scheduler@example.com".to_string()),
+        ReminderStore::load(dir.join("reminders.json"))?,
+        vec![chrono::Duration::hours(1)],
+    );
+
+    // one failure doesn't stop the other reminder being sent
+    assert_eq!(reminders.run_once().await?, 1);
+    assert_eq!(mailer.sent.lock().unwrap().len(), 2);
+
+    // the failed reminder isn't tried again straight away, and the sent one isn't repeated
+    assert_eq!(reminders.run_once().await?, 0);
+    assert_eq!(mailer.sent.lock().unwrap().len(), 2);
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn every_seat_holder_is_reminded() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let start = chrono::Utc::now() + chrono::Duration::minutes(30);
+    let attendee =
+        |email: &str| caldav_utils::caldav::event::Attendee::new(email.to_string(), None);
+    let details = NewEvent::new(start, start + chrono::Duration::hours(1), "Workshop")
+        .property("X-BOOKING-CAPACITY", "3")
+        .property("X-BOOKING-MEETING-TYPE", "workshop")
+        .attendee(attendee("first@example.com"))
+        .attendee(attendee("second@example.com"));
+    let ical = icalendar::Calendar::new()
+        .push(details.to_ical("workshop"))
+        .done()
+        .to_string();
+    server.put_item("booked", "workshop.ics", &ical);
+
+    let dir = temp_dir();
+    std::fs::create_dir_all(&dir)?;
+    let mailer = Arc::new(FailingMailer::default());
+    let state = CaldavAvailability::new(
+        None,
+        "Booked".to_string(),
+        DavClient::new(
+            server.url(),
+            DavCredentials::new(server.username().to_string(), server.password().to_string()),
+        )?,
+    );
+    let mut reminders = Reminders::new(
+        state,
+        Notifier::new(mailer.clone(), "scheduler@example.com".to_string()),
+        ReminderStore::load(dir.join("reminders.json"))?,
+        vec![chrono::Duration::hours(1)],
+    );
+
+    // each attendee is reminded of their own seat, once
+    assert_eq!(reminders.run_once().await?, 2);
+    let mut sent = mailer.sent.lock().unwrap().clone();
+    sent.sort();
+    assert_eq!(sent, ["first@example.com", "second@example.com"]);
+    assert_eq!(reminders.run_once().await?, 0);
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn webhook_signature() {
+    // HMAC-SHA256("secret", "1700000000.{}")
+    assert_eq!(
+        sign("secret", 1700000000, "{}"),
+        "t=1700000000,v1=b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+    );
+}
+
+#[test]
+fn webhook_backoff() {
+    let policy = RetryPolicy {
+        max_attempts: 5,
+        base_delay: chrono::Duration::seconds(10),
+        max_delay: chrono::Duration::seconds(60),
+    };
+    assert_eq!(policy.delay(1), chrono::Duration::seconds(10));
+    assert_eq!(policy.delay(2), chrono::Duration::seconds(20));
+    assert_eq!(policy.delay(3), chrono::Duration::seconds(40));
+    assert_eq!(policy.delay(4), chrono::Duration::seconds(60));
+    assert_eq!(policy.delay(40), chrono::Duration::seconds(60));
+}
+
+#[tokio::test]
+async fn webhook_delivery_retries_and_replays() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{extract::State, http::HeaderMap, http::StatusCode, routing::post, Router};
+    use std::sync::Mutex;
+
+    // a subscriber which fails the first request, then records the rest
+    #[derive(Clone, Default)]
+    struct Received(Arc<Mutex<Vec<(String, String)>>>);
+    async fn receive(
+        State(received): State<Received>,
+        headers: HeaderMap,
+        body: String,
+    ) -> StatusCode {
+        let mut received = received.0.lock().unwrap();
+        let signature = headers[crate::webhook::SIGNATURE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        received.push((signature, body));
+        if received.len() == 1 {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        }
+    }
+
+    let received = Received::default();
+    let app = Router::new()
+        .route("/hook", post(receive))
+        .with_state(received.clone());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::Server::from_tcp(listener)?.serve(app.into_make_service()));
+
+    let dir = temp_dir();
+    let queue = DeliveryQueue::new(dir.clone())?;
+    let webhooks = Webhooks::new(
+        vec![Subscription {
+            id: None,
+            url: format!("http://{addr}/hook"),
+            secret: "secret".to_string(),
+            events: vec![LifecycleEvent::Booked],
+        }],
+        queue.clone(),
+    )
+    .with_policy(RetryPolicy {
+        max_attempts: 3,
+        base_delay: chrono::Duration::zero(),
+        max_delay: chrono::Duration::zero(),
+    });
+
+    // subscriptions only receive the events they asked for
+    let booking = build_booking();
+    webhooks.enqueue(LifecycleEvent::Cancelled, &booking)?;
+    assert!(queue.list(DeliveryStatus::Pending)?.is_empty());
+
+    webhooks.enqueue(LifecycleEvent::Booked, &booking)?;
+    let pending = queue.list(DeliveryStatus::Pending)?;
+    assert_eq!(pending.len(), 1);
+    let id = pending[0].id.clone();
+
+    // the first attempt fails and is retried
+    let next = webhooks.process_due().await?;
+    assert!(next.is_some());
+    assert_eq!(queue.list(DeliveryStatus::Pending)?[0].attempts, 1);
+    let next = webhooks.process_due().await?;
+    assert!(next.is_none());
+    assert_eq!(queue.list(DeliveryStatus::Delivered)?.len(), 1);
+
+    // replaying sends the same payload again
+    let delivery = queue.replay(&id)?;
+    assert_eq!(webhooks.attempt(delivery).await?, DeliveryStatus::Delivered);
+
+    let received = received.0.lock().unwrap();
+    assert_eq!(received.len(), 3);
+    assert_eq!(received[1].1, received[2].1);
+    let payload: serde_json::Value = serde_json::from_str(&received[1].1)?;
+    assert_eq!(payload["event"], "booking.created");
+    assert_eq!(payload["booking_id"], "booking-id");
+    assert_eq!(payload["attendee"]["email"], "jane@example.com");
+
+    // the signature covers the timestamp and body
+    let (signature, body) = &received[1];
+    let timestamp: i64 = signature
+        .strip_prefix("t=")
+        .and_then(|s| s.split(',').next())
+        .unwrap()
+        .parse()?;
+    assert_eq!(signature, &sign("secret", timestamp, body));
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn webhooks_are_sent_per_subscription() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{extract::State, http::HeaderMap, routing::post, Router};
+
+    #[derive(Clone, Default)]
+    struct Received(Arc<std::sync::Mutex<Vec<(String, String)>>>);
+
+    async fn receive(State(received): State<Received>, headers: HeaderMap, body: String) {
+        let signature = headers[crate::webhook::SIGNATURE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        received.0.lock().unwrap().push((signature, body));
+    }
+
+    async fn hang() {
+        tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+    }
+
+    let received = Received::default();
+    let app = Router::new()
+        .route("/hook", post(receive))
+        .route("/slow", post(hang))
+        .with_state(received.clone());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(axum::Server::from_tcp(listener)?.serve(app.into_make_service()));
+
+    let subscription = |id: &str, path: &str, secret: &str| Subscription {
+        id: Some(id.to_string()),
+        url: format!("http://{addr}{path}"),
+        secret: secret.to_string(),
+        events: Vec::new(),
+    };
+    let dir = temp_dir();
+    let queue = DeliveryQueue::new(dir.clone())?;
+    let webhooks = Webhooks::new(
+        vec![
+            subscription("first", "/hook", "one"),
+            subscription("second", "/hook", "two"),
+            subscription("slow", "/slow", "three"),
+        ],
+        queue.clone(),
+    )
+    .with_timeout(std::time::Duration::from_millis(500))?;
+
+    // the hanging subscriber times out without holding up the others
+    webhooks.enqueue(LifecycleEvent::Booked, &build_booking())?;
+    webhooks.process_due().await?;
+    assert_eq!(queue.list(DeliveryStatus::Delivered)?.len(), 2);
+    let pending = queue.list(DeliveryStatus::Pending)?;
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].subscription.as_deref(), Some("slow"));
+
+    // subscriptions sharing a url are each signed with their own secret
+    let received = received.0.lock().unwrap();
+    let mut secrets: Vec<_> = received
+        .iter()
+        .map(|(signature, body)| {
+            let timestamp: i64 = signature
+                .strip_prefix("t=")
+                .and_then(|s| s.split(',').next())
+                .unwrap()
+                .parse()
+                .unwrap();
+            ["one", "two"]
+                .into_iter()
+                .find(|secret| *signature == sign(secret, timestamp, body))
+                .unwrap()
+        })
+        .collect();
+    secrets.sort();
+    assert_eq!(secrets, ["one", "two"]);
+
+    // they can't share a url without ids to tell them apart
+    let path = dir.join("subscriptions.json");
+    std::fs::write(
+        &path,
+        format!(
+            r#"[{{"url": "http://{addr}/hook", "secret": "one"}},
+                {{"url": "http://{addr}/hook", "secret": "two"}}]"#
+        ),
+    )?;
+    assert!(matches!(
+        crate::webhook::load_subscriptions(&path),
+        Err(crate::error::SchedulerError::DuplicateSubscription(_))
+    ));
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+fn build_meeting_type() -> MeetingType {
+    serde_json::from_value(serde_json::json!({
+        "id": "intro",
+        "name": "Intro call",
+        "questions": [
+            { "id": "company", "label": "Company", "type": "text", "max_length": 20, "required": true },
+            { "id": "topics", "label": "Topics", "type": "choice", "options": ["sales", "support"], "multiple": true },
+            { "id": "size", "label": "Team size", "type": "choice", "options": ["1-10", "11+"] },
+            { "id": "phone", "label": "Phone", "type": "phone" },
+            { "id": "ticket", "label": "Ticket", "type": "text", "pattern": "^T-[0-9]+$" }
+        ]
+    }))
+    .unwrap()
+}
+
+fn answers(pairs: &[(&str, Answer)]) -> BTreeMap<String, Answer> {
+    pairs
+        .iter()
+        .map(|(id, answer)| (id.to_string(), answer.clone()))
+        .collect()
+}
+
+fn one(value: &str) -> Answer {
+    Answer::One(value.to_string())
+}
+
+#[test]
+fn meeting_type_accepts_valid_answers() {
+    let meeting_type = build_meeting_type();
+    let valid = answers(&[
+        ("company", one("Acme")),
+        (
+            "topics",
+            Answer::Many(vec!["sales".to_string(), "support".to_string()]),
+        ),
+        ("size", one("11+")),
+        ("phone", one("+1 (555) 123-4567")),
+        ("ticket", one("T-42")),
+    ]);
+    assert_eq!(meeting_type.validate(&valid), Ok(()));
+
+    // optional questions may be left out
+    assert_eq!(
+        meeting_type.validate(&answers(&[("company", one("Acme"))])),
+        Ok(())
+    );
+}
+
+#[test]
+fn meeting_type_rejects_invalid_answers() {
+    let meeting_type = build_meeting_type();
+    let invalid = answers(&[
+        ("company", one("  ")),
+        (
+            "size",
+            Answer::Many(vec!["1-10".to_string(), "11+".to_string()]),
+        ),
+        ("topics", Answer::Many(vec!["marketing".to_string()])),
+        ("phone", one("call me")),
+        ("ticket", one("42")),
+        ("favourite_colour", one("blue")),
+    ]);
+
+    let errors = meeting_type.validate(&invalid).unwrap_err();
+    let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+    assert_eq!(
+        fields,
+        [
+            "company",
+            "topics",
+            "size",
+            "phone",
+            "ticket",
+            "favourite_colour"
+        ]
+    );
+
+    let too_long = answers(&[("company", one("a very long company name"))]);
+    let errors = meeting_type.validate(&too_long).unwrap_err();
+    assert_eq!(errors[0].message, "must be at most 20 characters");
+}
+
+#[test]
+fn invalid_questions_are_rejected_on_load() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = temp_dir();
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("meeting_types.json");
+    for (id, pattern) in [("a;b", "^x$"), ("ticket", "(unclosed")] {
+        let meeting_types = serde_json::json!([{
+            "id": "intro",
+            "name": "Intro call",
+            "questions": [{ "id": id, "label": "Ticket", "type": "text", "pattern": pattern }]
+        }]);
+        std::fs::write(&path, meeting_types.to_string())?;
+        assert!(matches!(
+            crate::meeting_type::load_meeting_types(&path),
+            Err(crate::error::SchedulerError::InvalidQuestion(question, _)) if question == id
+        ));
+    }
+
+    // group meeting types share one event, so they cannot wait for approval
+    let meeting_types = serde_json::json!([{
+        "id": "workshop",
+        "name": "Workshop",
+        "capacity": 10,
+        "requires_approval": true
+    }]);
+    std::fs::write(&path, meeting_types.to_string())?;
+    assert!(matches!(
+        crate::meeting_type::load_meeting_types(&path),
+        Err(crate::error::SchedulerError::InvalidMeetingType(id, _)) if id == "workshop"
+    ));
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn booking_answers_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+    let given = answers(&[
+        ("company", one("Acme; Inc")),
+        ("q1,x", one("back\\slash, comma; semi\nline")),
+        (
+            "topics",
+            Answer::Many(vec!["sales".to_string(), "support".to_string()]),
+        ),
+    ]);
+
+    let start = chrono::Utc::now();
+    let mut event = NewEvent::new(start, start + chrono::Duration::minutes(30), "Jane")
+        .property("X-BOOKING-EMAIL", "jane@example.com")
+        .property("X-BOOKING-MEETING-TYPE", "intro");
+    for property in answer_properties(&given, None) {
+        event = event.append_property(property);
+    }
+    let calendar = icalendar::Calendar::new()
+        .push(event.to_ical("booking-id"))
+        .done();
+
+    let event = Event::parse(&calendar.to_string())?;
+    let booking = Booking::from_event(&event).unwrap();
+    assert_eq!(booking.meeting_type.as_deref(), Some("intro"));
+    assert_eq!(booking.answers, given);
+    Ok(())
+}
+
+#[test]
+fn host_token_is_checked() {
+    let davclient = DavClient::new(
+        "http://localhost/".to_string(),
+        DavCredentials::new("user".to_string(), "password".to_string()),
+    )
+    .unwrap();
+    let state = CaldavAvailability::new(None, "Booked".to_string(), davclient);
+    // host endpoints are disabled without a token
+    assert!(state.authorize_host(Some("Bearer secret")).is_err());
+
+    let state = state.with_host_token("secret".to_string());
+    assert!(state.authorize_host(Some("Bearer secret")).is_ok());
+    for given in [
+        None,
+        Some("secret"),
+        Some("Bearer secreT"),
+        Some("Bearer secre"),
+    ] {
+        assert!(state.authorize_host(given).is_err());
+    }
+}
+
+#[test]
+fn tentative_holds_expire() {
+    let now = chrono::DateTime::parse_from_rfc3339("2023-01-10T12:00:00Z")
+        .unwrap()
+        .with_timezone(&chrono::Utc);
+    let expiry = chrono::Duration::hours(24);
+
+    let mut fresh = build_booking();
+    fresh.id = "fresh".to_string();
+    fresh.status = BookingStatus::Tentative;
+    fresh.created = Some(now - chrono::Duration::hours(1));
+
+    let mut stale = fresh.clone();
+    stale.id = "stale".to_string();
+    stale.created = Some(now - chrono::Duration::hours(25));
+
+    // confirmed bookings never expire
+    let mut confirmed = stale.clone();
+    confirmed.id = "confirmed".to_string();
+    confirmed.status = BookingStatus::Confirmed;
+
+    let expired = expired_holds(&[fresh.clone(), stale, confirmed], expiry, now);
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].id, "stale");
+
+    // nor are reminders sent for bookings awaiting approval
+    let path = temp_dir().join("reminders.json");
+    let store = ReminderStore::load(path).unwrap();
+    let due = due_reminders(&[fresh], &[chrono::Duration::days(3)], &store, now);
+    assert!(due.is_empty());
+}
+
+#[test]
+fn tentative_booking_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+    let start = chrono::Utc::now();
+    let event = NewEvent::new(start, start + chrono::Duration::minutes(30), "Jane")
+        .status(BookingStatus::Tentative.to_ical())
+        .property("X-BOOKING-EMAIL", "jane@example.com")
+        .property("X-BOOKING-CREATED", "20230110T120000Z");
+    let calendar = icalendar::Calendar::new()
+        .push(event.to_ical("booking-id"))
+        .done();
+
+    let mut event = Event::parse(&calendar.to_string())?;
+    let booking = Booking::from_event(&event).unwrap();
+    assert_eq!(booking.status, BookingStatus::Tentative);
+    assert_eq!(
+        booking.created.map(|c| c.to_rfc3339()).as_deref(),
+        Some("2023-01-10T12:00:00+00:00")
+    );
+    let invite = crate::notify::template::invite(NotificationKind::Requested, &booking);
+    assert!(invite.contains("STATUS:TENTATIVE"));
+
+    event.set_status(icalendar::EventStatus::Confirmed);
+    let booking = Booking::from_event(&event).unwrap();
+    assert_eq!(booking.status, BookingStatus::Confirmed);
+    assert_eq!(booking.sequence, 1);
+    Ok(())
+}
+
+#[test]
+fn holds_reserve_slots_until_they_expire() {
+    let now = chrono::DateTime::parse_from_rfc3339("2023-01-10T12:00:00Z")
+        .unwrap()
+        .with_timezone(&chrono::Utc);
+    let start = now + chrono::Duration::days(1);
+    let end = start + chrono::Duration::minutes(30);
+    let changes = AvailabilityChanges::default();
+    let holds = Holds::new(chrono::Duration::minutes(10)).with_changes(changes.clone());
+    let mut receiver = changes.subscribe();
+    let mut told = || std::iter::from_fn(|| receiver.try_recv().ok()).count();
+
+    let hold = holds.place(start, end, now, &[]).unwrap();
+    assert_eq!(told(), 1);
+    assert_eq!(hold.expires, now + chrono::Duration::minutes(10));
+    assert!(hold.covers(start, end));
+    assert_eq!(holds.busy(now, None), vec![(start, end)]);
+    assert!(holds.busy(now, Some(&hold.id)).is_empty());
+
+    // overlapping slots cannot be held twice, but adjacent ones can
+    let overlapping = holds.place(start + chrono::Duration::minutes(15), end, now, &[]);
+    assert!(overlapping.is_err());
+    assert!(holds
+        .place(end, end + chrono::Duration::minutes(30), now, &[])
+        .is_ok());
+    assert_eq!(told(), 1);
+
+    // once expired, the hold can no longer be used and the slot is free again
+    let later = now + chrono::Duration::minutes(11);
+    assert!(holds.get(&hold.id, now).is_ok());
+    assert!(holds.get(&hold.id, later).is_err());
+    assert!(holds.busy(later, None).is_empty());
+    assert_eq!(holds.release_expired(later), 2);
+    assert_eq!(told(), 1);
+    let hold = holds.place(start, end, later, &[]).unwrap();
+    holds.release(&hold.id);
+    assert_eq!(told(), 2);
+    holds.release(&hold.id);
+    assert_eq!(told(), 0);
+}
+
+#[test]
+fn holds_are_limited_per_owner() {
+    let now = chrono::Utc::now();
+    let start = now + chrono::Duration::days(1);
+    let slot = chrono::Duration::minutes(30);
+    let holds = Holds::new(chrono::Duration::minutes(10)).with_max_per_owner(2);
+    let owners = [
+        "ip:127.0.0.1".to_string(),
+        "email:jane@example.com".to_string(),
+    ];
+
+    // empty, reversed and past ranges can't be held
+    assert!(holds.place(start, start, now, &owners).is_err());
+    assert!(holds.place(start + slot, start, now, &owners).is_err());
+    assert!(holds
+        .place(now - slot * 2, now - slot, now, &owners)
+        .is_err());
+
+    assert!(holds.place(start, start + slot, now, &owners[..1]).is_ok());
+    assert!(holds
+        .place(start + slot, start + slot * 2, now, &owners)
+        .is_ok());
+    // the address has used up its holds, while the email has one left
+    let third = holds.place(start + slot * 2, start + slot * 3, now, &owners);
+    assert!(matches!(
+        third,
+        Err(crate::error::SchedulerError::TooManyHolds)
+    ));
+    assert!(holds
+        .place(start + slot * 2, start + slot * 3, now, &owners[1..])
+        .is_ok());
+
+    // expired holds no longer count
+    let later = now + chrono::Duration::minutes(11);
+    assert!(holds
+        .place(start + slot * 3, start + slot * 4, later, &owners)
+        .is_ok());
+}
+
+#[test]
+fn group_event_seats() -> Result<(), Box<dyn std::error::Error>> {
+    let start =
+        chrono::DateTime::parse_from_rfc3339("2023-01-12T14:00:00Z")?.with_timezone(&chrono::Utc);
+    let granularity = chrono::Duration::minutes(30);
+    let hour = chrono::Duration::hours(1);
+
+    let mut workshop = NewEvent::new(start, start + hour, "Workshop")
+        .property("X-BOOKING-CAPACITY", "3")
+        .property("X-BOOKING-MEETING-TYPE", "workshop");
+    for email in ["first@example.com", "second@example.com"] {
+        workshop = workshop.attendee(caldav_utils::caldav::event::Attendee::new(
+            email.to_string(),
+            None,
+        ));
+        let answers = answers(&[("company", one(email))]);
+        for property in answer_properties(&answers, Some(email)) {
+            workshop = workshop.append_property(property);
+        }
+    }
+    let calendar = icalendar::Calendar::new()
+        .push(workshop.to_ical("workshop-id"))
+        .done();
+    let mut event = Event::parse(&calendar.to_string())?;
+    assert!(crate::group::is_group_event_of(&event, "workshop"));
+
+    // each attendee and held seat takes one of the seats in the slots it covers
+    let seats = seats_matrix(
+        start,
+        start + hour * 2,
+        granularity,
+        3,
+        &[event.clone()],
+        &[(start + hour, start + hour * 2)],
+        None,
+    )?;
+    let booked = seats_matrix(
+        start,
+        start + hour * 2,
+        granularity,
+        3,
+        &[event.clone()],
+        &[],
+        None,
+    )?;
+    let attended = get_event_matrix(start, start + hour * 2, granularity, &event, None)?;
+    let held = ranges_matrix(
+        start,
+        start + hour * 2,
+        granularity,
+        &[(start + hour, start + hour * 2)],
+    )?;
+    for i in 0..booked.len() {
+        assert_eq!(booked[i], if attended[i] { 1 } else { 3 });
+        assert_eq!(seats[i], booked[i] - held[i] as u32);
+    }
+
+    // each attendee sees their own answers
+    let booking = Booking::for_attendee(&event, "second@example.com").unwrap();
+    assert_eq!(booking.email, "second@example.com");
+    assert_eq!(booking.capacity, Some(3));
+    assert_eq!(
+        booking.answers,
+        answers(&[("company", one("second@example.com"))])
+    );
+    assert!(Booking::for_attendee(&event, "third@example.com").is_none());
+
+    // giving up a seat removes the attendee and their answers
+    event.remove_attendee("first@example.com");
+    event.retain_properties(|p| !is_answer_from(p, Some("first@example.com")));
+    let event = Event::parse(&event.ical.to_string())?;
+    assert_eq!(event.attendees().len(), 1);
+    assert_eq!(
+        event
+            .properties_named(crate::booking::ANSWER_PROPERTY)
+            .len(),
+        1
+    );
+    Ok(())
+}
+
+#[test]
+fn seat_holds_share_slots() {
+    let now = chrono::Utc::now();
+    let start = now + chrono::Duration::days(1);
+    let end = start + chrono::Duration::hours(1);
+    let holds = Holds::new(chrono::Duration::minutes(10));
+
+    let hold = holds
+        .place_seat(start, end, now, "workshop", 2, &[])
+        .unwrap();
+    assert_eq!(hold.meeting_type.as_deref(), Some("workshop"));
+    assert!(holds
+        .place_seat(start, end, now, "workshop", 2, &[])
+        .is_ok());
+    // every remaining seat is held
+    assert!(holds
+        .place_seat(start, end, now, "workshop", 2, &[])
+        .is_err());
+    // the slot cannot be held for anything else
+    assert!(holds.place_seat(start, end, now, "other", 5, &[]).is_err());
+    assert!(holds.place(start, end, now, &[]).is_err());
+}
+
+#[test]
+fn recurring_booking_series() -> Result<(), Box<dyn std::error::Error>> {
+    let recurrence: Recurrence = serde_json::from_value(serde_json::json!({
+        "frequency": "weekly",
+        "count": 8,
+        "skip_conflicts": true
+    }))?;
+    assert_eq!(recurrence.frequency, Frequency::Weekly);
+    assert_eq!(recurrence.interval, 1);
+    assert!(recurrence.validate().is_ok());
+    assert_eq!(recurrence.to_rrule(), "FREQ=WEEKLY;INTERVAL=1;COUNT=8");
+
+    let start =
+        chrono::DateTime::parse_from_rfc3339("2023-01-10T14:00:00Z")?.with_timezone(&chrono::Utc);
+    let end = start + chrono::Duration::minutes(30);
+    let occurrences = recurrence.occurrences(start, end, chrono_tz::UTC);
+    assert_eq!(occurrences.len(), 8);
+    assert_eq!(occurrences[0], (start, end));
+    assert_eq!(
+        occurrences[7].0,
+        chrono::DateTime::parse_from_rfc3339("2023-02-28T14:00:00Z")?
+    );
+
+    let too_many = Recurrence {
+        count: 100,
+        interval: 0,
+        ..recurrence.clone()
+    };
+    let fields: Vec<String> = too_many
+        .validate()
+        .unwrap_err()
+        .into_iter()
+        .map(|e| e.field)
+        .collect();
+    assert_eq!(fields, ["recurrence.interval", "recurrence.count"]);
+
+    // skipped occurrences are stored as EXDATEs and sent in invites
+    let skipped = occurrences[2].0;
+    let event = NewEvent::new(start, end, "Jane")
+        .property("X-BOOKING-EMAIL", "jane@example.com")
+        .property("RRULE", &recurrence.to_rrule())
+        .append_property(exdate_property(skipped));
+    let calendar = icalendar::Calendar::new()
+        .push(event.to_ical("booking-id"))
+        .done();
+    let booking = Booking::from_event(&Event::parse(&calendar.to_string())?).unwrap();
+    assert_eq!(
+        booking.recurrence.as_deref(),
+        Some("FREQ=WEEKLY;INTERVAL=1;COUNT=8")
+    );
+    assert_eq!(booking.skipped, vec![skipped]);
+
+    let invite = crate::notify::template::invite(NotificationKind::Confirmation, &booking);
+    assert!(invite.contains("RRULE:FREQ=WEEKLY;INTERVAL=1;COUNT=8"));
+    assert!(invite.contains("EXDATE:20230124T140000Z"));
+    Ok(())
+}
+
+#[test]
+fn recurring_bookings_keep_their_local_time() -> Result<(), Box<dyn std::error::Error>> {
+    let recurrence = Recurrence {
+        frequency: Frequency::Weekly,
+        interval: 1,
+        count: 3,
+        skip_conflicts: false,
+    };
+    // the clocks in London go forward on the 26th of March
+    let start =
+        chrono::DateTime::parse_from_rfc3339("2023-03-21T14:00:00Z")?.with_timezone(&chrono::Utc);
+    let end = start + chrono::Duration::minutes(30);
+    let occurrences = recurrence.occurrences(start, end, chrono_tz::Europe::London);
+    let after_change =
+        chrono::DateTime::parse_from_rfc3339("2023-03-28T13:00:00Z")?.with_timezone(&chrono::Utc);
+    assert_eq!(occurrences[1], (after_change, after_change + (end - start)));
+
+    // the stored series is expanded to the same occurrences
+    let event = NewEvent::new(start, end, "Jane")
+        .property("X-BOOKING-EMAIL", "jane@example.com")
+        .timezone(chrono_tz::Europe::London)
+        .property("RRULE", &recurrence.to_rrule());
+    let calendar = event.to_calendar("booking-id").to_string();
+    assert!(calendar.contains("BEGIN:VTIMEZONE\r\n"));
+    let event = Event::parse(&calendar)?;
+    assert_eq!(event.start(), Some(start));
+    let bookings = Booking::occurrences(&event, start, start + chrono::Duration::weeks(4), None);
+    let expanded: Vec<_> = bookings.iter().map(|b| (b.start, b.end)).collect();
+    assert_eq!(expanded, occurrences);
+    assert_eq!(bookings[0].timezone.as_deref(), Some("Europe/London"));
+
+    // so each occurrence is reminded of, not just the first
+    let store = ReminderStore::load(temp_dir().join("reminders.json"))?;
+    let now = after_change - chrono::Duration::minutes(30);
+    let due = due_reminders(&bookings, &[chrono::Duration::hours(1)], &store, now);
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].0.start, after_change);
+
+    // and the availability matrix agrees with them
+    let (range_start, range_end) = (
+        after_change - chrono::Duration::hours(1),
+        after_change + chrono::Duration::hours(1),
+    );
+    let granularity = chrono::Duration::minutes(30);
+    assert_eq!(
+        get_event_matrix(range_start, range_end, granularity, &event, None)?,
+        ranges_matrix(range_start, range_end, granularity, &[occurrences[1]])?
+    );
+
+    let invite = crate::notify::template::invite(NotificationKind::Confirmation, &bookings[0]);
+    assert!(invite.contains("DTSTART;TZID=Europe/London:20230321T140000"));
+    // along with the timezone it refers to
+    assert!(invite.contains("BEGIN:VTIMEZONE\r\n"));
+    assert!(invite.contains("TZID:Europe/London\r\n"));
+    assert!(invite.contains("RRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU\r\n"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn blackouts_cover_whole_days() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = temp_dir();
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("holidays.ics");
+    std::fs::write(
+        &path,
+        "BEGIN:VCALENDAR\r\n\
+         VERSION:2.0\r\n\
+         PRODID:test\r\n\
+         BEGIN:VEVENT\r\n\
+         UID:boxing-day\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART;VALUE=DATE:20231226\r\n\
+         SUMMARY:Boxing Day\r\n\
+         END:VEVENT\r\n\
+         BEGIN:VEVENT\r\n\
+         UID:party\r\n\
+         DTSTAMP:20231201T000000Z\r\n\
+         DTSTART:20231224T180000Z\r\n\
+         DTEND:20231224T200000Z\r\n\
+         SUMMARY:Party\r\n\
+         END:VEVENT\r\n\
+         END:VCALENDAR\r\n",
+    )?;
+
+    let davclient = DavClient::new(
+        "http://localhost:5232".to_string(),
+        DavCredentials::new("user".to_string(), "pass".to_string()),
+    )?;
+    let state = CaldavAvailability::new(
+        Some("available".to_string()),
+        "booked".to_string(),
+        davclient,
+    )
+    .with_blackouts(vec![
+        BlackoutSource::Dates {
+            start: chrono::NaiveDate::from_ymd_opt(2023, 12, 25).unwrap(),
+            end: chrono::NaiveDate::from_ymd_opt(2023, 12, 25).unwrap(),
+            reason: Some("Christmas".to_string()),
+        },
+        BlackoutSource::File { path: path.clone() },
+    ]);
+
+    let start = chrono::DateTime::parse_from_rfc3339("2023-12-24T00:00:00Z")?.into();
+    let end = chrono::DateTime::parse_from_rfc3339("2023-12-28T00:00:00Z")?.into();
+    let granularity = chrono::Duration::minutes(30);
+    let matrix = blackout_matrix(&state, start, end, granularity).await?;
+
+    let day = |d: u32| -> Result<_, Box<dyn std::error::Error>> {
+        let from = chrono::DateTime::parse_from_rfc3339(&format!("2023-12-{d}T00:00:00Z"))?;
+        Ok((from.into(), (from + chrono::Duration::days(1)).into()))
+    };
+    let party = (
+        chrono::DateTime::parse_from_rfc3339("2023-12-24T18:00:00Z")?.into(),
+        chrono::DateTime::parse_from_rfc3339("2023-12-24T20:00:00Z")?.into(),
+    );
+    let expected = ranges_matrix(start, end, granularity, &[party, day(25)?, day(26)?])?;
+    assert_eq!(matrix, expected);
+    assert!(matrix[24 * 2 + 24]);
+    assert!(!matrix[12 * 2]);
+    assert!(!matrix[3 * 48 + 24]);
+
+    // the file is read again once it changes, and all-day events recur on every day they fall on
+    std::fs::write(
+        &path,
+        "BEGIN:VCALENDAR\r\n\
+         VERSION:2.0\r\n\
+         PRODID:test\r\n\
+         BEGIN:VEVENT\r\n\
+         UID:founders-day\r\n\
+         DTSTAMP:20191201T000000Z\r\n\
+         DTSTART;VALUE=DATE:20191227\r\n\
+         RRULE:FREQ=YEARLY\r\n\
+         SUMMARY:Founders Day\r\n\
+         END:VEVENT\r\n\
+         END:VCALENDAR\r\n",
+    )?;
+    std::fs::File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60))?;
+    let matrix = blackout_matrix(&state, start, end, granularity).await?;
+    let expected = ranges_matrix(start, end, granularity, &[day(25)?, day(27)?])?;
+    assert_eq!(matrix, expected);
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn discovered_calendars_are_cached() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let davclient = || {
+        DavClient::new(
+            server.url(),
+            DavCredentials::new(server.username().to_string(), server.password().to_string()),
+        )
+        .unwrap()
+    };
+    let state = CaldavAvailability::new(None, "Booked".to_string(), davclient());
+    let start = chrono::Utc::now();
+    let end = start + chrono::Duration::days(1);
+
+    let booked = state.calendar("Booked").await?;
+    server.clear_requests();
+    assert_eq!(state.calendar("Booked").await?.path(), booked.path());
+    assert!(server.requests().is_empty());
+
+    // the calendar is replaced elsewhere, so the cached one is gone from the server
+    let url = reqwest::Url::parse(&server.url())?.join(booked.path())?;
+    reqwest::Client::new()
+        .delete(url)
+        .basic_auth(server.username(), Some(server.password()))
+        .send()
+        .await?;
+    davclient()
+        .get_principal()
+        .await?
+        .create_calendar_mkcol("Booked")
+        .await?;
+    assert!(matches!(
+        booked.events(start, end).await,
+        Err(CaldavError::CalendarNotFound { .. })
+    ));
+    let replaced = state.calendar("Booked").await?;
+    assert_ne!(replaced.path(), booked.path());
+    assert!(replaced.events(start, end).await?.is_empty());
+
+    // without a ttl the calendars are discovered every time
+    let state = state.with_discovery_ttl(chrono::Duration::zero());
+    state.calendar("Booked").await?;
+    server.clear_requests();
+    state.calendar("Booked").await?;
+    assert!(!server.requests().is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn availability_cache_follows_calendar_changes() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("available", "Available")
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let day = (chrono::Utc::now() + chrono::Duration::days(1)).date_naive();
+    let event = |uid: &str, start: &str, end: &str| {
+        let ical = format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\nBEGIN:VEVENT\r\n\
+             UID:{uid}\r\nDTSTAMP:20231201T000000Z\r\n\
+             DTSTART:{day}T{start}Z\r\nDTEND:{day}T{end}Z\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+            day = day.format("%Y%m%d")
+        );
+        (format!("{uid}.ics"), ical)
+    };
+    let (name, ical) = event("open", "090000", "170000");
+    server.put_item("available", &name, &ical);
+
+    let davclient = DavClient::new(
+        server.url(),
+        DavCredentials::new(server.username().to_string(), server.password().to_string()),
+    )?;
+    let cache = AvailabilityCache::new(chrono::Duration::days(3));
+    let state = CaldavAvailability::new(
+        Some("Available".to_string()),
+        "Booked".to_string(),
+        davclient,
+    )
+    .with_availability_cache(cache.clone());
+    let start = day.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let end = start + chrono::Duration::days(1);
+    let direct = || async {
+        let (available, booked) = get_calendars(state.clone()).await?;
+        available_slots(
+            &state,
+            (available.as_deref(), booked.as_ref()),
+            (start, end),
+            &[],
+        )
+        .await
+    };
+
+    assert!(cache.refresh(&state).await?);
+    let cached = cache.get(start, end, chrono::Utc::now()).unwrap();
+    assert_eq!(cached.matrix, direct().await?.matrix);
+    assert_eq!(cached.matrix.iter().filter(|open| **open).count(), 16);
+    // slots which don't line up with the cached ones, or a cache which is out of date, aren't used
+    let offset = start + chrono::Duration::minutes(10);
+    assert!(cache.get(offset, end, chrono::Utc::now()).is_none());
+    assert!(cache
+        .get(start, end, chrono::Utc::now() + chrono::Duration::hours(1))
+        .is_none());
+
+    // nothing is computed while the calendars are unchanged
+    assert!(!cache.refresh(&state).await?);
+    let (name, ical) = event("booked", "100000", "110000");
+    server.put_item("booked", &name, &ical);
+    assert!(cache.refresh(&state).await?);
+    let cached = cache.get(start, end, chrono::Utc::now()).unwrap();
+    assert_eq!(cached.matrix, direct().await?.matrix);
+    assert_eq!(cached.matrix.iter().filter(|open| **open).count(), 14);
+
+    // bookings invalidate the cache until it is computed again,
+    // and streaming clients are only told once it has been
+    let mut changes = state.changes().subscribe();
+    cache.invalidate();
+    assert!(cache.get(start, end, chrono::Utc::now()).is_none());
+    assert!(changes.try_recv().is_err());
+    assert!(cache.refresh(&state).await?);
+    assert!(cache.get(start, end, chrono::Utc::now()).is_some());
+    assert!(changes.try_recv().is_ok());
+
+    // even when the availability, like that of a group event with seats left, is the same
+    cache.invalidate();
+    assert!(cache.refresh(&state).await?);
+    assert!(changes.try_recv().is_ok());
+    // or it can't be computed, when requests read the calendars themselves
+    cache.invalidate();
+    server.fail_next(1, axum::http::StatusCode::FORBIDDEN);
+    assert!(cache.refresh(&state).await.is_err());
+    assert!(changes.try_recv().is_ok());
+    // but only once for each change
+    server.fail_next(1, axum::http::StatusCode::FORBIDDEN);
+    assert!(cache.refresh(&state).await.is_err());
+    assert!(changes.try_recv().is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn availability_cache_follows_feed_and_blackout_changes(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("available", "Available")
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let day = (chrono::Utc::now() + chrono::Duration::days(1)).date_naive();
+    let calendar = |events: &[(&str, &str, &str)]| {
+        let mut ical = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\n".to_string();
+        for (uid, start, end) in events {
+            ical += &format!(
+                "BEGIN:VEVENT\r\nUID:{uid}\r\nDTSTAMP:20231201T000000Z\r\n\
+                 DTSTART:{day}T{start}Z\r\nDTEND:{day}T{end}Z\r\nEND:VEVENT\r\n",
+                day = day.format("%Y%m%d")
+            );
+        }
+        ical + "END:VCALENDAR\r\n"
+    };
+    server.put_item(
+        "available",
+        "open.ics",
+        &calendar(&[("open", "090000", "170000")]),
+    );
+    let dir = temp_dir();
+    std::fs::create_dir_all(&dir)?;
+    let feed = dir.join("feed.ics");
+    let blackout = dir.join("blackout.ics");
+    std::fs::write(&feed, calendar(&[]))?;
+    std::fs::write(&blackout, calendar(&[]))?;
+
+    let davclient = DavClient::new(
+        server.url(),
+        DavCredentials::new(server.username().to_string(), server.password().to_string()),
+    )?;
+    let cache = AvailabilityCache::new(chrono::Duration::days(3));
+    let state = CaldavAvailability::new(
+        Some("Available".to_string()),
+        "Booked".to_string(),
+        davclient,
+    )
+    .with_busy_feeds(vec![IcsSource::file(&feed)])
+    .with_blackouts(vec![BlackoutSource::File {
+        path: blackout.clone(),
+    }])
+    .with_availability_cache(cache.clone());
+    let start = day.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let end = start + chrono::Duration::days(1);
+    let open = || {
+        cache
+            .get(start, end, chrono::Utc::now())
+            .map(|cached| cached.matrix.iter().filter(|open| **open).count())
+    };
+
+    assert!(cache.refresh(&state).await?);
+    assert_eq!(open(), Some(16));
+    assert!(!cache.refresh(&state).await?);
+
+    // edits to the feed and the blackouts are picked up without waiting for max_age
+    std::fs::write(&feed, calendar(&[("meeting", "100000", "110000")]))?;
+    assert!(cache.refresh(&state).await?);
+    assert_eq!(open(), Some(14));
+    std::fs::write(&blackout, calendar(&[("holiday", "000000", "120000")]))?;
+    assert!(cache.refresh(&state).await?);
+    assert_eq!(open(), Some(10));
+    assert!(!cache.refresh(&state).await?);
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn availability_updates_follow_changes() -> Result<(), Box<dyn std::error::Error>> {
+    use futures_util::StreamExt;
+
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("available", "Available")
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let event = |uid: &str, start: &str, end: &str| {
+        let ical = format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\nBEGIN:VEVENT\r\n\
+             UID:{uid}\r\nDTSTAMP:20231201T000000Z\r\n\
+             DTSTART:20240110T{start}Z\r\nDTEND:20240110T{end}Z\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n"
+        );
+        (format!("{uid}.ics"), ical)
+    };
+    let (name, ical) = event("open", "090000", "170000");
+    server.put_item("available", &name, &ical);
+
+    let davclient = DavClient::new(
+        server.url(),
+        DavCredentials::new(server.username().to_string(), server.password().to_string()),
+    )?;
+    let state = CaldavAvailability::new(
+        Some("Available".to_string()),
+        "Booked".to_string(),
+        davclient,
+    );
+    let request = SlotRequest {
+        start: chrono::DateTime::parse_from_rfc3339("2024-01-10T08:00:00Z")?.into(),
+        end: chrono::DateTime::parse_from_rfc3339("2024-01-10T12:00:00Z")?.into(),
+        meeting_type: None,
+    };
+    let open = |response: &crate::SlotResponse| {
+        response
+            .availability
+            .matrix
+            .iter()
+            .filter(|open| **open)
+            .count()
+    };
+    let mut updates = Box::pin(availability_updates(state.clone(), request));
+    let mut watcher = ChangeWatcher::new(state.clone());
+    assert!(!watcher.check().await?);
+
+    // the current availability is sent straight away
+    let first = updates.next().await.unwrap()?;
+    assert_eq!(open(&first), 5);
+
+    // a change outside of the requested range isn't sent, but the next one inside it is,
+    // even though both were made directly on the server
+    let (name, ical) = event("later", "150000", "160000");
+    server.put_item("booked", &name, &ical);
+    assert!(watcher.check().await?);
+    let (name, ical) = event("booked", "100000", "110000");
+    server.put_item("booked", &name, &ical);
+    assert!(watcher.check().await?);
+    assert!(!watcher.check().await?);
+    let next = tokio::time::timeout(std::time::Duration::from_secs(5), updates.next())
+        .await?
+        .unwrap()?;
+    assert_eq!(open(&next), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn seat_changes_are_checked_and_retried() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{extract::State, Json};
+
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let davclient = DavClient::new(
+        server.url(),
+        DavCredentials::new(server.username().to_string(), server.password().to_string()),
+    )?;
+    let state = CaldavAvailability::new(None, "Booked".to_string(), davclient);
+    let booked = state.calendar("Booked").await?;
+    let start = chrono::Utc::now() + chrono::Duration::days(1);
+    let attendee =
+        |email: &str| caldav_utils::caldav::event::Attendee::new(email.to_string(), None);
+    let workshop = NewEvent::new(start, start + chrono::Duration::hours(1), "Workshop")
+        .property("X-BOOKING-CAPACITY", "2")
+        .property("X-BOOKING-MEETING-TYPE", "workshop")
+        .attendee(attendee("first@example.com"))
+        .append_property(seat_token_property("first@example.com", "first-token"));
+    let uid = booked
+        .create_event(&workshop)
+        .await?
+        .uid()
+        .unwrap()
+        .to_string();
+
+    // a write based on an outdated read is refused instead of losing the other change
+    let (stale, etag) = booked.get_event_with_etag(&uid).await?;
+    assert!(etag.is_some());
+    let mut other = booked.get_event(&uid).await?;
+    other.add_attendee(&attendee("second@example.com"));
+    booked.update_event(&other).await?;
+    assert!(matches!(
+        booked.update_event_if_match(&stale, etag.as_deref()).await,
+        Err(CaldavError::EventChanged { .. })
+    ));
+
+    // seats are counted again on the latest version of the event
+    let full = crate::update_seats(booked.as_ref(), &uid, |event| {
+        match booking::capacity(event) {
+            Some(seats) if event.attendees().len() >= seats as usize => {
+                Err(crate::error::SchedulerError::TimeNotAvailable(start))
+            }
+            _ => Ok(()),
+        }
+    })
+    .await;
+    assert!(full.is_err());
+
+    // an attendee's seat can only be given up with the token they were given for it
+    let cancel = |token: Option<&str>| {
+        crate::request_cancel(
+            State(state.clone()),
+            Json(crate::CancelRequest {
+                id: uid.clone(),
+                email: Some("first@example.com".to_string()),
+                token: token.map(|t| t.to_string()),
+            }),
+        )
+    };
+    assert!(matches!(
+        cancel(None).await,
+        Err(crate::error::SchedulerError::Unauthorized)
+    ));
+    assert!(matches!(
+        cancel(Some("second-token")).await,
+        Err(crate::error::SchedulerError::Unauthorized)
+    ));
+    let Json(cancelled) = cancel(Some("first-token")).await?;
+    assert_eq!(cancelled.email, "first@example.com");
+    let event = booked.get_event(&uid).await?;
+    assert_eq!(event.attendees().len(), 1);
+    assert!(event
+        .properties_named(booking::SEAT_TOKEN_PROPERTY)
+        .is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn bookings_are_validated_and_changed_only_with_their_token(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{extract::State, Json};
+
+    let server = MockCaldav::new(Quirks::radicale())
+        .with_calendar("available", "Available")
+        .with_calendar("booked", "Booked")
+        .start()
+        .await?;
+    let day = (chrono::Utc::now() + chrono::Duration::days(1)).date_naive();
+    server.put_item(
+        "available",
+        "open.ics",
+        &format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\nBEGIN:VEVENT\r\n\
+             UID:open\r\nDTSTAMP:20231201T000000Z\r\n\
+             DTSTART:{day}T090000Z\r\nDTEND:{day}T170000Z\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+            day = day.format("%Y%m%d")
+        ),
+    );
+    let davclient = DavClient::new(
+        server.url(),
+        DavCredentials::new(server.username().to_string(), server.password().to_string()),
+    )?;
+    let state = CaldavAvailability::new(
+        Some("Available".to_string()),
+        "Booked".to_string(),
+        davclient,
+    );
+    let start = day.and_hms_opt(10, 0, 0).unwrap().and_utc();
+    let request = |email: &str| crate::BookingRequest {
+        start,
+        end: start + chrono::Duration::minutes(30),
+        name: "Jane Doe".to_string(),
+        email: email.to_string(),
+        description: "talk about things".to_string(),
+        meeting_type: None,
+        answers: BTreeMap::new(),
+        hold: None,
+        recurrence: None,
+    };
+
+    // an address mail can't be sent to is rejected before anything is booked
+    match crate::request_booking(State(state.clone()), Json(request("jane at example"))).await {
+        Err(crate::error::SchedulerError::InvalidFields(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].field, "email");
+        }
+        other => panic!("expected an invalid email, got {other:?}"),
+    }
+    assert!(server.items("booked").is_empty());
+
+    let Json(booking) =
+        crate::request_booking(State(state.clone()), Json(request("jane@example.com"))).await?;
+    let token = booking.cancel_token.clone().unwrap();
+
+    // the uid alone, which notifications and webhooks carry, isn't enough
+    let reschedule = |token: Option<&str>| {
+        crate::request_reschedule(
+            State(state.clone()),
+            Json(crate::RescheduleRequest {
+                id: booking.id.clone(),
+                start: start + chrono::Duration::hours(2),
+                end: start + chrono::Duration::hours(2) + chrono::Duration::minutes(30),
+                token: token.map(|t| t.to_string()),
+            }),
+        )
+    };
+    assert!(matches!(
+        reschedule(None).await,
+        Err(crate::error::SchedulerError::Unauthorized)
+    ));
+    assert!(matches!(
+        reschedule(Some("guessed")).await,
+        Err(crate::error::SchedulerError::Unauthorized)
+    ));
+    let Json(moved) = reschedule(Some(&token)).await?;
+    assert_eq!(moved.start, start + chrono::Duration::hours(2));
+
+    let cancel = |token: Option<&str>| {
+        crate::request_cancel(
+            State(state.clone()),
+            Json(crate::CancelRequest {
+                id: booking.id.clone(),
+                email: None,
+                token: token.map(|t| t.to_string()),
+            }),
+        )
+    };
+    assert!(matches!(
+        cancel(None).await,
+        Err(crate::error::SchedulerError::Unauthorized)
+    ));
+    assert_eq!(server.items("booked").len(), 1);
+    let Json(cancelled) = cancel(Some(&token)).await?;
+    assert_eq!(cancelled.id, booking.id);
+    assert!(server.items("booked").is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_state_needs_no_caldav_server() -> Result<(), Box<dyn std::error::Error>> {
+    use caldav_utils::{caldav::calendar::NewCalendar, vdir::Vdir};
+
+    let root = std::env::temp_dir().join(ksuid::Ksuid::generate().to_base62());
+    std::fs::create_dir_all(&root)?;
+    let state = CaldavAvailability::local(None, "Booked".to_string(), Vdir::new(root.clone()));
+    assert!(state.davclient().is_none());
+
+    state
+        .create_calendar(&NewCalendar::new("Booked").timezone("UTC"))
+        .await?;
+    let booked = state.calendar("Booked").await?;
+    assert_eq!(booked.display_name(), "Booked");
+    assert_eq!(booked.timezone().as_deref(), Some("UTC"));
+    assert_eq!(state.calendars().await?.len(), 1);
+
+    std::fs::remove_dir_all(root)?;
+    Ok(())
+}
@@ -0,0 +1,360 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    sync::Arc,
+};
+
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    booking::{Booking, BookingStatus},
+    error::{SchedulerError, SchedulerResult},
+    meeting_type::Answer,
+};
+
+pub mod queue;
+
+use queue::{Delivery, DeliveryQueue, DeliveryStatus};
+
+/// The header containing the signature of a webhook payload
+pub static SIGNATURE_HEADER: &str = "X-Scheduler-Signature";
+
+/// A change to a booking which subscribers can be notified of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum LifecycleEvent {
+    #[serde(rename = "booking.created")]
+    Booked,
+    #[serde(rename = "booking.cancelled")]
+    Cancelled,
+    #[serde(rename = "booking.rescheduled")]
+    Rescheduled,
+    #[serde(rename = "booking.approved")]
+    Approved,
+    #[serde(rename = "booking.declined")]
+    Declined,
+    /// A tentative booking was not approved in time
+    #[serde(rename = "booking.expired")]
+    Expired,
+}
+
+/// An endpoint that receives webhook deliveries.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Subscription {
+    /// Identifies the subscription to its queued deliveries, so that its url or secret
+    /// can be changed without losing them. Defaults to the url.
+    #[serde(default)]
+    pub id: Option<String>,
+    pub url: String,
+    /// The key used to sign payloads sent to this subscription
+    pub secret: String,
+    /// The events to send. If empty, all events are sent.
+    #[serde(default)]
+    pub events: Vec<LifecycleEvent>,
+}
+
+impl Subscription {
+    pub fn id(&self) -> &str {
+        self.id.as_deref().unwrap_or(&self.url)
+    }
+
+    pub fn wants(&self, event: LifecycleEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+/// Load subscriptions from a JSON file containing a list of them.
+/// Subscriptions sharing a url must be given ids to tell them apart.
+pub fn load_subscriptions(path: &Path) -> SchedulerResult<Vec<Subscription>> {
+    let text = std::fs::read_to_string(path)?;
+    let subscriptions: Vec<Subscription> = serde_json::from_str(&text)?;
+    let mut ids = BTreeSet::new();
+    for subscription in &subscriptions {
+        if !ids.insert(subscription.id()) {
+            return Err(SchedulerError::DuplicateSubscription(
+                subscription.id().to_string(),
+            ));
+        }
+    }
+    Ok(subscriptions)
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Person {
+    pub name: Option<String>,
+    pub email: String,
+}
+
+/// The JSON body sent to subscribers.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Payload {
+    /// unique per delivery, can be used by receivers to discard duplicates
+    pub delivery_id: String,
+    pub event: LifecycleEvent,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub booking_id: String,
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+    pub status: BookingStatus,
+    pub attendee: Person,
+    pub host: Option<Person>,
+    pub meeting_type: Option<String>,
+    /// Answers to the meeting type's questions, keyed by question id
+    pub answers: BTreeMap<String, Answer>,
+    /// The RRULE of a recurring booking
+    pub recurrence: Option<String>,
+    /// Occurrences of a recurring booking that were skipped
+    pub skipped: Vec<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Payload {
+    pub fn new(delivery_id: String, event: LifecycleEvent, booking: &Booking) -> Payload {
+        Payload {
+            delivery_id,
+            event,
+            created_at: chrono::Utc::now(),
+            booking_id: booking.id.clone(),
+            start: booking.start,
+            end: booking.end,
+            status: booking.status,
+            attendee: Person {
+                name: Some(booking.name.clone()),
+                email: booking.email.clone(),
+            },
+            host: booking.host_email.as_ref().map(|email| Person {
+                name: booking.host_name.clone(),
+                email: email.clone(),
+            }),
+            meeting_type: booking.meeting_type.clone(),
+            answers: booking.answers.clone(),
+            recurrence: booking.recurrence.clone(),
+            skipped: booking.skipped.clone(),
+        }
+    }
+}
+
+/// Compute the signature header value for a payload.
+/// The signature is a hex encoded HMAC-SHA256 of `{timestamp}.{body}`,
+/// which receivers should recompute and compare to detect forged or replayed requests.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    let signature = hex::encode(mac.finalize().into_bytes());
+    format!("t={timestamp},v1={signature}")
+}
+
+/// How failed deliveries are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: chrono::Duration,
+    pub max_delay: chrono::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 8,
+            base_delay: chrono::Duration::seconds(30),
+            max_delay: chrono::Duration::hours(6),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the next attempt, doubling after each failure.
+    pub fn delay(&self, attempts: u32) -> chrono::Duration {
+        let factor = 2i32.saturating_pow(attempts.saturating_sub(1));
+        let delay = self.base_delay * factor;
+        if delay > self.max_delay || delay < chrono::Duration::zero() {
+            self.max_delay
+        } else {
+            delay
+        }
+    }
+}
+
+/// How long a subscriber has to respond to a delivery before it is treated as failed.
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Queues and sends webhook deliveries to subscribers.
+#[derive(Clone, Debug)]
+pub struct Webhooks {
+    subscriptions: Arc<Vec<Subscription>>,
+    queue: DeliveryQueue,
+    client: reqwest::Client,
+    policy: RetryPolicy,
+    wake: Arc<tokio::sync::Notify>,
+}
+
+impl Webhooks {
+    pub fn new(subscriptions: Vec<Subscription>, queue: DeliveryQueue) -> Webhooks {
+        Webhooks {
+            subscriptions: Arc::new(subscriptions),
+            queue,
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("the webhook client has a valid configuration"),
+            policy: RetryPolicy::default(),
+            wake: Arc::new(tokio::sync::Notify::new()),
+        }
+    }
+
+    /// How long subscribers have to respond, instead of the default of 10 seconds.
+    pub fn with_timeout(mut self, timeout: std::time::Duration) -> SchedulerResult<Self> {
+        self.client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(self)
+    }
+
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn queue(&self) -> &DeliveryQueue {
+        &self.queue
+    }
+
+    /// Queue a delivery to every subscription interested in the event.
+    pub fn enqueue(&self, event: LifecycleEvent, booking: &Booking) -> SchedulerResult<()> {
+        for subscription in self.subscriptions.iter().filter(|s| s.wants(event)) {
+            let id = ksuid::Ksuid::generate().to_base62();
+            let body = serde_json::to_string(&Payload::new(id.clone(), event, booking))?;
+            self.queue.push(&Delivery {
+                id,
+                subscription: Some(subscription.id().to_string()),
+                url: subscription.url.clone(),
+                event,
+                body,
+                attempts: 0,
+                next_attempt: chrono::Utc::now(),
+                last_error: None,
+            })?;
+        }
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Make a single attempt at sending a delivery.
+    async fn send(&self, delivery: &Delivery) -> Result<(), String> {
+        let subscription = self
+            .subscriptions
+            .iter()
+            .find(|s| s.id() == delivery.subscription_id())
+            .ok_or_else(|| format!("no subscription {}", delivery.subscription_id()))?;
+
+        let signature = sign(
+            &subscription.secret,
+            chrono::Utc::now().timestamp(),
+            &delivery.body,
+        );
+        let res = self
+            .client
+            .post(&subscription.url)
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .body(delivery.body.clone())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("subscriber responded with {}", res.status()))
+        }
+    }
+
+    /// Attempt a delivery, then record the outcome in the queue.
+    pub async fn attempt(&self, mut delivery: Delivery) -> SchedulerResult<DeliveryStatus> {
+        delivery.attempts += 1;
+        tracing::debug!(
+            "delivering {} to {} (attempt {})",
+            delivery.id,
+            delivery.url,
+            delivery.attempts
+        );
+
+        match self.send(&delivery).await {
+            Ok(()) => {
+                delivery.last_error = None;
+                self.queue.complete(&delivery, DeliveryStatus::Delivered)?;
+                Ok(DeliveryStatus::Delivered)
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "failed to deliver {} to {}: {}",
+                    delivery.id,
+                    delivery.url,
+                    e
+                );
+                delivery.last_error = Some(e);
+                if delivery.attempts >= self.policy.max_attempts {
+                    self.queue.complete(&delivery, DeliveryStatus::Failed)?;
+                    Ok(DeliveryStatus::Failed)
+                } else {
+                    delivery.next_attempt =
+                        chrono::Utc::now() + self.policy.delay(delivery.attempts);
+                    self.queue.push(&delivery)?;
+                    Ok(DeliveryStatus::Pending)
+                }
+            }
+        }
+    }
+
+    /// Attempt every pending delivery that is due.
+    /// Each subscription's deliveries are sent in order, while subscriptions are sent to
+    /// concurrently so that a slow subscriber doesn't hold up the others.
+    /// Returns the time at which the next pending delivery is due, if any.
+    pub async fn process_due(&self) -> SchedulerResult<Option<chrono::DateTime<chrono::Utc>>> {
+        let now = chrono::Utc::now();
+        let mut due: BTreeMap<String, Vec<Delivery>> = BTreeMap::new();
+        for delivery in self.queue.list(DeliveryStatus::Pending)? {
+            if delivery.next_attempt <= now {
+                due.entry(delivery.subscription_id().to_string())
+                    .or_default()
+                    .push(delivery);
+            }
+        }
+
+        let results = join_all(due.into_values().map(|deliveries| async move {
+            for delivery in deliveries {
+                self.attempt(delivery).await?;
+            }
+            SchedulerResult::Ok(())
+        }))
+        .await;
+        results.into_iter().collect::<SchedulerResult<()>>()?;
+
+        Ok(self
+            .queue
+            .list(DeliveryStatus::Pending)?
+            .iter()
+            .map(|d| d.next_attempt)
+            .min())
+    }
+
+    /// Send deliveries forever, waking when new ones are queued or retries are due.
+    pub async fn run(self) {
+        loop {
+            let next = match self.process_due().await {
+                Ok(next) => next,
+                Err(e) => {
+                    tracing::warn!("failed to process webhook deliveries: {}", e);
+                    Some(chrono::Utc::now() + self.policy.base_delay)
+                }
+            };
+
+            let sleep = next
+                .map(|next| (next - chrono::Utc::now()).to_std().unwrap_or_default())
+                .unwrap_or(std::time::Duration::from_secs(3600));
+            tokio::select! {
+                _ = tokio::time::sleep(sleep) => {},
+                _ = self.wake.notified() => {},
+            }
+        }
+    }
+}
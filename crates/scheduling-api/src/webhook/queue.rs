@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+
+use crate::error::{SchedulerError, SchedulerResult};
+
+use super::LifecycleEvent;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+/// A single payload to be sent to a single subscriber.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Delivery {
+    pub id: String,
+    /// the id of the subscription this delivery is for,
+    /// missing from deliveries queued before subscriptions had ids
+    #[serde(default)]
+    pub subscription: Option<String>,
+    /// the url of the subscription when the delivery was queued
+    pub url: String,
+    pub event: LifecycleEvent,
+    /// the serialized payload, kept as text so that retries sign identical bytes
+    pub body: String,
+    pub attempts: u32,
+    pub next_attempt: chrono::DateTime<chrono::Utc>,
+    pub last_error: Option<String>,
+}
+
+impl Delivery {
+    /// Deliveries queued before subscriptions had ids were sent to the subscription with their url.
+    pub fn subscription_id(&self) -> &str {
+        self.subscription.as_deref().unwrap_or(&self.url)
+    }
+}
+
+/// A queue of webhook deliveries persisted to a directory.
+/// Each delivery is stored as a JSON file in a subdirectory named after its status.
+#[derive(Clone, Debug)]
+pub struct DeliveryQueue {
+    dir: PathBuf,
+}
+
+impl DeliveryQueue {
+    pub fn new(dir: PathBuf) -> SchedulerResult<DeliveryQueue> {
+        for status in [
+            DeliveryStatus::Pending,
+            DeliveryStatus::Delivered,
+            DeliveryStatus::Failed,
+        ] {
+            std::fs::create_dir_all(dir.join(status.as_str()))?;
+        }
+        Ok(DeliveryQueue { dir })
+    }
+
+    fn path(&self, status: DeliveryStatus, id: &str) -> PathBuf {
+        self.dir.join(status.as_str()).join(format!("{id}.json"))
+    }
+
+    fn write(&self, status: DeliveryStatus, delivery: &Delivery) -> SchedulerResult<()> {
+        let path = self.path(status, &delivery.id);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(delivery)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Add a new delivery, or save changes to one that is still pending.
+    pub fn push(&self, delivery: &Delivery) -> SchedulerResult<()> {
+        self.write(DeliveryStatus::Pending, delivery)
+    }
+
+    /// Move a pending delivery to its final status.
+    pub fn complete(&self, delivery: &Delivery, status: DeliveryStatus) -> SchedulerResult<()> {
+        self.write(status, delivery)?;
+        match std::fs::remove_file(self.path(DeliveryStatus::Pending, &delivery.id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// All deliveries with the given status, oldest first.
+    pub fn list(&self, status: DeliveryStatus) -> SchedulerResult<Vec<Delivery>> {
+        let mut deliveries = read_dir(&self.dir.join(status.as_str()))?;
+        deliveries.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(deliveries)
+    }
+
+    pub fn get(&self, id: &str) -> SchedulerResult<Option<(DeliveryStatus, Delivery)>> {
+        for status in [
+            DeliveryStatus::Pending,
+            DeliveryStatus::Delivered,
+            DeliveryStatus::Failed,
+        ] {
+            match std::fs::read_to_string(self.path(status, id)) {
+                Ok(text) => return Ok(Some((status, serde_json::from_str(&text)?))),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Queue a delivery to be sent again, regardless of its current status.
+    pub fn replay(&self, id: &str) -> SchedulerResult<Delivery> {
+        let (status, mut delivery) = self
+            .get(id)?
+            .ok_or_else(|| SchedulerError::DeliveryNotFound(id.to_string()))?;
+
+        delivery.attempts = 0;
+        delivery.next_attempt = chrono::Utc::now();
+        delivery.last_error = None;
+        self.push(&delivery)?;
+        if status != DeliveryStatus::Pending {
+            std::fs::remove_file(self.path(status, id))?;
+        }
+
+        Ok(delivery)
+    }
+}
+
+fn read_dir(dir: &Path) -> SchedulerResult<Vec<Delivery>> {
+    let mut deliveries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let text = std::fs::read_to_string(&path)?;
+        deliveries.push(serde_json::from_str(&text)?);
+    }
+    Ok(deliveries)
+}